@@ -0,0 +1,73 @@
+/*
+    Copyright 2024 - Valentin Obst <coderec@vpao.io>
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+*/
+//! Bakes the n-gram counts of the corpus into the binary such that they do
+//! not have to be recomputed on every invocation.
+
+#[allow(dead_code)]
+#[path = "src/ngrams.rs"]
+mod ngrams;
+
+use ngrams::{write_str, write_varint, NgramCounts, MAGIC, VERSION};
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+
+const CORPUS_DIR: &str = "cpu_rec_corpus";
+
+fn main() {
+    println!("cargo:rerun-if-changed={}", CORPUS_DIR);
+
+    let mut entries: Vec<(String, PathBuf)> = std::fs::read_dir(CORPUS_DIR)
+        .unwrap_or_else(|e| panic!("Could not read {}, see README: {}", CORPUS_DIR, e))
+        .map(|entry| entry.unwrap().path())
+        .filter_map(|path| {
+            let arch = path
+                .file_name()?
+                .to_str()?
+                .strip_suffix(".corpus")?
+                .to_owned();
+            Some((arch, path))
+        })
+        .collect();
+    entries.sort_unstable();
+
+    let counts: Vec<NgramCounts> = thread::scope(|s| {
+        let handles: Vec<_> = entries
+            .iter()
+            .map(|(_, path)| s.spawn(|| count_file(path)))
+            .collect();
+        handles.into_iter().map(|h| h.join().unwrap()).collect()
+    });
+
+    let out_path = Path::new(&std::env::var("OUT_DIR").unwrap()).join("corpus.bin");
+    let mut out = BufWriter::new(File::create(out_path).unwrap());
+    out.write_all(MAGIC).unwrap();
+    out.write_all(&[VERSION]).unwrap();
+    write_varint(&mut out, entries.len() as u64).unwrap();
+    for ((arch, _), counts) in entries.iter().zip(counts.iter()) {
+        write_str(&mut out, arch).unwrap();
+        counts.write_to(&mut out).unwrap();
+    }
+    out.flush().unwrap();
+}
+
+fn count_file(path: &Path) -> NgramCounts {
+    let mut file = File::open(path).unwrap_or_else(|e| panic!("{}: {}", path.display(), e));
+
+    NgramCounts::from_reader(&mut file).unwrap_or_else(|e| panic!("{}: {}", path.display(), e))
+}
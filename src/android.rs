@@ -0,0 +1,215 @@
+/*
+    Copyright 2024 - Valentin Obst <coderec@vpao.io>
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+*/
+//! Components of Android boot images and data of sparse images.
+//!
+//! Boot images consist of a header page followed by the kernel, ramdisk, and,
+//! depending on the header version, a second stage loader, recovery DTBO, and
+//! DTB, each padded to the page size. Gzip compressed components are
+//! decompressed.
+//!
+//! Sparse images consist of chunks that are raw data, fill patterns, or blocks
+//! whose content does not matter. Only runs of raw chunks are analyzed, and
+//! they report their regions by their offset in the unsparsed image.
+
+use crate::container::{decompress_stream, read_u16, read_u32, read_u64, Member};
+
+use log::{debug, warn};
+
+const BOOT_MAGIC: &[u8] = b"ANDROID!";
+/// Page size of version 3 and later boot images.
+const BOOT_PAGE_SIZE_V3: usize = 4096;
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+
+const SPARSE_MAGIC: u32 = 0xed26ff3a;
+const SPARSE_HEADER_SIZE: usize = 28;
+const CHUNK_TYPE_RAW: u16 = 0xcac1;
+const CHUNK_TYPE_FILL: u16 = 0xcac2;
+const CHUNK_TYPE_DONT_CARE: u16 = 0xcac3;
+const CHUNK_TYPE_CRC32: u16 = 0xcac4;
+
+pub fn is_boot_image(data: &[u8]) -> bool {
+    data.starts_with(BOOT_MAGIC)
+}
+
+/// Returns the components of the boot image in `data`, which is the content
+/// of `file`. Compressed components are decompressed to at most `max_size`
+/// bytes.
+pub fn find_boot_components(file: &str, data: &[u8], max_size: usize) -> Vec<(Member, Vec<u8>)> {
+    let Some(version) = read_u32(data, 40) else {
+        warn!("{}: truncated boot image header", file);
+        return Vec::new();
+    };
+
+    // Name, size, and load address of each component, in the order in which
+    // they follow the header.
+    let (page_size, components) = if version >= 3 {
+        (
+            BOOT_PAGE_SIZE_V3,
+            vec![
+                ("kernel", read_u32(data, 8), None),
+                ("ramdisk", read_u32(data, 12), None),
+            ],
+        )
+    } else {
+        let mut components = vec![
+            (
+                "kernel",
+                read_u32(data, 8),
+                read_u32(data, 12).map(u64::from),
+            ),
+            (
+                "ramdisk",
+                read_u32(data, 16),
+                read_u32(data, 20).map(u64::from),
+            ),
+            (
+                "second",
+                read_u32(data, 24),
+                read_u32(data, 28).map(u64::from),
+            ),
+        ];
+        if version >= 1 {
+            components.push(("recovery_dtbo", read_u32(data, 1632), None));
+        }
+        if version >= 2 {
+            components.push(("dtb", read_u32(data, 1648), read_u64(data, 1652)));
+        }
+        (read_u32(data, 36).unwrap_or(0) as usize, components)
+    };
+    if page_size == 0 || !page_size.is_power_of_two() {
+        warn!("{}: invalid boot image page size {}", file, page_size);
+        return Vec::new();
+    }
+    debug!("{}: boot image version {}", file, version);
+
+    let mut offset = page_size;
+    let mut members = Vec::new();
+    for (name, size, load_address) in components {
+        let size = size.unwrap_or(0) as usize;
+        if size == 0 {
+            continue;
+        }
+        let Some(content) = data.get(offset..offset + size) else {
+            warn!("{}: truncated boot image {}", file, name);
+            break;
+        };
+
+        let member = Member {
+            format: "android-boot".to_owned(),
+            id: name.to_owned(),
+            path: name.to_owned(),
+            offset: Some(offset),
+            load_address,
+            partition: None,
+        };
+        if content.starts_with(GZIP_MAGIC) {
+            match decompress_stream("gzip", content, max_size) {
+                Ok((decompressed, _)) => members.push((
+                    Member {
+                        offset: None,
+                        ..member
+                    },
+                    decompressed,
+                )),
+                Err(e) => warn!("{}: skipping {}: {:#}", file, name, e),
+            }
+        } else {
+            members.push((member, content.to_vec()));
+        }
+
+        offset = (offset + size).next_multiple_of(page_size);
+    }
+
+    members
+}
+
+pub fn is_sparse_image(data: &[u8]) -> bool {
+    read_u32(data, 0) == Some(SPARSE_MAGIC)
+}
+
+/// Returns the runs of raw chunks of the sparse image in `data`, which is the
+/// content of `file`.
+pub fn find_sparse_data(file: &str, data: &[u8]) -> Vec<(Member, Vec<u8>)> {
+    let (Some(header_size), Some(chunk_header_size), Some(block_size)) =
+        (read_u16(data, 8), read_u16(data, 10), read_u32(data, 12))
+    else {
+        warn!("{}: truncated sparse image header", file);
+        return Vec::new();
+    };
+    let (header_size, chunk_header_size) = (header_size as usize, chunk_header_size as usize);
+    if header_size < SPARSE_HEADER_SIZE || chunk_header_size < 12 {
+        warn!("{}: invalid sparse image header", file);
+        return Vec::new();
+    }
+
+    // Runs of raw chunks as their offset in the unsparsed image and data.
+    let mut runs: Vec<(u64, Vec<u8>)> = Vec::new();
+    let mut address = 0;
+    let mut offset = header_size;
+    while let (Some(chunk_type), Some(blocks), Some(total_size)) = (
+        read_u16(data, offset),
+        read_u32(data, offset + 4),
+        read_u32(data, offset + 8),
+    ) {
+        let size = blocks as u64 * block_size as u64;
+        let Some(body) = data.get(offset + chunk_header_size..offset + total_size as usize) else {
+            warn!("{}: truncated sparse image chunk at {:#x}", file, offset);
+            break;
+        };
+        match chunk_type {
+            CHUNK_TYPE_RAW => match runs.last_mut() {
+                Some((start, run)) if *start + run.len() as u64 == address => {
+                    run.extend_from_slice(body)
+                }
+                _ => runs.push((address, body.to_vec())),
+            },
+            CHUNK_TYPE_FILL | CHUNK_TYPE_DONT_CARE | CHUNK_TYPE_CRC32 => {}
+            _ => {
+                warn!(
+                    "{}: invalid sparse image chunk type {:#x} at {:#x}",
+                    file, chunk_type, offset
+                );
+                break;
+            }
+        }
+
+        address += size;
+        offset += total_size as usize;
+    }
+    debug!(
+        "{}: {} runs of raw chunks in {} bytes",
+        file,
+        runs.len(),
+        address
+    );
+
+    runs.into_iter()
+        .map(|(address, run)| {
+            let end = address + run.len() as u64;
+            (
+                Member {
+                    format: "android-sparse".to_owned(),
+                    id: format!("{:x}", address),
+                    path: format!("{:#x}..{:#x}", address, end),
+                    offset: None,
+                    load_address: Some(address),
+                    partition: None,
+                },
+                run,
+            )
+        })
+        .collect()
+}
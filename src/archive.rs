@@ -0,0 +1,367 @@
+/*
+    Copyright 2024 - Valentin Obst <coderec@vpao.io>
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+*/
+//! Members of tar, cpio, and zip archives, see `--recurse`.
+//!
+//! Only regular files are members, directories, links, and device nodes are
+//! skipped. Tar archives may use GNU long names or pax path records, cpio
+//! archives the new ASCII or the portable ASCII format. Zip archives are read
+//! from their central directory, members are stored or deflated.
+
+use crate::container::{decompress_stream, read_u16, read_u32, Member};
+
+use log::{debug, warn};
+
+const TAR_BLOCK_SIZE: usize = 512;
+const TAR_MAGIC: &[u8] = b"ustar";
+
+const CPIO_NEWC_MAGIC: &[u8] = b"07070";
+const CPIO_ODC_MAGIC: &[u8] = b"070707";
+const CPIO_TRAILER: &str = "TRAILER!!!";
+const CPIO_MODE_TYPE: u32 = 0o170000;
+const CPIO_MODE_REGULAR: u32 = 0o100000;
+
+const ZIP_LOCAL_MAGIC: u32 = 0x04034b50;
+const ZIP_CENTRAL_MAGIC: u32 = 0x02014b50;
+const ZIP_END_MAGIC: u32 = 0x06054b50;
+const ZIP_END_SIZE: usize = 22;
+
+/// Parses a number of ASCII digits in base `radix`, surrounded by spaces or
+/// NULs.
+fn parse_number(field: &[u8], radix: u32) -> Option<u64> {
+    let field = std::str::from_utf8(field).ok()?;
+
+    u64::from_str_radix(field.trim_matches([' ', '\0']), radix).ok()
+}
+
+/// Parses a tar number, which is octal or, if the high bit is set, big
+/// endian binary.
+fn parse_tar_number(field: &[u8]) -> Option<u64> {
+    if field.first()? & 0x80 == 0 {
+        return parse_number(field, 8);
+    }
+
+    Some(
+        field[1..]
+            .iter()
+            .fold(0, |number, byte| number << 8 | *byte as u64),
+    )
+}
+
+fn parse_string(field: &[u8]) -> String {
+    let len = field
+        .iter()
+        .position(|byte| *byte == 0)
+        .unwrap_or(field.len());
+
+    String::from_utf8_lossy(&field[..len]).into_owned()
+}
+
+/// Returns true if `data` starts with a tar header with a valid checksum.
+fn is_tar(data: &[u8]) -> bool {
+    let Some(header) = data.get(..TAR_BLOCK_SIZE) else {
+        return false;
+    };
+    if !header[257..].starts_with(TAR_MAGIC) {
+        return false;
+    }
+
+    // The checksum field counts as spaces.
+    let sum: u64 = header
+        .iter()
+        .enumerate()
+        .map(|(idx, byte)| {
+            if (148..156).contains(&idx) {
+                32
+            } else {
+                *byte as u64
+            }
+        })
+        .sum();
+    parse_number(&header[148..156], 8) == Some(sum)
+}
+
+fn is_cpio(data: &[u8]) -> bool {
+    data.starts_with(CPIO_ODC_MAGIC)
+        || (data.starts_with(CPIO_NEWC_MAGIC) && matches!(data.get(5), Some(b'1' | b'2')))
+}
+
+fn is_zip(data: &[u8]) -> bool {
+    read_u32(data, 0) == Some(ZIP_LOCAL_MAGIC)
+}
+
+/// Returns the path that a pax extended header sets, if any.
+fn pax_path(records: &[u8]) -> Option<String> {
+    // Each record is `LEN KEY=VALUE\n`, where `LEN` includes the whole record.
+    let mut rest = records;
+    while !rest.is_empty() {
+        let space = rest.iter().position(|byte| *byte == b' ')?;
+        let len = parse_number(&rest[..space], 10)? as usize;
+        let record = rest.get(space + 1..len)?.strip_suffix(b"\n")?;
+        if let Some(path) = record.strip_prefix(b"path=") {
+            return Some(String::from_utf8_lossy(path).into_owned());
+        }
+        rest = &rest[len..];
+    }
+
+    None
+}
+
+fn find_tar_members(file: &str, data: &[u8]) -> Vec<(Member, Vec<u8>)> {
+    let mut members = Vec::new();
+    // Name of the next member from a GNU long name or pax header.
+    let mut next_name = None;
+    let mut offset = 0;
+    while let Some(header) = data.get(offset..offset + TAR_BLOCK_SIZE) {
+        if header.iter().all(|byte| *byte == 0) {
+            break;
+        }
+        let Some(size) = parse_tar_number(&header[124..136]) else {
+            warn!("{}: invalid tar header at {:#x}", file, offset);
+            break;
+        };
+        let start = offset + TAR_BLOCK_SIZE;
+        let Some(content) = data.get(start..start + size as usize) else {
+            warn!("{}: truncated tar member at {:#x}", file, offset);
+            break;
+        };
+
+        match header[156] {
+            b'0' | b'\0' | b'7' => {
+                let name = next_name.take().unwrap_or_else(|| {
+                    let prefix = parse_string(&header[345..500]);
+                    let name = parse_string(&header[..100]);
+                    if prefix.is_empty() {
+                        name
+                    } else {
+                        format!("{}/{}", prefix, name)
+                    }
+                });
+                members.push((
+                    Member {
+                        format: "tar".to_owned(),
+                        id: name.clone(),
+                        path: name,
+                        offset: Some(start),
+                        load_address: None,
+                        partition: None,
+                    },
+                    content.to_vec(),
+                ));
+            }
+            b'L' => next_name = Some(parse_string(content)),
+            b'x' => next_name = pax_path(content).or(next_name),
+            _ => next_name = None,
+        }
+
+        offset = (start + size as usize).next_multiple_of(TAR_BLOCK_SIZE);
+    }
+
+    members
+}
+
+fn find_cpio_members(file: &str, data: &[u8]) -> Vec<(Member, Vec<u8>)> {
+    let mut members = Vec::new();
+    let mut offset = 0;
+    loop {
+        let header = &data[offset..];
+        // Size of the header, mode, size of the name and the content, and
+        // alignment of the name and content.
+        let fields = if header.starts_with(CPIO_ODC_MAGIC) {
+            header.get(..76).and_then(|header| {
+                Some((
+                    76,
+                    parse_number(&header[18..24], 8)?,
+                    parse_number(&header[59..65], 8)?,
+                    parse_number(&header[65..76], 8)?,
+                    1,
+                ))
+            })
+        } else if is_cpio(header) {
+            header.get(..110).and_then(|header| {
+                Some((
+                    110,
+                    parse_number(&header[14..22], 16)?,
+                    parse_number(&header[94..102], 16)?,
+                    parse_number(&header[54..62], 16)?,
+                    4,
+                ))
+            })
+        } else {
+            None
+        };
+        let Some((header_size, mode, name_size, size, align)) = fields else {
+            warn!("{}: invalid cpio header at {:#x}", file, offset);
+            break;
+        };
+
+        let name_start = offset + header_size;
+        let start = (name_start + name_size as usize).next_multiple_of(align);
+        let (Some(name), Some(content)) = (
+            data.get(name_start..name_start + name_size as usize),
+            data.get(start..start + size as usize),
+        ) else {
+            warn!("{}: truncated cpio member at {:#x}", file, offset);
+            break;
+        };
+        let name = parse_string(name);
+        if name == CPIO_TRAILER {
+            break;
+        }
+
+        if mode as u32 & CPIO_MODE_TYPE == CPIO_MODE_REGULAR {
+            members.push((
+                Member {
+                    format: "cpio".to_owned(),
+                    id: name.clone(),
+                    path: name,
+                    offset: Some(start),
+                    load_address: None,
+                    partition: None,
+                },
+                content.to_vec(),
+            ));
+        }
+
+        offset = (start + size as usize).next_multiple_of(align);
+        if offset >= data.len() {
+            warn!("{}: cpio archive without trailer", file);
+            break;
+        }
+    }
+
+    members
+}
+
+fn find_zip_members(file: &str, data: &[u8], max_size: usize) -> Vec<(Member, Vec<u8>)> {
+    // The end of central directory record is followed by a comment of up to
+    // 64KiB.
+    let search = data.len().saturating_sub(ZIP_END_SIZE + u16::MAX as usize);
+    let Some(end) = (search..=data.len().saturating_sub(ZIP_END_SIZE))
+        .rev()
+        .find(|offset| read_u32(data, *offset) == Some(ZIP_END_MAGIC))
+    else {
+        warn!("{}: no zip central directory", file);
+        return Vec::new();
+    };
+    let entries = read_u16(data, end + 10).unwrap();
+    let mut offset = read_u32(data, end + 16).unwrap() as usize;
+
+    let mut members = Vec::new();
+    for _ in 0..entries {
+        if read_u32(data, offset) != Some(ZIP_CENTRAL_MAGIC) {
+            warn!("{}: invalid zip central directory at {:#x}", file, offset);
+            break;
+        }
+        let (Some(flags), Some(method), Some(size), Some(name_len), Some(extra_len)) = (
+            read_u16(data, offset + 8),
+            read_u16(data, offset + 10),
+            read_u32(data, offset + 20),
+            read_u16(data, offset + 28),
+            read_u16(data, offset + 30),
+        ) else {
+            warn!("{}: truncated zip central directory", file);
+            break;
+        };
+        let (Some(comment_len), Some(local)) =
+            (read_u16(data, offset + 32), read_u32(data, offset + 42))
+        else {
+            warn!("{}: truncated zip central directory", file);
+            break;
+        };
+        let name_start = offset + 46;
+        let Some(name) = data.get(name_start..name_start + name_len as usize) else {
+            warn!("{}: truncated zip central directory", file);
+            break;
+        };
+        let name = String::from_utf8_lossy(name).into_owned();
+        offset = name_start + name_len as usize + extra_len as usize + comment_len as usize;
+
+        if name.ends_with('/') {
+            continue;
+        }
+        if flags & 1 != 0 {
+            warn!("{}: skipping encrypted {}", file, name);
+            continue;
+        }
+        if size == u32::MAX || local == u32::MAX {
+            warn!("{}: skipping {}, zip64 is not supported", file, name);
+            continue;
+        }
+        let method = match method {
+            0 => "none",
+            8 => "deflate",
+            _ => {
+                warn!("{}: skipping {}, unsupported method {}", file, name, method);
+                continue;
+            }
+        };
+
+        let local = local as usize;
+        let (Some(ZIP_LOCAL_MAGIC), Some(local_name_len), Some(local_extra_len)) = (
+            read_u32(data, local),
+            read_u16(data, local + 26),
+            read_u16(data, local + 28),
+        ) else {
+            warn!("{}: invalid zip local header of {}", file, name);
+            continue;
+        };
+        let start = local + 30 + local_name_len as usize + local_extra_len as usize;
+        let Some(compressed) = data.get(start..start + size as usize) else {
+            warn!("{}: truncated zip member {}", file, name);
+            continue;
+        };
+
+        match decompress_stream(method, compressed, max_size) {
+            Ok((content, _)) => members.push((
+                Member {
+                    format: "zip".to_owned(),
+                    id: name.clone(),
+                    path: name,
+                    offset: (method == "none").then_some(start),
+                    load_address: None,
+                    partition: None,
+                },
+                content,
+            )),
+            Err(e) => warn!("{}: skipping {}: {:#}", file, name, e),
+        }
+    }
+
+    members
+}
+
+/// Returns the members of the archive in `data`, which is the content of
+/// `file`, or `None` if `data` is not an archive. Members are decompressed to
+/// at most `max_size` bytes.
+pub fn find_members(file: &str, data: &[u8], max_size: usize) -> Option<Vec<(Member, Vec<u8>)>> {
+    let (format, members) = if is_tar(data) {
+        ("tar", find_tar_members(file, data))
+    } else if is_cpio(data) {
+        ("cpio", find_cpio_members(file, data))
+    } else if is_zip(data) {
+        ("zip", find_zip_members(file, data, max_size))
+    } else {
+        return None;
+    };
+    debug!(
+        "{}: {} archive with {} members",
+        file,
+        format,
+        members.len()
+    );
+
+    Some(members)
+}
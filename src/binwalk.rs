@@ -0,0 +1,186 @@
+/*
+    Copyright 2024 - Valentin Obst <coderec@vpao.io>
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+*/
+//! Results in the layout of binwalk's signature scan, such that scripts that
+//! parse binwalk's output can consume them.
+//!
+//! The text layout is that of binwalk 2, a table of the decimal and hex offset
+//! and a description of each region. The JSON layout is that of the log of
+//! binwalk 3, an analysis per file whose file map lists the regions, which is
+//! written once the analysis is done. Regions of archive members and streams
+//! are included if the member is stored uncompressed, as binwalk only
+//! reports offsets in the file.
+
+use crate::output::{CliJsonOutput, ReportSink, TargetWriter};
+
+use anyhow::Result;
+use serde::Serialize;
+
+/// Column headers of the text layout, aligned with the rows.
+const HEADER: &str = "DECIMAL       HEXADECIMAL     DESCRIPTION";
+/// Width of the separator line of the text layout.
+const DISPLAY_WIDTH: usize = 80;
+/// Confidence of binwalk's signatures that are most reliable.
+const MAX_CONFIDENCE: f64 = 250.0;
+
+/// Region in the file, with its offset in the top-level file.
+struct FileRegion<'a> {
+    offset: usize,
+    size: usize,
+    arch: &'a str,
+    confidence: f64,
+}
+
+impl FileRegion<'_> {
+    fn description(&self) -> String {
+        format!(
+            "{} code, size: {} bytes, confidence: {:.2}",
+            self.arch, self.size, self.confidence
+        )
+    }
+}
+
+/// Regions of `report` and its nested reports at `base` in the file.
+fn file_regions(report: &CliJsonOutput, base: usize) -> Vec<FileRegion<'_>> {
+    let mut regions: Vec<FileRegion> = report
+        .regions()
+        .map(|(range, size, arch, confidence)| FileRegion {
+            offset: base + range.start,
+            size,
+            arch,
+            confidence,
+        })
+        .collect();
+    for nested in report
+        .members
+        .iter()
+        .flat_map(|members| members.values())
+        .chain(report.streams.iter().flatten())
+    {
+        if let Some(offset) = nested.member.as_ref().and_then(|member| member.offset) {
+            regions.extend(file_regions(nested, base + offset));
+        }
+    }
+    regions.sort_by_key(|region| region.offset);
+
+    regions
+}
+
+/// Entry of the file map of binwalk 3.
+#[derive(Serialize)]
+struct SignatureResult<'a> {
+    offset: usize,
+    id: String,
+    size: usize,
+    name: &'a str,
+    confidence: u8,
+    description: String,
+    always_display: bool,
+    extraction_declined: bool,
+}
+
+#[derive(Serialize)]
+struct AnalysisResults<'a> {
+    file_path: &'a str,
+    file_map: Vec<SignatureResult<'a>>,
+    extractions: serde_json::Map<String, serde_json::Value>,
+}
+
+#[derive(Serialize)]
+struct LogEntry<'a> {
+    #[serde(rename = "Analysis")]
+    analysis: AnalysisResults<'a>,
+}
+
+pub struct BinwalkSink {
+    out: Box<dyn TargetWriter>,
+    json: bool,
+    /// Log entries of the JSON layout, written at the end.
+    entries: Vec<serde_json::Value>,
+}
+
+impl BinwalkSink {
+    pub fn new(out: Box<dyn TargetWriter>, json: bool) -> Self {
+        Self {
+            out,
+            json,
+            entries: Vec::new(),
+        }
+    }
+
+    fn write_table(&mut self, report: &CliJsonOutput) -> Result<()> {
+        writeln!(self.out)?;
+        writeln!(self.out, "{}", HEADER)?;
+        writeln!(self.out, "{}", "-".repeat(DISPLAY_WIDTH))?;
+        for region in file_regions(report, 0) {
+            writeln!(
+                self.out,
+                "{:<12}  0x{:<12X}  {}",
+                region.offset,
+                region.offset,
+                region.description()
+            )?;
+        }
+        writeln!(self.out)?;
+
+        Ok(())
+    }
+
+    fn add_entry(&mut self, report: &CliJsonOutput) -> Result<()> {
+        let file_map = file_regions(report, 0)
+            .into_iter()
+            .map(|region| SignatureResult {
+                offset: region.offset,
+                id: format!("{:x}", region.offset),
+                size: region.size,
+                name: region.arch,
+                confidence: (region.confidence.clamp(0.0, 1.0) * MAX_CONFIDENCE).round() as u8,
+                description: region.description(),
+                always_display: false,
+                extraction_declined: false,
+            })
+            .collect();
+        self.entries.push(serde_json::to_value(LogEntry {
+            analysis: AnalysisResults {
+                file_path: &report.file,
+                file_map,
+                extractions: serde_json::Map::new(),
+            },
+        })?);
+
+        Ok(())
+    }
+}
+
+impl ReportSink for BinwalkSink {
+    fn write_report(&mut self, report: &CliJsonOutput) -> Result<()> {
+        if self.json {
+            return self.add_entry(report);
+        }
+        self.write_table(report)?;
+        self.out.flush()?;
+
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        if self.json {
+            serde_json::to_writer(&mut self.out, &self.entries)?;
+            writeln!(self.out)?;
+        }
+
+        self.out.close()
+    }
+}
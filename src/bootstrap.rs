@@ -0,0 +1,80 @@
+/*
+    Copyright 2024 - Valentin Obst <coderec@vpao.io>
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+*/
+//! Provisional corpus entries for unknown arches, see `--bootstrap`.
+//!
+//! The file is split into homogeneous segments, see `coderec segment`, and
+//! the largest segment that looks like code, i.e., is neither sparse nor of
+//! high entropy, but matches no arch of the corpus becomes the sample of a
+//! new entry. Analyzing other files with the extended corpus then finds more
+//! code that looks like the segment.
+
+use crate::changepoint::{segment, SegmentOptions};
+use crate::classify_window;
+use crate::config::Thresholds;
+use crate::corpus::CorpusStats;
+use crate::entropy::ByteStats;
+use crate::metric::MetricKind;
+use crate::ngrams::NgramCounts;
+
+use std::ops::Range;
+
+use log::debug;
+
+/// Segments below this size are too small for meaningful trigram statistics.
+pub const MIN_SAMPLE_SIZE: usize = 0x1000;
+
+/// Returns the largest segment of `data` that looks like code of an arch
+/// that is not in `corpus_stats`.
+pub fn pick_sample(
+    file: &str,
+    data: &[u8],
+    corpus_stats: &[CorpusStats],
+    metric: MetricKind,
+    thresholds: &Thresholds,
+) -> Option<Range<usize>> {
+    let options = SegmentOptions {
+        window_size: 0x400,
+        context: 4,
+        min_score: 8.0,
+    };
+
+    segment(file, data, &options)
+        .segments
+        .into_iter()
+        .map(|segment| segment.range)
+        .filter(|range| range.len() >= MIN_SAMPLE_SIZE)
+        .filter(|range| {
+            let bytes = &data[range.clone()];
+            if ByteStats::new(bytes).classify(bytes.len()).is_some() {
+                debug!("{}: {:#x?} is not code", file, range);
+                return false;
+            }
+            let decision = classify_window(corpus_stats, bytes, metric, thresholds);
+            if let Some(arch) = decision.arch {
+                debug!("{}: {:#x?} is {}", file, range, arch);
+                return false;
+            }
+
+            true
+        })
+        .rev()
+        .max_by_key(|range| range.len())
+}
+
+/// Corpus entry for `arch` with `sample` as code.
+pub fn bootstrap_entry(arch: &str, sample: &[u8]) -> CorpusStats {
+    CorpusStats::from_counts(arch.to_owned(), &NgramCounts::from_bytes(sample), 0.01)
+}
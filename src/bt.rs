@@ -0,0 +1,102 @@
+/*
+    Copyright 2024 - Valentin Obst <coderec@vpao.io>
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+*/
+//! 010 Editor binary templates, see `--emit 010`.
+//!
+//! The template declares a struct per detected arch that holds the code of a
+//! region and places one at the offset of each region in the analyzed file,
+//! colored by arch and commented with the confidence, such that the regions
+//! can be inspected in the template results.
+
+use crate::emit::{comment, quote, PlacedRegion};
+
+use std::collections::BTreeSet;
+use std::fmt::Write;
+
+/// Background colors of the arches, in the order of their names.
+const COLORS: &[&str] = &[
+    "cLtBlue",
+    "cLtGreen",
+    "cLtYellow",
+    "cLtPurple",
+    "cLtAqua",
+    "cLtRed",
+    "cLtGray",
+];
+
+/// Name of the struct of the regions of `arch`, a valid identifier.
+fn struct_name(arch: &str) -> String {
+    let arch: String = arch
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+
+    format!("CODE_{}", arch)
+}
+
+/// Returns the template that places `regions` of `file`.
+pub fn template(file: &str, regions: &[PlacedRegion]) -> String {
+    let file_name = file.rsplit('/').next().unwrap();
+    let arches: BTreeSet<&str> = regions.iter().map(|region| region.arch).collect();
+
+    let mut template = String::new();
+    writeln!(
+        template,
+        "//------------------------------------------------"
+    )
+    .unwrap();
+    writeln!(template, "//--- 010 Editor Binary Template").unwrap();
+    writeln!(template, "//").unwrap();
+    writeln!(template, "//      File: {}", comment(file_name)).unwrap();
+    writeln!(
+        template,
+        "//   Authors: coderec {}",
+        env!("CARGO_PKG_VERSION")
+    )
+    .unwrap();
+    writeln!(template, "//   Purpose: Regions of machine code").unwrap();
+    writeln!(
+        template,
+        "//------------------------------------------------"
+    )
+    .unwrap();
+    for arch in arches.iter() {
+        writeln!(template).unwrap();
+        writeln!(template, "typedef struct (int64 size) {{").unwrap();
+        writeln!(template, "    ubyte code[size];").unwrap();
+        writeln!(template, "}} {};", struct_name(arch)).unwrap();
+    }
+    writeln!(template).unwrap();
+    for region in regions {
+        let color =
+            COLORS[arches.iter().position(|arch| *arch == region.arch).unwrap() % COLORS.len()];
+        writeln!(template, "FSeek({:#x});", region.offset).unwrap();
+        writeln!(
+            template,
+            "{} region_{:x}({:#x}) <bgcolor={}, comment={}>;",
+            struct_name(region.arch),
+            region.offset,
+            region.size,
+            color,
+            quote(&format!(
+                "{}, confidence {:.2}",
+                region.arch, region.confidence
+            ))
+        )
+        .unwrap();
+    }
+
+    template
+}
@@ -0,0 +1,401 @@
+/*
+    Copyright 2024 - Valentin Obst <coderec@vpao.io>
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+*/
+//! Archive of everything that an analysis produced, see `--bundle`.
+//!
+//! The bundle is a zip archive with a directory per analyzed file that holds
+//! its JSON report and plots, such that files with the same name do not
+//! collide, and at the top level
+//!
+//! - `manifest.json`, the version, command line, corpus, and the content of
+//!   each directory,
+//! - `diagnostics.json`, the warnings of the pipeline for each file, and
+//! - `index.html`, the regions and plots of all files for a browser.
+//!
+//! Entries are written as they are produced, only the central directory of
+//! the archive is kept until the end.
+
+use crate::output::{open_target, CliJsonOutput, TargetWriter, SCHEMA_VERSION};
+use crate::pipeline::Hooks;
+
+use std::fs;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{bail, Context, Result};
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+use log::Level;
+use serde::Serialize;
+
+const ZIP_LOCAL_MAGIC: u32 = 0x04034b50;
+const ZIP_CENTRAL_MAGIC: u32 = 0x02014b50;
+const ZIP_END_MAGIC: u32 = 0x06054b50;
+/// Version 2.0, which supports deflate.
+const ZIP_VERSION: u16 = 20;
+/// Names are UTF-8.
+const ZIP_FLAG_UTF8: u16 = 0x0800;
+const ZIP_METHOD_DEFLATE: u16 = 8;
+/// 1980-01-01 in DOS format, such that bundles of the same results are
+/// identical.
+const ZIP_DATE: u16 = 0x21;
+/// Regular file with mode 644, as created on Unix.
+const ZIP_EXTERNAL_ATTRS: u32 = 0o100644 << 16;
+const ZIP_MADE_BY_UNIX: u16 = 3 << 8 | ZIP_VERSION;
+
+/// Entry of the central directory.
+struct ZipEntry {
+    name: String,
+    crc: u32,
+    compressed_size: u32,
+    size: u32,
+    offset: u32,
+}
+
+/// Writes a zip archive of deflated entries.
+struct ZipWriter {
+    out: Box<dyn TargetWriter>,
+    offset: usize,
+    entries: Vec<ZipEntry>,
+}
+
+impl ZipWriter {
+    fn write(&mut self, data: &[u8]) -> Result<()> {
+        self.out.write_all(data)?;
+        self.offset += data.len();
+
+        Ok(())
+    }
+
+    fn add(&mut self, name: &str, data: &[u8]) -> Result<()> {
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data)?;
+        let compressed = encoder.finish()?;
+        let (Ok(size), Ok(compressed_size), Ok(offset)) = (
+            u32::try_from(data.len()),
+            u32::try_from(compressed.len()),
+            u32::try_from(self.offset),
+        ) else {
+            bail!("cannot add {}, zip64 is not supported", name);
+        };
+        let entry = ZipEntry {
+            name: name.to_owned(),
+            crc: crc32fast::hash(data),
+            compressed_size,
+            size,
+            offset,
+        };
+
+        let mut header = Vec::new();
+        header.extend(ZIP_LOCAL_MAGIC.to_le_bytes());
+        header.extend(ZIP_VERSION.to_le_bytes());
+        header.extend(ZIP_FLAG_UTF8.to_le_bytes());
+        header.extend(ZIP_METHOD_DEFLATE.to_le_bytes());
+        header.extend(0u16.to_le_bytes());
+        header.extend(ZIP_DATE.to_le_bytes());
+        header.extend(entry.crc.to_le_bytes());
+        header.extend(entry.compressed_size.to_le_bytes());
+        header.extend(entry.size.to_le_bytes());
+        header.extend((name.len() as u16).to_le_bytes());
+        header.extend(0u16.to_le_bytes());
+        header.extend(name.as_bytes());
+        self.write(&header)?;
+        self.write(&compressed)?;
+        self.entries.push(entry);
+
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        let Ok(entries) = u16::try_from(self.entries.len()) else {
+            bail!(
+                "cannot write {} entries, zip64 is not supported",
+                self.entries.len()
+            );
+        };
+        let mut directory = Vec::new();
+        for entry in self.entries.iter() {
+            directory.extend(ZIP_CENTRAL_MAGIC.to_le_bytes());
+            directory.extend(ZIP_MADE_BY_UNIX.to_le_bytes());
+            directory.extend(ZIP_VERSION.to_le_bytes());
+            directory.extend(ZIP_FLAG_UTF8.to_le_bytes());
+            directory.extend(ZIP_METHOD_DEFLATE.to_le_bytes());
+            directory.extend(0u16.to_le_bytes());
+            directory.extend(ZIP_DATE.to_le_bytes());
+            directory.extend(entry.crc.to_le_bytes());
+            directory.extend(entry.compressed_size.to_le_bytes());
+            directory.extend(entry.size.to_le_bytes());
+            directory.extend((entry.name.len() as u16).to_le_bytes());
+            // Lengths of the extra field and comment, disk number, and
+            // internal attributes.
+            directory.extend([0; 8]);
+            directory.extend(ZIP_EXTERNAL_ATTRS.to_le_bytes());
+            directory.extend(entry.offset.to_le_bytes());
+            directory.extend(entry.name.as_bytes());
+        }
+        let (Ok(start), Ok(size)) = (u32::try_from(self.offset), u32::try_from(directory.len()))
+        else {
+            bail!("cannot write the central directory, zip64 is not supported");
+        };
+
+        let mut end = Vec::new();
+        end.extend(ZIP_END_MAGIC.to_le_bytes());
+        // Numbers of this and of the central directory's disk.
+        end.extend([0; 4]);
+        end.extend(entries.to_le_bytes());
+        end.extend(entries.to_le_bytes());
+        end.extend(size.to_le_bytes());
+        end.extend(start.to_le_bytes());
+        // Comment length.
+        end.extend([0; 2]);
+        self.write(&directory)?;
+        self.write(&end)?;
+
+        self.out.close()
+    }
+}
+
+/// Warning or other notable event of the pipeline.
+#[derive(Debug, Clone, Serialize)]
+pub struct Diagnostic {
+    pub file: String,
+    pub level: String,
+    pub message: String,
+}
+
+/// Collects the diagnostics of a pipeline for the bundle.
+#[derive(Debug, Clone, Default)]
+pub struct DiagnosticLog(Arc<Mutex<Vec<Diagnostic>>>);
+
+impl Hooks for DiagnosticLog {
+    fn on_diagnostic(&self, file: &str, level: Level, message: &str) {
+        self.0.lock().unwrap().push(Diagnostic {
+            file: file.to_owned(),
+            level: level.to_string(),
+            message: message.to_owned(),
+        });
+    }
+}
+
+/// Content of the directory of a file.
+#[derive(Debug, Serialize)]
+struct ManifestFile {
+    file: String,
+    report: String,
+    plots: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct Manifest<'a> {
+    schema_version: u32,
+    version: &'static str,
+    /// Command line of the analysis.
+    arguments: &'a [String],
+    corpus_entries: Option<usize>,
+    corpus_sha256: Option<&'a str>,
+    files: &'a [ManifestFile],
+}
+
+/// Escapes `s` for HTML text and attribute values.
+fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Appends the regions of `report` and its nested reports to `html`.
+fn html_regions(html: &mut String, report: &CliJsonOutput) {
+    html.push_str(&format!(
+        "<p><b>{}</b>: {} bytes, window {:#x}, dominant arch {}</p>\n",
+        escape_html(&report.file),
+        report.size,
+        report.window_size,
+        escape_html(report.dominant_arch.as_deref().unwrap_or("-"))
+    ));
+    if !report.range_results.is_empty() {
+        html.push_str(
+            "<table>\n<tr><th>Start</th><th>End</th><th>Size</th><th>Arch</th><th>Confidence</th></tr>\n",
+        );
+        for (range, size, arch, confidence) in report.regions() {
+            html.push_str(&format!(
+                "<tr><td>{:#x}</td><td>{:#x}</td><td>{}</td><td>{}</td><td>{:.2}</td></tr>\n",
+                range.start,
+                range.end,
+                size,
+                escape_html(arch),
+                confidence
+            ));
+        }
+        html.push_str("</table>\n");
+    }
+    for nested in report
+        .members
+        .iter()
+        .flat_map(|members| members.values())
+        .chain(report.streams.iter().flatten())
+    {
+        html_regions(html, nested);
+    }
+}
+
+/// Bundle that is being written.
+pub struct Bundle {
+    zip: ZipWriter,
+    arguments: Vec<String>,
+    diagnostics: DiagnosticLog,
+    /// Number of diagnostics that belong to files of earlier reports.
+    reported_diagnostics: usize,
+    /// Plots of the file whose report comes next.
+    plots: Vec<String>,
+    files: Vec<ManifestFile>,
+    corpus: Option<(usize, String)>,
+    /// Sections of the index, one per file.
+    sections: String,
+}
+
+impl Bundle {
+    /// Creates the bundle at `target` for an analysis with the command line
+    /// `arguments`, whose diagnostics are collected by `diagnostics`.
+    pub fn create(
+        target: &str,
+        arguments: Vec<String>,
+        diagnostics: DiagnosticLog,
+    ) -> Result<Self> {
+        Ok(Self {
+            zip: ZipWriter {
+                out: open_target(target)?,
+                offset: 0,
+                entries: Vec::new(),
+            },
+            arguments,
+            diagnostics,
+            reported_diagnostics: 0,
+            plots: Vec::new(),
+            files: Vec::new(),
+            corpus: None,
+            sections: String::new(),
+        })
+    }
+
+    /// Directory of the file whose report comes next.
+    fn directory(&self, file: &str) -> String {
+        format!(
+            "files/{}_{}",
+            self.files.len(),
+            file.rsplit('/').next().unwrap()
+        )
+    }
+
+    /// Adds the plot at `path` to the directory of `file`, whose report comes
+    /// next.
+    pub fn add_plot(&mut self, file: &str, path: &str) -> Result<()> {
+        let data = fs::read(path).with_context(|| format!("Could not read {}", path))?;
+        let name = format!(
+            "{}/{}",
+            self.directory(file),
+            path.rsplit('/').next().unwrap()
+        );
+        self.zip.add(&name, &data)?;
+        self.plots.push(name);
+
+        Ok(())
+    }
+
+    /// Adds the report of `file`, whose plots have been added before.
+    pub fn add_report(&mut self, file: &str, report: &CliJsonOutput) -> Result<()> {
+        let directory = self.directory(file);
+        let name = format!("{}/report.json", directory);
+        self.zip.add(&name, &serde_json::to_vec_pretty(report)?)?;
+        if let (None, Some(header)) = (self.corpus.as_ref(), report.header.as_ref()) {
+            self.corpus = Some((header.corpus_entries, header.corpus_sha256.clone()));
+        }
+
+        let mut html = format!("<h2>{}</h2>\n", escape_html(&report.file));
+        html_regions(&mut html, report);
+        let diagnostics = self.diagnostics.0.lock().unwrap();
+        let new_diagnostics = &diagnostics[self.reported_diagnostics..];
+        if !new_diagnostics.is_empty() {
+            html.push_str("<ul>\n");
+            for diagnostic in new_diagnostics {
+                html.push_str(&format!(
+                    "<li>{} {}: {}</li>\n",
+                    diagnostic.level,
+                    escape_html(&diagnostic.file),
+                    escape_html(&diagnostic.message)
+                ));
+            }
+            html.push_str("</ul>\n");
+        }
+        self.reported_diagnostics = diagnostics.len();
+        drop(diagnostics);
+        for plot in self.plots.iter() {
+            // Interactive plots are documents of their own.
+            if plot.ends_with(".html") {
+                html.push_str(&format!(
+                    "<iframe src=\"{}\" width=\"100%\" height=\"800\"></iframe>\n",
+                    escape_html(plot)
+                ));
+            } else {
+                html.push_str(&format!("<img src=\"{}\">\n", escape_html(plot)));
+            }
+        }
+        self.sections.push_str(&html);
+
+        self.files.push(ManifestFile {
+            file: report.file.clone(),
+            report: name,
+            plots: std::mem::take(&mut self.plots),
+        });
+
+        Ok(())
+    }
+
+    /// Adds the manifest, diagnostics, and index and completes the archive.
+    pub fn finish(mut self) -> Result<()> {
+        let manifest = Manifest {
+            schema_version: SCHEMA_VERSION,
+            version: env!("CARGO_PKG_VERSION"),
+            arguments: &self.arguments,
+            corpus_entries: self.corpus.as_ref().map(|(entries, _)| *entries),
+            corpus_sha256: self.corpus.as_ref().map(|(_, hash)| hash.as_str()),
+            files: &self.files,
+        };
+        let manifest = serde_json::to_vec_pretty(&manifest)?;
+        self.zip.add("manifest.json", &manifest)?;
+        let diagnostics = serde_json::to_vec_pretty(&*self.diagnostics.0.lock().unwrap())?;
+        self.zip.add("diagnostics.json", &diagnostics)?;
+
+        let title = format!("coderec {}", env!("CARGO_PKG_VERSION"));
+        let index = format!(
+            "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n<style>\n{}</style>\n</head>\n<body>\n<h1>{}</h1>\n<p><code>{}</code></p>\n{}</body>\n</html>\n",
+            title,
+            STYLE,
+            title,
+            escape_html(&self.arguments.join(" ")),
+            self.sections
+        );
+        self.zip.add("index.html", index.as_bytes())?;
+
+        self.zip.finish()
+    }
+}
+
+const STYLE: &str = "\
+body { font-family: sans-serif; }
+table { border-collapse: collapse; }
+th, td { border: 1px solid #ccc; padding: 2px 8px; text-align: left; }
+img { max-width: 100%; }
+";
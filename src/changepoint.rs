@@ -0,0 +1,225 @@
+/*
+    Copyright 2024 - Valentin Obst <coderec@vpao.io>
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+*/
+//! Segmentation of a file by change-points of its own statistics, see
+//! `coderec segment`.
+//!
+//! No corpus is needed: the file is split into windows, and at each boundary
+//! between two windows the `context` windows on each side are split into two
+//! halves. Two samples of the same data diverge as well, the more the sparser
+//! its bigrams are, so the score of the boundary is the mean Jensen–Shannon
+//! divergence of the bigram distributions of halves on different sides minus
+//! that of the halves on the same side. The scores are only compared with
+//! each other: a boundary is a change-point if its score is the largest
+//! within `context` windows and exceeds the median score by `min_score`
+//! robust standard deviations. The ranges between change-points are homogeneous segments, and
+//! the divergences of all pairs of segments show which ones are alike, e.g.,
+//! code of the same unknown arch, which makes them natural units for training
+//! corpus entries.
+
+use crate::common::bigram_divergence;
+use crate::entropy::ByteStats;
+
+use std::collections::BTreeMap;
+use std::ops::Range;
+
+use rayon::prelude::*;
+use serde::Serialize;
+
+/// Scale of the median absolute deviation that estimates the standard
+/// deviation of normally distributed scores.
+const MAD_SCALE: f64 = 1.4826;
+
+#[derive(Debug, Clone)]
+pub struct SegmentOptions {
+    pub window_size: usize,
+    /// Number of windows on each side of a boundary that are compared.
+    pub context: usize,
+    /// Robust standard deviations by which the score of a change-point
+    /// exceeds the median score.
+    pub min_score: f64,
+}
+
+/// Homogeneous segments of a file.
+#[derive(Debug, Serialize)]
+pub struct SegmentReport {
+    pub file: String,
+    pub size: usize,
+    pub window_size: usize,
+    pub segments: Vec<HomogeneousSegment>,
+    /// Similarity of each pair of segments, one minus their bigram divergence
+    /// relative to its maximum `ln 2`, by segment index.
+    pub similarity: Vec<Vec<f64>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct HomogeneousSegment {
+    pub range: Range<usize>,
+    /// Shannon entropy in bits per byte.
+    pub entropy: f64,
+    /// Score of the change-point at the start of the segment, in robust
+    /// standard deviations above the median.
+    pub change_score: Option<f64>,
+}
+
+/// Bigram counts of a window, sorted by bigram.
+type Counts = Vec<(u16, u32)>;
+
+fn counts(data: &[u8]) -> Counts {
+    let mut counts: BTreeMap<u16, u32> = BTreeMap::new();
+    for pair in data.windows(2) {
+        *counts
+            .entry(u16::from_be_bytes([pair[0], pair[1]]))
+            .or_default() += 1;
+    }
+
+    counts.into_iter().collect()
+}
+
+/// Bigram distribution of the pooled counts of `windows`, sorted by bigram.
+fn distribution(windows: &[Counts]) -> Vec<(u16, f64)> {
+    let mut pooled: BTreeMap<u16, u32> = BTreeMap::new();
+    for (bigram, count) in windows.iter().flatten() {
+        *pooled.entry(*bigram).or_default() += count;
+    }
+    let total: u32 = pooled.values().sum();
+
+    pooled
+        .into_iter()
+        .map(|(bigram, count)| (bigram, count as f64 / total as f64))
+        .collect()
+}
+
+fn median(values: &mut [f64]) -> f64 {
+    values.sort_unstable_by(|a, b| a.partial_cmp(b).unwrap());
+
+    values.get(values.len() / 2).copied().unwrap_or_default()
+}
+
+/// Splits `data` into homogeneous segments.
+pub fn segment(file: &str, data: &[u8], options: &SegmentOptions) -> SegmentReport {
+    let windows: Vec<Counts> = data.par_chunks(options.window_size).map(counts).collect();
+
+    // Score of the boundary at the start of each window after the first, with
+    // the same number of windows in all halves such that scores near the ends
+    // of the file are comparable. Boundaries without two windows on each side
+    // score zero.
+    let scores: Vec<f64> = (1..windows.len())
+        .into_par_iter()
+        .map(|boundary| {
+            let half = options.context.min(boundary).min(windows.len() - boundary) / 2;
+            if half == 0 {
+                return 0.0;
+            }
+            let [left_outer, left_inner, right_inner, right_outer] = [
+                boundary - 2 * half,
+                boundary - half,
+                boundary,
+                boundary + half,
+            ]
+            .map(|start| distribution(&windows[start..start + half]));
+            let across = [
+                (&left_outer, &right_inner),
+                (&left_outer, &right_outer),
+                (&left_inner, &right_inner),
+                (&left_inner, &right_outer),
+            ]
+            .map(|(a, b)| bigram_divergence(a, b));
+            let within = [(&left_outer, &left_inner), (&right_inner, &right_outer)]
+                .map(|(a, b)| bigram_divergence(a, b));
+
+            across.iter().sum::<f64>() / 4.0 - within.iter().sum::<f64>() / 2.0
+        })
+        .collect();
+
+    let median_score = median(&mut scores.clone());
+    let mad = median(
+        &mut scores
+            .iter()
+            .map(|score| (score - median_score).abs())
+            .collect::<Vec<_>>(),
+    ) * MAD_SCALE;
+    let normalized: Vec<f64> = scores
+        .iter()
+        .map(|score| (score - median_score) / mad.max(f64::EPSILON))
+        .collect();
+
+    // Boundaries, by the index of the window that they start, and their
+    // normalized scores.
+    let change_points: Vec<(usize, f64)> = normalized
+        .iter()
+        .enumerate()
+        .filter(|(idx, score)| {
+            let neighbors = idx.saturating_sub(options.context)
+                ..(idx + options.context + 1).min(normalized.len());
+            **score >= options.min_score
+                && neighbors.into_iter().all(|neighbor| {
+                    normalized[neighbor] < **score
+                        || (normalized[neighbor] == **score && neighbor >= *idx)
+                })
+        })
+        .map(|(idx, score)| (idx + 1, *score))
+        .collect();
+
+    let mut bounds: Vec<(usize, Option<f64>)> = vec![(0, None)];
+    bounds.extend(
+        change_points
+            .into_iter()
+            .map(|(window, score)| (window, Some(score))),
+    );
+    let segment_windows: Vec<(Range<usize>, Option<f64>)> = bounds
+        .iter()
+        .enumerate()
+        .map(|(idx, (start, score))| {
+            let end = bounds.get(idx + 1).map_or(windows.len(), |(end, _)| *end);
+            (*start..end, *score)
+        })
+        .filter(|(windows, _)| !windows.is_empty())
+        .collect();
+
+    let distributions: Vec<Vec<(u16, f64)>> = segment_windows
+        .par_iter()
+        .map(|(range, _)| distribution(&windows[range.clone()]))
+        .collect();
+    let similarity = distributions
+        .par_iter()
+        .map(|a| {
+            distributions
+                .iter()
+                .map(|b| (1.0 - bigram_divergence(a, b) / std::f64::consts::LN_2).max(0.0))
+                .collect()
+        })
+        .collect();
+    let segments = segment_windows
+        .into_iter()
+        .map(|(range, change_score)| {
+            let range = range.start * options.window_size
+                ..(range.end * options.window_size).min(data.len());
+            HomogeneousSegment {
+                entropy: ByteStats::new(&data[range.clone()]).entropy,
+                range,
+                change_score,
+            }
+        })
+        .collect();
+
+    SegmentReport {
+        file: file.to_owned(),
+        size: data.len(),
+        window_size: options.window_size,
+        segments,
+        similarity,
+    }
+}
@@ -0,0 +1,247 @@
+/*
+    Copyright 2024 - Valentin Obst <coderec@vpao.io>
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+*/
+//! Code shared between two inputs, see `coderec common`.
+//!
+//! The windows of the first input are compared with windows of the second
+//! one that start every `stride` bytes, by the Jensen–Shannon divergence of
+//! their bigram distributions. Windows with few distinct bigrams, e.g.,
+//! padding, would match each other and are skipped. As windows of the second
+//! input overlap, a window of the first one matches several neighboring ones,
+//! of which only those with a locally minimal divergence are kept. Matches of
+//! consecutive windows in both inputs are merged into one.
+//!
+//! Comparing all pairs of windows is quadratic, so pairs are first filtered
+//! by their byte distributions: the L1 distance of the byte distributions is
+//! at most that of the bigram distributions, which is at most
+//! `sqrt(8 * divergence)`.
+
+use crate::metric::{JensenShannon, Metric};
+
+use std::collections::HashMap;
+use std::ops::Range;
+
+use itertools::{EitherOrBoth, Itertools};
+use rayon::prelude::*;
+use serde::Serialize;
+
+/// Windows with fewer distinct bigrams are skipped.
+const MIN_BIGRAMS: usize = 64;
+
+#[derive(Debug, Clone)]
+pub struct CommonOptions {
+    pub window_size: usize,
+    /// Distance between the windows of the second input.
+    pub stride: usize,
+    /// Largest bigram divergence of matching windows.
+    pub max_div: f64,
+    /// Only match windows with identical bytes.
+    pub identical: bool,
+}
+
+/// Regions that two inputs have in common.
+#[derive(Debug, Serialize)]
+pub struct CommonReport {
+    pub a: String,
+    pub b: String,
+    pub window_size: usize,
+    pub regions: Vec<CommonRegion>,
+}
+
+/// Ranges of both inputs with near-identical bigram distributions.
+#[derive(Debug, Serialize)]
+pub struct CommonRegion {
+    pub a: Range<usize>,
+    pub b: Range<usize>,
+    pub windows: usize,
+    /// Mean divergence of the matched windows.
+    pub divergence: f64,
+    /// Whether the bytes of all matched windows are identical.
+    pub identical: bool,
+}
+
+struct Profile {
+    window: Range<usize>,
+    bytes: [f64; 256],
+    /// Sorted by bigram.
+    bigrams: Vec<(u16, f64)>,
+}
+
+impl Profile {
+    fn new(data: &[u8], window: Range<usize>) -> Option<Self> {
+        let bytes_of_window = &data[window.clone()];
+        let mut bytes = [0.0; 256];
+        for byte in bytes_of_window {
+            bytes[*byte as usize] += 1.0 / bytes_of_window.len() as f64;
+        }
+
+        let mut counts: HashMap<u16, usize> = HashMap::new();
+        for pair in bytes_of_window.windows(2) {
+            *counts
+                .entry(u16::from_be_bytes([pair[0], pair[1]]))
+                .or_default() += 1;
+        }
+        if counts.len() < MIN_BIGRAMS {
+            return None;
+        }
+        let total = bytes_of_window.len() - 1;
+        let mut bigrams: Vec<(u16, f64)> = counts
+            .into_iter()
+            .map(|(bigram, count)| (bigram, count as f64 / total as f64))
+            .collect();
+        bigrams.sort_unstable_by_key(|(bigram, _)| *bigram);
+
+        Some(Self {
+            window,
+            bytes,
+            bigrams,
+        })
+    }
+
+    fn byte_distance(&self, other: &Self) -> f64 {
+        self.bytes
+            .iter()
+            .zip(other.bytes.iter())
+            .map(|(p, q)| (p - q).abs())
+            .sum()
+    }
+
+    fn divergence(&self, other: &Self) -> f64 {
+        bigram_divergence(&self.bigrams, &other.bigrams)
+    }
+}
+
+/// Jensen–Shannon divergence of two bigram distributions that are sorted by
+/// bigram.
+pub(crate) fn bigram_divergence(a: &[(u16, f64)], b: &[(u16, f64)]) -> f64 {
+    // Bigrams that only one distribution has contribute `0.5 * p * ln 2`.
+    a.iter()
+        .merge_join_by(b.iter(), |(a, _), (b, _)| a.cmp(b))
+        .map(|pair| match pair {
+            EitherOrBoth::Both((_, p), (_, q)) => JensenShannon.term(*p, *q),
+            EitherOrBoth::Left((_, p)) | EitherOrBoth::Right((_, p)) => {
+                0.5 * p * std::f64::consts::LN_2
+            }
+        })
+        .sum()
+}
+
+fn profiles(data: &[u8], window_size: usize, stride: usize) -> Vec<Profile> {
+    let starts: Vec<usize> = (0..data.len().saturating_sub(window_size - 1))
+        .step_by(stride)
+        .collect();
+
+    starts
+        .into_par_iter()
+        .filter_map(|start| Profile::new(data, start..start + window_size))
+        .collect()
+}
+
+/// Keeps the `matches`, sorted by their window, whose divergence is not larger
+/// than that of the matches of the windows `stride` bytes before and after.
+fn local_minima(
+    matches: Vec<(&Range<usize>, f64, bool)>,
+    stride: usize,
+) -> impl Iterator<Item = (&Range<usize>, f64, bool)> {
+    let is_minimum = |idx: usize| {
+        let (window, div, _) = matches[idx];
+        [idx.checked_sub(1), Some(idx + 1)]
+            .into_iter()
+            .flatten()
+            .filter_map(|neighbor| matches.get(neighbor))
+            .filter(|(other, ..)| other.start.abs_diff(window.start) == stride)
+            .all(|(_, other_div, _)| div <= *other_div)
+    };
+    let keep: Vec<bool> = (0..matches.len()).map(is_minimum).collect();
+
+    matches
+        .into_iter()
+        .zip(keep)
+        .filter_map(|(entry, keep)| keep.then_some(entry))
+}
+
+/// Returns the regions of `a` whose bigram distribution is close to that of
+/// a region of `b`, sorted by their offset in `a`.
+pub fn find_common(a: &[u8], b: &[u8], options: &CommonOptions) -> Vec<CommonRegion> {
+    let profiles_a = profiles(a, options.window_size, options.window_size);
+    let profiles_b = profiles(b, options.window_size, options.stride);
+    let max_distance = (8.0 * options.max_div).sqrt();
+
+    // Matches of windows as their windows in both inputs and divergence,
+    // sorted by the offset in `a`.
+    let matches: Vec<(Range<usize>, Range<usize>, f64, bool)> = profiles_a
+        .par_iter()
+        .flat_map_iter(|profile_a| {
+            let candidates: Vec<_> = profiles_b
+                .iter()
+                .filter(|profile_b| profile_a.byte_distance(profile_b) <= max_distance)
+                .filter_map(|profile_b| {
+                    let identical = a[profile_a.window.clone()] == b[profile_b.window.clone()];
+                    if options.identical && !identical {
+                        return None;
+                    }
+                    let div = profile_a.divergence(profile_b);
+
+                    (div <= options.max_div).then_some((&profile_b.window, div, identical))
+                })
+                .collect();
+
+            local_minima(candidates, options.stride).map(|(window_b, div, identical)| {
+                (profile_a.window.clone(), window_b.clone(), div, identical)
+            })
+        })
+        .collect();
+
+    // Regions that the next window of `a` can continue, by the ends of their
+    // ranges.
+    let mut open: HashMap<(usize, usize), CommonRegion> = HashMap::new();
+    let mut regions = Vec::new();
+    let mut current = None;
+    for (window_a, window_b, div, identical) in matches {
+        // Regions that did not continue in the previous window of `a` end.
+        if current != Some(window_a.start) {
+            let start = window_a.start;
+            let (ended, continued): (Vec<_>, Vec<_>) =
+                open.drain().partition(|((end_a, _), _)| *end_a != start);
+            regions.extend(ended.into_iter().map(|(_, region)| region));
+            open.extend(continued);
+            current = Some(window_a.start);
+        }
+
+        let region = match open.remove(&(window_a.start, window_b.start)) {
+            Some(mut region) => {
+                region.divergence =
+                    (region.divergence * region.windows as f64 + div) / (region.windows + 1) as f64;
+                region.windows += 1;
+                region.identical &= identical;
+                region.a.end = window_a.end;
+                region.b.end = window_b.end;
+                region
+            }
+            None => CommonRegion {
+                a: window_a,
+                b: window_b,
+                windows: 1,
+                divergence: div,
+                identical,
+            },
+        };
+        open.insert((region.a.end, region.b.end), region);
+    }
+    regions.extend(open.into_values());
+    regions.sort_unstable_by_key(|region| (region.a.start, region.b.start));
+
+    regions
+}
@@ -0,0 +1,84 @@
+/*
+    Copyright 2024 - Valentin Obst <coderec@vpao.io>
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+*/
+//! Analysis of files that are parts of one image, e.g., a flash dumped in
+//! several reads, see `--concat`.
+//!
+//! The parts are analyzed as one image, such that regions that cross the
+//! boundaries between parts are detected as a whole, and the report tells
+//! which bytes of which part each region covers.
+
+use crate::input::{read_input, InputOptions};
+
+use std::cmp::{max, min};
+use std::ops::Range;
+
+use anyhow::Result;
+use log::info;
+use serde::{Deserialize, Serialize};
+
+/// File within the concatenated image.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Part {
+    pub file: String,
+    /// Offset of the file in the image.
+    pub offset: usize,
+    pub size: usize,
+}
+
+/// Bytes of a part that a region covers.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PartRange {
+    /// Index of the part.
+    pub part: usize,
+    /// Range within the part.
+    pub range: Range<usize>,
+}
+
+/// Reads `files` and returns their concatenation and the parts.
+pub fn read_concat(files: &[&String], options: &InputOptions) -> Result<(Vec<u8>, Vec<Part>)> {
+    let mut image = Vec::new();
+    let mut parts = Vec::with_capacity(files.len());
+    for file in files {
+        let data = read_input(file, None, options)?;
+        parts.push(Part {
+            file: file.to_string(),
+            offset: image.len(),
+            size: data.len(),
+        });
+        image.extend_from_slice(&data);
+    }
+    info!(
+        "Concatenated {} parts to an image of {:#x} bytes",
+        parts.len(),
+        image.len()
+    );
+
+    Ok((image, parts))
+}
+
+/// Returns the bytes of each part that `range` of the image covers.
+pub fn part_ranges(parts: &[Part], range: &Range<usize>) -> Vec<PartRange> {
+    parts
+        .iter()
+        .enumerate()
+        .filter(|(_, part)| part.offset < range.end && range.start < part.offset + part.size)
+        .map(|(idx, part)| PartRange {
+            part: idx,
+            range: max(range.start, part.offset) - part.offset
+                ..min(range.end, part.offset + part.size) - part.offset,
+        })
+        .collect()
+}
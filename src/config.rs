@@ -0,0 +1,398 @@
+/*
+    Copyright 2024 - Valentin Obst <coderec@vpao.io>
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+*/
+//! Configuration file and profiles.
+//!
+//! Each setting is taken from the first of these layers that sets it:
+//!
+//! 1. command line options,
+//! 2. the `CODEREC_*` environment variables of some options, e.g.,
+//!    `CODEREC_PROFILE` for `--profile`, see `--help`,
+//! 3. the configuration file of `--config`,
+//! 4. the profile of `--profile`, a built-in set of thresholds,
+//! 5. the built-in defaults.
+//!
+//! Tables of the configuration file and the profile are merged key by key.
+//! Flags override the boolean settings both ways, e.g., `--plots` and
+//! `--no-plots` override `plot.enabled`.
+//! `--print-effective-config` prints the result of all layers. All settings
+//! of the configuration file are optional. Example:
+//!
+//! ```toml
+//! [thresholds]
+//! max_abs_div_bg = 4.5
+//! strict_arches = ["OCaml", "IA-64"]
+//!
+//! [thresholds.arch.ARMhf]
+//! max_abs_div_tg = 5.5
+//! min_region_size = 0x1000
+//!
+//! [window]
+//! size = 0x800
+//!
+//! [plot]
+//! divs = true
+//! format = "svg"
+//! size = [1920, 1080]
+//!
+//! [filters]
+//! min_region_windows = 2
+//! max_gap = 0x400
+//!
+//! [families]
+//! ARM = ["MyARMVariant"]
+//!
+//! [policy]
+//! deny_arches = ["x86", "MIPSel"]
+//! ```
+
+use crate::corpus::is_strict;
+use crate::plotting::PlotFormat;
+use crate::MIN_HALF_WINDOW;
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+/// Built-in sets of settings below the configuration file.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Profile {
+    /// The built-in defaults.
+    #[default]
+    Default,
+    /// Fewer false positives at the cost of missing small or unusual regions.
+    Strict,
+    /// More regions of unusual code at the cost of more false positives.
+    Sensitive,
+}
+
+impl FromStr for Profile {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "default" => Ok(Self::Default),
+            "strict" => Ok(Self::Strict),
+            "sensitive" => Ok(Self::Sensitive),
+            _ => Err(format!("unknown profile {:?}", s)),
+        }
+    }
+}
+
+impl Profile {
+    /// Settings of the profile in the format of the configuration file.
+    fn settings(self) -> &'static str {
+        match self {
+            Self::Default => "",
+            Self::Strict => {
+                "
+                [thresholds]
+                max_abs_div_bg = 4.5
+                max_abs_div_tg = 5.5
+                instant_std_dev_bg = 2.5
+                instant_std_dev_tg = 2.5
+                comm_std_dev_bg = 1.25
+                comm_std_dev_tg = 1.25
+
+                [filters]
+                min_region_windows = 2
+                "
+            }
+            Self::Sensitive => {
+                "
+                [thresholds]
+                max_abs_div_bg = 5.5
+                max_abs_div_tg = 6.5
+                instant_std_dev_bg = 1.75
+                instant_std_dev_tg = 1.75
+                comm_std_dev_bg = 0.75
+                comm_std_dev_tg = 0.75
+                "
+            }
+        }
+    }
+}
+
+/// Sets the values of `over` in `base`, merging tables key by key.
+pub(crate) fn merge_tables(base: &mut toml::Table, over: toml::Table) {
+    for (key, value) in over {
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Table(base)), toml::Value::Table(over)) => merge_tables(base, over),
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub thresholds: Thresholds,
+    pub window: WindowConfig,
+    pub plot: PlotConfig,
+    pub filters: FilterConfig,
+    /// Additional arches of ISA families, keyed by the family name.
+    pub families: HashMap<String, Vec<String>>,
+    pub policy: PolicyConfig,
+}
+
+impl Config {
+    /// Loads the configuration file at `path`, if any, on top of `profile`.
+    pub fn load(profile: Profile, path: Option<&Path>) -> Result<Self> {
+        let mut table: toml::Table = profile.settings().parse().unwrap();
+        let Some(path) = path else {
+            return Ok(toml::Value::Table(table).try_into().unwrap());
+        };
+
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Could not read {}", path.display()))?;
+        let file: toml::Table = text
+            .parse()
+            .with_context(|| format!("Invalid config {}", path.display()))?;
+        merge_tables(&mut table, file);
+
+        Self::from_table(table).with_context(|| format!("Invalid config {}", path.display()))
+    }
+
+    /// Configuration of `table`, which is in the format of the file.
+    pub(crate) fn from_table(table: toml::Table) -> Result<Self> {
+        let config: Self = toml::Value::Table(table).try_into()?;
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    /// Applies the same restrictions as the command line.
+    fn validate(&self) -> Result<()> {
+        if self
+            .window
+            .size
+            .is_some_and(|size| size < 2 * MIN_HALF_WINDOW)
+        {
+            bail!("window size must be at least {:#x}", 2 * MIN_HALF_WINDOW);
+        }
+        if self.window.min_windows == Some(0) {
+            bail!("min_windows must not be zero");
+        }
+        if self.window.stride == Some(0) {
+            bail!("stride must not be zero");
+        }
+        if self.filters.min_region_windows == Some(0) {
+            bail!("min_region_windows must not be zero");
+        }
+        if self.filters.max_gap == Some(0) {
+            bail!("max_gap must not be zero");
+        }
+
+        Ok(())
+    }
+}
+
+/// Thresholds of the heuristic in `final_range_decision`. The `strict`
+/// variants apply to arches that cause many false positives.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Thresholds {
+    /// Limits on the absolute divergence of the closest arch.
+    pub max_abs_div_bg: f64,
+    pub max_abs_div_tg: f64,
+    pub max_abs_div_strict_bg: f64,
+    pub max_abs_div_strict_tg: f64,
+    /// Threshold for instant detection via standard deviation.
+    pub instant_std_dev_bg: f64,
+    pub instant_std_dev_tg: f64,
+    pub instant_std_dev_strict_bg: f64,
+    pub instant_std_dev_strict_tg: f64,
+    /// Threshold for conditional detection via standard deviation.
+    pub comm_std_dev_bg: f64,
+    pub comm_std_dev_tg: f64,
+    pub comm_std_dev_strict_bg: f64,
+    pub comm_std_dev_strict_tg: f64,
+    /// Threshold for the detection of text via trigrams.
+    pub text_std_dev_tg: f64,
+    /// Arches that use the strict thresholds, replaces the built-in list.
+    pub strict_arches: Option<Vec<String>>,
+    /// Overrides for single arches, keyed by the name of the corpus entry.
+    #[serde(rename = "arch")]
+    pub arches: BTreeMap<String, ArchOverrides>,
+}
+
+impl Default for Thresholds {
+    fn default() -> Self {
+        Self {
+            max_abs_div_bg: 5.0,
+            max_abs_div_tg: 6.0,
+            max_abs_div_strict_bg: 4.0,
+            max_abs_div_strict_tg: 5.0,
+            instant_std_dev_bg: 2.0,
+            instant_std_dev_tg: 2.0,
+            instant_std_dev_strict_bg: 2.5,
+            instant_std_dev_strict_tg: 2.5,
+            comm_std_dev_bg: 1.0,
+            comm_std_dev_tg: 1.0,
+            comm_std_dev_strict_bg: 1.5,
+            comm_std_dev_strict_tg: 1.5,
+            text_std_dev_tg: 1.0,
+            strict_arches: None,
+            arches: BTreeMap::new(),
+        }
+    }
+}
+
+impl Thresholds {
+    pub fn is_strict(&self, arch: &str) -> bool {
+        if let Some(strict) = self.arches.get(arch).and_then(|o| o.strict) {
+            return strict;
+        }
+
+        match &self.strict_arches {
+            Some(strict_arches) => strict_arches.iter().any(|a| a == arch),
+            None => is_strict(arch),
+        }
+    }
+
+    /// Limits on the absolute bi- and trigram divergence of `arch`.
+    pub fn max_abs_div(&self, arch: &str) -> (f64, f64) {
+        let (bg, tg) = if self.is_strict(arch) {
+            (self.max_abs_div_strict_bg, self.max_abs_div_strict_tg)
+        } else {
+            (self.max_abs_div_bg, self.max_abs_div_tg)
+        };
+        let overrides = self.arches.get(arch);
+
+        (
+            overrides.and_then(|o| o.max_abs_div_bg).unwrap_or(bg),
+            overrides.and_then(|o| o.max_abs_div_tg).unwrap_or(tg),
+        )
+    }
+
+    /// Thresholds that apply to a range whose closest arches are `arch_bg`
+    /// in bigrams and `arch_tg` in trigrams.
+    pub fn applied(&self, arch_bg: &str, arch_tg: &str) -> AppliedThresholds {
+        let (instant_std_dev_bg, comm_std_dev_bg) = if self.is_strict(arch_bg) {
+            (self.instant_std_dev_strict_bg, self.comm_std_dev_strict_bg)
+        } else {
+            (self.instant_std_dev_bg, self.comm_std_dev_bg)
+        };
+        let (instant_std_dev_tg, comm_std_dev_tg) = if self.is_strict(arch_tg) {
+            (self.instant_std_dev_strict_tg, self.comm_std_dev_strict_tg)
+        } else {
+            (self.instant_std_dev_tg, self.comm_std_dev_tg)
+        };
+
+        AppliedThresholds {
+            max_abs_div_bg: self.max_abs_div(arch_bg).0,
+            max_abs_div_tg: self.max_abs_div(arch_tg).1,
+            instant_std_dev_bg,
+            instant_std_dev_tg,
+            comm_std_dev_bg,
+            comm_std_dev_tg,
+            text_std_dev_tg: self.text_std_dev_tg,
+        }
+    }
+
+    /// Regions of `arch` that are smaller than this are discarded.
+    pub fn min_region_size(&self, arch: &str) -> usize {
+        self.arches
+            .get(arch)
+            .and_then(|o| o.min_region_size)
+            .unwrap_or(0)
+    }
+}
+
+/// Thresholds of `Thresholds` that apply to a range, see `applied`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AppliedThresholds {
+    pub max_abs_div_bg: f64,
+    pub max_abs_div_tg: f64,
+    pub instant_std_dev_bg: f64,
+    pub instant_std_dev_tg: f64,
+    pub comm_std_dev_bg: f64,
+    pub comm_std_dev_tg: f64,
+    pub text_std_dev_tg: f64,
+}
+
+/// Settings for a single arch, unset values fall back to the global ones.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ArchOverrides {
+    pub strict: Option<bool>,
+    pub max_abs_div_bg: Option<f64>,
+    pub max_abs_div_tg: Option<f64>,
+    pub min_region_size: Option<usize>,
+}
+
+/// Defaults for the window options of the command line.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WindowConfig {
+    pub size: Option<usize>,
+    pub min_windows: Option<usize>,
+    pub stride: Option<usize>,
+}
+
+/// Post-processing of the segments, also used as defaults for the command
+/// line.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FilterConfig {
+    /// Regions that are smaller than this many strides are discarded.
+    pub min_region_windows: Option<usize>,
+    /// Unknown gaps of at most this many bytes between two regions of the
+    /// same arch are attributed to that arch.
+    pub max_gap: Option<usize>,
+}
+
+/// Defaults for the plot flags of the command line.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PlotConfig {
+    pub enabled: bool,
+    pub divs: bool,
+    pub heatmap: bool,
+    pub hilbert: bool,
+    pub big_file: bool,
+    pub format: Option<PlotFormat>,
+    /// Width and height in pixels.
+    pub size: Option<(u32, u32)>,
+    pub dpi: Option<u32>,
+}
+
+impl Default for PlotConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            divs: false,
+            heatmap: false,
+            hilbert: false,
+            big_file: false,
+            format: None,
+            size: None,
+            dpi: None,
+        }
+    }
+}
+
+/// Defaults for the policy options of the command line.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PolicyConfig {
+    /// Arches or families whose regions fail the run, see `--deny-arch`.
+    pub deny_arches: Vec<String>,
+}
@@ -0,0 +1,216 @@
+/*
+    Copyright 2024 - Valentin Obst <coderec@vpao.io>
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+*/
+//! Consolidation of the segments into the regions of the report.
+//!
+//! Runs of segments with the same arch always form a region. Depending on the
+//! strategy, regions are then absorbed by a neighbor, which trades precision
+//! for less fragmented reports. Absorbed bytes count with zero confidence
+//! towards the region that absorbed them, and padding and device tree blobs are
+//! never absorbed. With a block size, regions of the same arch are only merged
+//! across the boundaries between blocks if their confidences agree.
+
+use crate::padding::is_exact;
+use crate::{Arch, Segment};
+
+use std::ops::Range;
+use std::str::FromStr;
+
+use itertools::Itertools;
+
+/// Largest difference of the confidences of regions of the same arch on both
+/// sides of a block boundary that are merged.
+const MAX_BLOCK_CONFIDENCE_DIFF: f64 = 0.1;
+
+/// Ways to consolidate the segments into regions.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Consolidation {
+    /// Only merge runs of segments with the same arch.
+    #[default]
+    Strict,
+    /// Regions below a minimum length are absorbed by their longer neighbor.
+    MinLength,
+    /// Regions whose score, i.e., their size weighted by confidence, is below
+    /// that of both neighbors are absorbed if the neighbors agree.
+    ScoreWeighted,
+}
+
+impl FromStr for Consolidation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "strict" => Ok(Self::Strict),
+            "min-length" => Ok(Self::MinLength),
+            "score-weighted" => Ok(Self::ScoreWeighted),
+            _ => Err(format!(
+                "unknown consolidation {:?}, expected strict, min-length, or score-weighted",
+                s
+            )),
+        }
+    }
+}
+
+/// Consecutive segments that are reported together.
+#[derive(Debug, Clone)]
+pub struct Region {
+    pub range: Range<usize>,
+    pub arch: Option<Arch>,
+    /// Confidence of the segments, weighted by their size.
+    pub confidence: f64,
+    /// Number of merged segments.
+    pub segments: usize,
+}
+
+impl Region {
+    fn score(&self) -> f64 {
+        self.confidence * self.range.len() as f64
+    }
+
+    fn is_exact(&self) -> bool {
+        is_exact(self.arch.as_deref())
+    }
+
+    /// Hands the region over to `arch`, its bytes lose their confidence.
+    fn absorb_into(&mut self, arch: Option<Arch>) {
+        self.arch = arch;
+        self.confidence = 0.0;
+    }
+}
+
+/// Consolidates `segments` into regions with `strategy`. Regions shorter
+/// than `min_length` are absorbed by the `MinLength` strategy. Regions are
+/// kept apart at the boundaries of blocks of `align` bytes unless their
+/// confidences agree.
+pub fn consolidate(
+    segments: &[Segment],
+    strategy: Consolidation,
+    min_length: usize,
+    align: Option<usize>,
+) -> Vec<Region> {
+    let mut regions: Vec<Region> = segments
+        .iter()
+        .map(|segment| Region {
+            range: segment.range.clone(),
+            arch: segment.arch.clone(),
+            confidence: segment.confidence,
+            segments: 1,
+        })
+        .collect();
+    merge_equal(&mut regions, align);
+
+    loop {
+        let absorbed = match strategy {
+            Consolidation::Strict => false,
+            Consolidation::MinLength => absorb_short(&mut regions, min_length),
+            Consolidation::ScoreWeighted => absorb_minority(&mut regions),
+        };
+        if !absorbed {
+            break;
+        }
+        merge_equal(&mut regions, align);
+    }
+
+    regions
+}
+
+/// Merges neighboring regions with the same arch, see `consolidate` for
+/// `align`.
+fn merge_equal(regions: &mut Vec<Region>, align: Option<usize>) {
+    *regions = regions
+        .drain(..)
+        .coalesce(|a, b| {
+            if a.arch != b.arch {
+                return Err((a, b));
+            }
+            let at_boundary = align.is_some_and(|align| b.range.start % align == 0);
+            if at_boundary && (a.confidence - b.confidence).abs() > MAX_BLOCK_CONFIDENCE_DIFF {
+                return Err((a, b));
+            }
+            let range = a.range.start..b.range.end;
+            Ok(Region {
+                confidence: (a.score() + b.score()) / range.len().max(1) as f64,
+                range,
+                arch: a.arch,
+                segments: a.segments + b.segments,
+            })
+        })
+        .collect();
+}
+
+/// Absorbs the shortest region below `min_length` into its longer neighbor,
+/// returns false if there is none.
+fn absorb_short(regions: &mut [Region], min_length: usize) -> bool {
+    let idx = (0..regions.len())
+        .filter(|idx| {
+            let region = &regions[*idx];
+            region.range.len() < min_length && !region.is_exact()
+        })
+        .filter_map(|idx| Some((idx, longest_neighbor(regions, idx)?)))
+        // Regions that were not merged at a block boundary already have the
+        // arch of their neighbor.
+        .filter(|(idx, neighbor)| regions[*neighbor].arch != regions[*idx].arch)
+        .min_by_key(|(idx, _)| regions[*idx].range.len());
+    let Some((idx, neighbor)) = idx else {
+        return false;
+    };
+
+    let arch = regions[neighbor].arch.clone();
+    regions[idx].absorb_into(arch);
+
+    true
+}
+
+/// Absorbs the region with the lowest score among those whose neighbors
+/// agree on an arch and both have a higher score, returns false if there is
+/// none.
+fn absorb_minority(regions: &mut [Region]) -> bool {
+    let idx = (1..regions.len().saturating_sub(1))
+        .filter(|idx| {
+            let (left, region, right) = (&regions[idx - 1], &regions[*idx], &regions[idx + 1]);
+            left.arch.is_some()
+                && left.arch == right.arch
+                && !left.is_exact()
+                && !region.is_exact()
+                && region.arch != left.arch
+                && region.score() < left.score().min(right.score())
+        })
+        .min_by(|a, b| regions[*a].score().total_cmp(&regions[*b].score()));
+    let Some(idx) = idx else {
+        return false;
+    };
+
+    let arch = regions[idx - 1].arch.clone();
+    regions[idx].absorb_into(arch);
+
+    true
+}
+
+/// Index of the longest neighbor of the region at `idx` that can absorb it.
+fn longest_neighbor(regions: &[Region], idx: usize) -> Option<usize> {
+    neighbors(regions, idx).max_by_key(|neighbor| regions[*neighbor].range.len())
+}
+
+/// Indices of the neighbors of the region at `idx` that can absorb it.
+fn neighbors(regions: &[Region], idx: usize) -> impl Iterator<Item = usize> + '_ {
+    [idx.checked_sub(1), Some(idx + 1)]
+        .into_iter()
+        .flatten()
+        .filter(|neighbor| {
+            regions
+                .get(*neighbor)
+                .is_some_and(|region| !region.is_exact())
+        })
+}
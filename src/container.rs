@@ -0,0 +1,268 @@
+/*
+    Copyright 2024 - Valentin Obst <coderec@vpao.io>
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+*/
+//! Members of containers and memory images that are analyzed on their own.
+//!
+//! Each member is reported like a file named `FILE@ID`, and the report
+//! records where in the container the member came from.
+
+use crate::android::{find_boot_components, find_sparse_data, is_boot_image, is_sparse_image};
+use crate::fdt::fdt_size;
+use crate::partition::{find_partitions, is_disk_image, Partition};
+use crate::uboot::{find_fit_images, find_uimage_payloads, is_uimage};
+use crate::uefi::find_modules;
+
+use std::io::{self, Write};
+
+use anyhow::{bail, Result};
+use flate2::bufread::{DeflateDecoder, GzDecoder};
+use log::warn;
+use lz4_flex::frame::FrameDecoder;
+use ruzstd::StreamingDecoder;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Part of a container, e.g., a module of a UEFI firmware volume.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Member {
+    /// Format of the container.
+    pub format: String,
+    /// Name of the member, unique within the container.
+    pub id: String,
+    /// Location of the member within the container, `/` separated.
+    pub path: String,
+    /// Offset of the member in the input, if it is known and the member is
+    /// stored uncompressed.
+    pub offset: Option<usize>,
+    /// Address at which the member is loaded, if the container tells.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub load_address: Option<u64>,
+    /// Entry of the partition table, if the member is a partition.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub partition: Option<Partition>,
+}
+
+impl Member {
+    /// Name under which the member of `file` is reported.
+    pub fn report_name(&self, file: &str) -> String {
+        format!("{}@{}", file, self.id)
+    }
+
+    /// Replaces the name and path, which may be strings from within the
+    /// container, by their SHA-256 and drops the partition name, see
+    /// `--redact`.
+    pub fn redact(&mut self) {
+        self.id = format!("{:x}", Sha256::digest(&self.id));
+        self.path = format!("{:x}", Sha256::digest(&self.path));
+        if let Some(partition) = self.partition.as_mut() {
+            partition.name = None;
+        }
+    }
+}
+
+pub fn read_u16(data: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(
+        data.get(offset..offset + 2)?.try_into().unwrap(),
+    ))
+}
+
+pub fn read_u24(data: &[u8], offset: usize) -> Option<u32> {
+    let bytes = data.get(offset..offset + 3)?;
+
+    Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], 0]))
+}
+
+pub fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(
+        data.get(offset..offset + 4)?.try_into().unwrap(),
+    ))
+}
+
+pub fn read_u64(data: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(
+        data.get(offset..offset + 8)?.try_into().unwrap(),
+    ))
+}
+
+/// Collects decompressed data and fails once it exceeds the limit.
+struct LimitedWriter {
+    data: Vec<u8>,
+    limit: usize,
+}
+
+impl Write for LimitedWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.data.len() + buf.len() > self.limit {
+            return Err(io::Error::other(format!(
+                "decompressed size exceeds {} bytes",
+                self.limit
+            )));
+        }
+        self.data.extend_from_slice(buf);
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Decompresses the stream at the start of `data` with `method`, which is
+/// named as in U-Boot, or `deflate` for raw deflate data, to at most `limit`
+/// bytes. Returns the decompressed data and the size of the stream.
+///
+/// LZ4 frames are decoded until the data does not continue with another
+/// frame, as the format does not mark the last one.
+pub fn decompress_stream(method: &str, data: &[u8], limit: usize) -> Result<(Vec<u8>, usize)> {
+    let mut input = data;
+    let mut output = LimitedWriter {
+        data: Vec::new(),
+        limit,
+    };
+    match method {
+        "none" => {
+            output.write_all(data)?;
+            input = &[];
+        }
+        "deflate" => {
+            io::copy(&mut DeflateDecoder::new(&mut input), &mut output)?;
+        }
+        "gzip" => {
+            io::copy(&mut GzDecoder::new(&mut input), &mut output)?;
+        }
+        "lzma" | "xz" => {
+            let decode = |input: &mut &[u8], output: &mut LimitedWriter| match method {
+                "lzma" => lzma_rs::lzma_decompress(input, output),
+                _ => lzma_rs::xz_decompress(input, output),
+            };
+            // lzma-rs fails if data follows the stream, so decode it again up
+            // to where the decoder stopped.
+            if let Err(e) = decode(&mut input, &mut output) {
+                if input.is_empty() {
+                    return Err(e.into());
+                }
+                let end = data.len() - input.len();
+                output.data.clear();
+                decode(&mut &data[..end], &mut output)?;
+            }
+        }
+        "zstd" => {
+            io::copy(&mut StreamingDecoder::new(&mut input)?, &mut output)?;
+        }
+        "lz4" => {
+            if let Err(e) = io::copy(&mut FrameDecoder::new(&mut input), &mut output) {
+                if output.data.is_empty() || e.kind() == io::ErrorKind::Other {
+                    return Err(e.into());
+                }
+            }
+        }
+        _ => bail!("unsupported compression {}", method),
+    }
+
+    Ok((output.data, data.len() - input.len()))
+}
+
+/// Options of unpacking containers.
+#[derive(Debug, Clone)]
+pub struct ContainerOptions {
+    /// Search for UEFI firmware volumes, which are only searched for on
+    /// request, as their signatures can appear anywhere in the input.
+    pub uefi: bool,
+    /// Compressed payloads that decompress to more bytes are skipped.
+    pub max_size: usize,
+}
+
+/// Returns the members of `data`, which is the content of `file`, together
+/// with their content. Returns no members if `data` is not a container.
+pub fn unpack(file: &str, data: &[u8], options: &ContainerOptions) -> Vec<(Member, Vec<u8>)> {
+    if is_boot_image(data) {
+        return find_boot_components(file, data, options.max_size);
+    }
+    if is_sparse_image(data) {
+        return find_sparse_data(file, data);
+    }
+    if is_uimage(data) {
+        return find_uimage_payloads(file, data, options.max_size);
+    }
+    if is_disk_image(data) {
+        return find_partitions(file, data);
+    }
+    if fdt_size(data).is_some() {
+        let images = find_fit_images(file, data, options.max_size);
+        if !images.is_empty() {
+            return images;
+        }
+    }
+    if options.uefi {
+        let modules = find_modules(file, data, options.max_size);
+        if !modules.is_empty() {
+            return modules;
+        }
+        warn!("{}: no UEFI firmware volume found", file);
+    }
+
+    Vec::new()
+}
+
+/// Returns a hint if `data` starts with a format that the raw analysis
+/// handles badly, i.e., one that a mode that is not enabled unpacks, see
+/// `--decompress` and `--recurse`, or that should be unpacked beforehand.
+pub fn mode_hint(data: &[u8], streams: bool, archives: bool) -> Option<&'static str> {
+    const ELF_MAGIC: &[u8] = b"\x7fELF";
+    const PE_MAGIC: &[u8] = b"PE\0\0";
+    const ZIP_MAGIC: &[u8] = b"PK\x03\x04";
+    const SQUASHFS_MAGICS: [&[u8]; 2] = [b"hsqs", b"sqsh"];
+    const STREAM_MAGICS: [(&[u8], &str); 3] = [
+        (
+            &[0x1f, 0x8b, 0x08],
+            "this is a gzip file, consider --decompress",
+        ),
+        (
+            &[0xfd, b'7', b'z', b'X', b'Z', 0x00],
+            "this is an xz file, consider --decompress",
+        ),
+        (
+            &[0x28, 0xb5, 0x2f, 0xfd],
+            "this is a zstd file, consider --decompress",
+        ),
+    ];
+
+    let is_pe = data.starts_with(b"MZ")
+        && read_u32(data, 0x3c)
+            .and_then(|offset| data.get(offset as usize..))
+            .is_some_and(|header| header.starts_with(PE_MAGIC));
+    if data.starts_with(ELF_MAGIC) || is_pe {
+        return Some(
+            "this is an executable whose headers name its arch, consider analyzing only its code with --offset and --length",
+        );
+    }
+    if !archives && data.starts_with(ZIP_MAGIC) {
+        return Some("this is a zip archive, consider --recurse");
+    }
+    if SQUASHFS_MAGICS.iter().any(|magic| data.starts_with(magic)) {
+        return Some(
+            "this is a squashfs image, consider extracting its files, e.g., with unsquashfs",
+        );
+    }
+    if !streams {
+        return STREAM_MAGICS
+            .iter()
+            .find(|(magic, _)| data.starts_with(magic))
+            .map(|(_, hint)| *hint);
+    }
+
+    None
+}
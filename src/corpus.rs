@@ -14,22 +14,36 @@
     limitations under the License.
 */
 // Includes (many) changes by Valentin Obst.
+use crate::metric::Metric;
+use crate::ngrams::{read_str, read_varint, write_str, write_varint, NgramCounts, MAGIC, VERSION};
 use crate::Arch;
 
 use std::collections::HashMap;
 use std::fmt::Debug;
+use std::fs::File;
+use std::hash::Hash;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
 use std::time::Instant;
 
-use log::{debug, info};
+use anyhow::{bail, Context, Result};
+use itertools::Itertools;
+use log::{debug, info, warn};
 use rayon::prelude::*;
-use rust_embed::Embed;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
 
-#[derive(Embed)]
-#[folder = "cpu_rec_corpus"]
-struct Corpus;
+/// N-gram counts of the corpus entries in `cpu_rec_corpus`, computed by the
+/// build script.
+static CORPUS_COUNTS: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/corpus.bin"));
 
+/// N-gram frequencies of a corpus entry or of an analyzed window.
+///
+/// Only n-grams that were seen are stored. All other n-grams have the base
+/// frequency of their length, which is non-zero for corpus entries, see
+/// `from_counts`.
 #[allow(dead_code)]
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct CorpusStats {
     pub arch: String,
     pub ungrams_freq: HashMap<u8, f64>,
@@ -38,40 +52,39 @@ pub struct CorpusStats {
     pub ug_base_freq: f64,
     pub bg_base_freq: f64,
     pub tg_base_freq: f64,
+    /// Logarithms of the bigram and trigram frequencies of corpus entries, such
+    /// that divergences that only need `ln q` do not compute it per lookup.
+    pub bigrams_ln_freq: HashMap<(u8, u8), f64>,
+    pub trigrams_ln_freq: HashMap<(u8, u8, u8), f64>,
+    pub bg_base_ln_freq: f64,
+    pub tg_base_ln_freq: f64,
+    /// Entropy `-sum p ln p` of the seen bigrams and trigrams.
+    pub bg_entropy: f64,
+    pub tg_entropy: f64,
 }
 
 /// For some arches we need to be a bit more strict as they cause many false
 /// positives.
-pub fn is_strict(arch: &Arch) -> bool {
+pub fn is_strict(arch: &str) -> bool {
     const STRICT_ARCHES: &[&str] = &["OCaml", "IA-64", "IQ2000"];
 
-    STRICT_ARCHES.contains(&arch.as_str())
+    STRICT_ARCHES.contains(&arch)
 }
 
 pub fn load_corpus() -> Vec<CorpusStats> {
     let now = Instant::now();
 
-    let corpus_entries: Vec<_> = Corpus::iter()
-        .map(|arch| {
-            let arch = match arch {
-                std::borrow::Cow::Borrowed(arch) => arch,
-                _ => core::unreachable!(),
-            };
-            match Corpus::get(arch).unwrap().data {
-                std::borrow::Cow::Borrowed(data) => (arch.trim_end_matches(".corpus"), data),
-                _ => core::unreachable!(),
-            }
-        })
-        .collect();
+    let corpus_entries =
+        read_corpus_counts(&mut &CORPUS_COUNTS[..]).expect("embedded corpus is corrupt");
 
     let corpus_stats: Vec<CorpusStats> = corpus_entries
         .into_par_iter()
-        .map(|(arch, data)| {
+        .map(|(arch, counts)| {
             debug!("Loading corpus entry for arch {}.", arch);
 
             // Corpus statistics are computed with a base count of 0.01 as
             // it will be used as divisor during guessing.
-            CorpusStats::new(arch.to_owned(), data, 0.01)
+            CorpusStats::from_counts(arch, &counts, 0.01)
         })
         .collect();
 
@@ -80,6 +93,322 @@ pub fn load_corpus() -> Vec<CorpusStats> {
     corpus_stats
 }
 
+/// SHA-256 of the arches and n-gram frequencies of `corpus`, which identifies
+/// the corpus that results were produced with.
+pub fn corpus_hash(corpus: &[CorpusStats]) -> String {
+    let entry_hashes: Vec<_> = corpus
+        .par_iter()
+        .map(|stats| {
+            let mut hasher = Sha256::new();
+            hasher.update(stats.arch.as_bytes());
+            // Frequencies are hashed relative to the base frequency, which
+            // cancels the total count. Its sum depends on the iteration order
+            // of the counts, so the frequencies are rounded as well.
+            for (key, freq) in stats
+                .bigrams_freq
+                .iter()
+                .map(|((a, b), freq)| {
+                    (
+                        (*a as u32) << 8 | *b as u32,
+                        (freq / stats.bg_base_freq) as f32,
+                    )
+                })
+                .chain(stats.trigrams_freq.iter().map(|((a, b, c), freq)| {
+                    // Trigrams are keyed after all bigrams.
+                    (
+                        1 << 24 | (*a as u32) << 16 | (*b as u32) << 8 | *c as u32,
+                        (freq / stats.tg_base_freq) as f32,
+                    )
+                }))
+                .sorted_unstable_by_key(|(key, _)| *key)
+            {
+                hasher.update(key.to_le_bytes());
+                hasher.update(freq.to_le_bytes());
+            }
+            hasher.finalize()
+        })
+        .collect();
+
+    let mut hasher = Sha256::new();
+    for entry_hash in entry_hashes {
+        hasher.update(entry_hash);
+    }
+
+    format!("{:x}", hasher.finalize())
+}
+
+/// Loads the entries in `dir`, i.e., raw code samples named `<arch>.corpus`,
+/// precomputed statistics named `*.stats`, see `train_corpus_entry`, and
+/// manifests of weighted samples named `*.toml`, see `load_manifest`.
+/// Entries that cannot be loaded are skipped with a warning, or, if `strict`
+/// is set, fail the whole directory.
+pub fn load_corpus_dir(dir: &Path, strict: bool) -> Result<Vec<CorpusStats>> {
+    let mut paths: Vec<_> = std::fs::read_dir(dir)
+        .with_context(|| format!("Could not read {}", dir.display()))?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<io::Result<_>>()
+        .with_context(|| format!("Could not read {}", dir.display()))?;
+    paths.sort_unstable();
+
+    let mut entries = Vec::new();
+    let mut failed = Vec::new();
+    for path in paths {
+        let Some(loaded) = load_corpus_entry(&path) else {
+            continue;
+        };
+        match loaded {
+            Ok(loaded) => entries.extend(loaded),
+            Err(e) => {
+                warn!("{}: skipping corpus entry: {:#}", path.display(), e);
+                failed.push(path);
+            }
+        }
+    }
+
+    if strict && !failed.is_empty() {
+        bail!(
+            "{}: {} corpus entries could not be loaded: {}",
+            dir.display(),
+            failed.len(),
+            failed.iter().map(|path| path.display()).join(", ")
+        );
+    }
+    info!(
+        "{}: loaded corpus entries {}",
+        dir.display(),
+        entries
+            .iter()
+            .map(|entry: &CorpusStats| entry.arch.as_str())
+            .join(", ")
+    );
+
+    Ok(entries)
+}
+
+/// Loads the entries in `path` according to its extension, see
+/// `load_corpus_dir`. Returns `None` if the extension is unknown.
+pub fn load_corpus_entry(path: &Path) -> Option<Result<Vec<CorpusStats>>> {
+    let loaded = match path.extension().and_then(|ext| ext.to_str())? {
+        "corpus" => load_corpus_file(path).map(|entry| vec![entry]),
+        "stats" => load_stats_file(path),
+        "toml" => load_manifest(path)
+            .map(|(arch, counts)| vec![CorpusStats::from_counts(arch, &counts, 0.01)]),
+        _ => return None,
+    };
+
+    Some(loaded)
+}
+
+fn load_corpus_file(path: &Path) -> Result<CorpusStats> {
+    let arch = path
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .context("invalid file name")?
+        .to_owned();
+    let counts = File::open(path)
+        .and_then(|mut file| NgramCounts::from_reader(&mut file))
+        .context("could not read file")?;
+    if counts.trigrams.is_empty() {
+        bail!("file is too short");
+    }
+
+    Ok(CorpusStats::from_counts(arch, &counts, 0.01))
+}
+
+fn load_stats_file(path: &Path) -> Result<Vec<CorpusStats>> {
+    let data = std::fs::read(path).context("could not read file")?;
+
+    Ok(read_corpus_counts(&mut data.as_slice())
+        .context("corrupt statistics")?
+        .into_iter()
+        .map(|(arch, counts)| CorpusStats::from_counts(arch, &counts, 0.01))
+        .collect())
+}
+
+/// Corpus entry that is merged from several weighted samples. Example:
+///
+/// ```toml
+/// arch = "ARMel"
+///
+/// [[samples]]
+/// path = "kernel.bin"
+///
+/// [[samples]]
+/// path = "userland.bin"
+/// weight = 0.5
+/// ```
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CorpusManifest {
+    /// Defaults to the name of the manifest without extension.
+    arch: Option<Arch>,
+    samples: Vec<ManifestSample>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ManifestSample {
+    /// Raw code, relative to the manifest.
+    path: PathBuf,
+    #[serde(default = "default_weight")]
+    weight: f64,
+}
+
+fn default_weight() -> f64 {
+    1.0
+}
+
+/// Loads the manifest in `path` and merges the counts of its samples, see
+/// `NgramCounts::weighted_sum`.
+pub fn load_manifest(path: &Path) -> Result<(Arch, NgramCounts)> {
+    let text = std::fs::read_to_string(path).context("could not read file")?;
+    let manifest: CorpusManifest = toml::from_str(&text).context("invalid manifest")?;
+
+    let arch = match manifest.arch {
+        Some(arch) => arch,
+        None => path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .context("invalid file name")?
+            .to_owned(),
+    };
+    if manifest.samples.is_empty() {
+        bail!("no samples");
+    }
+
+    let dir = path.parent().unwrap_or(Path::new(""));
+    let mut samples = Vec::new();
+    for sample in manifest.samples {
+        if !(sample.weight.is_finite() && sample.weight > 0.0) {
+            bail!("weight of {} must be positive", sample.path.display());
+        }
+        let sample_path = dir.join(&sample.path);
+        let counts = File::open(&sample_path)
+            .and_then(|mut file| NgramCounts::from_reader(&mut file))
+            .with_context(|| format!("Could not read {}", sample_path.display()))?;
+        if counts.trigrams.is_empty() {
+            bail!("{}: sample is too short", sample_path.display());
+        }
+        debug!(
+            "{}: added {} with weight {}",
+            arch,
+            sample_path.display(),
+            sample.weight
+        );
+        samples.push((counts, sample.weight));
+    }
+
+    Ok((arch, NgramCounts::weighted_sum(&samples)))
+}
+
+/// Merges the weighted samples of the manifest in `path` and writes the
+/// resulting statistics to `output`.
+pub fn merge_corpus_entry(path: &Path, output: &Path) -> Result<()> {
+    let (arch, counts) =
+        load_manifest(path).with_context(|| format!("Could not load {}", path.display()))?;
+
+    let mut out = BufWriter::new(
+        File::create(output).with_context(|| format!("Could not create {}", output.display()))?,
+    );
+    write_corpus_counts(&mut out, &[(&arch, &counts)])?;
+    out.flush()?;
+    info!("Wrote statistics for arch {} to {}", arch, output.display());
+
+    Ok(())
+}
+
+/// Reads the serialized counts of all entries of a corpus.
+fn read_corpus_counts<R: io::Read>(r: &mut R) -> io::Result<Vec<(Arch, NgramCounts)>> {
+    let mut magic = [0u8; 4];
+    r.read_exact(&mut magic)?;
+    let mut version = [0u8];
+    r.read_exact(&mut version)?;
+    if &magic != MAGIC || version[0] != VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "unknown corpus format",
+        ));
+    }
+
+    let n = read_varint(r)?;
+    (0..n)
+        .map(|_| Ok((read_str(r)?, NgramCounts::read_from(r)?)))
+        .collect()
+}
+
+/// Writes the counts of corpus entries in the format that is also used for
+/// the embedded corpus.
+fn write_corpus_counts<W: io::Write>(
+    w: &mut W,
+    entries: &[(&str, &NgramCounts)],
+) -> io::Result<()> {
+    w.write_all(MAGIC)?;
+    w.write_all(&[VERSION])?;
+    write_varint(w, entries.len() as u64)?;
+    for (arch, counts) in entries {
+        write_str(w, arch)?;
+        counts.write_to(w)?;
+    }
+
+    Ok(())
+}
+
+/// Builds a new corpus entry for `arch` by concatenating the raw code
+/// `samples`. The resulting file uses the same format as the entries in
+/// `cpu_rec_corpus`, i.e., it can be dropped into that directory as
+/// `<arch>.corpus`. If `stats` is given, the precomputed n-gram counts of the
+/// entry are written to that file.
+pub fn train_corpus_entry(
+    arch: &str,
+    samples: &[&Path],
+    output: &Path,
+    stats: Option<&Path>,
+) -> Result<()> {
+    if arch.is_empty() || arch.contains(['/', '\\']) {
+        bail!("Invalid arch name: {:?}", arch);
+    }
+
+    let mut out = BufWriter::new(
+        File::create(output).with_context(|| format!("Could not create {}", output.display()))?,
+    );
+    let mut total = 0;
+    for sample in samples {
+        let mut sample_file =
+            File::open(sample).with_context(|| format!("Could not open {}", sample.display()))?;
+        let n = io::copy(&mut sample_file, &mut out)
+            .with_context(|| format!("Could not read {}", sample.display()))?;
+        debug!("{}: added {} bytes from {}", arch, n, sample.display());
+        total += n;
+    }
+    out.flush()?;
+
+    // Trigram statistics of tiny samples are meaningless.
+    if total < 0x1000 {
+        warn!("{}: corpus entry only has {} bytes", arch, total);
+    }
+    info!(
+        "Wrote {} bytes for arch {} to {}",
+        total,
+        arch,
+        output.display()
+    );
+
+    if let Some(stats) = stats {
+        // Samples may be much larger than the memory, count them in chunks.
+        let counts = File::open(output)
+            .and_then(|mut file| NgramCounts::from_reader(&mut file))
+            .with_context(|| format!("Could not read {}", output.display()))?;
+        let mut stats_out = BufWriter::new(
+            File::create(stats).with_context(|| format!("Could not create {}", stats.display()))?,
+        );
+        write_corpus_counts(&mut stats_out, &[(arch, &counts)])?;
+        stats_out.flush()?;
+        info!("Wrote statistics for arch {} to {}", arch, stats.display());
+    }
+
+    Ok(())
+}
+
 pub struct Divergences {
     pub bigrams: f64,
     pub trigrams: f64,
@@ -87,34 +416,31 @@ pub struct Divergences {
 
 impl CorpusStats {
     pub fn new(arch: String, data: &[u8], base_count: f64) -> Self {
-        let mut ug_counts = HashMap::new();
-        let mut bg_counts = HashMap::new();
-        let mut tg_counts = HashMap::new();
-
-        for w in data.windows(3) {
-            let ug = w[0];
-            ug_counts
-                .entry(ug)
-                .and_modify(|count| *count += 1.0)
-                .or_insert(1.0 + base_count);
-
-            let bg = (w[0], w[1]);
-            bg_counts
-                .entry(bg)
-                .and_modify(|count| *count += 1.0)
-                .or_insert(1.0 + base_count);
-
-            let tg = (w[0], w[1], w[2]);
-            tg_counts
-                .entry(tg)
-                .and_modify(|count| *count += 1.0)
-                .or_insert(1.0 + base_count);
-        }
+        Self::from_counts(arch, &NgramCounts::from_bytes(data), base_count)
+    }
+
+    /// Computes the statistics from absolute n-gram counts. Each n-gram that
+    /// was seen is assigned its count plus `base_count`.
+    pub(crate) fn from_counts(arch: String, counts: &NgramCounts, base_count: f64) -> Self {
+        let ug_counts: HashMap<u8, f64> = counts
+            .ungrams
+            .iter()
+            .map(|(k, v)| (*k, v + base_count))
+            .collect();
+        let bg_counts: HashMap<(u8, u8), f64> = counts
+            .bigrams
+            .iter()
+            .map(|(k, v)| (*k, v + base_count))
+            .collect();
+        let tg_counts: HashMap<(u8, u8, u8), f64> = counts
+            .trigrams
+            .iter()
+            .map(|(k, v)| (*k, v + base_count))
+            .collect();
 
         debug!(
-            "{}: {} bytes, {:x} ungrams, {:x} bigrams, {:x} trigrams",
+            "{}: {:x} ungrams, {:x} bigrams, {:x} trigrams",
             arch,
-            data.len(),
             ug_counts.len(),
             bg_counts.len(),
             tg_counts.len()
@@ -137,44 +463,125 @@ impl CorpusStats {
             .into_iter()
             .map(|(k, v)| (k, (v / ug_qtotal)))
             .collect();
-        let bg_freq = bg_counts
+        let bg_freq: HashMap<(u8, u8), f64> = bg_counts
             .into_iter()
             .map(|(k, v)| (k, (v / bi_qtotal)))
             .collect();
-        let tg_freq = tg_counts
+        let tg_freq: HashMap<(u8, u8, u8), f64> = tg_counts
             .into_iter()
             .map(|(k, v)| (k, (v / tri_qtotal)))
             .collect();
+        // Only corpus entries, which have a base count, are compared against,
+        // so windows skip the tables.
+        let (bg_ln_freq, tg_ln_freq) = if base_count > 0.0 {
+            (ln_freqs(&bg_freq), ln_freqs(&tg_freq))
+        } else {
+            Default::default()
+        };
 
         CorpusStats {
             arch,
             ungrams_freq: ug_freq,
+            bg_entropy: entropy(&bg_freq),
+            tg_entropy: entropy(&tg_freq),
             bigrams_freq: bg_freq,
             trigrams_freq: tg_freq,
             ug_base_freq: base_count / ug_qtotal,
             bg_base_freq: base_count / bi_qtotal,
             tg_base_freq: base_count / tri_qtotal,
+            bigrams_ln_freq: bg_ln_freq,
+            trigrams_ln_freq: tg_ln_freq,
+            bg_base_ln_freq: (base_count / bi_qtotal).ln(),
+            tg_base_ln_freq: (base_count / tri_qtotal).ln(),
         }
     }
 
-    /// Compute the Kullback–Leibler divergence (cross entropy) of the
-    /// current file with the reference from corpus `q`.
-    pub fn compute_kl(&self, q: &Self) -> Divergences {
-        let mut kld_bg = 0.0;
-        for (bg, f) in &self.bigrams_freq {
-            if *f != 0.0 {
-                kld_bg += f * (f / q.bigrams_freq.get(bg).unwrap_or(&q.bg_base_freq)).ln();
-            }
-        }
-        let mut kld_tg = 0.0;
-        for (tg, f) in &self.trigrams_freq {
-            if *f != 0.0 {
-                kld_tg += f * (f / q.trigrams_freq.get(tg).unwrap_or(&q.tg_base_freq)).ln();
-            }
+    /// Frequency of `ungram`, including the base frequency of unseen ones.
+    pub fn ungram_freq(&self, ungram: u8) -> f64 {
+        *self.ungrams_freq.get(&ungram).unwrap_or(&self.ug_base_freq)
+    }
+
+    /// Frequency of `bigram`, including the base frequency of unseen ones.
+    pub fn bigram_freq(&self, bigram: (u8, u8)) -> f64 {
+        *self.bigrams_freq.get(&bigram).unwrap_or(&self.bg_base_freq)
+    }
+
+    /// Frequency of `trigram`, including the base frequency of unseen ones.
+    pub fn trigram_freq(&self, trigram: (u8, u8, u8)) -> f64 {
+        *self
+            .trigrams_freq
+            .get(&trigram)
+            .unwrap_or(&self.tg_base_freq)
+    }
+
+    /// Compute the divergence of the current file from the reference from
+    /// corpus `q` according to `metric`, e.g., the Kullback–Leibler divergence
+    /// (cross entropy).
+    pub fn compute_divergence(&self, q: &Self, metric: &dyn Metric) -> Divergences {
+        if metric.is_relative_entropy() {
+            return Divergences {
+                bigrams: cross_entropy(&self.bigrams_freq, &q.bigrams_ln_freq, q.bg_base_ln_freq)
+                    - self.bg_entropy,
+                trigrams: cross_entropy(
+                    &self.trigrams_freq,
+                    &q.trigrams_ln_freq,
+                    q.tg_base_ln_freq,
+                ) - self.tg_entropy,
+            };
         }
+
         Divergences {
-            bigrams: kld_bg,
-            trigrams: kld_tg,
+            bigrams: divergence(&self.bigrams_freq, &q.bigrams_freq, q.bg_base_freq, metric),
+            trigrams: divergence(
+                &self.trigrams_freq,
+                &q.trigrams_freq,
+                q.tg_base_freq,
+                metric,
+            ),
+        }
+    }
+}
+
+fn ln_freqs<K: Eq + Hash + Copy>(freqs: &HashMap<K, f64>) -> HashMap<K, f64> {
+    freqs.iter().map(|(ngram, f)| (*ngram, f.ln())).collect()
+}
+
+fn entropy<K>(freqs: &HashMap<K, f64>) -> f64 {
+    -freqs
+        .values()
+        .filter(|f| **f != 0.0)
+        .map(|f| f * f.ln())
+        .sum::<f64>()
+}
+
+/// Cross entropy `-sum p ln q` of `p` with the distribution whose logarithms
+/// are `q_ln`.
+fn cross_entropy<K: Eq + Hash>(p: &HashMap<K, f64>, q_ln: &HashMap<K, f64>, q_base_ln: f64) -> f64 {
+    let mut sum = 0.0;
+    for (ngram, f) in p {
+        if *f != 0.0 {
+            sum += f * q_ln.get(ngram).unwrap_or(&q_base_ln);
         }
     }
+
+    -sum
+}
+
+fn divergence<K: Eq + Hash>(
+    p: &HashMap<K, f64>,
+    q: &HashMap<K, f64>,
+    q_base_freq: f64,
+    metric: &dyn Metric,
+) -> f64 {
+    let mut sum = 0.0;
+    let mut q_covered = 0.0;
+    for (ngram, f) in p {
+        if *f != 0.0 {
+            let q_f = *q.get(ngram).unwrap_or(&q_base_freq);
+            sum += metric.term(*f, q_f);
+            q_covered += q_f;
+        }
+    }
+
+    metric.finish(sum, q_covered)
 }
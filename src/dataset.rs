@@ -0,0 +1,157 @@
+/*
+    Copyright 2024 - Valentin Obst <coderec@vpao.io>
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+*/
+//! Export of labeled windows for training other models.
+//!
+//! Each analyzed window of full size is a sample, labeled with the arch that
+//! its segment was attributed to, or `unknown`. The dataset directory has the
+//! following layout:
+//!
+//! - `DIR/LABEL/HASH.bin`: the raw bytes of a sample, where `HASH` is their
+//!   SHA-256 and `/` in the label is replaced by `_`. Identical samples are
+//!   only stored once per label.
+//! - `DIR/samples.jsonl`: one line per sample with the fields `file`,
+//!   `window` (start and end offset), `label`, `confidence`, and `path`
+//!   (relative to `DIR`).
+//!
+//! If a maximum number of samples per label is given, samples of a label are
+//! skipped once it is reached, across all files of the run. The windows of
+//! each file are then visited in a random order that only depends on the
+//! seed, such that the samples are spread over the whole file.
+
+use crate::extract::store_object;
+use crate::ProcessedDetectionResult;
+
+use std::collections::{HashMap, HashSet};
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use log::info;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+use rand_chacha::ChaCha8Rng;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+const SAMPLES: &str = "samples.jsonl";
+/// Label of windows that were not attributed to any arch.
+const UNKNOWN: &str = "unknown";
+
+/// Line of the sample list.
+#[derive(Serialize)]
+struct Sample<'a> {
+    file: &'a str,
+    window: &'a Range<usize>,
+    label: &'a str,
+    confidence: f64,
+    path: &'a str,
+}
+
+pub struct DatasetWriter {
+    root: PathBuf,
+    samples: BufWriter<File>,
+    /// Maximum number of samples per label.
+    per_label: Option<usize>,
+    label_counts: HashMap<String, usize>,
+    rng: ChaCha8Rng,
+}
+
+impl DatasetWriter {
+    /// Opens the dataset in `root`, which is created if needed. New samples
+    /// are appended to an existing sample list. `seed` determines which
+    /// samples are exported if there is a maximum per label.
+    pub fn open(root: &Path, per_label: Option<usize>, seed: u64) -> Result<Self> {
+        std::fs::create_dir_all(root)
+            .with_context(|| format!("Could not create {}", root.display()))?;
+        let samples_path = root.join(SAMPLES);
+        let samples = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&samples_path)
+            .with_context(|| format!("Could not open {}", samples_path.display()))?;
+
+        Ok(Self {
+            root: root.to_owned(),
+            samples: BufWriter::new(samples),
+            per_label,
+            label_counts: HashMap::new(),
+            rng: ChaCha8Rng::seed_from_u64(seed),
+        })
+    }
+
+    /// Adds the windows of `res` as samples, `data` are the analyzed bytes.
+    pub fn add_samples(
+        &mut self,
+        file: &str,
+        res: &ProcessedDetectionResult,
+        data: &[u8],
+    ) -> Result<()> {
+        // Segments of refined boundaries may share a window, and the last
+        // windows of a file can be shorter.
+        let mut seen = HashSet::new();
+        let mut segments: Vec<_> = res
+            .segments
+            .iter()
+            .filter(|segment| {
+                segment.window.len() == res.win_sz && seen.insert(segment.window.clone())
+            })
+            .collect();
+        if self.per_label.is_some() {
+            segments.shuffle(&mut self.rng);
+        }
+
+        let mut added = 0;
+        for segment in segments {
+            let window = &segment.window;
+            let label = segment.arch.as_deref().unwrap_or(UNKNOWN);
+            let count = self.label_counts.entry(label.to_owned()).or_default();
+            if self.per_label.is_some_and(|max| *count >= max) {
+                continue;
+            }
+            *count += 1;
+
+            let sample = &data[window.clone()];
+            let sha256 = format!("{:x}", Sha256::digest(sample));
+            let path = format!("{}/{}.bin", label.replace('/', "_"), sha256);
+            store_object(&self.root, &path, sample)?;
+
+            serde_json::to_writer(
+                &mut self.samples,
+                &Sample {
+                    file,
+                    window,
+                    label,
+                    confidence: segment.confidence,
+                    path: &path,
+                },
+            )?;
+            self.samples.write_all(b"\n")?;
+            added += 1;
+        }
+        self.samples.flush()?;
+
+        info!(
+            "{}: exported {} samples to {}",
+            file,
+            added,
+            self.root.display()
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,117 @@
+/*
+    Copyright 2024 - Valentin Obst <coderec@vpao.io>
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+*/
+//! Database of past results.
+//!
+//! The composition of every analyzed file is appended as one JSON line to the
+//! database file, tagged with the product and version that the file belongs
+//! to. This allows to follow products across versions, see `trend`.
+
+use crate::output::CliJsonOutput;
+use crate::Arch;
+
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+/// Line of the database.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ResultRecord {
+    pub product: String,
+    pub version: String,
+    pub file: String,
+    /// Seconds since the epoch at which the file was analyzed.
+    pub time: u64,
+    pub size: usize,
+    /// Number of bytes per arch.
+    pub arches: BTreeMap<Arch, usize>,
+    /// Metadata of the file, see `--meta`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub meta: BTreeMap<String, String>,
+}
+
+impl ResultRecord {
+    pub fn new(product: &str, version: &str, report: &CliJsonOutput) -> Self {
+        let mut arches = BTreeMap::new();
+        for (_, size, arch) in report.range_results.iter() {
+            *arches.entry(arch.clone()).or_default() += size;
+        }
+
+        Self {
+            product: product.to_owned(),
+            version: version.to_owned(),
+            file: report.file.clone(),
+            time: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |time| time.as_secs()),
+            size: report.size,
+            arches,
+            meta: report.meta.clone().unwrap_or_default(),
+        }
+    }
+}
+
+pub struct ResultsDb {
+    out: BufWriter<File>,
+}
+
+impl ResultsDb {
+    /// Opens the database in `path` for appending, it is created if needed.
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Could not open {}", path.display()))?;
+
+        Ok(Self {
+            out: BufWriter::new(file),
+        })
+    }
+
+    pub fn append(&mut self, record: &ResultRecord) -> Result<()> {
+        serde_json::to_writer(&mut self.out, record)?;
+        self.out.write_all(b"\n")?;
+        self.out.flush()?;
+
+        Ok(())
+    }
+
+    /// Reads all records of `product` in the order in which they were added.
+    pub fn read(path: &Path, product: &str) -> Result<Vec<ResultRecord>> {
+        let file =
+            File::open(path).with_context(|| format!("Could not open {}", path.display()))?;
+
+        let mut records = Vec::new();
+        for (idx, line) in BufReader::new(file).lines().enumerate() {
+            let line = line.with_context(|| format!("Could not read {}", path.display()))?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let record: ResultRecord = serde_json::from_str(&line)
+                .with_context(|| format!("{}:{}: invalid record", path.display(), idx + 1))?;
+            if record.product == product {
+                records.push(record);
+            }
+        }
+
+        Ok(records)
+    }
+}
@@ -0,0 +1,301 @@
+/*
+    Copyright 2024 - Valentin Obst <coderec@vpao.io>
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+*/
+//! Divergences from dense `f32` tables, see `--f32`.
+//!
+//! The bigram frequencies of each corpus entry are stored in a table with one
+//! entry per bigram, and the trigrams that were seen in a sorted array, as a
+//! table of all trigrams would take 64MiB per entry. Frequencies are stored as
+//! `f32`, which halves the memory traffic of the lookups compared to the
+//! hash maps of `f64` frequencies. For relative entropies, the tables hold the
+//! logarithms of the frequencies instead. The trigrams of a window are looked
+//! up in ascending order, such that each search continues where the previous
+//! one ended. Terms are still summed in `f64`.
+//!
+//! The precision loss shifts divergences slightly and may thus change the
+//! labels of windows close to a threshold, `corpus validate-f32` measures
+//! this on a benchmark set.
+
+use crate::config::Thresholds;
+use crate::corpus::CorpusStats;
+use crate::impact::{read_benchmark, UNKNOWN};
+use crate::metric::{Metric, MetricKind};
+use crate::scorer::WindowScorer;
+use crate::{calculate_kl, Arch, KlRes, RangeFullKlRes};
+
+use std::fmt;
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use rayon::prelude::*;
+use serde::Serialize;
+
+fn bigram_key((a, b): (u8, u8)) -> usize {
+    (a as usize) << 8 | b as usize
+}
+
+fn trigram_key((a, b, c): (u8, u8, u8)) -> u32 {
+    (a as u32) << 16 | (b as u32) << 8 | c as u32
+}
+
+/// Combines the window frequencies `p` with the values `q` of the entry for
+/// the n-grams of a window, see `DenseEntry`.
+fn divergence(pairs: impl Iterator<Item = (f32, f32)>, metric: &dyn Metric, entropy: f64) -> f64 {
+    let mut sum = 0.0;
+    if metric.is_relative_entropy() {
+        for (p, ln_q) in pairs {
+            sum -= p as f64 * ln_q as f64;
+        }
+
+        return sum - entropy;
+    }
+
+    let mut q_covered = 0.0;
+    for (p, q) in pairs {
+        sum += metric.term(p as f64, q as f64);
+        q_covered += q as f64;
+    }
+
+    metric.finish(sum, q_covered)
+}
+
+/// N-gram frequencies of a corpus entry, or their logarithms if the metric is
+/// a relative entropy.
+struct DenseEntry {
+    arch: Arch,
+    /// Value of each bigram, including the base value of unseen ones.
+    bigrams: Vec<f32>,
+    /// Seen trigrams in ascending order and their values.
+    trigram_keys: Vec<u32>,
+    trigram_values: Vec<f32>,
+    tg_base_value: f32,
+}
+
+impl DenseEntry {
+    fn new(stats: &CorpusStats, logarithms: bool) -> Self {
+        let (bigram_values, trigram_values, bg_base_value, tg_base_value) = if logarithms {
+            (
+                &stats.bigrams_ln_freq,
+                &stats.trigrams_ln_freq,
+                stats.bg_base_ln_freq,
+                stats.tg_base_ln_freq,
+            )
+        } else {
+            (
+                &stats.bigrams_freq,
+                &stats.trigrams_freq,
+                stats.bg_base_freq,
+                stats.tg_base_freq,
+            )
+        };
+
+        let mut bigrams = vec![bg_base_value as f32; 1 << 16];
+        for (bigram, value) in bigram_values.iter() {
+            bigrams[bigram_key(*bigram)] = *value as f32;
+        }
+
+        let mut trigrams: Vec<(u32, f32)> = trigram_values
+            .iter()
+            .map(|(trigram, value)| (trigram_key(*trigram), *value as f32))
+            .collect();
+        trigrams.sort_unstable_by_key(|(key, _)| *key);
+
+        Self {
+            arch: stats.arch.clone(),
+            bigrams,
+            trigram_keys: trigrams.iter().map(|(key, _)| *key).collect(),
+            trigram_values: trigrams.iter().map(|(_, value)| *value).collect(),
+            tg_base_value: tg_base_value as f32,
+        }
+    }
+
+    fn bigram_divergence(&self, window: &[(usize, f32)], metric: &dyn Metric, entropy: f64) -> f64 {
+        divergence(
+            window.iter().map(|(key, p)| (*p, self.bigrams[*key])),
+            metric,
+            entropy,
+        )
+    }
+
+    /// `window` must be sorted by trigram.
+    fn trigram_divergence(&self, window: &[(u32, f32)], metric: &dyn Metric, entropy: f64) -> f64 {
+        let mut from = 0;
+        let pairs = window.iter().map(|(key, p)| {
+            let q = match self.trigram_keys[from..].binary_search(key) {
+                Ok(idx) => {
+                    from += idx + 1;
+                    self.trigram_values[from - 1]
+                }
+                Err(idx) => {
+                    from += idx;
+                    self.tg_base_value
+                }
+            };
+            (*p, q)
+        });
+
+        divergence(pairs, metric, entropy)
+    }
+}
+
+/// Corpus in dense `f32` tables for one metric.
+pub struct DenseCorpus {
+    metric: MetricKind,
+    entries: Vec<DenseEntry>,
+}
+
+impl fmt::Debug for DenseCorpus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list()
+            .entries(self.entries.iter().map(|entry| &entry.arch))
+            .finish()
+    }
+}
+
+impl DenseCorpus {
+    pub fn new(corpus: &[CorpusStats], metric: MetricKind) -> Self {
+        let logarithms = metric.metric().is_relative_entropy();
+
+        Self {
+            metric,
+            entries: corpus
+                .par_iter()
+                .map(|stats| DenseEntry::new(stats, logarithms))
+                .collect(),
+        }
+    }
+
+    /// Divergences of the window with the n-gram frequencies in `window` from
+    /// all entries, like `calculate_kl` with the metric of the tables.
+    pub(crate) fn calculate_kl(&self, window: &CorpusStats) -> RangeFullKlRes {
+        let bigrams: Vec<(usize, f32)> = window
+            .bigrams_freq
+            .iter()
+            .filter(|(_, freq)| **freq != 0.0)
+            .map(|(bigram, freq)| (bigram_key(*bigram), *freq as f32))
+            .collect();
+        let mut trigrams: Vec<(u32, f32)> = window
+            .trigrams_freq
+            .iter()
+            .filter(|(_, freq)| **freq != 0.0)
+            .map(|(trigram, freq)| (trigram_key(*trigram), *freq as f32))
+            .collect();
+        trigrams.sort_unstable_by_key(|(key, _)| *key);
+
+        let metric = self.metric.metric();
+        let (kl_bg, kl_tg) = self
+            .entries
+            .iter()
+            .map(|entry| {
+                (
+                    KlRes {
+                        arch: entry.arch.clone(),
+                        div: entry.bigram_divergence(&bigrams, metric, window.bg_entropy),
+                    },
+                    KlRes {
+                        arch: entry.arch.clone(),
+                        div: entry.trigram_divergence(&trigrams, metric, window.tg_entropy),
+                    },
+                )
+            })
+            .unzip();
+
+        RangeFullKlRes::sorted(kl_bg, kl_tg)
+    }
+}
+
+impl WindowScorer for DenseCorpus {
+    fn name(&self) -> &'static str {
+        "dense f32 divergence"
+    }
+
+    fn score(&self, _corpus_stats: &[CorpusStats], window: &CorpusStats) -> RangeFullKlRes {
+        self.calculate_kl(window)
+    }
+}
+
+/// Agreement of the `f32` and `f64` computations on a benchmark set.
+#[derive(Debug, Serialize)]
+pub struct Validation {
+    pub windows: usize,
+    /// Number of correctly labeled windows with `f64` and `f32`.
+    pub correct_f64: usize,
+    pub correct_f32: usize,
+    /// Number of windows whose label differs.
+    pub changed: usize,
+    /// Largest absolute difference of the divergences of a window from any
+    /// entry, and its mean over all windows.
+    pub max_error: f64,
+    pub mean_error: f64,
+}
+
+/// Classifies the windows in `benchmark`, see `corpus impact`, with both
+/// computations.
+pub fn validate_f32(corpus: &[CorpusStats], benchmark: &Path) -> Result<Validation> {
+    let windows = read_benchmark(benchmark)?;
+    if windows.is_empty() {
+        bail!("{}: no benchmark windows", benchmark.display());
+    }
+
+    let metric = MetricKind::default();
+    let dense = DenseCorpus::new(corpus, metric);
+    let thresholds = Thresholds::default();
+    let results: Vec<(String, String, f64)> = windows
+        .par_iter()
+        .map(|(_, path)| {
+            let data = std::fs::read(path)
+                .with_context(|| format!("Could not read {}", path.display()))?;
+            let window = CorpusStats::new("target".to_string(), &data, 0.0);
+            let label = |res: &RangeFullKlRes| {
+                res.decide(&thresholds)
+                    .arch
+                    .map_or(UNKNOWN.to_owned(), |arch| arch.replace('/', "_"))
+            };
+
+            let exact = calculate_kl(corpus, &window, metric);
+            let approx = dense.calculate_kl(&window);
+            // Both are sorted by divergence, which need not be the same order.
+            let error = [(&exact.kl_bg, &approx.kl_bg), (&exact.kl_tg, &approx.kl_tg)]
+                .into_iter()
+                .flat_map(|(exact, approx)| {
+                    exact.iter().map(|exact| {
+                        let approx = approx.iter().find(|res| res.arch == exact.arch).unwrap();
+                        (exact.div - approx.div).abs()
+                    })
+                })
+                .fold(0.0, f64::max);
+
+            Ok((label(&exact), label(&approx), error))
+        })
+        .collect::<Result<_>>()?;
+
+    let mut validation = Validation {
+        windows: windows.len(),
+        correct_f64: 0,
+        correct_f32: 0,
+        changed: 0,
+        max_error: 0.0,
+        mean_error: 0.0,
+    };
+    for ((expected, _), (exact, approx, error)) in windows.iter().zip(results.iter()) {
+        validation.correct_f64 += (exact == expected) as usize;
+        validation.correct_f32 += (approx == expected) as usize;
+        validation.changed += (exact != approx) as usize;
+        validation.max_error = validation.max_error.max(*error);
+        validation.mean_error += error / windows.len() as f64;
+    }
+
+    Ok(validation)
+}
@@ -0,0 +1,186 @@
+/*
+    Copyright 2024 - Valentin Obst <coderec@vpao.io>
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+*/
+//! Comparison of the regions of two analyses, see `coderec diff`.
+//!
+//! Regions are paired by their overlap, largest overlap first, as long as it
+//! covers more than half of the smaller region. A pair with the same arch is
+//! unchanged if both boundaries agree up to `tolerance`, as region boundaries
+//! are only as precise as the windows, and resized otherwise; a pair with
+//! different arches changed. Of the remaining regions, those of the same arch
+//! and size in both analyses moved, and all others disappeared from the first
+//! one or appeared in the second one.
+
+use crate::output::CliJsonOutput;
+
+use std::ops::Range;
+
+use serde::Serialize;
+
+/// Differences between the regions of two analyses.
+#[derive(Debug, Serialize)]
+pub struct DiffReport {
+    pub a: String,
+    pub b: String,
+    pub tolerance: usize,
+    /// Number of regions that are the same in both analyses.
+    pub unchanged: usize,
+    /// Sorted by offset, in the first analysis if the region is in it.
+    pub changes: Vec<Change>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangeKind {
+    Appeared,
+    Disappeared,
+    Changed,
+    Resized,
+    Moved,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DiffRegion {
+    pub range: Range<usize>,
+    pub arch: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Change {
+    pub kind: ChangeKind,
+    /// The region in the first analysis, if any.
+    pub a: Option<DiffRegion>,
+    /// The region in the second analysis, if any.
+    pub b: Option<DiffRegion>,
+}
+
+fn regions(report: &CliJsonOutput) -> Vec<DiffRegion> {
+    report
+        .range_results
+        .iter()
+        .map(|(range, _, arch)| DiffRegion {
+            range: range.clone(),
+            arch: arch.clone(),
+        })
+        .collect()
+}
+
+fn overlap(a: &Range<usize>, b: &Range<usize>) -> usize {
+    a.end.min(b.end).saturating_sub(a.start.max(b.start))
+}
+
+/// Compares the regions of report `a` with those of report `b`, whose
+/// boundaries may differ by up to `tolerance` bytes.
+pub fn diff_reports(a: &CliJsonOutput, b: &CliJsonOutput, tolerance: usize) -> DiffReport {
+    let (regions_a, regions_b) = (regions(a), regions(b));
+    let mut paired_a = vec![false; regions_a.len()];
+    let mut paired_b = vec![false; regions_b.len()];
+    let mut unchanged = 0;
+    let mut changes = Vec::new();
+
+    let mut overlapping: Vec<(usize, usize, usize)> = regions_a
+        .iter()
+        .enumerate()
+        .flat_map(|(i, ra)| {
+            regions_b.iter().enumerate().filter_map(move |(j, rb)| {
+                let size = overlap(&ra.range, &rb.range);
+                let smaller = ra.range.len().min(rb.range.len());
+                (size * 2 > smaller).then_some((size, i, j))
+            })
+        })
+        .collect();
+    overlapping.sort_by(|x, y| y.0.cmp(&x.0).then((x.1, x.2).cmp(&(y.1, y.2))));
+    for (_, i, j) in overlapping {
+        if paired_a[i] || paired_b[j] {
+            continue;
+        }
+        paired_a[i] = true;
+        paired_b[j] = true;
+        let (ra, rb) = (&regions_a[i], &regions_b[j]);
+        let kind = if ra.arch != rb.arch {
+            ChangeKind::Changed
+        } else if ra.range.start.abs_diff(rb.range.start) > tolerance
+            || ra.range.end.abs_diff(rb.range.end) > tolerance
+        {
+            ChangeKind::Resized
+        } else {
+            unchanged += 1;
+            continue;
+        };
+        changes.push(Change {
+            kind,
+            a: Some(ra.clone()),
+            b: Some(rb.clone()),
+        });
+    }
+
+    for (i, ra) in regions_a.iter().enumerate() {
+        if paired_a[i] {
+            continue;
+        }
+        let moved = regions_b
+            .iter()
+            .enumerate()
+            .filter(|(j, rb)| {
+                !paired_b[*j]
+                    && rb.arch == ra.arch
+                    && rb.range.len().abs_diff(ra.range.len()) <= tolerance
+            })
+            .min_by_key(|(_, rb)| {
+                (
+                    rb.range.len().abs_diff(ra.range.len()),
+                    rb.range.start.abs_diff(ra.range.start),
+                )
+            })
+            .map(|(j, _)| j);
+        if let Some(j) = moved {
+            paired_b[j] = true;
+        }
+        changes.push(Change {
+            kind: if moved.is_some() {
+                ChangeKind::Moved
+            } else {
+                ChangeKind::Disappeared
+            },
+            a: Some(ra.clone()),
+            b: moved.map(|j| regions_b[j].clone()),
+        });
+    }
+    for (j, rb) in regions_b.iter().enumerate() {
+        if !paired_b[j] {
+            changes.push(Change {
+                kind: ChangeKind::Appeared,
+                a: None,
+                b: Some(rb.clone()),
+            });
+        }
+    }
+
+    changes.sort_by_key(|change| {
+        change
+            .a
+            .as_ref()
+            .or(change.b.as_ref())
+            .map(|region| region.range.start)
+    });
+
+    DiffReport {
+        a: a.file.clone(),
+        b: b.file.clone(),
+        tolerance,
+        unchanged,
+        changes,
+    }
+}
@@ -0,0 +1,72 @@
+/*
+    Copyright 2024 - Valentin Obst <coderec@vpao.io>
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+*/
+//! Detection of flattened device tree blobs, see `--detect-dtb`.
+//!
+//! Device trees that are embedded in firmware are neither code nor text, but
+//! their strings and big endian cells shift the verdicts of the windows they
+//! overlap. Blobs are found by their magic, a header whose blocks lie within
+//! the blob, and a structure block that parses. Like padding, windows that
+//! lie entirely within a blob are not analyzed, and the blobs are reported as
+//! regions of the pseudo-arch `dtb` with their exact extent.
+
+use crate::fdt::{self, fdt_size, read_be_u32};
+
+use std::ops::Range;
+
+/// Pseudo-arch of device tree blob regions.
+pub const DTB: &str = "dtb";
+
+const FDT_MAGIC: &[u8] = &[0xd0, 0x0d, 0xfe, 0xed];
+/// Version of the format, the first whose header has the sizes of the
+/// structure and strings blocks.
+const VERSION: usize = 17;
+
+/// Returns true if the header of the device tree of `size` bytes at the start
+/// of `data` describes blocks within the tree.
+fn is_valid_header(data: &[u8], size: usize) -> bool {
+    let header = |idx: usize| read_be_u32(data, 4 * idx).unwrap() as usize;
+    let within = |offset: usize, len: usize| offset.checked_add(len).is_some_and(|end| end <= size);
+
+    // Later versions must be compatible with this one.
+    header(5) >= VERSION
+        && header(6) <= VERSION
+        && header(4).is_multiple_of(8)
+        && within(header(4), 16)
+        && within(header(2), header(9))
+        && within(header(3), header(8))
+}
+
+/// Returns the sorted, disjoint ranges of the device tree blobs in `data`.
+pub fn find_dtbs(data: &[u8]) -> Vec<Range<usize>> {
+    let mut dtbs = Vec::new();
+    let mut offset = 0;
+    while let Some(pos) = data[offset..]
+        .windows(FDT_MAGIC.len())
+        .position(|bytes| bytes == FDT_MAGIC)
+    {
+        let start = offset + pos;
+        let blob = &data[start..];
+        match fdt_size(blob) {
+            Some(size) if is_valid_header(blob, size) && fdt::parse(blob).is_ok() => {
+                dtbs.push(start..start + size);
+                offset = start + size;
+            }
+            _ => offset = start + 1,
+        }
+    }
+
+    dtbs
+}
@@ -0,0 +1,228 @@
+/*
+    Copyright 2024 - Valentin Obst <coderec@vpao.io>
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+*/
+//! Scripts that load or annotate the detected regions in reverse engineering
+//! tools, see `--emit`.
+//!
+//! Scripts locate the regions in the analyzed file, which is why regions of
+//! archive members and streams are only included if the member is stored
+//! uncompressed, and no script is written for data that is not stored in the
+//! file as is, e.g., decoded records.
+
+use crate::bt;
+use crate::ghidra;
+use crate::ida;
+use crate::kaitai;
+use crate::output::CliJsonOutput;
+use crate::r2;
+use crate::yara;
+
+use std::{fmt, fs, str::FromStr};
+
+use anyhow::{bail, Context, Result};
+
+/// Tools that scripts can be written for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScriptFormat {
+    /// Python script for Ghidra's headless analyzer.
+    Ghidra,
+    /// IDAPython script for the database of the analyzed file.
+    Ida,
+    /// radare2 script for a session on the analyzed file.
+    R2,
+    /// 010 Editor binary template for the analyzed file.
+    Template010,
+    /// Kaitai Struct definition of the layout of the analyzed file.
+    Kaitai,
+    /// YARA rules that match snippets of the regions.
+    Yara,
+}
+
+impl FromStr for ScriptFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ghidra" => Ok(Self::Ghidra),
+            "ida" => Ok(Self::Ida),
+            "r2" => Ok(Self::R2),
+            "010" => Ok(Self::Template010),
+            "kaitai" => Ok(Self::Kaitai),
+            "yara" => Ok(Self::Yara),
+            _ => Err(format!("unknown script format {:?}", s)),
+        }
+    }
+}
+
+impl fmt::Display for ScriptFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Ghidra => "ghidra",
+            Self::Ida => "ida",
+            Self::R2 => "r2",
+            Self::Template010 => "010",
+            Self::Kaitai => "kaitai",
+            Self::Yara => "yara",
+        })
+    }
+}
+
+impl ScriptFormat {
+    /// Whether the scripts contain bytes of the analyzed data, which
+    /// `--redact` forbids.
+    pub fn embeds_bytes(&self) -> bool {
+        matches!(self, Self::Yara)
+    }
+
+    /// Suffix of the script files.
+    fn extension(&self) -> &'static str {
+        match self {
+            Self::Ghidra => "ghidra.py",
+            Self::Ida => "ida.py",
+            Self::R2 => "r2",
+            Self::Template010 => "bt",
+            Self::Kaitai => "ksy",
+            Self::Yara => "yar",
+        }
+    }
+}
+
+/// Location of the analyzed data.
+#[derive(Debug, Clone, Copy)]
+pub struct ScriptInput<'a> {
+    /// Path of the analyzed file.
+    pub path: &'a str,
+    /// Offset of the analyzed data in the file.
+    pub offset: u64,
+    /// Address at which the analyzed data is loaded.
+    pub base_address: u64,
+    /// The analyzed data.
+    pub data: &'a [u8],
+    /// Whether bytes of the data must not be written, see `--redact`.
+    pub redact: bool,
+}
+
+/// Region with its location in the analyzed file and in memory.
+#[derive(Debug, Clone, Copy)]
+pub struct PlacedRegion<'a> {
+    pub offset: u64,
+    pub address: u64,
+    pub size: usize,
+    pub arch: &'a str,
+    pub confidence: f64,
+}
+
+/// Quotes `s` as a Python string literal.
+pub fn quote(s: &str) -> String {
+    serde_json::to_string(s).unwrap()
+}
+
+/// Escapes newlines and other control characters in `s`, such that names of
+/// samples cannot end a comment line of a script and inject code.
+pub fn comment(s: &str) -> String {
+    s.chars()
+        .map(|c| match c {
+            '\u{2028}' | '\u{2029}' => c.escape_unicode().to_string(),
+            c if c.is_control() => c.escape_default().to_string(),
+            c => c.to_string(),
+        })
+        .collect()
+}
+
+/// Regions of `report` and its nested reports, sorted by offset.
+pub fn placed_regions<'a>(report: &'a CliJsonOutput, input: &ScriptInput) -> Vec<PlacedRegion<'a>> {
+    let mut regions = Vec::new();
+    add_regions(report, input.offset, input.base_address, &mut regions);
+    regions.sort_by_key(|region| region.offset);
+
+    regions
+}
+
+fn add_regions<'a>(
+    report: &'a CliJsonOutput,
+    offset: u64,
+    address: u64,
+    regions: &mut Vec<PlacedRegion<'a>>,
+) {
+    regions.extend(
+        report
+            .regions()
+            .map(|(range, size, arch, confidence)| PlacedRegion {
+                offset: offset + range.start as u64,
+                address: address + range.start as u64,
+                size,
+                arch,
+                confidence,
+            }),
+    );
+    for nested in report
+        .members
+        .iter()
+        .flat_map(|members| members.values())
+        .chain(report.streams.iter().flatten())
+    {
+        let Some(member) = nested.member.as_ref() else {
+            continue;
+        };
+        if let Some(member_offset) = member.offset {
+            let member_offset = member_offset as u64;
+            add_regions(
+                nested,
+                offset + member_offset,
+                member.load_address.unwrap_or(address + member_offset),
+                regions,
+            );
+        }
+    }
+}
+
+/// Writes a script in `format` for `report` on the data at `input` to the
+/// working directory and returns its name.
+pub fn emit_script(
+    format: ScriptFormat,
+    input: &ScriptInput,
+    report: &CliJsonOutput,
+) -> Result<String> {
+    if input.redact && format.embeds_bytes() {
+        bail!("--emit {} writes raw bytes, which --redact forbids", format);
+    }
+    let regions = placed_regions(report, input);
+    let script = match format {
+        ScriptFormat::Ghidra => ghidra::script(&report.file, input.path, &regions),
+        ScriptFormat::Ida => ida::script(&report.file, &regions),
+        ScriptFormat::R2 => r2::script(&report.file, input.path, &regions),
+        ScriptFormat::Template010 => bt::template(&report.file, &regions),
+        ScriptFormat::Kaitai => kaitai::definition(&report.file, &regions),
+        ScriptFormat::Yara => yara::rules(&report.file, input.data, input.offset, &regions),
+    };
+
+    let file_name = report.file.rsplit('/').next().unwrap();
+    let script_name = format!("{}.{}", file_name, format.extension());
+    fs::write(&script_name, script).with_context(|| format!("cannot write {}", script_name))?;
+
+    Ok(script_name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn comment_escapes_line_breaks() {
+        assert_eq!(comment("fw-1.0.bin"), "fw-1.0.bin");
+        assert_eq!(comment("a\nb\rc\u{0}d"), "a\\nb\\rc\\u{0}d");
+        assert_eq!(comment("a\u{85}b\u{2028}c"), "a\\u{85}b\\u{2028}c");
+    }
+}
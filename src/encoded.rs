@@ -0,0 +1,244 @@
+/*
+    Copyright 2024 - Valentin Obst <coderec@vpao.io>
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+*/
+//! Base64 and hex encoded blobs in text regions, see `--decode-text`.
+//!
+//! Configuration files and scripts carry payloads such as shellcode or
+//! firmware images as long runs of Base64, hex digits, or `\xNN` escapes. Runs
+//! within the text regions of a report that decode to enough bytes are
+//! decoded and analyzed on their own, like compressed streams. Runs may be
+//! wrapped into lines of equal width, as Base64 and hex often are.
+
+use crate::container::Member;
+use crate::output::CliJsonOutput;
+use crate::text::TextClass;
+
+use std::ops::Range;
+
+use log::debug;
+
+/// Runs that decode to fewer bytes are skipped, as short runs are mostly
+/// identifiers, hashes, or keys.
+const MIN_DECODED_SIZE: usize = 64;
+
+/// Text classes whose regions may contain encoded runs.
+const CLASSES: [TextClass; 3] = [TextClass::Ascii, TextClass::Base64, TextClass::Hex];
+
+fn is_base64(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || matches!(byte, b'+' | b'/')
+}
+
+fn is_line_break(byte: u8) -> bool {
+    matches!(byte, b'\r' | b'\n')
+}
+
+fn hex_value(byte: u8) -> Option<u8> {
+    (byte as char).to_digit(16).map(|value| value as u8)
+}
+
+fn base64_value(byte: u8) -> Option<u8> {
+    match byte {
+        b'A'..=b'Z' => Some(byte - b'A'),
+        b'a'..=b'z' => Some(byte - b'a' + 26),
+        b'0'..=b'9' => Some(byte - b'0' + 52),
+        b'+' => Some(62),
+        b'/' => Some(63),
+        _ => None,
+    }
+}
+
+/// Decodes the hex digits `chars`.
+fn decode_hex(chars: &[u8]) -> Option<Vec<u8>> {
+    if !chars.len().is_multiple_of(2) {
+        return None;
+    }
+
+    chars
+        .chunks_exact(2)
+        .map(|pair| Some(hex_value(pair[0])? << 4 | hex_value(pair[1])?))
+        .collect()
+}
+
+/// Decodes the padded Base64 `chars`.
+fn decode_base64(chars: &[u8]) -> Option<Vec<u8>> {
+    if !chars.len().is_multiple_of(4) {
+        return None;
+    }
+    let chars = chars
+        .strip_suffix(b"==")
+        .or(chars.strip_suffix(b"="))
+        .unwrap_or(chars);
+
+    let mut decoded = Vec::with_capacity(chars.len() * 3 / 4);
+    let (mut acc, mut bits) = (0u32, 0);
+    for byte in chars {
+        acc = (acc << 6 | base64_value(*byte)? as u32) & 0x3fff;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            decoded.push((acc >> bits) as u8);
+        }
+    }
+
+    Some(decoded)
+}
+
+/// Decodes the run of Base64 or hex digits at the start of `data`. Returns the
+/// length of the run and, if it decodes, the encoding and the decoded bytes.
+fn decode_run(data: &[u8]) -> (usize, Option<(&'static str, Vec<u8>)>) {
+    let line_len = |start: usize| {
+        data[start..]
+            .iter()
+            .take_while(|byte| is_base64(**byte))
+            .count()
+    };
+    let width = line_len(0);
+    let (mut len, mut line) = (width, width);
+    // A wrapped run continues after lines of the full width, on lines that
+    // hold nothing but the run, such that the text after it is not taken.
+    while line == width {
+        let breaks = data[len..]
+            .iter()
+            .take(2)
+            .take_while(|byte| is_line_break(**byte))
+            .count();
+        let next = line_len(len + breaks);
+        let end = len + breaks + next;
+        let complete = data
+            .get(end)
+            .is_none_or(|byte| is_line_break(*byte) || *byte == b'=');
+        if breaks == 0 || next == 0 || next > width || !complete {
+            break;
+        }
+        (len, line) = (end, next);
+    }
+    len += data[len..]
+        .iter()
+        .take(2)
+        .take_while(|byte| **byte == b'=')
+        .count();
+
+    let chars: Vec<u8> = data[..len]
+        .iter()
+        .copied()
+        .filter(|byte| !is_line_break(*byte))
+        .collect();
+    // Hex digits are also Base64 characters, but Base64 uses all classes.
+    let decoded = if chars.iter().all(u8::is_ascii_hexdigit) {
+        decode_hex(&chars).map(|decoded| ("hex", decoded))
+    } else if chars.iter().any(u8::is_ascii_uppercase)
+        && chars.iter().any(u8::is_ascii_lowercase)
+        && chars.iter().any(u8::is_ascii_digit)
+    {
+        decode_base64(&chars).map(|decoded| ("base64", decoded))
+    } else {
+        None
+    };
+
+    (len, decoded)
+}
+
+/// Decodes the run of `\xNN` escapes at the start of `data`. Returns the
+/// length of the run and the decoded bytes.
+fn decode_escapes(data: &[u8]) -> (usize, Vec<u8>) {
+    let decoded: Vec<u8> = data
+        .chunks_exact(4)
+        .map_while(|escape| match escape {
+            [b'\\', b'x', high, low] => Some(hex_value(*high)? << 4 | hex_value(*low)?),
+            _ => None,
+        })
+        .collect();
+
+    (decoded.len() * 4, decoded)
+}
+
+/// Returns the sorted, disjoint ranges of the text regions of `report`, with
+/// adjacent regions merged.
+fn text_ranges(report: &CliJsonOutput) -> Vec<Range<usize>> {
+    let mut ranges: Vec<Range<usize>> = Vec::new();
+    for (range, _, arch) in report.range_results.iter() {
+        if !CLASSES.iter().any(|class| class.label() == arch) {
+            continue;
+        }
+        match ranges.last_mut() {
+            Some(last) if last.end == range.start => last.end = range.end,
+            _ => ranges.push(range.clone()),
+        }
+    }
+
+    ranges
+}
+
+/// Returns the encoded runs within the text regions of `report`, which is
+/// about `data`, that decode to at most `max_size` bytes, together with their
+/// decoded content. The path of each is its encoded range.
+pub fn find_encoded(
+    report: &CliJsonOutput,
+    data: &[u8],
+    max_size: usize,
+) -> Vec<(Member, Vec<u8>)> {
+    let mut blobs = Vec::new();
+    for range in text_ranges(report) {
+        let mut offset = range.start;
+        while offset < range.end {
+            let rest = &data[offset..range.end];
+            let (len, decoded) = if rest.starts_with(b"\\x") {
+                let (len, decoded) = decode_escapes(rest);
+                (len, Some(("hex", decoded)))
+            } else if is_base64(rest[0]) {
+                decode_run(rest)
+            } else {
+                (0, None)
+            };
+
+            match decoded {
+                Some((encoding, decoded))
+                    if decoded.len() >= MIN_DECODED_SIZE && decoded.len() <= max_size =>
+                {
+                    debug!(
+                        "{}: {} run of {} bytes at {:#x} decodes to {} bytes",
+                        report.file,
+                        encoding,
+                        len,
+                        offset,
+                        decoded.len()
+                    );
+                    blobs.push((
+                        Member {
+                            format: encoding.to_owned(),
+                            id: format!("{:x}", offset),
+                            path: format!("{:#x}..{:#x}", offset, offset + len),
+                            offset: None,
+                            load_address: None,
+                            partition: None,
+                        },
+                        decoded,
+                    ));
+                }
+                Some((encoding, decoded)) if decoded.len() > max_size => debug!(
+                    "{}: skipping {} run at {:#x} that decodes to {} bytes",
+                    report.file,
+                    encoding,
+                    offset,
+                    decoded.len()
+                ),
+                _ => (),
+            }
+            offset += len.max(1);
+        }
+    }
+
+    blobs
+}
@@ -0,0 +1,93 @@
+/*
+    Copyright 2024 - Valentin Obst <coderec@vpao.io>
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+*/
+//! Endianness of detected regions.
+//!
+//! Many corpus entries come in pairs that only differ in their byte order,
+//! e.g., `MIPSel` and `MIPSeb`. For regions of such an arch, the trigram
+//! divergences of both siblings are compared to decide on the endianness.
+
+use crate::Arch;
+
+use std::collections::HashMap;
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Endianness {
+    Little,
+    Big,
+}
+
+impl fmt::Display for Endianness {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Little => write!(f, "LE"),
+            Self::Big => write!(f, "BE"),
+        }
+    }
+}
+
+/// Endianness of a region.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EndianInfo {
+    pub endianness: Endianness,
+    /// How clearly the byte order is preferred over the swapped one, from 0
+    /// (undecided) to 1.
+    pub swap_confidence: f64,
+}
+
+/// Endianness of `arch` and the name of its sibling with swapped byte order,
+/// if `arch` follows the `el`/`eb` naming convention of the corpus.
+pub fn sibling(arch: &str) -> Option<(Endianness, Arch)> {
+    if let Some(base) = arch.strip_suffix("el") {
+        Some((Endianness::Little, format!("{}eb", base)))
+    } else {
+        arch.strip_suffix("eb")
+            .map(|base| (Endianness::Big, format!("{}el", base)))
+    }
+}
+
+/// Decides on the endianness of a region of `arch`. `divs` are the bi- and
+/// trigram divergences of all arches over the region, see
+/// `ProcessedDetectionResult::segment_divs`. Returns `None` if `arch` has no
+/// sibling in the corpus.
+pub fn detect(arch: &str, divs: &HashMap<Arch, (f64, f64)>) -> Option<EndianInfo> {
+    let (endianness, sibling) = sibling(arch)?;
+    let (_, div) = divs.get(arch)?;
+    let (_, sibling_div) = divs.get(&sibling)?;
+
+    let (endianness, win, lose) = if div <= sibling_div {
+        (endianness, div, sibling_div)
+    } else {
+        let swapped = match endianness {
+            Endianness::Little => Endianness::Big,
+            Endianness::Big => Endianness::Little,
+        };
+        (swapped, sibling_div, div)
+    };
+    let swap_confidence = if *lose <= 0.0 {
+        0.0
+    } else {
+        (1.0 - win / lose).clamp(0.0, 1.0)
+    };
+
+    Some(EndianInfo {
+        endianness,
+        swap_confidence,
+    })
+}
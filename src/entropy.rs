@@ -0,0 +1,177 @@
+/*
+    Copyright 2024 - Valentin Obst <coderec@vpao.io>
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+*/
+//! Classification of regions that match no arch.
+//!
+//! The byte distribution of the window of each unknown segment is used to
+//! label it with a pseudo-arch: `sparse` for low entropy data like padding,
+//! `encrypted/random` for data that is indistinguishable from uniform noise,
+//! and `compressed` for other high entropy data.
+
+use crate::{is_traced, ProcessedDetectionResult, TRACE_TARGET};
+
+use log::info;
+use rayon::prelude::*;
+
+/// Data below this entropy, in bits per byte, is sparse.
+const SPARSE_MAX_ENTROPY: f64 = 2.0;
+/// Data above this entropy, in bits per byte, is compressed or random.
+const HIGH_MIN_ENTROPY: f64 = 7.2;
+/// Number of standard deviations that the chi-squared statistic and the
+/// serial correlation of random data may deviate from their expected values.
+const RANDOM_MAX_SIGMA: f64 = 4.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntropyClass {
+    Compressed,
+    Random,
+    Sparse,
+}
+
+impl EntropyClass {
+    pub const ALL: [Self; 3] = [Self::Compressed, Self::Random, Self::Sparse];
+
+    /// Pseudo-arch that is reported for regions of this class.
+    pub fn label(self) -> &'static str {
+        match self {
+            Self::Compressed => "compressed",
+            Self::Random => "encrypted/random",
+            Self::Sparse => "sparse",
+        }
+    }
+}
+
+/// Statistics of the byte distribution of some data, as computed by `ent`.
+#[derive(Debug)]
+pub struct ByteStats {
+    /// Shannon entropy in bits per byte.
+    pub entropy: f64,
+    /// Chi-squared statistic against the uniform distribution.
+    pub chi_squared: f64,
+    /// Correlation of each byte with its successor, between -1 and 1.
+    pub serial_correlation: f64,
+}
+
+impl ByteStats {
+    pub fn new(data: &[u8]) -> Self {
+        let n = data.len() as f64;
+        let mut counts = [0usize; 256];
+        for byte in data {
+            counts[*byte as usize] += 1;
+        }
+
+        let expected = n / 256.0;
+        let mut entropy = 0.0;
+        let mut chi_squared = 0.0;
+        for count in counts {
+            if count > 0 {
+                let p = count as f64 / n;
+                entropy -= p * p.log2();
+            }
+            chi_squared += (count as f64 - expected).powi(2) / expected;
+        }
+
+        // The data is treated as cyclic, i.e., the last byte is followed by
+        // the first.
+        let (mut sum, mut sum_sq, mut sum_succ) = (0.0, 0.0, 0.0);
+        for (idx, byte) in data.iter().enumerate() {
+            let x = *byte as f64;
+            let succ = data[(idx + 1) % data.len()] as f64;
+            sum += x;
+            sum_sq += x * x;
+            sum_succ += x * succ;
+        }
+        let denominator = n * sum_sq - sum * sum;
+        let serial_correlation = if denominator == 0.0 {
+            1.0
+        } else {
+            (n * sum_succ - sum * sum) / denominator
+        };
+
+        Self {
+            entropy,
+            chi_squared,
+            serial_correlation,
+        }
+    }
+
+    pub fn classify(&self, len: usize) -> Option<EntropyClass> {
+        if self.entropy < SPARSE_MAX_ENTROPY {
+            return Some(EntropyClass::Sparse);
+        }
+        if self.entropy < HIGH_MIN_ENTROPY {
+            return None;
+        }
+
+        // The statistic has 255 degrees of freedom, i.e., a mean of 255 and a
+        // variance of 510.
+        let max_chi_squared = 255.0 + RANDOM_MAX_SIGMA * 510f64.sqrt();
+        let max_serial_correlation = RANDOM_MAX_SIGMA / (len as f64).sqrt();
+        if self.chi_squared <= max_chi_squared
+            && self.serial_correlation.abs() <= max_serial_correlation
+        {
+            Some(EntropyClass::Random)
+        } else {
+            Some(EntropyClass::Compressed)
+        }
+    }
+}
+
+/// Labels the unknown segments of `res` with the pseudo-arch of their entropy
+/// class, if any. `data` are the analyzed bytes.
+pub fn label_unknown(res: &mut ProcessedDetectionResult, data: &[u8]) {
+    let mut segments = res.segments.clone();
+    for segment in segments.iter_mut().filter(|segment| segment.arch.is_none()) {
+        let window = &data[segment.window.clone()];
+        let stats = ByteStats::new(window);
+        let class = stats.classify(window.len());
+
+        if is_traced(&res.trace, &segment.window) {
+            info!(
+                target: TRACE_TARGET,
+                "{:x?}: {:?}, {:?}",
+                segment.window,
+                stats,
+                class
+            );
+        }
+
+        if let Some(class) = class {
+            segment.arch = Some(class.label().to_owned());
+            segment.confidence = 1.0;
+        }
+    }
+
+    res.set_segments(segments);
+}
+
+/// Returns the entropy, in bits per byte, of the windows of `window_size`
+/// bytes of `data` that start every `stride` bytes, by the center of the
+/// window. Data smaller than a window is a single one.
+pub fn entropy_curve(data: &[u8], window_size: usize, stride: usize) -> Vec<(usize, f64)> {
+    if data.len() <= window_size {
+        return vec![(data.len() / 2, ByteStats::new(data).entropy)];
+    }
+
+    (0..=data.len() - window_size)
+        .step_by(stride)
+        .collect::<Vec<_>>()
+        .into_par_iter()
+        .map(|start| {
+            let window = &data[start..start + window_size];
+            (start + window_size / 2, ByteStats::new(window).entropy)
+        })
+        .collect()
+}
@@ -0,0 +1,213 @@
+/*
+    Copyright 2024 - Valentin Obst <coderec@vpao.io>
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+*/
+//! Extraction of the detected regions.
+//!
+//! With `--extract-cas`, regions are stored by content, i.e., as
+//! `DIR/XX/HASH` where `HASH` is the SHA-256 of the region and `XX` its first
+//! two digits. Identical regions in different files are thus only stored once.
+//! With `--extract`, each region is carved to `DIR/FILE/OFFSET_SIZE_ARCH.bin`,
+//! where `FILE` is the name of the analyzed file, ready for disassemblers.
+//! Every extracted region is recorded in `DIR/manifest.jsonl`.
+
+use crate::output::CliJsonOutput;
+
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use log::{debug, info};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+const MANIFEST: &str = "manifest.jsonl";
+
+/// Line of the manifest.
+#[derive(Serialize)]
+struct ManifestEntry<'a> {
+    file: &'a str,
+    range: &'a Range<usize>,
+    arch: &'a str,
+    confidence: f64,
+    sha256: &'a str,
+    /// Path of the region, relative to the store or directory.
+    path: &'a str,
+}
+
+/// Opens the manifest in `root`, which is created if needed. New entries are
+/// appended to an existing manifest.
+fn open_manifest(root: &Path) -> Result<BufWriter<File>> {
+    std::fs::create_dir_all(root)
+        .with_context(|| format!("Could not create {}", root.display()))?;
+    let manifest_path = root.join(MANIFEST);
+    let manifest = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&manifest_path)
+        .with_context(|| format!("Could not open {}", manifest_path.display()))?;
+
+    Ok(BufWriter::new(manifest))
+}
+
+/// Replaces the characters of `name` that are not safe in file names.
+fn file_name(name: &str) -> String {
+    name.chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '.' | '-' | '@' => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+/// Content-addressed store for extracted regions.
+pub struct CasStore {
+    root: PathBuf,
+    manifest: BufWriter<File>,
+}
+
+impl CasStore {
+    /// Opens the store in `root`, which is created if needed. New entries are
+    /// appended to an existing manifest.
+    pub fn open(root: &Path) -> Result<Self> {
+        Ok(Self {
+            root: root.to_owned(),
+            manifest: open_manifest(root)?,
+        })
+    }
+
+    /// Stores all regions of `report`, `data` are the analyzed bytes.
+    pub fn store_regions(&mut self, report: &CliJsonOutput, data: &[u8]) -> Result<()> {
+        for (range, _, arch, confidence) in report.regions() {
+            let region = &data[range.clone()];
+            let sha256 = format!("{:x}", Sha256::digest(region));
+            let path = format!("{}/{}", &sha256[..2], sha256);
+
+            if store_object(&self.root, &path, region)? {
+                debug!("{}: stored {:x?} as {}", report.file, range, path);
+            } else {
+                debug!(
+                    "{}: {:x?} is already stored as {}",
+                    report.file, range, path
+                );
+            }
+
+            serde_json::to_writer(
+                &mut self.manifest,
+                &ManifestEntry {
+                    file: &report.file,
+                    range,
+                    arch,
+                    confidence,
+                    sha256: &sha256,
+                    path: &path,
+                },
+            )?;
+            self.manifest.write_all(b"\n")?;
+        }
+        self.manifest.flush()?;
+
+        info!(
+            "{}: extracted {} regions to {}",
+            report.file,
+            report.range_results.len(),
+            self.root.display()
+        );
+
+        Ok(())
+    }
+}
+
+/// Directory of carved regions, one file per region.
+pub struct RegionDir {
+    root: PathBuf,
+    manifest: BufWriter<File>,
+}
+
+impl RegionDir {
+    /// Opens the directory `root`, which is created if needed. New entries
+    /// are appended to an existing manifest.
+    pub fn open(root: &Path) -> Result<Self> {
+        Ok(Self {
+            root: root.to_owned(),
+            manifest: open_manifest(root)?,
+        })
+    }
+
+    /// Writes each region of `report` to its own file, `data` are the
+    /// analyzed bytes.
+    pub fn write_regions(&mut self, report: &CliJsonOutput, data: &[u8]) -> Result<()> {
+        let dir = file_name(&report.file);
+        std::fs::create_dir_all(self.root.join(&dir))
+            .with_context(|| format!("Could not create {}", self.root.join(&dir).display()))?;
+        for (range, size, arch, confidence) in report.regions() {
+            let region = &data[range.clone()];
+            let path = format!(
+                "{}/{:08x}_{:x}_{}.bin",
+                dir,
+                range.start,
+                size,
+                file_name(arch)
+            );
+            std::fs::write(self.root.join(&path), region)
+                .with_context(|| format!("Could not write {}", self.root.join(&path).display()))?;
+            debug!("{}: wrote {:x?} to {}", report.file, range, path);
+
+            serde_json::to_writer(
+                &mut self.manifest,
+                &ManifestEntry {
+                    file: &report.file,
+                    range,
+                    arch,
+                    confidence,
+                    sha256: &format!("{:x}", Sha256::digest(region)),
+                    path: &path,
+                },
+            )?;
+            self.manifest.write_all(b"\n")?;
+        }
+        self.manifest.flush()?;
+
+        info!(
+            "{}: carved {} regions to {}",
+            report.file,
+            report.range_results.len(),
+            self.root.join(&dir).display()
+        );
+
+        Ok(())
+    }
+}
+
+/// Writes `content` to `path` below `root` unless it already exists. Returns
+/// true if the object was written.
+pub fn store_object(root: &Path, path: &str, content: &[u8]) -> Result<bool> {
+    let object = root.join(path);
+    if object.exists() {
+        return Ok(false);
+    }
+
+    let dir = object.parent().unwrap();
+    std::fs::create_dir_all(dir).with_context(|| format!("Could not create {}", dir.display()))?;
+    // Write to a temporary file first such that an interrupted run never
+    // leaves a truncated object under the final name.
+    let tmp = object.with_extension("tmp");
+    std::fs::write(&tmp, content).with_context(|| format!("Could not write {}", tmp.display()))?;
+    std::fs::rename(&tmp, &object)
+        .with_context(|| format!("Could not write {}", object.display()))?;
+
+    Ok(true)
+}
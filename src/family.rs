@@ -0,0 +1,70 @@
+/*
+    Copyright 2024 - Valentin Obst <coderec@vpao.io>
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+*/
+//! Grouping of corpus entries into ISA families.
+
+use std::collections::HashMap;
+
+/// Families of the entries in `cpu_rec_corpus` that come in several
+/// variants. Arches that are not listed form a family of their own.
+const FAMILIES: &[(&str, &[&str])] = &[
+    ("ARM", &["ARMel", "ARMeb", "ARMhf", "ARM64", "Thumb"]),
+    ("x86", &["X86", "X86-64", "8086"]),
+    ("MIPS", &["MIPSel", "MIPSeb", "MIPS16"]),
+    ("PowerPC", &["PPCel", "PPCeb"]),
+    ("ARC", &["ARC32el", "ARC32eb", "ARcompact"]),
+    ("PIC", &["PIC10", "PIC16", "PIC18", "PIC24"]),
+    ("68HC", &["68HC08", "68HC11"]),
+    ("H8", &["H8-300", "H8S"]),
+    ("TMS320", &["TMS320C2x", "TMS320C6x"]),
+];
+
+/// Maps arches to their family.
+#[derive(Debug, Clone)]
+pub struct Families {
+    arch_to_family: HashMap<String, String>,
+}
+
+impl Families {
+    /// Built-in families, extended by `custom`, which maps family names to
+    /// arches. Custom entries take precedence.
+    pub fn new(custom: &HashMap<String, Vec<String>>) -> Self {
+        let mut arch_to_family = HashMap::new();
+        for (family, arches) in FAMILIES {
+            for arch in arches.iter() {
+                arch_to_family.insert(arch.to_string(), family.to_string());
+            }
+        }
+        for (family, arches) in custom {
+            for arch in arches {
+                arch_to_family.insert(arch.clone(), family.clone());
+            }
+        }
+
+        Self { arch_to_family }
+    }
+
+    pub fn family<'a>(&'a self, arch: &'a str) -> &'a str {
+        self.arch_to_family
+            .get(arch)
+            .map(String::as_str)
+            .unwrap_or(arch)
+    }
+
+    /// Returns true if `name` is the name of a family with listed arches.
+    pub fn is_family(&self, name: &str) -> bool {
+        self.arch_to_family.values().any(|family| family == name)
+    }
+}
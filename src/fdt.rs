@@ -0,0 +1,151 @@
+/*
+    Copyright 2024 - Valentin Obst <coderec@vpao.io>
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+*/
+//! Parsing of flattened device trees, which U-Boot FIT images are as well.
+
+use anyhow::{bail, Context, Result};
+
+const FDT_MAGIC: u32 = 0xd00dfeed;
+const FDT_HEADER_SIZE: usize = 40;
+
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_NOP: u32 = 4;
+const FDT_END: u32 = 9;
+
+pub fn read_be_u32(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(
+        data.get(offset..offset.checked_add(4)?)?
+            .try_into()
+            .unwrap(),
+    ))
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Node {
+    pub name: String,
+    pub properties: Vec<(String, Vec<u8>)>,
+    pub children: Vec<Node>,
+}
+
+impl Node {
+    pub fn property(&self, name: &str) -> Option<&[u8]> {
+        self.properties
+            .iter()
+            .find(|(property, _)| property == name)
+            .map(|(_, value)| value.as_slice())
+    }
+
+    /// Returns the property `name` as a string, without its terminator.
+    pub fn string(&self, name: &str) -> Option<&str> {
+        let value = self.property(name)?;
+        let value = value.strip_suffix(&[0]).unwrap_or(value);
+
+        std::str::from_utf8(value).ok()
+    }
+
+    /// Returns the property `name` as a number of one or two cells.
+    pub fn number(&self, name: &str) -> Option<u64> {
+        let value = self.property(name)?;
+        match value.len() {
+            4 => read_be_u32(value, 0).map(u64::from),
+            8 => Some(u64::from_be_bytes(value.try_into().unwrap())),
+            _ => None,
+        }
+    }
+
+    pub fn child(&self, name: &str) -> Option<&Node> {
+        self.children.iter().find(|child| child.name == name)
+    }
+}
+
+/// Returns the size of the device tree at the start of `data`, if there is
+/// one.
+pub fn fdt_size(data: &[u8]) -> Option<usize> {
+    if read_be_u32(data, 0)? != FDT_MAGIC {
+        return None;
+    }
+    let size = read_be_u32(data, 4)? as usize;
+    if size < FDT_HEADER_SIZE || size > data.len() {
+        return None;
+    }
+
+    Some(size)
+}
+
+/// Parses the device tree at the start of `data` and returns its root node.
+pub fn parse(data: &[u8]) -> Result<Node> {
+    let Some(size) = fdt_size(data) else {
+        bail!("not a device tree");
+    };
+    let data = &data[..size];
+    let header = |idx: usize| read_be_u32(data, 4 * idx).unwrap() as usize;
+    let structs = data
+        .get(header(2)..)
+        .context("structure block out of bounds")?;
+    let strings = data
+        .get(header(3)..)
+        .context("strings block out of bounds")?;
+
+    // Nodes that are not closed yet, the last one is the current node.
+    let mut open: Vec<Node> = Vec::new();
+    let mut pos = 0;
+    loop {
+        let token = read_be_u32(structs, pos).context("truncated structure block")?;
+        pos += 4;
+        match token {
+            FDT_BEGIN_NODE => {
+                let name = structs.get(pos..).context("truncated structure block")?;
+                let len = name
+                    .iter()
+                    .position(|byte| *byte == 0)
+                    .context("unterminated node name")?;
+                open.push(Node {
+                    name: String::from_utf8_lossy(&name[..len]).into_owned(),
+                    ..Default::default()
+                });
+                pos = (pos + len + 1).next_multiple_of(4);
+            }
+            FDT_END_NODE => {
+                let node = open.pop().context("unbalanced nodes")?;
+                match open.last_mut() {
+                    Some(parent) => parent.children.push(node),
+                    None => return Ok(node),
+                }
+            }
+            FDT_PROP => {
+                let len = read_be_u32(structs, pos).context("truncated property")? as usize;
+                let name_offset =
+                    read_be_u32(structs, pos + 4).context("truncated property")? as usize;
+                let value = structs
+                    .get(pos + 8..pos + 8 + len)
+                    .context("truncated property")?;
+                let name = strings
+                    .get(name_offset..)
+                    .and_then(|name| name.split(|byte| *byte == 0).next())
+                    .context("property name out of bounds")?;
+                open.last_mut()
+                    .context("property outside of a node")?
+                    .properties
+                    .push((String::from_utf8_lossy(name).into_owned(), value.to_vec()));
+                pos = (pos + 8 + len).next_multiple_of(4);
+            }
+            FDT_NOP => {}
+            FDT_END => bail!("unbalanced nodes"),
+            _ => bail!("invalid token {:#x}", token),
+        }
+    }
+}
@@ -0,0 +1,238 @@
+/*
+    Copyright 2024 - Valentin Obst <coderec@vpao.io>
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+*/
+//! Formatting of the floats in reports, see `--float-decimals`.
+//!
+//! Floats are written in their shortest representation that parses back to
+//! the same value, which does not depend on the locale or platform. As
+//! divergences are sums over hash maps, their last digits may still differ
+//! between runs, rounding them to fewer decimals makes reports comparable.
+//! Infinite and NaN values, which are not valid JSON, are written as `null`,
+//! or as empty fields in CSV. Values that round to zero are written as `0`,
+//! never as `-0`.
+
+use serde::Serialize;
+use serde_json::Value;
+
+/// Rounds `x` to `decimals` decimal digits, such that its shortest
+/// representation has at most that many.
+pub fn round(x: f64, decimals: u32) -> f64 {
+    // Adding zero turns negative zero into zero.
+    format!("{:.*}", decimals as usize, x)
+        .parse::<f64>()
+        .unwrap()
+        + 0.0
+}
+
+/// Converts `value` to JSON with its floats rounded to `decimals` if set.
+/// Keys keep the order of the fields.
+pub fn to_value<T: Serialize>(value: &T, decimals: Option<u32>) -> serde_json::Result<Value> {
+    let mut value = serde_json::to_value(value)?;
+    normalize(&mut value, decimals);
+
+    Ok(value)
+}
+
+/// Rounds the floats in `value` to `decimals` if set, and turns negative zero
+/// into zero and non-finite floats into null.
+fn normalize(value: &mut Value, decimals: Option<u32>) {
+    match value {
+        Value::Number(number) if number.is_f64() => {
+            let x = number.as_f64().unwrap();
+            let normalized = decimals.map_or(x + 0.0, |decimals| round(x, decimals));
+            *value = serde_json::Number::from_f64(normalized).map_or(Value::Null, Value::Number);
+        }
+        Value::Array(values) => values
+            .iter_mut()
+            .for_each(|value| normalize(value, decimals)),
+        Value::Object(map) => map
+            .values_mut()
+            .for_each(|value| normalize(value, decimals)),
+        _ => {}
+    }
+}
+
+/// Formats `x` for a CSV field, see `to_value`.
+pub fn format(x: f64, decimals: Option<u32>) -> String {
+    if !x.is_finite() {
+        return String::new();
+    }
+
+    decimals
+        .map_or(x + 0.0, |decimals| round(x, decimals))
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::output::CliJsonOutput;
+    use crate::{detect_code, load_corpus, DetectionOptions, ProcessedDetectionResult};
+
+    use rand::{Rng, SeedableRng};
+    use rand_chacha::ChaCha8Rng;
+    use serde_json::json;
+
+    const VALUES: [f64; 12] = [
+        0.0,
+        1.0,
+        0.1,
+        0.5,
+        2.5,
+        -1.005,
+        1.0 / 3.0,
+        std::f64::consts::PI,
+        1e-20,
+        123456789.98765432,
+        f64::MIN_POSITIVE,
+        f64::EPSILON,
+    ];
+
+    #[test]
+    fn non_finite_values() {
+        for x in [f64::NAN, f64::INFINITY, f64::NEG_INFINITY] {
+            for decimals in [None, Some(0), Some(3)] {
+                let value = to_value(&json!({ "x": x, "xs": [x, 1.0] }), decimals).unwrap();
+                assert_eq!(value["x"], Value::Null, "{} {:?}", x, decimals);
+                assert_eq!(value["xs"][0], Value::Null, "{} {:?}", x, decimals);
+                assert_eq!(format(x, decimals), "", "{} {:?}", x, decimals);
+            }
+        }
+    }
+
+    #[test]
+    fn round_is_stable() {
+        for x in VALUES.iter().flat_map(|x| [*x, -x]) {
+            for decimals in 0..=17 {
+                let rounded = round(x, decimals);
+                assert_eq!(round(rounded, decimals), rounded, "{} {}", x, decimals);
+
+                let shortest = rounded.to_string();
+                assert_eq!(shortest.parse::<f64>().unwrap(), rounded);
+                let digits = shortest
+                    .split_once('.')
+                    .map_or(0, |(_, digits)| digits.len());
+                assert!(
+                    digits <= decimals as usize,
+                    "{} {}: {}",
+                    x,
+                    decimals,
+                    shortest
+                );
+
+                let json = serde_json::to_string(&to_value(&x, Some(decimals)).unwrap()).unwrap();
+                assert_eq!(json.parse::<f64>().unwrap(), rounded, "{} {}", x, decimals);
+            }
+        }
+    }
+
+    #[test]
+    fn negative_zero() {
+        for x in [-0.0, -0.001, -1e-20] {
+            for decimals in [0, 2] {
+                let rounded = round(x, decimals);
+                assert!(
+                    rounded == 0.0 && rounded.is_sign_positive(),
+                    "{} {}",
+                    x,
+                    decimals
+                );
+                assert_eq!(format(x, Some(decimals)), "0");
+                assert_eq!(
+                    serde_json::to_string(&to_value(&x, Some(decimals)).unwrap()).unwrap(),
+                    "0.0"
+                );
+            }
+        }
+        assert_eq!(format(-0.0, None), "0");
+        assert_eq!(
+            serde_json::to_string(&to_value(&json!([-0.0]), None).unwrap()).unwrap(),
+            "[0.0]"
+        );
+    }
+
+    #[test]
+    fn arbitrary_values() {
+        let mut rng = ChaCha8Rng::seed_from_u64(0);
+        for _ in 0..100_000 {
+            // Random bits cover NaNs, infinities, subnormals, and zeros of
+            // both signs, which uniform values would miss.
+            let x = match rng.gen_range(0..4) {
+                0 => f64::from_bits(rng.gen()),
+                1 => rng.gen_range(-1.0..1.0),
+                2 => rng.gen_range(-1e6..1e6),
+                _ => [0.0, -0.0, f64::NAN, f64::INFINITY, -f64::MAX][rng.gen_range(0..5)],
+            };
+            let decimals = rng.gen_bool(0.8).then(|| rng.gen_range(0..=17));
+
+            let value = to_value(&x, decimals).unwrap();
+            let field = format(x, decimals);
+            if !x.is_finite() {
+                assert_eq!(value, Value::Null, "{} {:?}", x, decimals);
+                assert_eq!(field, "", "{} {:?}", x, decimals);
+                continue;
+            }
+
+            let expected = decimals.map_or(x, |decimals| round(x, decimals));
+            let json = serde_json::to_string(&value).unwrap();
+            let parsed = json.parse::<f64>().unwrap();
+            assert_eq!(parsed, expected, "{} {:?}: {}", x, decimals, json);
+            assert!(
+                !json.starts_with("-0") || parsed != 0.0,
+                "{} {:?}",
+                x,
+                decimals
+            );
+            assert!(
+                !field.starts_with("-0") || parsed != 0.0,
+                "{} {:?}",
+                x,
+                decimals
+            );
+            assert_eq!(
+                field.parse::<f64>().unwrap(),
+                parsed,
+                "{} {:?}",
+                x,
+                decimals
+            );
+            if let Some(decimals) = decimals {
+                assert_eq!(round(parsed, decimals), parsed, "{} {}", x, decimals);
+            }
+        }
+    }
+
+    #[test]
+    fn serialization_is_deterministic() {
+        let corpus = load_corpus();
+        let data = b"The quick brown fox jumps over the lazy dog. ".repeat(200);
+        let res: ProcessedDetectionResult = detect_code(
+            &corpus,
+            &data,
+            &[],
+            "text",
+            &DetectionOptions::default(),
+            &[],
+        )
+        .into();
+        let report = CliJsonOutput::from(("text", &res));
+
+        for decimals in [None, Some(0), Some(3), Some(17)] {
+            let serialize = || serde_json::to_vec(&to_value(&report, decimals).unwrap()).unwrap();
+            assert_eq!(serialize(), serialize(), "{:?}", decimals);
+        }
+    }
+}
@@ -0,0 +1,176 @@
+/*
+    Copyright 2024 - Valentin Obst <coderec@vpao.io>
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+*/
+//! Python scripts for Ghidra's headless analyzer, see `--emit ghidra`.
+//!
+//! A Ghidra program has a single language, so the script creates one program
+//! per detected arch in the project, with a memory block for each region of
+//! the arch at its load address. It runs with Jython as well as PyGhidra.
+
+use crate::emit::{comment, quote, PlacedRegion};
+
+use std::{collections::BTreeMap, fmt::Write};
+
+/// Returns the Ghidra language ID of `arch`, or `None` if Ghidra does not
+/// ship a processor for it.
+fn language_id(arch: &str) -> Option<&'static str> {
+    let id = match arch {
+        "X86" => "x86:LE:32:default",
+        "X86-64" => "x86:LE:64:default",
+        "8086" => "x86:LE:16:Real Mode",
+        "ARMel" | "ARMhf" => "ARM:LE:32:v8",
+        "ARMeb" => "ARM:BE:32:v8",
+        "Thumb" => "ARM:LE:32:v8T",
+        "ARM64" => "AARCH64:LE:64:v8A",
+        "MIPSel" => "MIPS:LE:32:default",
+        "MIPSeb" => "MIPS:BE:32:default",
+        "PPCel" => "PowerPC:LE:32:default",
+        "PPCeb" => "PowerPC:BE:32:default",
+        "RISC-V" => "RISCV:LE:64:RV64GC",
+        "SPARC" => "sparc:BE:32:default",
+        "M68k" => "68000:BE:32:default",
+        "68HC08" => "HC08:BE:16:default",
+        "AVR" => "avr8:LE:16:default",
+        "MSP430" => "TI_MSP430:LE:16:default",
+        "SH4" => "SuperH4:LE:32:default",
+        "6502" => "6502:LE:16:default",
+        "Z80" => "z80:LE:16:default",
+        "8051" => "8051:BE:16:default",
+        "HP-PA" => "pa-risc:BE:32:default",
+        "V850" => "V850:LE:32:default",
+        "TriCore" => "tricore:LE:32:default",
+        "Xtensa" => "Xtensa:LE:32:default",
+        _ => return None,
+    };
+
+    Some(id)
+}
+
+const BODY: &str = r#"
+args = getScriptArgs()
+path = args[0] if args else INPUT
+languages = DefaultLanguageService.getLanguageService()
+for name, language_id, regions in PROGRAMS:
+    language = languages.getLanguage(LanguageID(language_id))
+    program = ProgramDB(name, language, language.getDefaultCompilerSpec(), this)
+    transaction = program.startTransaction("coderec")
+    space = program.getAddressFactory().getDefaultAddressSpace()
+    memory = program.getMemory()
+    with open(path, "rb") as f:
+        for offset, address, size, arch, confidence in regions:
+            f.seek(offset)
+            start = space.getAddress(address)
+            # Regions of archive members can overlap those of the archive.
+            overlay = memory.intersects(start, start.add(size - 1))
+            block = memory.createInitializedBlock(
+                "%s_%x" % (arch, offset),
+                start,
+                ByteArrayInputStream(f.read(size)),
+                size,
+                monitor,
+                overlay,
+            )
+            block.setExecute(True)
+            block.setComment("%s, confidence %.2f" % (arch, confidence))
+    program.endTransaction(transaction, True)
+    state.getProject().getProjectData().getRootFolder().createFile(name, program, monitor)
+    program.release(this)
+    println("coderec: created %s with %d regions" % (name, len(regions)))
+"#;
+
+/// Returns the script that loads `regions` of the file at `path`, which
+/// `file` was analyzed from.
+pub fn script(file: &str, path: &str, regions: &[PlacedRegion]) -> String {
+    let mut programs: BTreeMap<(&str, &str), Vec<&PlacedRegion>> = BTreeMap::new();
+    let mut skipped = Vec::new();
+    for region in regions {
+        match language_id(region.arch) {
+            Some(id) => programs.entry((region.arch, id)).or_default().push(region),
+            None => skipped.push(region),
+        }
+    }
+
+    let file_name = file.rsplit('/').next().unwrap();
+    let mut script = String::new();
+    writeln!(
+        script,
+        "# Loads the regions that coderec {} detected in {} into a program per arch.",
+        env!("CARGO_PKG_VERSION"),
+        comment(file_name)
+    )
+    .unwrap();
+    writeln!(script, "#").unwrap();
+    writeln!(
+        script,
+        "# analyzeHeadless PROJECT_DIR PROJECT -scriptPath DIR -preScript SCRIPT [INPUT]"
+    )
+    .unwrap();
+    writeln!(script, "#").unwrap();
+    writeln!(script, "# INPUT defaults to the analyzed file.").unwrap();
+    for region in skipped {
+        writeln!(
+            script,
+            "# Skipped {:#x}..{:#x}, Ghidra has no language for {}.",
+            region.offset,
+            region.offset + region.size as u64,
+            region.arch
+        )
+        .unwrap();
+    }
+    writeln!(script, "#@category coderec").unwrap();
+    writeln!(script).unwrap();
+    writeln!(script, "from java.io import ByteArrayInputStream").unwrap();
+    writeln!(script, "from ghidra.program.database import ProgramDB").unwrap();
+    writeln!(script, "from ghidra.program.model.lang import LanguageID").unwrap();
+    writeln!(
+        script,
+        "from ghidra.program.util import DefaultLanguageService"
+    )
+    .unwrap();
+    writeln!(script).unwrap();
+    writeln!(script, "INPUT = {}", quote(path)).unwrap();
+    writeln!(
+        script,
+        "# (name, language ID, [(offset, address, size, arch, confidence)])"
+    )
+    .unwrap();
+    writeln!(script, "PROGRAMS = [").unwrap();
+    for ((arch, id), regions) in programs {
+        writeln!(
+            script,
+            "    ({}, {}, [",
+            quote(&format!("{}_{}", file_name, arch)),
+            quote(id)
+        )
+        .unwrap();
+        for region in regions {
+            writeln!(
+                script,
+                "        ({:#x}, {:#x}, {:#x}, {}, {:.2}),",
+                region.offset,
+                region.address,
+                region.size,
+                quote(region.arch),
+                region.confidence
+            )
+            .unwrap();
+        }
+        writeln!(script, "    ]),").unwrap();
+    }
+    writeln!(script, "]").unwrap();
+    script.push_str(BODY);
+
+    script
+}
@@ -0,0 +1,117 @@
+/*
+    Copyright 2024 - Valentin Obst <coderec@vpao.io>
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+*/
+//! Hash sets of known files, see `--hash-set`.
+//!
+//! Regions and files whose SHA-256 is in a hash set, e.g., an export of the
+//! NSRL or the manifest of `--extract-cas` for vendor blobs, are tagged as
+//! known-good components, such that analysts can skip them. Archive members
+//! are files of their own, see `--recurse`. Each line of a hash set holds a
+//! SHA-256 as its first token of 64 hex digits, lines without one, e.g.,
+//! headers, are skipped. In the output of `sha256sum`, the rest of the line
+//! is the name of the known file.
+
+use crate::output::CliJsonOutput;
+
+use std::collections::HashMap;
+use std::ops::Range;
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use log::info;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Region or file whose hash is in a hash set.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Known {
+    pub range: Range<usize>,
+    pub sha256: String,
+    /// Name of the known file, if the hash set tells.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+}
+
+/// SHA-256 hashes of known files and their names.
+#[derive(Debug, Default)]
+pub struct KnownHashes {
+    hashes: HashMap<String, Option<String>>,
+}
+
+impl KnownHashes {
+    /// Adds the hashes of the hash set at `path`.
+    pub fn load(&mut self, path: &Path) -> Result<()> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Could not read {}", path.display()))?;
+
+        let count = self.hashes.len();
+        for line in text.lines() {
+            let Some(hash) = line
+                .split(|c: char| !c.is_ascii_alphanumeric())
+                .find(|token| token.len() == 64 && token.bytes().all(|b| b.is_ascii_hexdigit()))
+            else {
+                continue;
+            };
+            let name = line
+                .strip_prefix(hash)
+                .filter(|rest| rest.starts_with(char::is_whitespace))
+                .map(|rest| rest.trim().trim_start_matches('*').to_owned())
+                .filter(|name| !name.is_empty());
+            self.hashes.insert(hash.to_ascii_lowercase(), name);
+        }
+        if self.hashes.len() == count {
+            bail!("{} holds no SHA-256 hashes", path.display());
+        }
+        info!(
+            "{}: loaded {} hashes",
+            path.display(),
+            self.hashes.len() - count
+        );
+
+        Ok(())
+    }
+
+    fn lookup(&self, range: Range<usize>, data: &[u8]) -> Option<Known> {
+        let sha256 = format!("{:x}", Sha256::digest(&data[range.clone()]));
+        let name = self.hashes.get(&sha256)?.clone();
+
+        Some(Known {
+            range,
+            sha256,
+            name,
+        })
+    }
+
+    /// Tags `report`, whose analyzed bytes are `data`, with the whole file and
+    /// the regions whose hash is known.
+    pub fn tag(&self, report: &mut CliJsonOutput, data: &[u8]) {
+        let known: Vec<Known> = std::iter::once(0..data.len())
+            .chain(
+                report
+                    .range_results
+                    .iter()
+                    .map(|(range, ..)| range.clone())
+                    .filter(|range| range.len() < data.len()),
+            )
+            .filter_map(|range| self.lookup(range, data))
+            .collect();
+        if known.is_empty() {
+            return;
+        }
+
+        info!("{}: {} ranges known by hash", report.file, known.len());
+        report.known = Some(known);
+    }
+}
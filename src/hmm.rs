@@ -0,0 +1,204 @@
+/*
+    Copyright 2024 - Valentin Obst <coderec@vpao.io>
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+*/
+//! Segmentation of the file by a hidden Markov model.
+//!
+//! The windows, ordered by their offset, are the observations and the arches
+//! plus unknown are the states. The score of an arch in a window is how far
+//! its divergences stand out from those of the other arches, in standard
+//! deviations, beyond the thresholds of the main heuristic. Unknown always
+//! scores zero. Switching between states costs a fixed penalty, and the
+//! Viterbi algorithm finds the sequence of states with the best total score.
+//! Compared to independent decisions, short excursions to related arches are
+//! thus smoothed out.
+
+use crate::config::Thresholds;
+use crate::{confidence, Arch, Decision, DecisionBranch, RangeResult};
+
+use std::collections::HashMap;
+use std::ops::Range;
+use std::str::FromStr;
+
+/// Cost of a transition between two states, in standard deviations.
+const SWITCH_PENALTY: f64 = 1.0;
+
+/// Ways to turn the divergences of the windows into verdicts.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Segmenter {
+    /// Independent decision for each window, see `final_range_decision`.
+    #[default]
+    Window,
+    Hmm,
+}
+
+impl FromStr for Segmenter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "window" => Ok(Self::Window),
+            "hmm" => Ok(Self::Hmm),
+            _ => Err(format!("unknown segmenter {:?}, expected window or hmm", s)),
+        }
+    }
+}
+
+/// Divergences of all arches in a single window.
+pub struct WindowDivs<'a> {
+    pub bg: &'a [(Arch, f64)],
+    pub tg: &'a [(Arch, f64)],
+    pub res_bg: &'a RangeResult,
+    pub res_tg: &'a RangeResult,
+}
+
+/// Score of `arch` in a window, and the confidence of a verdict for it.
+fn emission(
+    arch: &Arch,
+    div_bg: f64,
+    div_tg: f64,
+    window: &WindowDivs,
+    thresholds: &Thresholds,
+) -> (f64, f64) {
+    let (max_abs_div_bg, max_abs_div_tg) = thresholds.max_abs_div(arch);
+    if div_bg > max_abs_div_bg && div_tg > max_abs_div_tg {
+        return (f64::NEG_INFINITY, 0.0);
+    }
+
+    let (comm_std_dev_bg, comm_std_dev_tg) = if thresholds.is_strict(arch) {
+        (
+            thresholds.comm_std_dev_strict_bg,
+            thresholds.comm_std_dev_strict_tg,
+        )
+    } else {
+        (thresholds.comm_std_dev_bg, thresholds.comm_std_dev_tg)
+    };
+    let z = |div: f64, res: &RangeResult| {
+        let std_deviation = res.range_var.sqrt();
+        if std_deviation == 0.0 {
+            0.0
+        } else {
+            (res.range_mean - div) / std_deviation
+        }
+    };
+
+    let score = ((z(div_bg, window.res_bg) - comm_std_dev_bg)
+        + (z(div_tg, window.res_tg) - comm_std_dev_tg))
+        / 2.0;
+    let confidence = f64::min(
+        confidence(
+            div_bg,
+            window.res_bg.range_mean,
+            window.res_bg.range_var.sqrt(),
+            comm_std_dev_bg,
+        ),
+        confidence(
+            div_tg,
+            window.res_tg.range_mean,
+            window.res_tg.range_var.sqrt(),
+            comm_std_dev_tg,
+        ),
+    );
+
+    (score, confidence)
+}
+
+/// Decides on all `windows` at once with the Viterbi algorithm.
+pub fn decode(
+    windows: &HashMap<Range<usize>, WindowDivs>,
+    thresholds: &Thresholds,
+) -> HashMap<Range<usize>, Decision> {
+    let mut ranges: Vec<_> = windows.keys().collect();
+    ranges.sort_unstable_by_key(|range| range.start);
+    let Some(first) = ranges.first() else {
+        return HashMap::new();
+    };
+
+    // The last state is unknown.
+    let mut arches: Vec<&Arch> = windows[*first].bg.iter().map(|(arch, _)| arch).collect();
+    arches.sort_unstable();
+    let unknown = arches.len();
+
+    let emissions: Vec<Vec<(f64, f64)>> = ranges
+        .iter()
+        .map(|range| {
+            let window = &windows[*range];
+            let divs_tg: HashMap<&Arch, f64> =
+                window.tg.iter().map(|(arch, div)| (arch, *div)).collect();
+            let divs_bg: HashMap<&Arch, f64> =
+                window.bg.iter().map(|(arch, div)| (arch, *div)).collect();
+
+            arches
+                .iter()
+                .map(|arch| emission(arch, divs_bg[arch], divs_tg[arch], window, thresholds))
+                .chain([(0.0, 0.0)])
+                .collect()
+        })
+        .collect();
+
+    let mut scores: Vec<f64> = emissions[0].iter().map(|(score, _)| *score).collect();
+    let mut back: Vec<Vec<usize>> = Vec::with_capacity(ranges.len());
+    for emission in emissions.iter().skip(1) {
+        let (best_prev, best_score) = scores
+            .iter()
+            .copied()
+            .enumerate()
+            .max_by(|a, b| a.1.total_cmp(&b.1))
+            .unwrap();
+        let switch = best_score - SWITCH_PENALTY;
+
+        let mut prev = Vec::with_capacity(scores.len());
+        for (state, score) in scores.iter_mut().enumerate() {
+            if *score >= switch {
+                prev.push(state);
+            } else {
+                *score = switch;
+                prev.push(best_prev);
+            }
+            *score += emission[state].0;
+        }
+        back.push(prev);
+    }
+
+    let mut state = scores
+        .iter()
+        .enumerate()
+        .max_by(|a, b| a.1.total_cmp(b.1))
+        .unwrap()
+        .0;
+    let mut states = vec![state; ranges.len()];
+    for (idx, prev) in back.iter().enumerate().rev() {
+        state = prev[state];
+        states[idx] = state;
+    }
+
+    ranges
+        .into_iter()
+        .zip(states)
+        .zip(emissions)
+        .map(|((range, state), emission)| {
+            let decision = if state == unknown {
+                Decision::new(None, DecisionBranch::Hmm, 0.0)
+            } else {
+                Decision::new(
+                    Some(arches[state].clone()),
+                    DecisionBranch::Hmm,
+                    emission[state].1,
+                )
+            };
+
+            (range.clone(), decision)
+        })
+        .collect()
+}
@@ -0,0 +1,135 @@
+/*
+    Copyright 2024 - Valentin Obst <coderec@vpao.io>
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+*/
+//! Posting reports to HTTP endpoints.
+
+use crate::float;
+use crate::output::{CliJsonOutput, ReportSink};
+
+use std::thread::sleep;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use log::{debug, warn};
+
+/// Delay before the first retry, doubled for every further attempt.
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Settings shared by all HTTP sinks.
+#[derive(Debug, Clone, Default)]
+pub struct HttpOptions {
+    /// Additional headers, e.g., for authentication.
+    pub headers: Vec<(String, String)>,
+    /// How often a failed request is retried.
+    pub retries: u32,
+}
+
+/// Parses a header of the form `NAME: VALUE`.
+pub fn parse_header(arg: &str) -> Result<(String, String), String> {
+    let (name, value) = arg
+        .split_once(':')
+        .ok_or_else(|| format!("expected NAME: VALUE, got {:?}", arg))?;
+    let name = name.trim();
+    if name.is_empty() {
+        return Err("empty header name".to_owned());
+    }
+
+    Ok((name.to_owned(), value.trim().to_owned()))
+}
+
+/// Parses an `http://` or `https://` URL, other targets of the sinks are
+/// rejected as they would not be POSTed to.
+pub fn parse_url(arg: &str) -> Result<String, String> {
+    match arg
+        .strip_prefix("http://")
+        .or_else(|| arg.strip_prefix("https://"))
+    {
+        Some(rest) if !rest.is_empty() => Ok(arg.to_owned()),
+        Some(_) => Err(format!("missing host in {:?}", arg)),
+        None => Err(format!(
+            "expected an http:// or https:// URL, got {:?}",
+            arg
+        )),
+    }
+}
+
+/// POSTs the JSON report of each file to an URL.
+pub struct HttpSink {
+    agent: ureq::Agent,
+    url: String,
+    options: HttpOptions,
+    float_decimals: Option<u32>,
+}
+
+impl HttpSink {
+    pub fn new(url: &str, options: &HttpOptions, float_decimals: Option<u32>) -> Self {
+        Self {
+            agent: ureq::AgentBuilder::new().timeout(TIMEOUT).build(),
+            url: url.to_owned(),
+            options: options.clone(),
+            float_decimals,
+        }
+    }
+
+    fn post(&self, body: &str) -> Result<()> {
+        let mut backoff = INITIAL_BACKOFF;
+
+        for attempt in 0..=self.options.retries {
+            let mut request = self
+                .agent
+                .post(&self.url)
+                .set("Content-Type", "application/json");
+            for (name, value) in self.options.headers.iter() {
+                request = request.set(name, value);
+            }
+
+            match request.send_string(body) {
+                Ok(response) => {
+                    debug!("POST to {}: HTTP {}", self.url, response.status());
+                    return Ok(());
+                }
+                // Retrying will not help for client errors.
+                Err(ureq::Error::Status(code, _)) if code < 500 && code != 429 => {
+                    bail!("{} rejected the report: HTTP {}", self.url, code)
+                }
+                Err(e) if attempt < self.options.retries => {
+                    warn!(
+                        "POST to {} failed: {}, retrying in {}ms",
+                        self.url,
+                        e,
+                        backoff.as_millis()
+                    );
+                    sleep(backoff);
+                    backoff *= 2;
+                }
+                Err(e) => {
+                    return Err(e).with_context(|| format!("Could not POST report to {}", self.url))
+                }
+            }
+        }
+
+        unreachable!()
+    }
+}
+
+impl ReportSink for HttpSink {
+    fn write_report(&mut self, report: &CliJsonOutput) -> Result<()> {
+        self.post(&serde_json::to_string(&float::to_value(
+            report,
+            self.float_decimals,
+        )?)?)
+    }
+}
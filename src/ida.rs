@@ -0,0 +1,148 @@
+/*
+    Copyright 2024 - Valentin Obst <coderec@vpao.io>
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+*/
+//! IDAPython scripts, see `--emit ida`.
+//!
+//! The script annotates the database of the analyzed file, loaded at the base
+//! address of the analysis: it makes each detected region a code segment with
+//! the bitness of its arch, comments its start with the arch and confidence,
+//! and starts code analysis there. An IDA database has a single processor
+//! module, so regions of arches of other modules are only annotated.
+
+use crate::emit::{comment, quote, PlacedRegion};
+
+use std::fmt::Write;
+
+/// Returns the IDA processor module and the bitness of `arch`, or `None` if
+/// IDA does not ship a module for it.
+fn processor(arch: &str) -> Option<(&'static str, u32)> {
+    let processor = match arch {
+        "X86" => ("metapc", 32),
+        "X86-64" => ("metapc", 64),
+        "8086" => ("metapc", 16),
+        "ARMel" | "ARMhf" | "ARMeb" | "Thumb" => ("arm", 32),
+        "ARM64" => ("arm", 64),
+        "MIPSel" | "MIPSeb" | "MIPS16" => ("mips", 32),
+        "PPCel" | "PPCeb" => ("ppc", 32),
+        "RISC-V" => ("riscv", 64),
+        "SPARC" => ("sparcb", 32),
+        "M68k" => ("68k", 32),
+        "68HC08" => ("6808", 16),
+        "68HC11" => ("6811", 16),
+        "AVR" => ("avr", 16),
+        "MSP430" => ("msp430", 16),
+        "SH4" => ("sh4", 32),
+        "6502" => ("m6502", 16),
+        "Z80" => ("z80", 16),
+        "8051" => ("8051", 16),
+        "HP-PA" => ("hppa", 32),
+        "V850" => ("v850", 32),
+        "TriCore" => ("tricore", 32),
+        _ => return None,
+    };
+
+    Some(processor)
+}
+
+const BODY: &str = r#"
+# Segment addressing of each bitness.
+ADDRESSING = {16: 0, 32: 1, 64: 2}
+
+current = ida_idp.get_idp_name()
+for address, size, arch, module, bits, confidence in REGIONS:
+    end = address + size
+    comment = "coderec: %s, confidence %.2f, %#x bytes" % (arch, confidence, size)
+    if module != current:
+        # Disassembling with the module of another arch yields garbage.
+        print("coderec: %s region at %#x needs processor module %s" % (arch, address, module))
+        idc.set_cmt(address, comment, 1)
+        continue
+    segment = ida_segment.getseg(address)
+    if segment is None or segment.start_ea != address or segment.end_ea != end:
+        ida_segment.add_segm(0, address, end, "%s_%x" % (arch, address), "CODE")
+        segment = ida_segment.getseg(address)
+    segment.type = ida_segment.SEG_CODE
+    ida_segment.set_segm_addressing(segment, ADDRESSING[bits])
+    ida_segment.update_segm(segment)
+    if arch == "Thumb":
+        idc.split_sreg_range(address, "T", 1, idc.SR_user)
+    idc.set_cmt(address, comment, 1)
+    ida_auto.auto_make_code(address)
+ida_auto.auto_wait()
+print("coderec: annotated %d regions" % len(REGIONS))
+"#;
+
+/// Returns the script that annotates `regions` of `file`.
+pub fn script(file: &str, regions: &[PlacedRegion]) -> String {
+    let file_name = file.rsplit('/').next().unwrap();
+    let mut script = String::new();
+    writeln!(
+        script,
+        "# Annotates the regions that coderec {} detected in {}.",
+        env!("CARGO_PKG_VERSION"),
+        comment(file_name)
+    )
+    .unwrap();
+    writeln!(script, "#").unwrap();
+    writeln!(
+        script,
+        "# Load the file at the base address of the analysis and run File > Script file."
+    )
+    .unwrap();
+    for region in regions.iter() {
+        if processor(region.arch).is_none() {
+            writeln!(
+                script,
+                "# Skipped {:#x}..{:#x}, IDA has no processor module for {}.",
+                region.address,
+                region.address + region.size as u64,
+                region.arch
+            )
+            .unwrap();
+        }
+    }
+    writeln!(script).unwrap();
+    writeln!(script, "import ida_auto").unwrap();
+    writeln!(script, "import ida_idp").unwrap();
+    writeln!(script, "import ida_segment").unwrap();
+    writeln!(script, "import idc").unwrap();
+    writeln!(script).unwrap();
+    writeln!(
+        script,
+        "# (address, size, arch, processor module, bitness, confidence)"
+    )
+    .unwrap();
+    writeln!(script, "REGIONS = [").unwrap();
+    for region in regions {
+        let Some((module, bits)) = processor(region.arch) else {
+            continue;
+        };
+        writeln!(
+            script,
+            "    ({:#x}, {:#x}, {}, {}, {}, {:.2}),",
+            region.address,
+            region.size,
+            quote(region.arch),
+            quote(module),
+            bits,
+            region.confidence
+        )
+        .unwrap();
+    }
+    writeln!(script, "]").unwrap();
+    script.push_str(BODY);
+
+    script
+}
@@ -0,0 +1,172 @@
+/*
+    Copyright 2024 - Valentin Obst <coderec@vpao.io>
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+*/
+//! Impact of new corpus entries on the detection of a benchmark set.
+//!
+//! The benchmark set uses the layout of `--export-dataset`, i.e., each file
+//! `DIR/LABEL/*.bin` is a window whose expected label is `LABEL`. Every window
+//! is classified once with the corpus as is and once with the new entries
+//! added, where new entries replace existing ones of the same arch. Windows
+//! that were labeled correctly before but not after are new confusions, and
+//! windows that moved from some arch to a new entry were stolen from it.
+
+use crate::config::Thresholds;
+use crate::corpus::CorpusStats;
+use crate::metric::MetricKind;
+use crate::{classify_window, Arch};
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use rayon::prelude::*;
+use serde::Serialize;
+
+/// Label of windows that are not attributed to any arch, see
+/// `--export-dataset`.
+pub(crate) const UNKNOWN: &str = "unknown";
+
+/// Windows of a label that are confused with another arch after adding the
+/// entries.
+#[derive(Debug, Serialize)]
+pub struct Confusion {
+    pub label: String,
+    pub before: String,
+    pub after: String,
+    pub windows: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Impact {
+    /// Arches of the new entries.
+    pub entries: Vec<Arch>,
+    pub windows: usize,
+    /// Number of correctly labeled windows without and with the entries.
+    pub correct_before: usize,
+    pub correct_after: usize,
+    /// Number of windows whose label changed.
+    pub changed: usize,
+    pub confusions: Vec<Confusion>,
+    /// Number of windows per arch that were attributed to a new entry instead.
+    pub stolen: BTreeMap<Arch, usize>,
+}
+
+/// Classifies the windows in `benchmark` with `corpus` and with `corpus`
+/// extended by `entries`.
+pub fn corpus_impact(
+    corpus: Vec<CorpusStats>,
+    entries: Vec<CorpusStats>,
+    benchmark: &Path,
+) -> Result<Impact> {
+    let windows = read_benchmark(benchmark)?;
+    if windows.is_empty() {
+        bail!("{}: no benchmark windows", benchmark.display());
+    }
+
+    let entry_arches: Vec<Arch> = entries.iter().map(|entry| entry.arch.clone()).collect();
+    let after: Vec<CorpusStats> = corpus
+        .iter()
+        .filter(|stats| !entry_arches.contains(&stats.arch))
+        .cloned()
+        .chain(entries)
+        .collect();
+
+    let thresholds = Thresholds::default();
+    let labels: Vec<(String, String)> = windows
+        .par_iter()
+        .map(|(_, path)| {
+            let data = std::fs::read(path)
+                .with_context(|| format!("Could not read {}", path.display()))?;
+            let label = |corpus: &[CorpusStats]| {
+                classify_window(corpus, &data, MetricKind::default(), &thresholds)
+                    .arch
+                    .map_or(UNKNOWN.to_owned(), |arch| arch.replace('/', "_"))
+            };
+
+            Ok((label(&corpus), label(&after)))
+        })
+        .collect::<Result<_>>()?;
+
+    let mut impact = Impact {
+        entries: entry_arches,
+        windows: windows.len(),
+        correct_before: 0,
+        correct_after: 0,
+        changed: 0,
+        confusions: Vec::new(),
+        stolen: BTreeMap::new(),
+    };
+    let mut confusions: BTreeMap<(&str, &str, &str), usize> = BTreeMap::new();
+    for ((expected, _), (before, after)) in windows.iter().zip(labels.iter()) {
+        if before == expected {
+            impact.correct_before += 1;
+        }
+        if after == expected {
+            impact.correct_after += 1;
+        }
+        if before == after {
+            continue;
+        }
+        impact.changed += 1;
+        if before == expected {
+            *confusions
+                .entry((expected.as_str(), before.as_str(), after.as_str()))
+                .or_default() += 1;
+        }
+        if before != UNKNOWN && impact.entries.contains(after) {
+            *impact.stolen.entry(before.clone()).or_default() += 1;
+        }
+    }
+    impact.confusions = confusions
+        .into_iter()
+        .map(|((label, before, after), windows)| Confusion {
+            label: label.to_owned(),
+            before: before.to_owned(),
+            after: after.to_owned(),
+            windows,
+        })
+        .collect();
+
+    Ok(impact)
+}
+
+/// Returns the expected label and path of every window in `dir`.
+pub(crate) fn read_benchmark(dir: &Path) -> Result<Vec<(String, PathBuf)>> {
+    let mut windows = Vec::new();
+    for label_dir in
+        std::fs::read_dir(dir).with_context(|| format!("Could not read {}", dir.display()))?
+    {
+        let label_dir = label_dir?.path();
+        if !label_dir.is_dir() {
+            continue;
+        }
+        let label = label_dir
+            .file_name()
+            .and_then(|name| name.to_str())
+            .with_context(|| format!("Invalid label {}", label_dir.display()))?
+            .to_owned();
+        for window in std::fs::read_dir(&label_dir)
+            .with_context(|| format!("Could not read {}", label_dir.display()))?
+        {
+            let window = window?.path();
+            if window.extension().is_some_and(|ext| ext == "bin") {
+                windows.push((label.clone(), window));
+            }
+        }
+    }
+    windows.sort_unstable();
+
+    Ok(windows)
+}
@@ -0,0 +1,250 @@
+/*
+    Copyright 2024 - Valentin Obst <coderec@vpao.io>
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+*/
+//! Reading of stored reports.
+//!
+//! JSON reports are imported into the same model that the sinks write, such
+//! that stored results can be written in any other format without analyzing
+//! the files again. The JSON sink writes one object per file, which may be
+//! separated by whitespace, and arrays of reports are accepted as well.
+
+use crate::input::{read_input, InputOptions};
+use crate::output::CliJsonOutput;
+
+use anyhow::{Context, Result};
+use serde_json::Deserializer;
+
+/// Parses the JSON reports in `data`.
+pub fn parse_reports(data: &[u8]) -> Result<Vec<CliJsonOutput>> {
+    if data.trim_ascii_start().starts_with(b"[") {
+        return Ok(serde_json::from_slice(data)?);
+    }
+
+    Deserializer::from_slice(data)
+        .into_iter::<CliJsonOutput>()
+        .enumerate()
+        .map(|(idx, report)| report.with_context(|| format!("invalid report {}", idx + 1)))
+        .collect()
+}
+
+/// Reads the JSON reports in `name`, which is either a local path or an
+/// `s3://` URL.
+pub fn read_reports(name: &str) -> Result<Vec<CliJsonOutput>> {
+    let data = read_input(name, None, &InputOptions::default())?;
+
+    parse_reports(&data).with_context(|| format!("Could not read reports from {}", name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::container::Member;
+    use crate::output::{SinkFormat, SinkOptions, SinkSpec};
+    use crate::{detect_code, load_corpus, DetectionOptions, ProcessedDetectionResult};
+
+    use std::collections::BTreeMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Returns the reports of the analyses of two generated files.
+    fn reports() -> Vec<CliJsonOutput> {
+        let corpus = load_corpus();
+        let text = b"The quick brown fox jumps over the lazy dog. ".repeat(400);
+        let noise: Vec<u8> = (0..0x4000u32)
+            .map(|idx| (idx.wrapping_mul(2654435761) >> 13) as u8)
+            .collect();
+
+        [
+            ("text.bin", [text.as_slice(), &noise].concat()),
+            ("noise.bin", noise.clone()),
+        ]
+        .into_iter()
+        .map(|(name, data)| {
+            let res: ProcessedDetectionResult =
+                detect_code(&corpus, &data, &[], name, &DetectionOptions::default(), &[]).into();
+            let mut report = CliJsonOutput::from((name, &res));
+            report.add_gaps();
+            report
+        })
+        .chain([{
+            let mut report = CliJsonOutput::from((
+                "archive.tar@empty",
+                &detect_code(
+                    &corpus,
+                    &[],
+                    &[],
+                    "empty",
+                    &DetectionOptions::default(),
+                    &[],
+                )
+                .into(),
+            ));
+            report.member = Some(Member {
+                format: "tar".to_owned(),
+                id: "empty".to_owned(),
+                path: "empty".to_owned(),
+                offset: Some(512),
+                load_address: None,
+                partition: None,
+            });
+            report.meta = Some(BTreeMap::from([("product".to_owned(), "a,b".to_owned())]));
+            report
+        }])
+        .collect()
+    }
+
+    /// Writes `reports` with a sink of `format` to a file and returns its
+    /// path.
+    fn write(reports: &[CliJsonOutput], format: SinkFormat) -> String {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir()
+            .join(format!(
+                "coderec-import-{}-{}",
+                std::process::id(),
+                COUNT.fetch_add(1, Ordering::Relaxed)
+            ))
+            .display()
+            .to_string();
+
+        let mut sink = SinkSpec {
+            format,
+            target: path.clone(),
+        }
+        .open(&SinkOptions::default())
+        .unwrap();
+        for report in reports {
+            sink.write_report(report).unwrap();
+        }
+        sink.finish().unwrap();
+
+        path
+    }
+
+    fn read_and_remove(path: &str) -> Vec<u8> {
+        let data = std::fs::read(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        data
+    }
+
+    fn values(reports: &[CliJsonOutput]) -> Vec<serde_json::Value> {
+        reports
+            .iter()
+            .map(|report| serde_json::to_value(report).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        let reports = reports();
+        assert!(reports
+            .iter()
+            .any(|report| !report.range_results.is_empty()));
+        for format in [SinkFormat::Json, SinkFormat::Ndjson] {
+            let path = write(&reports, format);
+            let read = read_reports(&path).unwrap();
+            std::fs::remove_file(&path).unwrap();
+
+            assert_eq!(values(&read), values(&reports), "{:?}", format);
+        }
+    }
+
+    #[test]
+    fn converted_output_matches_direct_output() {
+        let reports = reports();
+        let path = write(&reports, SinkFormat::Json);
+        let read = read_reports(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        for format in [
+            SinkFormat::Csv,
+            SinkFormat::Sarif,
+            SinkFormat::Table,
+            SinkFormat::Yaml,
+        ] {
+            let direct = read_and_remove(&write(&reports, format));
+            let converted = read_and_remove(&write(&read, format));
+
+            assert!(!direct.is_empty(), "{:?}", format);
+            assert_eq!(
+                String::from_utf8_lossy(&converted),
+                String::from_utf8_lossy(&direct),
+                "{:?}",
+                format
+            );
+        }
+    }
+
+    #[test]
+    fn parse_array_and_concatenated_reports() {
+        let reports = reports();
+        let json: Vec<String> = reports
+            .iter()
+            .map(|report| serde_json::to_string(report).unwrap())
+            .collect();
+
+        for data in [
+            format!("[{}]", json.join(",")),
+            format!("  \n[\n{}\n]\n", json.join(",\n")),
+            json.concat(),
+            json.join("\n"),
+            format!("{}\n", json.join("\r\n\n")),
+        ] {
+            let parsed = parse_reports(data.as_bytes()).unwrap();
+            assert_eq!(values(&parsed), values(&reports), "{:?}", data);
+        }
+
+        assert!(parse_reports(b"").unwrap().is_empty());
+        assert!(parse_reports(b"[]").unwrap().is_empty());
+    }
+
+    #[test]
+    fn reports_without_confidences() {
+        let reports = reports();
+        let report = reports
+            .iter()
+            .find(|report| !report.range_results.is_empty())
+            .unwrap();
+        let mut value = serde_json::to_value(report).unwrap();
+        // Regions are written as range, size, and arch.
+        assert_eq!(value["range_results"][0].as_array().unwrap().len(), 3);
+        assert_eq!(
+            value["confidences"].as_array().unwrap().len(),
+            report.range_results.len()
+        );
+
+        value.as_object_mut().unwrap().remove("confidences");
+        let parsed = parse_reports(value.to_string().as_bytes()).unwrap();
+        assert_eq!(parsed[0].range_results, report.range_results);
+        assert!(parsed[0]
+            .regions()
+            .all(|(.., confidence)| confidence.is_nan()));
+        let csv = read_and_remove(&write(&parsed, SinkFormat::Csv));
+        assert!(!csv.is_empty());
+    }
+
+    #[test]
+    fn invalid_report() {
+        let json = serde_json::to_string(&reports()[0]).unwrap();
+        let Err(err) = parse_reports(format!("{} {{\"file\": 1}}", json).as_bytes()) else {
+            panic!("parsed an invalid report");
+        };
+
+        assert!(
+            format!("{:#}", err).contains("invalid report 2"),
+            "{:#}",
+            err
+        );
+    }
+}
@@ -0,0 +1,302 @@
+/*
+    Copyright 2024 - Valentin Obst <coderec@vpao.io>
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+*/
+//! Reading the analyzed files.
+//!
+//! Local files are read in chunks. If a chunk cannot be read, e.g., due to bad
+//! sectors of a device, it is read again sector by sector, and the sectors
+//! that still fail are zero-filled and reported as unreadable, such that the
+//! rest of the file can be analyzed.
+
+#[cfg(feature = "s3")]
+use crate::remote::{object_size, read_object};
+
+use std::cmp::min;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::ops::Range;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+use log::{debug, warn};
+
+/// Pseudo-arch of ranges that could not be read.
+pub const UNREADABLE: &str = "unreadable";
+
+/// Size of the chunks in which inputs are read.
+const CHUNK_SIZE: u64 = 0x100000;
+/// Size of the blocks in which chunks that cannot be read are read again.
+const SECTOR_SIZE: u64 = 0x200;
+
+/// Settings for reading inputs.
+#[derive(Debug, Clone, Default)]
+pub struct InputOptions {
+    /// Maximum read bandwidth in bytes per second.
+    pub io_throttle: Option<u64>,
+}
+
+/// Data of an input, in which ranges that could not be read are zero-filled.
+#[derive(Debug, Clone, Default)]
+pub struct Input {
+    pub data: Vec<u8>,
+    /// Sorted, disjoint ranges of `data` that could not be read.
+    pub unreadable: Vec<Range<usize>>,
+}
+
+/// Returns true if `name` refers to an object in a remote store.
+pub fn is_remote(name: &str) -> bool {
+    name.starts_with("s3://")
+}
+
+/// Reads the input `name`, which is either a local path or an `s3://` URL. If
+/// `range` is given, only those bytes are read.
+pub fn read_input(
+    name: &str,
+    range: Option<Range<u64>>,
+    options: &InputOptions,
+) -> Result<Vec<u8>> {
+    let data = match options.io_throttle {
+        Some(rate) => read_throttled(name, range.clone(), rate)?,
+        None => read_range(name, range.clone())?,
+    };
+
+    if let Some(range) = range {
+        if (data.len() as u64) < range.end - range.start {
+            bail!(
+                "{}: range {:#x}..{:#x} is out of bounds",
+                name,
+                range.start,
+                range.end
+            );
+        }
+    }
+
+    Ok(data)
+}
+
+/// Reads the input `name` like `read_input`, but continues after read errors
+/// of a local file and reports the ranges that could not be read instead.
+pub fn read_input_lossy(
+    name: &str,
+    range: Option<Range<u64>>,
+    options: &InputOptions,
+) -> Result<Input> {
+    let mut file = if is_remote(name) {
+        None
+    } else {
+        Some(File::open(name).with_context(|| format!("Could not open {}", name))?)
+    };
+    // Pipes and files of pseudo file systems have no size to read up to.
+    let size = match file.as_mut() {
+        Some(file) if range.is_none() => seekable_size(file)?,
+        _ => None,
+    };
+    let (Some(file), Some(range)) = (file.as_mut(), range.clone().or(size.map(|size| 0..size)))
+    else {
+        return Ok(Input {
+            data: read_input(name, range, options)?,
+            unreadable: Vec::new(),
+        });
+    };
+
+    let now = Instant::now();
+    let mut input = Input::default();
+    for start in range.clone().step_by(CHUNK_SIZE as usize) {
+        let chunk = start..min(start + CHUNK_SIZE, range.end);
+        let eof = read_chunk_lossy(name, file, chunk, range.start, &mut input);
+        if let Some(rate) = options.io_throttle {
+            pace(now, input.data.len(), rate);
+        }
+        if eof {
+            break;
+        }
+    }
+
+    if (input.data.len() as u64) < range.end - range.start && size.is_none() {
+        bail!(
+            "{}: range {:#x}..{:#x} is out of bounds",
+            name,
+            range.start,
+            range.end
+        );
+    }
+    let unreadable: usize = input.unreadable.iter().map(|range| range.len()).sum();
+    if unreadable > 0 && unreadable == input.data.len() {
+        bail!("Could not read any data from {}", name);
+    }
+    if unreadable > 0 {
+        warn!(
+            "{}: could not read {:#x} bytes in {} ranges, analyzing them as zeros",
+            name,
+            unreadable,
+            input.unreadable.len()
+        );
+    }
+
+    Ok(input)
+}
+
+/// Returns the size of `file` if it is a regular file or a block device.
+fn seekable_size(file: &mut File) -> Result<Option<u64>> {
+    let file_type = file.metadata()?.file_type();
+    #[cfg(unix)]
+    let is_device = std::os::unix::fs::FileTypeExt::is_block_device(&file_type);
+    #[cfg(not(unix))]
+    let is_device = false;
+    if !file_type.is_file() && !is_device {
+        return Ok(None);
+    }
+
+    Ok(Some(file.seek(SeekFrom::End(0))?))
+}
+
+/// Reads `chunk` of `file` into `input`, whose data starts at `base`. If that
+/// fails, the chunk is read again sector by sector and the sectors that fail
+/// again are zero-filled. Returns true if the end of the file was reached.
+fn read_chunk_lossy(
+    name: &str,
+    file: &mut File,
+    chunk: Range<u64>,
+    base: u64,
+    input: &mut Input,
+) -> bool {
+    let chunk_len = chunk.end - chunk.start;
+    match read_file_range(file, chunk.clone()) {
+        Ok(chunk_data) => {
+            input.data.extend_from_slice(&chunk_data);
+            return (chunk_data.len() as u64) < chunk_len;
+        }
+        Err(e) => debug!(
+            "{}: could not read {:#x}..{:#x}, retrying by sector: {}",
+            name, chunk.start, chunk.end, e
+        ),
+    }
+
+    for start in chunk.clone().step_by(SECTOR_SIZE as usize) {
+        let sector = start..min(start + SECTOR_SIZE, chunk.end);
+        let sector_len = sector.end - sector.start;
+        match read_file_range(file, sector.clone()) {
+            Ok(sector_data) => {
+                input.data.extend_from_slice(&sector_data);
+                if (sector_data.len() as u64) < sector_len {
+                    return true;
+                }
+            }
+            Err(e) => {
+                debug!(
+                    "{}: could not read {:#x}..{:#x}: {}",
+                    name, sector.start, sector.end, e
+                );
+                let unreadable = (sector.start - base) as usize..(sector.end - base) as usize;
+                match input.unreadable.last_mut() {
+                    Some(last) if last.end == unreadable.start => last.end = unreadable.end,
+                    _ => input.unreadable.push(unreadable),
+                }
+                input.data.resize(input.data.len() + sector_len as usize, 0);
+            }
+        }
+    }
+
+    false
+}
+
+fn read_range(name: &str, range: Option<Range<u64>>) -> Result<Vec<u8>> {
+    if is_remote(name) {
+        #[cfg(feature = "s3")]
+        return read_object(name, range);
+        #[cfg(not(feature = "s3"))]
+        bail!("Built without S3 support, cannot read {}", name);
+    }
+
+    let Some(range) = range else {
+        return std::fs::read(name).with_context(|| format!("Could not open {}", name));
+    };
+    let mut file = File::open(name).with_context(|| format!("Could not open {}", name))?;
+
+    read_file_range(&mut file, range).with_context(|| format!("Could not read {}", name))
+}
+
+fn read_file_range(file: &mut File, range: Range<u64>) -> Result<Vec<u8>> {
+    file.seek(SeekFrom::Start(range.start))?;
+    let mut data = Vec::new();
+    file.take(range.end - range.start).read_to_end(&mut data)?;
+
+    Ok(data)
+}
+
+fn input_size(name: &str) -> Result<u64> {
+    if is_remote(name) {
+        #[cfg(feature = "s3")]
+        return object_size(name);
+        #[cfg(not(feature = "s3"))]
+        bail!("Built without S3 support, cannot read {}", name);
+    }
+
+    Ok(std::fs::metadata(name)
+        .with_context(|| format!("Could not open {}", name))?
+        .len())
+}
+
+/// Reads the input in chunks and sleeps in between to stay below `rate` bytes
+/// per second.
+fn read_throttled(name: &str, range: Option<Range<u64>>, rate: u64) -> Result<Vec<u8>> {
+    let range = match range {
+        Some(range) => range,
+        None => 0..input_size(name)?,
+    };
+    let mut file = if is_remote(name) {
+        None
+    } else {
+        Some(File::open(name).with_context(|| format!("Could not open {}", name))?)
+    };
+
+    let now = Instant::now();
+    let mut data = Vec::new();
+    for start in range.clone().step_by(CHUNK_SIZE as usize) {
+        let chunk = start..min(start + CHUNK_SIZE, range.end);
+        let chunk_len = chunk.end - chunk.start;
+        let chunk_data = match file.as_mut() {
+            Some(file) => {
+                read_file_range(file, chunk).with_context(|| format!("Could not read {}", name))?
+            }
+            None => read_range(name, Some(chunk))?,
+        };
+        let eof = (chunk_data.len() as u64) < chunk_len;
+        data.extend_from_slice(&chunk_data);
+
+        pace(now, data.len(), rate);
+        if eof {
+            break;
+        }
+    }
+    debug!(
+        "{}: read {} bytes in {}ms",
+        name,
+        data.len(),
+        now.elapsed().as_millis()
+    );
+
+    Ok(data)
+}
+
+/// Sleeps as long as reading `read` bytes since `start` is ahead of `rate`
+/// bytes per second.
+fn pace(start: Instant, read: usize, rate: u64) {
+    let due = Duration::from_secs_f64(read as f64 / rate as f64);
+    if let Some(ahead) = due.checked_sub(start.elapsed()) {
+        sleep(ahead);
+    }
+}
@@ -0,0 +1,225 @@
+/*
+    Copyright 2024 - Valentin Obst <coderec@vpao.io>
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+*/
+//! Interactive plots, see `--plot-format html`.
+//!
+//! Each plot is a standalone HTML file that needs no network access: the data
+//! of the plot is embedded as JSON and drawn as SVG by a short script, which
+//! zooms with the mouse wheel, pans by dragging, shows the offset, region,
+//! and divergences under the cursor, and hides arches whose legend entry is
+//! clicked. The regions plot has a lane per arch and the entropy curve, the
+//! divergence plots show the divergence of each arch in each window below a
+//! strip of the regions. No bytes of the file are embedded.
+
+use crate::plotting::arch_color;
+use crate::ProcessedDetectionResult;
+
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::io;
+use std::ops::Range;
+
+use plotters::style::Color;
+use serde::Serialize;
+
+const TEMPLATE: &str = include_str!("interactive.html");
+
+/// N-grams of a divergence plot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ngrams {
+    Bigrams,
+    Trigrams,
+}
+
+/// Content of an interactive plot.
+#[derive(Debug, Clone, Copy)]
+pub enum Chart<'a> {
+    /// The regions and the entropy in bits per byte by offset.
+    Regions { entropy: &'a [(usize, f64)] },
+    /// The divergences of each arch.
+    Divs(Ngrams),
+}
+
+#[derive(Debug, Serialize)]
+struct PlotData {
+    title: String,
+    size: usize,
+    base_address: u64,
+    width: u32,
+    height: u32,
+    arches: Vec<ArchStyle>,
+    /// Consecutive segments of the same arch: start, end, index into
+    /// `arches` or `None` if unknown, and the mean confidence.
+    regions: Vec<(usize, usize, Option<usize>, f64)>,
+    /// Analyzed windows, sorted by their start.
+    windows: Vec<WindowData>,
+    /// Divergence of each arch, an index into `arches`, in each window.
+    divs: Option<Vec<(usize, Vec<Option<f64>>)>>,
+    entropy: Option<Vec<(usize, f64)>>,
+}
+
+#[derive(Debug, Serialize)]
+struct ArchStyle {
+    name: String,
+    color: String,
+}
+
+/// Closest arch of a window by bigrams and trigrams.
+#[derive(Debug, Serialize)]
+struct WindowData {
+    start: usize,
+    end: usize,
+    bigrams: Option<(String, f64)>,
+    trigrams: Option<(String, f64)>,
+}
+
+/// Rounds divergences and confidences to keep the files small.
+fn round(value: f64) -> f64 {
+    (value * 1000.0).round() / 1000.0
+}
+
+fn plot_data(
+    title: &str,
+    det_res: &ProcessedDetectionResult,
+    file_len: usize,
+    base_address: u64,
+    size: (u32, u32),
+    chart: Chart,
+) -> PlotData {
+    // Corpus entries by their index, then pseudo-arches of the segments.
+    let mut names: Vec<&String> = det_res.idx_to_arch.values().collect();
+    names.sort_by_key(|arch| det_res.arch_to_idx[*arch]);
+    let pseudo: BTreeSet<&String> = det_res
+        .segments
+        .iter()
+        .filter_map(|segment| segment.arch.as_ref())
+        .filter(|arch| !det_res.arch_to_idx.contains_key(*arch))
+        .collect();
+    names.extend(pseudo);
+    let arch_idx: HashMap<&String, usize> = names
+        .iter()
+        .enumerate()
+        .map(|(idx, arch)| (*arch, idx))
+        .collect();
+    let arches = names
+        .iter()
+        .map(|arch| {
+            let (r, g, b) = arch_color(det_res, arch).rgb();
+            ArchStyle {
+                name: arch.to_string(),
+                color: format!("#{:02x}{:02x}{:02x}", r, g, b),
+            }
+        })
+        .collect();
+
+    let mut regions: Vec<(usize, usize, Option<usize>, f64)> = Vec::new();
+    for segment in det_res.segments.iter() {
+        let arch = segment.arch.as_ref().map(|arch| arch_idx[arch]);
+        let len = segment.range.len() as f64;
+        match regions.last_mut() {
+            Some((_, end, last, confidence)) if *last == arch && *end == segment.range.start => {
+                // Weighted by size, divided by the total size below.
+                *confidence += segment.confidence * len;
+                *end = segment.range.end;
+            }
+            _ => regions.push((
+                segment.range.start,
+                segment.range.end,
+                arch,
+                segment.confidence * len,
+            )),
+        }
+    }
+    for (start, end, _, confidence) in regions.iter_mut() {
+        *confidence = round(*confidence / (*end - *start) as f64);
+    }
+
+    let mut windows: Vec<&Range<usize>> = det_res.range_to_result_bg.keys().collect();
+    windows.sort_unstable_by_key(|window| window.start);
+    let divs = match chart {
+        Chart::Divs(ngrams) => Some(ngrams),
+        Chart::Regions { .. } => None,
+    }
+    .map(|ngrams| {
+        let kl_range_to_arch = match ngrams {
+            Ngrams::Bigrams => &det_res.kl_range_to_arch_bg,
+            Ngrams::Trigrams => &det_res.kl_range_to_arch_tg,
+        };
+        let mut divs: Vec<(usize, Vec<Option<f64>>)> = (0..det_res.arch_to_idx.len())
+            .map(|idx| (idx, vec![None; windows.len()]))
+            .collect();
+        for (window_idx, window) in windows.iter().enumerate() {
+            for (arch, div) in kl_range_to_arch.get(*window).into_iter().flatten() {
+                divs[arch_idx[arch]].1[window_idx] = div.is_finite().then(|| round(*div));
+            }
+        }
+
+        divs
+    });
+    let windows = windows
+        .into_iter()
+        .map(|window| {
+            let best = |res: &HashMap<Range<usize>, crate::RangeResult>| {
+                res.get(window)
+                    .map(|res| (res.arch.clone(), round(res.div)))
+            };
+            WindowData {
+                start: window.start,
+                end: window.end,
+                bigrams: best(&det_res.range_to_result_bg),
+                trigrams: best(&det_res.range_to_result_tg),
+            }
+        })
+        .collect();
+
+    PlotData {
+        title: title.to_owned(),
+        size: file_len,
+        base_address,
+        width: size.0,
+        height: size.1,
+        arches,
+        regions,
+        windows,
+        divs,
+        entropy: match chart {
+            Chart::Regions { entropy } => Some(
+                entropy
+                    .iter()
+                    .map(|(offset, bits)| (*offset, round(*bits)))
+                    .collect(),
+            ),
+            Chart::Divs(_) => None,
+        },
+    }
+}
+
+/// Writes the interactive plot titled `title` of `chart` of `det_res` to
+/// `path`.
+pub fn write_plot(
+    path: &str,
+    title: &str,
+    det_res: &ProcessedDetectionResult,
+    file_len: usize,
+    base_address: u64,
+    size: (u32, u32),
+    chart: Chart,
+) -> io::Result<()> {
+    let data = plot_data(title, det_res, file_len, base_address, size, chart);
+    // The data must not end the script element.
+    let json = serde_json::to_string(&data)?.replace("</", "<\\/");
+
+    fs::write(path, TEMPLATE.replace("/*DATA*/null", &json))
+}
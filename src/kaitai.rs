@@ -0,0 +1,113 @@
+/*
+    Copyright 2024 - Valentin Obst <coderec@vpao.io>
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+*/
+//! Kaitai Struct skeletons, see `--emit kaitai`.
+//!
+//! The definition splits the analyzed file into a sequence of the detected
+//! regions and the gaps between them. Each region is a byte block of a type
+//! per arch, so the definition is a starting point for a parser of an
+//! unknown firmware format. Regions that overlap earlier ones, e.g., of
+//! nested archive members, are instances at their offset instead.
+
+use crate::emit::{comment, quote, PlacedRegion};
+
+use std::collections::BTreeSet;
+use std::fmt::Write;
+
+/// Valid Kaitai identifier for `name` with `prefix`.
+fn identifier(prefix: &str, name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_lowercase()
+            } else {
+                '_'
+            }
+        })
+        .collect();
+
+    format!("{}_{}", prefix, name)
+}
+
+/// Returns the definition of the layout of `regions` of `file`.
+pub fn definition(file: &str, regions: &[PlacedRegion]) -> String {
+    let file_name = file.rsplit('/').next().unwrap();
+    let arches: BTreeSet<&str> = regions.iter().map(|region| region.arch).collect();
+
+    let mut seq = String::new();
+    let mut instances = String::new();
+    let mut end = 0;
+    for region in regions {
+        let id = format!("region_{:x}", region.offset);
+        let doc = quote(&format!(
+            "{}, confidence {:.2}",
+            region.arch, region.confidence
+        ));
+        if region.offset < end {
+            writeln!(instances, "  {}:", id).unwrap();
+            writeln!(instances, "    pos: {:#x}", region.offset).unwrap();
+            writeln!(instances, "    size: {:#x}", region.size).unwrap();
+            writeln!(instances, "    type: {}", identifier("code", region.arch)).unwrap();
+            writeln!(instances, "    doc: {}", doc).unwrap();
+            continue;
+        }
+        if region.offset > end {
+            writeln!(seq, "  - id: gap_{:x}", end).unwrap();
+            writeln!(seq, "    size: {:#x}", region.offset - end).unwrap();
+        }
+        writeln!(seq, "  - id: {}", id).unwrap();
+        writeln!(seq, "    size: {:#x}", region.size).unwrap();
+        writeln!(seq, "    type: {}", identifier("code", region.arch)).unwrap();
+        writeln!(seq, "    doc: {}", doc).unwrap();
+        end = region.offset + region.size as u64;
+    }
+    writeln!(seq, "  - id: gap_{:x}", end).unwrap();
+    writeln!(seq, "    size-eos: true").unwrap();
+
+    let mut definition = String::new();
+    writeln!(
+        definition,
+        "# Layout of {} detected by coderec {}.",
+        comment(file_name),
+        env!("CARGO_PKG_VERSION")
+    )
+    .unwrap();
+    writeln!(definition, "meta:").unwrap();
+    writeln!(definition, "  id: {}", identifier("firmware", file_name)).unwrap();
+    writeln!(definition, "seq:").unwrap();
+    definition.push_str(&seq);
+    if !instances.is_empty() {
+        writeln!(definition, "instances:").unwrap();
+        definition.push_str(&instances);
+    }
+    if !arches.is_empty() {
+        writeln!(definition, "types:").unwrap();
+    }
+    for arch in arches {
+        writeln!(definition, "  {}:", identifier("code", arch)).unwrap();
+        writeln!(
+            definition,
+            "    doc: {}",
+            quote(&format!("Machine code of {}", arch))
+        )
+        .unwrap();
+        writeln!(definition, "    seq:").unwrap();
+        writeln!(definition, "      - id: code").unwrap();
+        writeln!(definition, "        size-eos: true").unwrap();
+    }
+
+    definition
+}
@@ -15,46 +15,178 @@
 */
 // Includes (many) changes by Valentin Obst.
 
+mod android;
+mod archive;
+mod binwalk;
+mod bootstrap;
+mod bt;
+mod bundle;
+mod changepoint;
+mod common;
+mod concat;
+mod config;
+mod consolidate;
+mod container;
 mod corpus;
+mod dataset;
+mod db;
+#[cfg(feature = "f32")]
+mod dense;
+mod diff;
+mod dtb;
+mod emit;
+mod encoded;
+mod endian;
+mod entropy;
+mod extract;
+mod family;
+mod fdt;
+mod float;
+mod ghidra;
+mod hashset;
+mod hmm;
+mod http;
+mod ida;
+mod impact;
+mod import;
+mod input;
+mod interactive;
+mod kaitai;
+mod matrix;
+mod metric;
+#[cfg(feature = "kafka")]
+mod mq;
+mod nand;
+mod ngrams;
+mod numa;
 mod output;
+mod padding;
+mod partition;
+mod pipeline;
 mod plotting;
-
-use crate::corpus::{is_strict, load_corpus, CorpusStats};
-use crate::output::CliJsonOutput;
-
-use std::cmp::min;
-use std::collections::{BTreeMap, HashMap};
+mod policy;
+mod r2;
+mod records;
+mod refine;
+#[cfg(feature = "s3")]
+mod remote;
+mod rules;
+mod sarif;
+mod scorer;
+mod split;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+mod streams;
+mod swap;
+mod text;
+mod trend;
+mod tune;
+mod uboot;
+mod uefi;
+#[cfg(feature = "verify")]
+mod verify;
+mod yara;
+
+pub use crate::consolidate::Consolidation;
+pub use crate::container::Member;
+pub use crate::corpus::{
+    load_corpus, load_corpus_dir, load_corpus_entry, CorpusStats, Divergences,
+};
+#[cfg(feature = "f32")]
+pub use crate::dense::DenseCorpus;
+pub use crate::import::{parse_reports, read_reports};
+pub use crate::metric::{Metric, MetricKind};
+pub use crate::numa::NumaPools;
+pub use crate::output::CliJsonOutput;
+pub use crate::partition::Partition;
+pub use crate::pipeline::{Hooks, Pipeline, ProcessingOptions};
+
+use crate::archive::find_members;
+use crate::bootstrap::{bootstrap_entry, pick_sample, MIN_SAMPLE_SIZE};
+use crate::bundle::{Bundle, DiagnosticLog};
+use crate::changepoint::{segment, SegmentOptions};
+use crate::common::{find_common, CommonOptions, CommonReport};
+use crate::concat::read_concat;
+use crate::config::{
+    AppliedThresholds, Config, FilterConfig, PlotConfig, PolicyConfig, Profile, Thresholds,
+    WindowConfig,
+};
+use crate::container::{mode_hint, unpack, ContainerOptions};
+use crate::corpus::{merge_corpus_entry, train_corpus_entry};
+use crate::dataset::DatasetWriter;
+use crate::db::{ResultRecord, ResultsDb};
+use crate::diff::{diff_reports, DiffRegion};
+use crate::dtb::{find_dtbs, DTB};
+use crate::emit::{emit_script, ScriptFormat, ScriptInput};
+use crate::encoded::find_encoded;
+use crate::extract::{CasStore, RegionDir};
+use crate::family::Families;
+use crate::hashset::KnownHashes;
+use crate::hmm::{decode, Segmenter, WindowDivs};
+use crate::http::{parse_header, parse_url, HttpOptions};
+use crate::impact::corpus_impact;
+use crate::input::{is_remote, read_input, read_input_lossy, Input, InputOptions, UNREADABLE};
+use crate::matrix::CorpusMatrix;
+use crate::nand::{detect_layout, NandLayout};
+use crate::output::{ReportSink, SinkFormat, SinkOptions, SinkSpec};
+use crate::padding::{apply_runs, find_padding, is_within, PADDING};
+use crate::pipeline::diagnostic;
+use crate::plotting::{PlotFormat, PlotMetadata, PlotOptions};
+use crate::policy::DenyList;
+use crate::records::decode_records;
+use crate::rules::RuleSet;
+use crate::scorer::{DivergenceScorer, WindowScorer};
+use crate::streams::find_streams;
+use crate::swap::detect_swap;
+use crate::trend::Trend;
+use crate::tune::{tune, TuneOptions};
+
+use std::cell::RefCell;
+use std::cmp::{max, min};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::convert::From;
-use std::io;
+use std::ffi::{CStr, CString};
 use std::ops::Range;
-use std::ffi::{CStr,CString};
 use std::os::raw::c_char;
-use jni::JNIEnv;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+use anyhow::{bail, Context, Result};
+use clap::parser::ValueSource;
+use clap::{arg, Arg, ArgAction, ArgMatches};
 use jni::objects::{JClass, JString};
 use jni::sys::jstring;
-
-use anyhow::{Context, Result};
-use clap::{arg, Arg, ArgAction};
-use log::{debug, info};
+use jni::JNIEnv;
+use log::{debug, error, info, warn};
 use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct KlRes {
     arch: String,
     div: f64,
 }
 
+#[derive(Clone)]
 struct RangeFullKlRes {
     kl_bg: Vec<KlRes>,
     kl_tg: Vec<KlRes>,
 }
 
-fn calculate_kl(corpus_stats: &[CorpusStats], target: &CorpusStats) -> RangeFullKlRes {
+fn calculate_kl(
+    corpus_stats: &[CorpusStats],
+    target: &CorpusStats,
+    metric: MetricKind,
+) -> RangeFullKlRes {
     let mut kl_bg = Vec::<KlRes>::with_capacity(corpus_stats.len());
     let mut kl_tg = Vec::<KlRes>::with_capacity(corpus_stats.len());
 
     for arch_stats in corpus_stats {
-        let r = target.compute_kl(arch_stats);
+        let r = target.compute_divergence(arch_stats, metric.metric());
         kl_bg.push(KlRes {
             arch: arch_stats.arch.clone(),
             div: r.bigrams,
@@ -65,17 +197,54 @@ fn calculate_kl(corpus_stats: &[CorpusStats], target: &CorpusStats) -> RangeFull
         });
     }
 
-    // Sort
-    kl_bg.sort_unstable_by(|a, b| a.div.partial_cmp(&b.div).unwrap());
-    debug!("Results 2-gram: {:?}", &kl_bg[0..2]);
-    kl_tg.sort_unstable_by(|a, b| a.div.partial_cmp(&b.div).unwrap());
-    debug!("Results 3-gram: {:?}", &kl_tg[0..2]);
+    RangeFullKlRes::sorted(kl_bg, kl_tg)
+}
+
+impl RangeFullKlRes {
+    /// Sorts the divergences of each arch in ascending order.
+    fn sorted(mut kl_bg: Vec<KlRes>, mut kl_tg: Vec<KlRes>) -> Self {
+        kl_bg.sort_unstable_by(|a, b| a.div.partial_cmp(&b.div).unwrap());
+        debug!("Results 2-gram: {:?}", &kl_bg[0..2]);
+        kl_tg.sort_unstable_by(|a, b| a.div.partial_cmp(&b.div).unwrap());
+        debug!("Results 3-gram: {:?}", &kl_tg[0..2]);
+
+        Self { kl_bg, kl_tg }
+    }
+
+    /// Runs the heuristic that decides on the arch of the window.
+    fn decide(&self, thresholds: &Thresholds) -> Decision {
+        final_range_decision(
+            &best_range_result(&self.kl_bg),
+            &best_range_result(&self.kl_tg),
+            thresholds,
+        )
+    }
+}
+
+/// Runs the complete detection on a single window.
+fn classify_window(
+    corpus_stats: &[CorpusStats],
+    window: &[u8],
+    metric: MetricKind,
+    thresholds: &Thresholds,
+) -> Decision {
+    let win_stats = CorpusStats::new("target".to_string(), window, 0.0);
+
+    calculate_kl(corpus_stats, &win_stats, metric).decide(thresholds)
+}
+
+/// Summarizes the sorted divergences of a window.
+fn best_range_result(kl: &[KlRes]) -> RangeResult {
+    let divs: Vec<_> = kl.iter().map(|res| res.div).collect();
+    let mean = calculate_mean(&divs);
+    let var = calculate_variance(&divs, mean);
 
-    RangeFullKlRes { kl_bg, kl_tg }
+    (kl[0].arch.clone(), kl[0].div, mean, var).into()
 }
 
 struct ProcessedDetectionResult {
     pub win_sz: usize,
+    pub stride: usize,
     pub max_kl_bg: f64,
     pub min_kl_bg: f64,
     pub max_kl_tg: f64,
@@ -86,8 +255,58 @@ struct ProcessedDetectionResult {
     pub idx_to_arch: HashMap<usize, Arch>,
     pub kl_arch_to_range_bg: BTreeMap<Arch, Vec<(Range<usize>, f64)>>,
     pub kl_arch_to_range_tg: BTreeMap<Arch, Vec<(Range<usize>, f64)>>,
-    pub range_to_final_result: HashMap<Range<usize>, Option<Arch>>,
-    pub arch_to_final_ranges: HashMap<Arch, Vec<Range<usize>>>,
+    pub kl_range_to_arch_bg: HashMap<Range<usize>, Vec<(Arch, f64)>>,
+    pub kl_range_to_arch_tg: HashMap<Range<usize>, Vec<(Arch, f64)>>,
+    pub segments: Vec<Segment>,
+    pub arch_to_final_segments: HashMap<Arch, Vec<Segment>>,
+    pub trace: Option<Range<usize>>,
+    pub metric: MetricKind,
+    pub thresholds: Thresholds,
+    pub filters: FilterConfig,
+    pub consolidation: Consolidation,
+    pub min_region_length: Option<usize>,
+    /// Block size that regions are aligned to, see `--align`.
+    pub align: Option<usize>,
+}
+
+/// Non-overlapping part of the file with the verdict of a single window.
+#[derive(Debug, Clone)]
+pub struct Segment {
+    /// Bytes covered by this segment.
+    pub range: Range<usize>,
+    /// Window whose verdict is used for the segment.
+    pub window: Range<usize>,
+    pub arch: Option<Arch>,
+    /// Confidence of the window's verdict.
+    pub confidence: f64,
+}
+
+/// Splits the file into segments. As windows may overlap, each byte is
+/// attributed to the window whose center is closest to it.
+fn segments_from_windows(range_to_final_result: &HashMap<Range<usize>, Decision>) -> Vec<Segment> {
+    let mut windows: Vec<_> = range_to_final_result.iter().collect();
+    windows.sort_unstable_by_key(|(range, _)| range.start);
+
+    let center = |range: &Range<usize>| (range.start + range.end) / 2;
+    let mut segments = Vec::with_capacity(windows.len());
+    let mut start = 0;
+    for (idx, (window, decision)) in windows.iter().enumerate() {
+        let end = match windows.get(idx + 1) {
+            Some((next, _)) => (center(window) + center(next)) / 2,
+            None => window.end,
+        };
+        if end > start {
+            segments.push(Segment {
+                range: start..end,
+                window: (*window).clone(),
+                arch: decision.arch.clone(),
+                confidence: decision.confidence,
+            });
+            start = end;
+        }
+    }
+
+    segments
 }
 
 pub struct RangeResult {
@@ -97,8 +316,72 @@ pub struct RangeResult {
     range_var: f64,
 }
 
-/// Main heuristic that decides which arch is assigned to a range.
+/// Branch of the main heuristic that decided a range.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DecisionBranch {
+    /// Closest arch is too far away in absolute numbers.
+    TooFar,
+    /// Closest arch in trigrams clearly stands out.
+    InstantTrigrams,
+    /// Closest arch in bigrams clearly stands out.
+    InstantBigrams,
+    /// Bi- and trigrams agree and the divergence stands out.
+    Agreement,
+    /// Text detected via trigrams.
+    Text,
+    /// No rule matched.
+    NoMatch,
+    /// Viterbi decoding of the whole sequence of windows, see `hmm`.
+    Hmm,
+}
+
+/// Verdict of the main heuristic for a range.
+#[derive(Debug, Clone)]
+pub struct Decision {
+    pub arch: Option<Arch>,
+    /// Branch of the heuristic that made the decision.
+    pub branch: DecisionBranch,
+    /// How clearly the divergence of the arch stands out, between 0 and 1.
+    /// Detections that just pass the threshold of their branch have 0.5, the
+    /// value is 0 if nothing was detected.
+    pub confidence: f64,
+}
+
+impl Decision {
+    fn new(arch: Option<Arch>, branch: DecisionBranch, confidence: f64) -> Self {
+        Self {
+            arch,
+            branch,
+            confidence,
+        }
+    }
+}
+
+/// Maps the distance of `div` below `mean`, in standard deviations, to a
+/// value between 0 and 1. Being `threshold` standard deviations below the
+/// mean maps to 0.5.
+fn confidence(div: f64, mean: f64, std_deviation: f64, threshold: f64) -> f64 {
+    if std_deviation == 0.0 || threshold <= 0.0 {
+        return 1.0;
+    }
+    let z = (mean - div) / std_deviation;
+
+    (1.0 - 0.5f64.powf(z / threshold)).clamp(0.0, 1.0)
+}
+
+/// Main heuristic that decides which arch is assigned to a range, using the
+/// default thresholds.
 pub fn final_range_result(res_bg: &RangeResult, res_tg: &RangeResult) -> Option<Arch> {
+    final_range_decision(res_bg, res_tg, &Thresholds::default()).arch
+}
+
+/// Like `final_range_result`, but with custom thresholds and also returns how
+/// the decision was made.
+pub fn final_range_decision(
+    res_bg: &RangeResult,
+    res_tg: &RangeResult,
+    thresholds: &Thresholds,
+) -> Decision {
     let RangeResult {
         arch: arch_bg,
         div: div_bg,
@@ -114,51 +397,22 @@ pub fn final_range_result(res_bg: &RangeResult, res_tg: &RangeResult) -> Option<
     } = res_tg;
     let std_deviation_tg = var_tg.sqrt();
 
-    // Limits on the absolute divergence of the closest arch.
-    const MAX_ABS_DIV_BG: f64 = 5.0;
-    const MAX_ABS_DIV_TG: f64 = 6.0;
-    const MAX_ABS_DIV_STRICT_BG: f64 = 4.0;
-    const MAX_ABS_DIV_STRICT_TG: f64 = 5.0;
-
-    // Threshold for instant detection via standard deviation.
-    const INSTANT_STD_DEV_BG: f64 = 2.0;
-    const INSTANT_STD_DEV_TG: f64 = 2.0;
-    const INSTANT_STD_DEV_STRICT_BG: f64 = 2.5;
-    const INSTANT_STD_DEV_STRICT_TG: f64 = 2.5;
-
-    // Threshold for conditional detection via standard deviation.
-    const COMM_STD_DEV_BG: f64 = 1.0;
-    const COMM_STD_DEV_TG: f64 = 1.0;
-    const COMM_STD_DEV_STRICT_BG: f64 = 1.5;
-    const COMM_STD_DEV_STRICT_TG: f64 = 1.5;
-
-    let (max_abs_div_bg, instant_std_dev_bg, comm_std_dev_bg): (f64, f64, f64) =
-        if is_strict(arch_bg) {
-            (
-                MAX_ABS_DIV_STRICT_BG,
-                INSTANT_STD_DEV_STRICT_BG,
-                COMM_STD_DEV_STRICT_BG,
-            )
-        } else {
-            (MAX_ABS_DIV_BG, INSTANT_STD_DEV_BG, COMM_STD_DEV_BG)
-        };
-    let (max_abs_div_tg, instant_std_dev_tg, comm_std_dev_tg): (f64, f64, f64) =
-        if is_strict(arch_tg) {
-            (
-                MAX_ABS_DIV_STRICT_TG,
-                INSTANT_STD_DEV_STRICT_TG,
-                COMM_STD_DEV_STRICT_TG,
-            )
-        } else {
-            (MAX_ABS_DIV_TG, INSTANT_STD_DEV_TG, COMM_STD_DEV_TG)
-        };
+    let AppliedThresholds {
+        max_abs_div_bg,
+        max_abs_div_tg,
+        instant_std_dev_bg,
+        instant_std_dev_tg,
+        comm_std_dev_bg,
+        comm_std_dev_tg,
+        text_std_dev_tg,
+    } = thresholds.applied(arch_bg, arch_tg);
 
     #[allow(clippy::if_same_then_else)]
     // Detect nothing if the closest arch is too far away in absolute numbers.
     if div_bg.partial_cmp(&max_abs_div_bg).unwrap() == core::cmp::Ordering::Greater
         && div_tg.partial_cmp(&max_abs_div_tg).unwrap() == core::cmp::Ordering::Greater
     {
-        None
+        Decision::new(None, DecisionBranch::TooFar, 0.0)
     // Instant detection if an arch is clearly the best in either tri- or
     // bigrams. Test trigrams first as they seem to be somewhat better.
     } else if div_tg
@@ -166,13 +420,21 @@ pub fn final_range_result(res_bg: &RangeResult, res_tg: &RangeResult) -> Option<
         .unwrap()
         == core::cmp::Ordering::Less
     {
-        Some(arch_tg.clone())
+        Decision::new(
+            Some(arch_tg.clone()),
+            DecisionBranch::InstantTrigrams,
+            confidence(*div_tg, *mean_tg, std_deviation_tg, instant_std_dev_tg),
+        )
     } else if div_bg
         .partial_cmp(&(mean_bg - instant_std_dev_bg * std_deviation_bg))
         .unwrap()
         == core::cmp::Ordering::Less
     {
-        Some(arch_bg.clone())
+        Decision::new(
+            Some(arch_bg.clone()),
+            DecisionBranch::InstantBigrams,
+            confidence(*div_bg, *mean_bg, std_deviation_bg, instant_std_dev_bg),
+        )
     // Main heuristic: Bi- and trigrams agree and the divergence stands out from
     // the others.
     } else if div_bg
@@ -185,17 +447,28 @@ pub fn final_range_result(res_bg: &RangeResult, res_tg: &RangeResult) -> Option<
             == core::cmp::Ordering::Less
         && arch_tg == arch_bg
     {
-        Some(arch_tg.clone())
+        Decision::new(
+            Some(arch_tg.clone()),
+            DecisionBranch::Agreement,
+            f64::min(
+                confidence(*div_bg, *mean_bg, std_deviation_bg, comm_std_dev_bg),
+                confidence(*div_tg, *mean_tg, std_deviation_tg, comm_std_dev_tg),
+            ),
+        )
     // Special case for detection of text via trigrams.
     } else if div_tg
-        .partial_cmp(&(mean_tg - 1.0 * std_deviation_tg))
+        .partial_cmp(&(mean_tg - text_std_dev_tg * std_deviation_tg))
         .unwrap()
         == core::cmp::Ordering::Less
         && arch_tg.starts_with("_words")
     {
-        Some(arch_tg.clone())
+        Decision::new(
+            Some(arch_tg.clone()),
+            DecisionBranch::Text,
+            confidence(*div_tg, *mean_tg, std_deviation_tg, text_std_dev_tg),
+        )
     } else {
-        None
+        Decision::new(None, DecisionBranch::NoMatch, 0.0)
     }
 }
 
@@ -221,7 +494,7 @@ pub fn calculate_variance(data: &[f64], mean: f64) -> f64 {
 impl From<DetectionResult> for ProcessedDetectionResult {
     fn from(res_ex: DetectionResult) -> Self {
         // Size of a range.
-        let win_sz = res_ex.kl_bg_range_to_arch.keys().next().unwrap().len();
+        let win_sz = res_ex.win_sz;
 
         // Numbering of arches.
         let mut arch_to_idx: HashMap<Arch, usize> = HashMap::new();
@@ -238,22 +511,24 @@ impl From<DetectionResult> for ProcessedDetectionResult {
             .flat_map(|arch| arch.iter().map(|(_, div)| *div))
             .collect();
         all_divs_bg.sort_unstable_by(|a, b| a.partial_cmp(b).unwrap());
-        let max_kl_bg = *all_divs_bg.last().unwrap();
-        let min_kl_bg = *all_divs_bg
+        let max_kl_bg = all_divs_bg.last().copied().unwrap_or_default();
+        let min_kl_bg = all_divs_bg
             .iter()
-            .find(|div| (*div).partial_cmp(&0.1).unwrap() != core::cmp::Ordering::Less)
-            .unwrap();
+            .copied()
+            .find(|div| div.partial_cmp(&0.1).unwrap() != core::cmp::Ordering::Less)
+            .unwrap_or(max_kl_bg);
         let mut all_divs_tg: Vec<f64> = res_ex
             .kl_tg_arch_to_range
             .values()
             .flat_map(|arch| arch.iter().map(|(_, div)| *div))
             .collect();
         all_divs_tg.sort_unstable_by(|a, b| a.partial_cmp(b).unwrap());
-        let max_kl_tg = *all_divs_tg.last().unwrap();
-        let min_kl_tg = *all_divs_tg
+        let max_kl_tg = all_divs_tg.last().copied().unwrap_or_default();
+        let min_kl_tg = all_divs_tg
             .iter()
-            .find(|div| (*div).partial_cmp(&0.1).unwrap() != core::cmp::Ordering::Less)
-            .unwrap();
+            .copied()
+            .find(|div| div.partial_cmp(&0.1).unwrap() != core::cmp::Ordering::Less)
+            .unwrap_or(max_kl_tg);
 
         // Per-range min (with arch), mean, and variance.
         let range_to_result_bg: HashMap<Range<usize>, RangeResult> = res_ex
@@ -294,27 +569,49 @@ impl From<DetectionResult> for ProcessedDetectionResult {
             .collect();
 
         // Our final verdict.
-        let range_to_final_result: HashMap<Range<usize>, Option<String>> = range_to_result_bg
-            .iter()
-            .map(|(range, res_bg)| {
-                let res_tg = range_to_result_tg.get(range).unwrap();
-
-                (range.clone(), final_range_result(res_bg, res_tg))
-            })
-            .collect();
-
-        let mut arch_to_final_ranges: HashMap<Arch, Vec<Range<usize>>> = HashMap::new();
-        for (range, arch_op) in range_to_final_result.iter() {
-            if let Some(arch) = arch_op {
-                arch_to_final_ranges
-                    .entry(arch.clone())
-                    .and_modify(|ranges| ranges.push(range.clone()))
-                    .or_insert(vec![range.clone()]);
+        let range_to_final_result: HashMap<Range<usize>, Decision> = match res_ex.segmenter {
+            Segmenter::Window => Self::decide_windows(
+                &range_to_result_bg,
+                &range_to_result_tg,
+                &res_ex.thresholds,
+                &res_ex.trace,
+            ),
+            Segmenter::Hmm => {
+                let windows = range_to_result_bg
+                    .iter()
+                    .map(|(range, res_bg)| {
+                        (
+                            range.clone(),
+                            WindowDivs {
+                                bg: &res_ex.kl_bg_range_to_arch[range],
+                                tg: &res_ex.kl_tg_range_to_arch[range],
+                                res_bg,
+                                res_tg: &range_to_result_tg[range],
+                            },
+                        )
+                    })
+                    .collect();
+                let decisions = decode(&windows, &res_ex.thresholds);
+                for (range, decision) in decisions.iter() {
+                    if is_traced(&res_ex.trace, range) {
+                        info!(
+                            target: TRACE_TARGET,
+                            "{:x?}: decided {:?} via {:?}, confidence {}",
+                            range,
+                            decision.arch,
+                            decision.branch,
+                            decision.confidence
+                        );
+                    }
+                }
+
+                decisions
             }
-        }
+        };
 
-        Self {
+        let mut res = Self {
             win_sz,
+            stride: res_ex.stride,
             arch_to_idx,
             idx_to_arch,
             max_kl_bg,
@@ -325,18 +622,251 @@ impl From<DetectionResult> for ProcessedDetectionResult {
             range_to_result_tg,
             kl_arch_to_range_bg: res_ex.kl_bg_arch_to_range,
             kl_arch_to_range_tg: res_ex.kl_tg_arch_to_range,
-            range_to_final_result,
-            arch_to_final_ranges,
+            kl_range_to_arch_bg: res_ex.kl_bg_range_to_arch,
+            kl_range_to_arch_tg: res_ex.kl_tg_range_to_arch,
+            segments: Vec::new(),
+            arch_to_final_segments: HashMap::new(),
+            trace: res_ex.trace,
+            metric: res_ex.metric,
+            thresholds: res_ex.thresholds,
+            filters: res_ex.filters,
+            consolidation: res_ex.consolidation,
+            min_region_length: res_ex.min_region_length,
+            align: res_ex.align,
+        };
+        let segments = apply_runs(
+            segments_from_windows(&range_to_final_result),
+            &res_ex.padding,
+            PADDING,
+        );
+        let segments = apply_runs(segments, &res_ex.dtbs, DTB);
+        res.set_segments(apply_runs(segments, &res_ex.unreadable, UNREADABLE));
+
+        res
+    }
+}
+
+impl ProcessedDetectionResult {
+    /// Decides on each window independently with the main heuristic.
+    fn decide_windows(
+        range_to_result_bg: &HashMap<Range<usize>, RangeResult>,
+        range_to_result_tg: &HashMap<Range<usize>, RangeResult>,
+        thresholds: &Thresholds,
+        trace: &Option<Range<usize>>,
+    ) -> HashMap<Range<usize>, Decision> {
+        range_to_result_bg
+            .iter()
+            .map(|(range, res_bg)| {
+                let res_tg = range_to_result_tg.get(range).unwrap();
+
+                let decision = final_range_decision(res_bg, res_tg, thresholds);
+
+                if is_traced(trace, range) {
+                    info!(
+                        target: TRACE_TARGET,
+                        "{:x?}: bigrams best {} ({}), mean {}, var {}",
+                        range,
+                        res_bg.arch,
+                        res_bg.div,
+                        res_bg.range_mean,
+                        res_bg.range_var
+                    );
+                    info!(
+                        target: TRACE_TARGET,
+                        "{:x?}: trigrams best {} ({}), mean {}, var {}",
+                        range,
+                        res_tg.arch,
+                        res_tg.div,
+                        res_tg.range_mean,
+                        res_tg.range_var
+                    );
+                    info!(
+                        target: TRACE_TARGET,
+                        "{:x?}: decided {:?} via {:?}, confidence {}",
+                        range,
+                        decision.arch,
+                        decision.branch,
+                        decision.confidence
+                    );
+                }
+
+                (range.clone(), decision)
+            })
+            .collect()
+    }
+
+    /// Bi- and trigram divergences of each arch, averaged over the windows of
+    /// `segments` and weighted by the size of each segment.
+    pub fn segment_divs<'a>(
+        &self,
+        segments: impl IntoIterator<Item = &'a Segment>,
+    ) -> HashMap<Arch, (f64, f64)> {
+        let mut sums: HashMap<Arch, (f64, f64)> = HashMap::new();
+        let mut total = 0;
+        for segment in segments {
+            // Windows of padding were not analyzed.
+            let (Some(divs_bg), Some(divs_tg)) = (
+                self.kl_range_to_arch_bg.get(&segment.window),
+                self.kl_range_to_arch_tg.get(&segment.window),
+            ) else {
+                continue;
+            };
+            let weight = segment.range.len() as f64;
+            total += segment.range.len();
+            for (arch, div) in divs_bg.iter() {
+                sums.entry(arch.clone()).or_default().0 += div * weight;
+            }
+            for (arch, div) in divs_tg.iter() {
+                sums.entry(arch.clone()).or_default().1 += div * weight;
+            }
+        }
+
+        sums.into_iter()
+            .map(|(arch, (bg, tg))| (arch, (bg / total as f64, tg / total as f64)))
+            .collect()
+    }
+
+    /// Segments that lie within `range`.
+    pub fn segments_in(&self, range: &Range<usize>) -> &[Segment] {
+        let start = self
+            .segments
+            .partition_point(|segment| segment.range.start < range.start);
+        let end = self
+            .segments
+            .partition_point(|segment| segment.range.end <= range.end);
+
+        &self.segments[start..end.max(start)]
+    }
+
+    /// Replaces the final verdicts, empty segments are dropped.
+    pub fn set_segments(&mut self, mut segments: Vec<Segment>) {
+        segments.retain(|segment| !segment.range.is_empty());
+        self.bridge_gaps(&mut segments);
+        self.drop_small_regions(&mut segments);
+
+        self.arch_to_final_segments.clear();
+        for segment in segments.iter() {
+            if let Some(arch) = &segment.arch {
+                self.arch_to_final_segments
+                    .entry(arch.clone())
+                    .or_default()
+                    .push(segment.clone());
+            }
+        }
+        self.segments = segments;
+    }
+
+    /// Attributes runs of unknown segments of at most `max_gap` bytes to the
+    /// arch of the regions on both sides, if they agree.
+    fn bridge_gaps(&self, segments: &mut [Segment]) {
+        let Some(max_gap) = self.filters.max_gap else {
+            return;
+        };
+
+        let mut start = 0;
+        while start < segments.len() {
+            let end = start
+                + segments[start..]
+                    .iter()
+                    .take_while(|segment| segment.arch.is_none())
+                    .count();
+            if end == start {
+                start += 1;
+                continue;
+            }
+
+            if start > 0 && end < segments.len() && segments[start - 1].arch == segments[end].arch {
+                let gap = segments[start].range.start..segments[end - 1].range.end;
+                if gap.len() <= max_gap {
+                    if segments[start..end]
+                        .iter()
+                        .any(|segment| is_traced(&self.trace, &segment.window))
+                    {
+                        info!(
+                            target: TRACE_TARGET,
+                            "{:x?}: bridged gap between regions of {:?}",
+                            gap,
+                            segments[end].arch
+                        );
+                    }
+                    let arch = segments[end].arch.clone();
+                    let confidence = segments[start - 1].confidence.min(segments[end].confidence);
+                    for segment in segments[start..end].iter_mut() {
+                        segment.arch = arch.clone();
+                        segment.confidence = confidence;
+                    }
+                }
+            }
+
+            start = end;
+        }
+    }
+
+    /// Regions that are smaller than the minimum region size of their arch
+    /// become unknown.
+    fn drop_small_regions(&self, segments: &mut [Segment]) {
+        let mut start = 0;
+        while start < segments.len() {
+            let arch_op = segments[start].arch.clone();
+            let end = start
+                + segments[start..]
+                    .iter()
+                    .take_while(|segment| segment.arch == arch_op)
+                    .count();
+
+            if let Some(arch) = arch_op {
+                let region = segments[start].range.start..segments[end - 1].range.end;
+                let min_size = max(
+                    self.thresholds.min_region_size(&arch),
+                    self.filters.min_region_windows.unwrap_or(0) * self.stride,
+                );
+                if region.len() < min_size {
+                    if segments[start..end]
+                        .iter()
+                        .any(|segment| is_traced(&self.trace, &segment.window))
+                    {
+                        info!(
+                            target: TRACE_TARGET,
+                            "{:x?}: dropped region of {}, smaller than minimum size",
+                            region,
+                            arch
+                        );
+                    }
+                    for segment in segments[start..end].iter_mut() {
+                        segment.arch = None;
+                        segment.confidence = 0.0;
+                    }
+                }
+            }
+
+            start = end;
         }
     }
 }
 
 type Arch = String;
+#[derive(Clone)]
 struct DetectionResult {
     pub kl_bg_arch_to_range: BTreeMap<Arch, Vec<(Range<usize>, f64)>>,
     pub kl_tg_arch_to_range: BTreeMap<Arch, Vec<(Range<usize>, f64)>>,
     pub kl_bg_range_to_arch: HashMap<Range<usize>, Vec<(Arch, f64)>>,
     pub kl_tg_range_to_arch: HashMap<Range<usize>, Vec<(Arch, f64)>>,
+    pub win_sz: usize,
+    pub stride: usize,
+    pub trace: Option<Range<usize>>,
+    pub metric: MetricKind,
+    pub thresholds: Thresholds,
+    pub filters: FilterConfig,
+    pub segmenter: Segmenter,
+    pub consolidation: Consolidation,
+    pub min_region_length: Option<usize>,
+    pub align: Option<usize>,
+    /// Padding runs, windows within them were not analyzed.
+    pub padding: Vec<Range<usize>>,
+    /// Device tree blobs, windows within them were not analyzed.
+    pub dtbs: Vec<Range<usize>>,
+    /// Ranges that could not be read, windows within them were not analyzed.
+    pub unreadable: Vec<Range<usize>>,
 }
 
 impl<I: ParallelIterator<Item = (Range<usize>, RangeFullKlRes)>> From<I> for DetectionResult {
@@ -346,11 +876,24 @@ impl<I: ParallelIterator<Item = (Range<usize>, RangeFullKlRes)>> From<I> for Det
             kl_tg_arch_to_range: BTreeMap::new(),
             kl_bg_range_to_arch: HashMap::new(),
             kl_tg_range_to_arch: HashMap::new(),
+            win_sz: 0,
+            stride: 0,
+            trace: None,
+            metric: MetricKind::default(),
+            thresholds: Thresholds::default(),
+            filters: FilterConfig::default(),
+            segmenter: Segmenter::default(),
+            consolidation: Consolidation::default(),
+            min_region_length: None,
+            align: None,
+            padding: Vec::new(),
+            dtbs: Vec::new(),
+            unreadable: Vec::new(),
         };
         let res: Vec<_> = i.collect();
 
         for (range, RangeFullKlRes { kl_bg, kl_tg }) in res {
-            for (kl_bg_arch, kl_tg_arch) in kl_bg.into_iter().zip(kl_tg.into_iter()) {
+            for (kl_bg_arch, kl_tg_arch) in kl_bg.into_iter().zip(kl_tg) {
                 res_ex
                     .kl_bg_arch_to_range
                     .entry(kl_bg_arch.arch.clone())
@@ -378,179 +921,2683 @@ impl<I: ParallelIterator<Item = (Range<usize>, RangeFullKlRes)>> From<I> for Det
     }
 }
 
-fn detect_code(corpus_stats: &[CorpusStats], file_data: &[u8], filename: &str) -> DetectionResult {
-    // Heuristic depending on file size, the number is actually half the window
-    // size.
-    let window = match file_data.len() {
-        0x100001..=0x1000000 => 0x1000, // 257 - 4096, 1MiB - 16MiB
-        0x20001..=0x100000 => 0x800,    // 65 - 512, 128KiB - 1MiB
-        0x8001..=0x20000 => 0x400,      // 33 - 128, 32KiB - 128KiB
-        0x1001..=0x8000 => 0x200,       // 9 - 64, 4KiB - 32KiB
-        0..=0x1000 => 0x100,            // 1 - 16, 0B - 4KiB
+/// Log target used for the output of `--trace`.
+const TRACE_TARGET: &str = "coderec::trace";
+
+/// Returns true if `range` overlaps the traced range.
+fn is_traced(trace: &Option<Range<usize>>, range: &Range<usize>) -> bool {
+    trace
+        .as_ref()
+        .is_some_and(|trace| trace.start < range.end && range.start < trace.end)
+}
+
+/// Parameters that influence the detection.
+#[derive(Debug, Clone, Default)]
+pub struct DetectionOptions {
+    /// Log all intermediate values for windows that overlap this range.
+    pub trace: Option<Range<usize>>,
+    /// Window size to use instead of the file size heuristic.
+    pub window_size: Option<usize>,
+    /// Shrink windows such that the file is split into at least this many
+    /// windows.
+    pub min_windows: Option<usize>,
+    /// Distance between the starts of consecutive windows, defaults to half
+    /// the window size.
+    pub stride: Option<usize>,
+    /// Distance measure between the window and the corpus entries.
+    pub metric: MetricKind,
+    /// Thresholds of the heuristic that decides on the arch of a window.
+    pub thresholds: Thresholds,
+    /// Post-processing of the segments.
+    pub filters: FilterConfig,
+    /// How the verdicts of the windows are made.
+    pub segmenter: Segmenter,
+    /// How the segments are merged into the regions of the report.
+    pub consolidation: Consolidation,
+    /// Minimum length of a region for `Consolidation::MinLength`, defaults to
+    /// the window size.
+    pub min_region_length: Option<usize>,
+    /// Size of the blocks that windows never cross and regions are only
+    /// merged across if their confidences agree.
+    pub align: Option<usize>,
+    /// Skip windows within runs of fill patterns of at least this size and
+    /// report the runs as padding.
+    pub min_padding: Option<usize>,
+    /// Skip windows within device tree blobs and report the blobs as dtb.
+    pub detect_dtb: bool,
+    /// Analyze the windows on one thread pool per NUMA node instead of the
+    /// default pool.
+    pub numa: Option<Arc<NumaPools>>,
+    /// Scores the windows instead of the divergences under `metric`, e.g.,
+    /// from dense `f32` tables.
+    pub(crate) scorer: Option<Arc<dyn WindowScorer>>,
+}
+
+/// Smallest half window size that is used when shrinking windows.
+const MIN_HALF_WINDOW: usize = 0x10;
+/// Largest half window size, the end of a window that starts within a slice,
+/// which is at most `isize::MAX` bytes long, does not overflow.
+const MAX_HALF_WINDOW: usize = usize::MAX / 4;
+/// Largest half window size of the fixed tiers of the file size heuristic.
+const MAX_TIER_HALF_WINDOW: usize = 0x1000;
+/// Files beyond the fixed tiers of the file size heuristic are split into at
+/// least this many windows.
+const MIN_WINDOWS: usize = 2000;
+
+/// Heuristic for the half window size of a file of `len` bytes. It never
+/// decreases with the file size, is at least `0x100`, and, beyond the fixed
+/// tiers, at most the size of `MIN_WINDOWS` windows.
+fn default_half_window(len: usize) -> usize {
+    match len {
+        0x100001..=0x1000000 => MAX_TIER_HALF_WINDOW, // 257 - 4096, 1MiB - 16MiB
+        0x20001..=0x100000 => 0x800,                  // 65 - 512, 128KiB - 1MiB
+        0x8001..=0x20000 => 0x400,                    // 33 - 128, 32KiB - 128KiB
+        0x1001..=0x8000 => 0x200,                     // 9 - 64, 4KiB - 32KiB
+        0..=0x1000 => 0x100,                          // 1 - 16, 0B - 4KiB
         // From here on we grow the number of windows logarithmically in the
-        // file size. Constant factor ensures smooth transition.
-        l => (l / (170 * ((l as f64).log2() as usize))) & 0xFFFFF000,
-    };
+        // file size. Constant factor ensures smooth transition. Unlike the
+        // integral part of the logarithm, `l / log2(l)` is increasing, and
+        // the rounding must not clear high bits of large sizes. The upper bound
+        // is explicit rather than left to the saturating conversion from
+        // `f64`, and as a minimum of two non-decreasing functions keeps the
+        // heuristic non-decreasing.
+        l => {
+            let half_window = max(
+                (l as f64 / (170.0 * (l as f64).log2())) as usize & !0xfff,
+                MAX_TIER_HALF_WINDOW,
+            );
+            min(half_window, l / (2 * MIN_WINDOWS))
+        }
+    }
+}
 
-    info!("{}: window_size : 0x{:x} ", filename, window * 2);
+fn detect_code(
+    corpus_stats: &[CorpusStats],
+    file_data: &[u8],
+    unreadable: &[Range<usize>],
+    filename: &str,
+    options: &DetectionOptions,
+    hooks: &[Box<dyn Hooks>],
+) -> DetectionResult {
+    // The number is actually half the window size. Options that did not pass
+    // the validation of the command line or config must not lead to empty
+    // windows or strides.
+    let mut window = default_half_window(file_data.len());
+    if let Some(window_size) = options.window_size {
+        window = (window_size / 2).clamp(MIN_HALF_WINDOW, MAX_HALF_WINDOW);
+    }
+    if let Some(min_windows) = options.min_windows {
+        window = min(
+            window,
+            max(file_data.len() / max(min_windows, 1), MIN_HALF_WINDOW),
+        );
+    }
 
-    let res_ex: DetectionResult = (0..file_data.len())
-        .into_par_iter()
-        .step_by(window)
-        .map(|start| {
-            let end = min(file_data.len(), start + window * 2);
+    // Windows and strides that are powers of two no larger than the block
+    // fit the blocks without crossing them. Empty blocks are no blocks.
+    let align = options.align.filter(|align| *align > 0);
+    if let Some(align) = align {
+        let aligned = max(min(1 << (window * 2).ilog2(), align) / 2, MIN_HALF_WINDOW);
+        if aligned != window {
+            diagnostic(
+                hooks,
+                filename,
+                log::Level::Info,
+                &format!(
+                    "window size 0x{:x} does not fit blocks of 0x{:x}, using 0x{:x}",
+                    window * 2,
+                    align,
+                    aligned * 2
+                ),
+            );
+            window = aligned;
+        }
+    }
 
-            let win_stats = CorpusStats::new("target".to_string(), &file_data[start..end], 0.0);
+    let stride = match options.stride {
+        Some(stride) if stride > window * 2 => {
+            diagnostic(
+                hooks,
+                filename,
+                log::Level::Warn,
+                &format!(
+                    "stride 0x{:x} is larger than the window, using 0x{:x}",
+                    stride,
+                    window * 2
+                ),
+            );
+            window * 2
+        }
+        Some(0) | None => window,
+        Some(stride) => stride,
+    };
+    let stride = match align {
+        Some(_) => 1 << stride.ilog2(),
+        None => stride,
+    };
 
-            let range_res = calculate_kl(corpus_stats, &win_stats);
+    info!(
+        "{}: window_size : 0x{:x}, stride : 0x{:x}",
+        filename,
+        window * 2,
+        stride
+    );
 
-            (start..end, range_res)
+    let padding = match options.min_padding {
+        Some(min_padding) => find_padding(file_data, min_padding),
+        None => Vec::new(),
+    };
+    let dtbs = if options.detect_dtb {
+        find_dtbs(file_data)
+    } else {
+        Vec::new()
+    };
+    let mut starts: Vec<usize> = (0..file_data.len())
+        .step_by(stride)
+        .filter(|start| {
+            let window = *start..min(file_data.len(), start + window * 2);
+            !is_within(&padding, &window)
+                && !is_within(&dtbs, &window)
+                && !is_within(unreadable, &window)
+                && align.is_none_or(|align| window.start / align == (window.end - 1) / align)
         })
-        .into();
+        .collect();
+    // Analyze at least one window such that the statistics are defined.
+    if starts.is_empty() && !file_data.is_empty() {
+        starts.push(0);
+    }
+    debug!(
+        "{}: {} padding runs, {} device tree blobs, skipped {} windows",
+        filename,
+        padding.len(),
+        dtbs.len(),
+        file_data.len().div_ceil(stride) - starts.len()
+    );
+
+    // Byte-identical windows, e.g., of padding or repeated resources, have the
+    // same divergences, so only the first of each is scored. Traced windows
+    // are always scored such that they are logged.
+    let (unique, representatives) = dedup_windows(file_data, &starts, window * 2, |range| {
+        is_traced(&options.trace, range)
+    });
+    debug!(
+        "{}: scoring {} unique of {} windows",
+        filename,
+        unique.len(),
+        starts.len()
+    );
+
+    let default_scorer = DivergenceScorer {
+        metric: options.metric,
+    };
+    let scorer = options.scorer.as_deref().unwrap_or(&default_scorer);
+    debug!("{}: scoring windows by {}", filename, scorer.name());
+
+    let total = unique.len();
+    let done = AtomicUsize::new(0);
+    let analyze_window = |corpus_stats: &[CorpusStats], start: usize| {
+        let end = min(file_data.len(), start + window * 2);
+
+        let win_stats = CorpusStats::new("target".to_string(), &file_data[start..end], 0.0);
+
+        let range_res = scorer.score(corpus_stats, &win_stats);
+
+        if is_traced(&options.trace, &(start..end)) {
+            info!(
+                target: TRACE_TARGET,
+                "{:x?}: {} ungrams, {} bigrams, {} trigrams",
+                start..end,
+                win_stats.ungrams_freq.len(),
+                win_stats.bigrams_freq.len(),
+                win_stats.trigrams_freq.len()
+            );
+            for (bg, tg) in range_res.kl_bg.iter().zip(range_res.kl_tg.iter()) {
+                info!(
+                    target: TRACE_TARGET,
+                    "{:x?}: bigrams {} {}, trigrams {} {}",
+                    start..end,
+                    bg.arch,
+                    bg.div,
+                    tg.arch,
+                    tg.div
+                );
+            }
+        }
+
+        let done = done.fetch_add(1, Ordering::Relaxed) + 1;
+        for hooks in hooks {
+            hooks.on_progress(filename, done, total);
+        }
+
+        (start..end, range_res)
+    };
+    let scored: Vec<(Range<usize>, RangeFullKlRes)> = match options.numa.as_deref() {
+        Some(pools) => pools.map(unique, analyze_window),
+        None => unique
+            .into_par_iter()
+            .map(|start| analyze_window(corpus_stats, start))
+            .collect(),
+    };
+    let windows: Vec<(Range<usize>, RangeFullKlRes)> = if scored.len() == starts.len() {
+        scored
+    } else {
+        starts
+            .par_iter()
+            .zip(representatives)
+            .map(|(start, representative)| {
+                let end = min(file_data.len(), start + window * 2);
+                (*start..end, scored[representative].1.clone())
+            })
+            .collect()
+    };
+    let mut res_ex: DetectionResult = windows.into_par_iter().into();
+    res_ex.win_sz = window * 2;
+    res_ex.stride = stride;
+    res_ex.trace = options.trace.clone();
+    res_ex.metric = options.metric;
+    res_ex.thresholds = options.thresholds.clone();
+    res_ex.filters = options.filters.clone();
+    res_ex.segmenter = options.segmenter;
+    res_ex.consolidation = options.consolidation;
+    res_ex.min_region_length = options.min_region_length;
+    res_ex.align = align;
+    res_ex.padding = padding;
+    res_ex.dtbs = dtbs;
+    res_ex.unreadable = unreadable.to_vec();
 
     res_ex
 }
 
+/// Groups the windows of `size` bytes at `starts` by their content. Returns
+/// the starts of the windows with distinct content and, for each window, the
+/// index of the one with the same content among them. Windows for which
+/// `keep` is true are never merged.
+fn dedup_windows(
+    data: &[u8],
+    starts: &[usize],
+    size: usize,
+    keep: impl Fn(&Range<usize>) -> bool,
+) -> (Vec<usize>, Vec<usize>) {
+    let mut unique = Vec::new();
+    let mut representatives = Vec::with_capacity(starts.len());
+    let mut seen: HashMap<&[u8], usize> = HashMap::new();
+    for start in starts {
+        let range = *start..min(data.len(), start + size);
+        let representative = if keep(&range) {
+            unique.len()
+        } else {
+            *seen.entry(&data[range]).or_insert(unique.len())
+        };
+        if representative == unique.len() {
+            unique.push(*start);
+        }
+        representatives.push(representative);
+    }
+
+    (unique, representatives)
+}
+
+/// Parses a range of the form `START-END`, where both bounds are hex numbers.
+fn parse_range(arg: &str) -> Result<Range<usize>, String> {
+    let (start, end) = arg
+        .split_once('-')
+        .ok_or_else(|| format!("expected START-END, got {:?}", arg))?;
+    let start = hex_to_int(start).map_err(|e| e.to_string())? as usize;
+    let end = hex_to_int(end).map_err(|e| e.to_string())? as usize;
+    if start >= end {
+        return Err(format!("empty range {:x}-{:x}", start, end));
+    }
+
+    Ok(start..end)
+}
+
+/// Parses metadata of the form `KEY=VALUE`.
+fn parse_meta(arg: &str) -> Result<(String, String), String> {
+    let (key, value) = arg
+        .split_once('=')
+        .ok_or_else(|| format!("expected KEY=VALUE, got {:?}", arg))?;
+    if key.is_empty() {
+        return Err("empty metadata key".to_owned());
+    }
+
+    Ok((key.to_owned(), value.to_owned()))
+}
+
 fn hex_to_int(arg: &str) -> Result<u64, std::num::ParseIntError> {
     let tmp = arg.trim_start_matches("0x");
     u64::from_str_radix(tmp, 16)
 }
 
-pub fn run() -> Result<()> {
+fn parse_nonzero_hex(arg: &str) -> Result<usize, String> {
+    match hex_to_int(arg).map_err(|e| e.to_string())? as usize {
+        0 => Err("must not be zero".to_owned()),
+        n => Ok(n),
+    }
+}
+
+fn parse_align(arg: &str) -> Result<usize, String> {
+    let align = hex_to_int(arg).map_err(|e| e.to_string())? as usize;
+    if !align.is_power_of_two() || align < 2 * MIN_HALF_WINDOW {
+        return Err(format!(
+            "block size must be a power of two of at least {:#x}",
+            2 * MIN_HALF_WINDOW
+        ));
+    }
+
+    Ok(align)
+}
+
+/// Resolves a boolean setting that `--FLAG` sets and `--NO_FLAG` clears. The
+/// command line takes precedence over the environment, which takes precedence
+/// over `config`.
+fn resolve_flag(args: &ArgMatches, flag: &str, no_flag: &str, config: bool) -> Result<bool> {
+    for source in [ValueSource::CommandLine, ValueSource::EnvVariable] {
+        let given = |id: &str| args.get_flag(id) && args.value_source(id) == Some(source);
+        match (given(flag), given(no_flag)) {
+            (true, true) => bail!(UsageError(format!(
+                "--{} conflicts with --{}",
+                flag, no_flag
+            ))),
+            (true, false) => return Ok(true),
+            (false, true) => return Ok(false),
+            (false, false) => {}
+        }
+    }
+
+    Ok(config)
+}
+
+/// Parses a plot size of the form `WIDTHxHEIGHT` in pixels.
+fn parse_plot_size(arg: &str) -> Result<(u32, u32), String> {
+    let (width, height) = arg
+        .split_once('x')
+        .ok_or_else(|| format!("expected WIDTHxHEIGHT, got {:?}", arg))?;
+    let parse = |n: &str| match n.parse::<u32>().map_err(|e| e.to_string())? {
+        0 => Err("must not be zero".to_owned()),
+        n => Ok(n),
+    };
+
+    Ok((parse(width)?, parse(height)?))
+}
+
+fn parse_window_size(arg: &str) -> Result<usize, String> {
+    let window_size = hex_to_int(arg).map_err(|e| e.to_string())? as usize;
+    if window_size < 2 * MIN_HALF_WINDOW {
+        return Err(format!(
+            "window size must be at least {:#x}",
+            2 * MIN_HALF_WINDOW
+        ));
+    }
+
+    Ok(window_size)
+}
+
+/// Exit code if no region was detected in any file, see `--fail-on-empty`.
+const EXIT_NO_DETECTIONS: u8 = 1;
+/// Exit code if the arguments are invalid, as for clap's parse errors.
+const EXIT_USAGE: u8 = 2;
+/// Exit code if some files could not be analyzed, which were skipped.
+const EXIT_PARTIAL: u8 = 3;
+/// Exit code if the run failed for any other reason.
+const EXIT_INTERNAL: u8 = 4;
+/// Exit code if the detected arches of a file do not match `--expect-arch`.
+const EXIT_UNEXPECTED: u8 = 5;
+/// Exit code if regions of an arch of `--deny-arch` were detected.
+const EXIT_DENIED: u8 = 6;
+/// Exit codes up to this one are reserved, alerting rules must not use them.
+const EXIT_MAX_RESERVED: u8 = EXIT_DENIED;
+/// Exit code of alerting rules that fire and do not set one.
+const EXIT_RULE: u8 = 10;
+
+const EXIT_CODES_HELP: &str = "\
+Exit codes:
+  0  Success
+  1  No region was detected in any file, with --fail-on-empty
+  2  Invalid arguments
+  3  Some files could not be analyzed and were skipped
+  4  Internal error
+  5  The detected arches of a file do not match --expect-arch
+  6  Regions of an arch of --deny-arch were detected
+  10 An alerting rule fired, rules may set other codes above 6
+Alerting rules that fire exit with their exit code instead of 0 or 1.";
+
+/// Error in the arguments, which are only known to be invalid after parsing.
+#[derive(Debug)]
+struct UsageError(String);
+
+impl std::fmt::Display for UsageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for UsageError {}
+
+/// Runs the command line interface, see `EXIT_CODES_HELP` for the exit codes.
+pub fn run() -> ExitCode {
+    // Panics are reported by the panic hook, they are internal errors, too.
+    let Ok(res) = std::panic::catch_unwind(run_cli) else {
+        return ExitCode::from(EXIT_INTERNAL);
+    };
+    match res {
+        Ok(code) => code,
+        Err(e) => {
+            error!("{:#}", e);
+            if e.downcast_ref::<UsageError>().is_some() {
+                ExitCode::from(EXIT_USAGE)
+            } else {
+                ExitCode::from(EXIT_INTERNAL)
+            }
+        }
+    }
+}
+
+fn run_cli() -> Result<ExitCode> {
     let app = clap::Command::new("coderec")
         .version(env!("CARGO_PKG_VERSION"))
         .propagate_version(true)
         .author("Valentin Obst <coderec@vpao.io>")
         .about("Identifies machine code in binary files.")
+        .after_help(EXIT_CODES_HELP)
         .arg(arg!(-d - -debug))
         .arg(arg!(-q - -quiet))
         .arg(arg!(-v - -verbose))
+        .arg(
+            Arg::new("corpus-dir")
+                .long("corpus-dir")
+                .env("CODEREC_CORPUS_DIR")
+                .required(false)
+                .action(clap::ArgAction::Append)
+                .value_parser(clap::value_parser!(PathBuf))
+                .value_name("DIR")
+                .help("Also load the <ARCH>.corpus, *.stats, and *.toml manifest entries in DIR."),
+        )
+        .arg(arg!(--"strict-corpus" "Fail if an entry in a corpus directory cannot be loaded."))
+        .arg(
+            Arg::new("bootstrap")
+                .long("bootstrap")
+                .required(false)
+                .action(clap::ArgAction::Set)
+                .value_parser(clap::value_parser!(PathBuf))
+                .value_name("FILE")
+                .help("Add the largest homogeneous segment of FILE that looks like code of no known arch to the corpus, to find more code like it."),
+        )
+        .arg(
+            Arg::new("bootstrap-arch")
+                .long("bootstrap-arch")
+                .required(false)
+                .action(clap::ArgAction::Set)
+                .default_value("bootstrap")
+                .value_name("ARCH")
+                .requires("bootstrap")
+                .help("Name of the arch of the entry of --bootstrap."),
+        )
+        .arg(
+            Arg::new("bootstrap-out")
+                .long("bootstrap-out")
+                .required(false)
+                .action(clap::ArgAction::Set)
+                .value_parser(clap::value_parser!(PathBuf))
+                .value_name("DIR")
+                .requires("bootstrap")
+                .help("Also write the sample of --bootstrap to DIR/<ARCH>.corpus, for --corpus-dir."),
+        )
+        .arg(
+            Arg::new("config")
+                .short('c')
+                .long("config")
+                .env("CODEREC_CONFIG")
+                .required(false)
+                .action(clap::ArgAction::Set)
+                .value_parser(clap::value_parser!(PathBuf))
+                .value_name("FILE")
+                .help(
+                    "TOML file with thresholds and defaults, overridden by command line options.",
+                ),
+        )
+        .arg(
+            Arg::new("profile")
+                .long("profile")
+                .env("CODEREC_PROFILE")
+                .required(false)
+                .action(clap::ArgAction::Set)
+                .value_parser(Profile::from_str)
+                .default_value("default")
+                .value_name("PROFILE")
+                .help("Built-in thresholds, overridden by --config: default, strict, or sensitive."),
+        )
+        .arg(arg!(--"print-effective-config" "Print the settings from all of the command line, --config, and --profile as a config file and exit."))
         .arg(arg!(--"big-file" "Optimized analysis for files larger than X00MiB."))
+        .arg(arg!(--"no-big-file" "Plot the bytes of files even if plot.big_file is set.").conflicts_with("redact"))
         .arg(arg!(--"plot-corpus" "Plot distributions of samples in corpus and exit."))
+        .arg(arg!(--"plot-corpus-matrix" "Plot the divergence between each pair of corpus entries as a clustered heatmap, write it to corpus_matrix.csv, and exit."))
         .arg(arg!(--"plot-divs" "Plot raw analysis results in addition to region plot."))
-        .arg(arg!(--"no-plots" "Do not generate any plots."))
-        .arg(arg!(--"no-out" "Do not write detection results to stdout."))
+        .arg(arg!(--"plot-heatmap" "Plot the divergence of each arch in each window as a heatmap in addition to region plot."))
+        .arg(arg!(--"plot-hilbert" "Plot the file along a Hilbert curve, colored by arch, in addition to region plot."))
+        .arg(arg!(--"no-plot-divs" "Do not plot raw analysis results even if plot.divs is set."))
+        .arg(arg!(--"no-plot-heatmap" "Do not plot the heatmap even if plot.heatmap is set."))
+        .arg(arg!(--"no-plot-hilbert" "Do not plot the Hilbert curve even if plot.hilbert is set."))
+        .arg(arg!(--nice "Run with the lowest CPU priority."))
         .arg(
-            Arg::new("offset")
-                .short('o')
-                .long("offset")
+            Arg::new("threads")
+                .long("threads")
+                .env("CODEREC_THREADS")
                 .required(false)
-                .requires("length")
                 .action(clap::ArgAction::Set)
-                .value_parser(hex_to_int)
-                .help("Offset into the file where analysis starts."),
+                .value_parser(clap::builder::RangedU64ValueParser::<usize>::new().range(1..))
+                .value_name("N")
+                .help("Analyze on N threads instead of one per CPU."),
         )
+        .arg(arg!(--f32 "Compute divergences from dense f32 tables, faster but less precise."))
+        .arg(arg!(--numa "Analyze windows on one thread pool per NUMA node, each with a copy of the corpus."))
         .arg(
-            Arg::new("length")
-                .short('l')
-                .long("length")
+            Arg::new("io-throttle")
+                .long("io-throttle")
                 .required(false)
                 .action(clap::ArgAction::Set)
-                .value_parser(hex_to_int)
-                .help("Number of bytes that are analyzed."),
+                // The rate is kept in bytes per second.
+                .value_parser(
+                    clap::builder::RangedU64ValueParser::<u64>::new().range(1..=u64::MAX / 1_000_000),
+                )
+                .value_name("MBps")
+                .help("Limit the read bandwidth to this many megabytes per second."),
         )
         .arg(
-            Arg::new("base")
-                .short('b')
-                .long("base-address")
+            arg!(--"no-plots" "Do not generate any plots.")
+                .env("CODEREC_NO_PLOTS")
+                .value_parser(clap::builder::FalseyValueParser::new()),
+        )
+        .arg(arg!(--plots "Generate plots even if plot.enabled is false."))
+        .arg(
+            Arg::new("upload-plots")
+                .long("upload-plots")
                 .required(false)
                 .action(clap::ArgAction::Set)
-                .value_parser(hex_to_int)
-                .help("Base address of the file.")
-                .default_value("0"),
+                .value_name("s3://BUCKET/PREFIX")
+                .help("Upload the generated plots to an object store instead of keeping them."),
         )
         .arg(
-            Arg::new("files")
-                .action(ArgAction::Append)
-                .value_parser(clap::builder::NonEmptyStringValueParser::new())
-                .required_unless_present("plot-corpus"),
-        );
-
-    let args = app.get_matches();
-
-    let level = if args.get_flag("debug") {
-        log::Level::Debug
-    } else if args.get_flag("verbose") {
-        log::Level::Info
-    } else if args.get_flag("quiet") {
-        log::Level::Error
-    } else {
-        log::Level::Warn
-    };
-    simple_logger::init_with_level(level)?;
-
-    let big_file = args.get_flag("big-file");
-
-    let base_address: &u64 = args.get_one("base").unwrap();
-
-    let corpus_stats = load_corpus();
-
-    if args.get_flag("plot-corpus") {
+            Arg::new("plot-format")
+                .long("plot-format")
+                .required(false)
+                .action(clap::ArgAction::Set)
+                .value_parser(PlotFormat::from_str)
+                .value_name("FORMAT")
+                .help("Write all plots as png, svg, bmp, or interactive html, instead of svg for divergences and the corpus and png for regions. Corpus plots have no html version."),
+        )
+        .arg(
+            Arg::new("plot-size")
+                .long("plot-size")
+                .required(false)
+                .action(clap::ArgAction::Set)
+                .value_parser(parse_plot_size)
+                .value_name("WIDTHxHEIGHT")
+                .help("Size of all plots in pixels, instead of 3000x3000 for divergences and the corpus, 5000x500 for regions, and 1200x600 for html."),
+        )
+        .arg(
+            Arg::new("plot-dpi")
+                .long("plot-dpi")
+                .required(false)
+                .action(clap::ArgAction::Set)
+                .value_parser(clap::value_parser!(u32).range(1..))
+                .value_name("DPI")
+                .help("Print resolution embedded into the plots, SVG plots get their size in inches."),
+        )
+        .arg(
+            Arg::new("format")
+                .long("format")
+                .env("CODEREC_FORMAT")
+                .required(false)
+                .action(clap::ArgAction::Set)
+                .value_parser(SinkFormat::from_str)
+                .default_value("json")
+                .value_name("FORMAT")
+                .help("Format of the detection results: json, ndjson, csv, yaml, sarif, table, binwalk, or binwalk-json."),
+        )
+        .arg(
+            Arg::new("output")
+                .long("output")
+                .env("CODEREC_OUTPUT")
+                .required(false)
+                .action(clap::ArgAction::Set)
+                .default_value("stdout")
+                .value_name("PATH")
+                .help("Write the detection results to PATH instead of stdout."),
+        )
+        .arg(
+            Arg::new("bundle")
+                .long("bundle")
+                .required(false)
+                .action(clap::ArgAction::Set)
+                .value_name("ZIP")
+                .help("Also write the reports, plots, diagnostics, and an HTML overview of all files to this zip archive."),
+        )
+        .arg(
+            Arg::new("sink")
+                .long("sink")
+                .required(false)
+                .action(clap::ArgAction::Append)
+                .value_parser(SinkSpec::from_str)
+                .value_name("FORMAT[=TARGET]")
+                .help("Write detection results in FORMAT to TARGET, may be given multiple times. The sqlite format needs a path as TARGET."),
+        )
+        .arg(
+            Arg::new("emit")
+                .long("emit")
+                .required(false)
+                .action(clap::ArgAction::Append)
+                .value_parser(ScriptFormat::from_str)
+                .value_name("TOOL")
+                .help("Also write a script per file that loads the detected regions into TOOL: ghidra, ida, r2, 010, kaitai, or yara."),
+        )
+        .arg(
+            Arg::new("group-by")
+                .long("group-by")
+                .required(false)
+                .action(clap::ArgAction::Set)
+                .value_parser(["arch", "family"])
+                .default_value("arch")
+                .help("Report regions per arch or per ISA family."),
+        )
+        .arg(
+            Arg::new("top-k")
+                .long("top-k")
+                .required(false)
+                .action(clap::ArgAction::Set)
+                .value_parser(clap::builder::RangedU64ValueParser::<usize>::new().range(1..))
+                .value_name("N")
+                .help("Include the N closest arches of each region in the results."),
+        )
+        .arg(arg!(--"decision-dump" "Include the inputs and branch of the main heuristic for each window of each region in the results."))
+        .arg(
+            arg!(--redact "Report member names as hashes and do not plot, extract, or emit raw bytes, e.g., to share results.")
+                .conflicts_with_all(["extract", "extract-cas", "export-dataset", "bootstrap-out"]),
+        )
+        .arg(
+            Arg::new("rules")
+                .long("rules")
+                .required(false)
+                .action(clap::ArgAction::Set)
+                .value_parser(clap::value_parser!(PathBuf))
+                .value_name("FILE")
+                .help("Evaluate the alerting rules in this YAML file for each file."),
+        )
+        .arg(arg!(--"fail-on-empty" "Exit with 1 if no region was detected in any file."))
+        .arg(
+            Arg::new("expect-arch")
+                .long("expect-arch")
+                .required(false)
+                .action(clap::ArgAction::Append)
+                .value_delimiter(',')
+                .value_name("ARCH")
+                .help("Exit with 5 if any of these arches is not detected in a file, or, for none, if any code is detected in a file."),
+        )
+        .arg(
+            Arg::new("deny-arch")
+                .long("deny-arch")
+                .required(false)
+                .action(clap::ArgAction::Append)
+                .value_delimiter(',')
+                .value_name("ARCH")
+                .help("Exit with 6 if regions of any of these arches or families are detected. Replaces policy.deny_arches of the config."),
+        )
+        .arg(
+            Arg::new("results-db")
+                .long("results-db")
+                .required(false)
+                .action(clap::ArgAction::Set)
+                .value_parser(clap::value_parser!(PathBuf))
+                .value_name("FILE")
+                .requires("product")
+                .help("Record the composition of each file in this results database."),
+        )
+        .arg(
+            Arg::new("product")
+                .long("product")
+                .required(false)
+                .action(clap::ArgAction::Set)
+                .value_name("TAG")
+                .help("Product that the files belong to, for --results-db."),
+        )
+        .arg(
+            Arg::new("product-version")
+                .long("product-version")
+                .required(false)
+                .action(clap::ArgAction::Set)
+                .value_name("VERSION")
+                .help("Version of the product, for --results-db. Defaults to the file name."),
+        )
+        .arg(
+            Arg::new("meta")
+                .long("meta")
+                .required(false)
+                .action(clap::ArgAction::Append)
+                .value_parser(parse_meta)
+                .value_name("KEY=VALUE")
+                .help("Attach metadata to the results of all files, e.g., a case ID, may be given multiple times."),
+        )
+        .arg(arg!(--"mq-region-events" "Message queue sinks also publish one message per region."))
+        .arg(arg!(--"ndjson-region-events" "NDJSON sinks also write one line per region."))
+        .arg(
+            Arg::new("float-decimals")
+                .long("float-decimals")
+                .required(false)
+                .action(clap::ArgAction::Set)
+                .value_parser(clap::value_parser!(u32).range(0..=17))
+                .value_name("DIGITS")
+                .help("Round floats in the detection results to DIGITS decimals, e.g., to compare reports."),
+        )
+        .arg(
+            Arg::new("post-results")
+                .long("post-results")
+                .required(false)
+                .action(clap::ArgAction::Append)
+                .value_parser(parse_url)
+                .value_name("URL")
+                .help("POST the JSON result of each file to URL."),
+        )
+        .arg(
+            Arg::new("post-header")
+                .long("post-header")
+                .required(false)
+                .action(clap::ArgAction::Append)
+                .value_parser(parse_header)
+                .value_name("NAME: VALUE")
+                .help("Add a header to each POST request, e.g., for authentication."),
+        )
+        .arg(
+            Arg::new("post-retries")
+                .long("post-retries")
+                .required(false)
+                .action(clap::ArgAction::Set)
+                .value_parser(clap::value_parser!(u32))
+                .default_value("3")
+                .help("Number of retries for failed POST requests."),
+        )
+        .arg(
+            Arg::new("offset")
+                .short('o')
+                .long("offset")
+                .required(false)
+                .requires("length")
+                .action(clap::ArgAction::Set)
+                .value_parser(hex_to_int)
+                .help("Offset into the file where analysis starts."),
+        )
+        .arg(
+            Arg::new("length")
+                .short('l')
+                .long("length")
+                .required(false)
+                .action(clap::ArgAction::Set)
+                .value_parser(hex_to_int)
+                .help("Number of bytes that are analyzed."),
+        )
+        .arg(
+            arg!(--concat "Analyze the files as the parts of one image, in the given order, e.g., a flash dumped in parts.")
+                .conflicts_with("emit"),
+        )
+        .arg(
+            Arg::new("page-size")
+                .long("page-size")
+                .required(false)
+                .requires("oob-size")
+                .action(clap::ArgAction::Set)
+                .value_parser(parse_nonzero_hex)
+                .help("Page size of a raw NAND dump, whose spare areas are stripped before the analysis."),
+        )
+        .arg(
+            Arg::new("oob-size")
+                .long("oob-size")
+                .required(false)
+                .requires("page-size")
+                .action(clap::ArgAction::Set)
+                .value_parser(parse_nonzero_hex)
+                .help("Size of the spare area that follows each page of a raw NAND dump."),
+        )
+        .arg(
+            arg!(--"detect-oob" "Detect raw NAND dumps of common layouts and strip their spare areas.")
+                .conflicts_with("page-size"),
+        )
+        .arg(
+            Arg::new("base")
+                .short('b')
+                .long("base-address")
+                .required(false)
+                .action(clap::ArgAction::Set)
+                .value_parser(hex_to_int)
+                .help("Base address of the file.")
+                .default_value("0"),
+        )
+        .arg(
+            Arg::new("window-size")
+                .short('w')
+                .long("window-size")
+                .required(false)
+                .action(clap::ArgAction::Set)
+                .value_parser(parse_window_size)
+                .help("Size of the analyzed windows, overrides the file size heuristic."),
+        )
+        .arg(
+            Arg::new("min-windows")
+                .long("min-windows")
+                .required(false)
+                .action(clap::ArgAction::Set)
+                .value_parser(clap::builder::RangedU64ValueParser::<usize>::new().range(1..))
+                .help("Shrink windows such that the file is split into at least this many."),
+        )
+        .arg(
+            Arg::new("stride")
+                .long("stride")
+                .required(false)
+                .action(clap::ArgAction::Set)
+                .value_parser(parse_nonzero_hex)
+                .help("Distance between consecutive windows, defaults to half the window size."),
+        )
+        .arg(
+            Arg::new("align")
+                .long("align")
+                .required(false)
+                .action(clap::ArgAction::Set)
+                .value_parser(parse_align)
+                .value_name("BLOCK_SIZE")
+                .help("Never let windows cross the boundaries of blocks of this size, e.g., erase blocks, nor merge regions across them unless their confidences agree."),
+        )
+        .arg(
+            Arg::new("extract-cas")
+                .long("extract-cas")
+                .required(false)
+                .action(clap::ArgAction::Set)
+                .value_parser(clap::value_parser!(PathBuf))
+                .value_name("DIR")
+                .help("Extract the detected regions into a content-addressed store in DIR."),
+        )
+        .arg(
+            Arg::new("hash-set")
+                .long("hash-set")
+                .required(false)
+                .action(clap::ArgAction::Append)
+                .value_parser(clap::value_parser!(PathBuf))
+                .value_name("FILE")
+                .help("Tag the files and regions whose SHA-256 is listed in this hash set, e.g., sha256sum output or an NSRL export, as known."),
+        )
+        .arg(
+            Arg::new("extract")
+                .long("extract")
+                .required(false)
+                .action(clap::ArgAction::Set)
+                .value_parser(clap::value_parser!(PathBuf))
+                .value_name("DIR")
+                .help("Write each detected region to DIR/FILE/OFFSET_SIZE_ARCH.bin and list them in DIR/manifest.jsonl."),
+        )
+        .arg(
+            Arg::new("export-dataset")
+                .long("export-dataset")
+                .required(false)
+                .action(clap::ArgAction::Set)
+                .value_parser(clap::value_parser!(PathBuf))
+                .value_name("DIR")
+                .help("Export the analyzed windows with their labels as a dataset in DIR."),
+        )
+        .arg(
+            Arg::new("dataset-per-label")
+                .long("dataset-per-label")
+                .required(false)
+                .action(clap::ArgAction::Set)
+                .value_parser(clap::builder::RangedU64ValueParser::<usize>::new().range(1..))
+                .value_name("N")
+                .requires("export-dataset")
+                .help("Export at most N samples per label to balance the dataset."),
+        )
+        .arg(
+            Arg::new("seed")
+                .long("seed")
+                .required(false)
+                .action(clap::ArgAction::Set)
+                .value_parser(clap::value_parser!(u64))
+                .help("Seed for all random sampling, random if not given."),
+        )
+        .arg(
+            Arg::new("metric")
+                .long("metric")
+                .required(false)
+                .action(clap::ArgAction::Set)
+                .value_parser(MetricKind::from_str)
+                .default_value("kl")
+                .help("Divergence metric: kl, js, or chi2. Thresholds are tuned for kl."),
+        )
+        .arg(
+            Arg::new("segmenter")
+                .long("segmenter")
+                .required(false)
+                .action(clap::ArgAction::Set)
+                .value_parser(Segmenter::from_str)
+                .default_value("window")
+                .help("Decide on each window independently (window) or on all at once (hmm)."),
+        )
+        .arg(
+            Arg::new("consolidation")
+                .long("consolidation")
+                .required(false)
+                .action(clap::ArgAction::Set)
+                .value_parser(Consolidation::from_str)
+                .default_value("strict")
+                .help("Merge segments into regions by arch only (strict), absorb regions below --min-region-length (min-length), or absorb low-confidence minorities (score-weighted)."),
+        )
+        .arg(
+            Arg::new("min-region-length")
+                .long("min-region-length")
+                .required(false)
+                .action(clap::ArgAction::Set)
+                .value_parser(parse_nonzero_hex)
+                .value_name("SIZE")
+                .help("Minimum region length for --consolidation min-length, defaults to the window size."),
+        )
+        .arg(arg!(--refine "Refine the boundaries between regions."))
+        .arg(arg!(--"split-mixed" "Split unknown windows with mixed evidence and classify the halves."))
+        .arg(
+            Arg::new("min-region-windows")
+                .long("min-region-windows")
+                .required(false)
+                .action(clap::ArgAction::Set)
+                .value_parser(clap::builder::RangedU64ValueParser::<usize>::new().range(1..))
+                .value_name("N")
+                .help("Drop regions that are smaller than N strides."),
+        )
+        .arg(
+            Arg::new("max-gap")
+                .long("max-gap")
+                .required(false)
+                .action(clap::ArgAction::Set)
+                .value_parser(parse_nonzero_hex)
+                .help("Bridge unknown gaps of at most this size between regions of the same arch."),
+        )
+        .arg(arg!(--"detect-padding" "Skip and report runs of 0x00, 0xff, or short fill patterns as padding."))
+        .arg(
+            Arg::new("min-padding")
+                .long("min-padding")
+                .required(false)
+                .action(clap::ArgAction::Set)
+                .value_parser(parse_nonzero_hex)
+                .default_value("200")
+                .help("Minimum size of padding runs."),
+        )
+        .arg(arg!(--"detect-dtb" "Skip and report flattened device tree blobs as dtb."))
+        .arg(arg!(--"detect-text" "Label regions of ASCII, UTF-16, Base64, or hex encoded text."))
+        .arg(arg!(--"classify-unknown" "Label unknown regions as compressed, encrypted/random, or sparse."))
+        .arg(arg!(--"include-unknown" "Also report the gaps between regions and the coverage of each file."))
+        .arg(arg!(--summary "Also report the dominant arch, the share of each arch and of unknown bytes, the number of regions, and the analysis time of each file."))
+        .arg(arg!(--"detect-swap" "Also analyze the file with the bytes of each 16- and 32-bit word swapped."))
+        .arg(arg!(--decompress "Also analyze the gzip, xz, LZMA, zstd, and LZ4 streams in the input, recursively."))
+        .arg(
+            arg!(--"decode-text" "Also analyze the long Base64, hex, and \\xNN escaped runs in text regions, recursively.")
+                .requires("detect-text"),
+        )
+        .arg(arg!(--recurse "Also analyze each member of tar, cpio, and zip archives in the input, recursively."))
+        .arg(
+            Arg::new("max-decompressed-size")
+                .long("max-decompressed-size")
+                .required(false)
+                .action(clap::ArgAction::Set)
+                .value_parser(parse_nonzero_hex)
+                .default_value("10000000")
+                .help("Skip streams, encoded runs, archive members, and compressed firmware payloads that decompress to more bytes."),
+        )
+        .arg(
+            Arg::new("max-depth")
+                .long("max-depth")
+                .required(false)
+                .action(clap::ArgAction::Set)
+                .value_parser(clap::value_parser!(usize))
+                .default_value("3")
+                .help("Maximum nesting of decompressed streams, encoded runs, and archive members."),
+        )
+        .arg(arg!(--uefi "Analyze each module of UEFI firmware volumes in the input separately."))
+        .arg(arg!(--verify "Disassemble the regions with capstone and demote those that fail."))
+        .arg(
+            Arg::new("verify-min-ratio")
+                .long("verify-min-ratio")
+                .required(false)
+                .action(clap::ArgAction::Set)
+                .value_parser(clap::value_parser!(f64))
+                .default_value("0.8")
+                .value_name("RATIO")
+                .help("Demote regions with a lower share of valid instructions."),
+        )
+        .arg(
+            Arg::new("refine-granularity")
+                .long("refine-granularity")
+                .required(false)
+                .action(clap::ArgAction::Set)
+                .value_parser(parse_nonzero_hex)
+                .default_value("100")
+                .help("Granularity of refined region boundaries."),
+        )
+        .arg(
+            Arg::new("min-split-size")
+                .long("min-split-size")
+                .required(false)
+                .action(clap::ArgAction::Set)
+                .value_parser(parse_nonzero_hex)
+                .default_value("200")
+                .help("Minimum size of the parts of split windows."),
+        )
+        .arg(
+            Arg::new("trace")
+                .long("trace")
+                .required(false)
+                .action(clap::ArgAction::Set)
+                .value_parser(parse_range)
+                .value_name("START-END")
+                .help("Log all intermediate values for windows overlapping this range."),
+        )
+        .arg(
+            Arg::new("files")
+                .action(ArgAction::Append)
+                .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                .required_unless_present_any([
+                    "plot-corpus",
+                    "plot-corpus-matrix",
+                    "print-effective-config",
+                ]),
+        )
+        .subcommand_negates_reqs(true)
+        .args_conflicts_with_subcommands(true)
+        .subcommand(
+            clap::Command::new("corpus")
+                .about("Manage corpus entries.")
+                .subcommand_required(true)
+                .subcommand(
+                    clap::Command::new("train")
+                        .about("Build a new corpus entry from raw code samples.")
+                        .arg(arg!(-a --arch <ARCH> "Name of the architecture.").required(true))
+                        .arg(
+                            arg!(-o --output <FILE> "Output file, defaults to <ARCH>.corpus.")
+                                .value_parser(clap::value_parser!(PathBuf)),
+                        )
+                        .arg(
+                            arg!(--stats <FILE> "Also write precomputed statistics to this file.")
+                                .value_parser(clap::value_parser!(PathBuf)),
+                        )
+                        .arg(
+                            Arg::new("samples")
+                                .action(ArgAction::Append)
+                                .value_parser(clap::value_parser!(PathBuf))
+                                .required(true),
+                        ),
+                )
+                .subcommand(
+                    clap::Command::new("merge")
+                        .about("Merge the weighted samples of a manifest into statistics.")
+                        .arg(
+                            arg!(-o --output <FILE> "Output file for the statistics.")
+                                .required(true)
+                                .value_parser(clap::value_parser!(PathBuf)),
+                        )
+                        .arg(
+                            Arg::new("manifest")
+                                .value_parser(clap::value_parser!(PathBuf))
+                                .required(true),
+                        ),
+                )
+                .subcommand(
+                    clap::Command::new("impact")
+                        .about("Measure how new corpus entries change the detection of a benchmark set.")
+                        .arg(
+                            arg!(--benchmark <DIR> "Labeled windows in the layout of --export-dataset.")
+                                .required(true)
+                                .value_parser(clap::value_parser!(PathBuf)),
+                        )
+                        .arg(
+                            Arg::new("entries")
+                                .action(ArgAction::Append)
+                                .value_parser(clap::value_parser!(PathBuf))
+                                .required(true),
+                        ),
+                )
+                .subcommand(
+                    clap::Command::new("validate-f32")
+                        .about("Compare the labels of a benchmark set with divergences from dense f32 tables against the default computation.")
+                        .arg(
+                            arg!(--benchmark <DIR> "Labeled windows in the layout of --export-dataset.")
+                                .required(true)
+                                .value_parser(clap::value_parser!(PathBuf)),
+                        ),
+                ),
+        )
+        .subcommand(
+            clap::Command::new("convert")
+                .about("Write stored JSON reports in another format without analyzing the files again.")
+                .arg(
+                    Arg::new("reports")
+                        .required(true)
+                        .action(clap::ArgAction::Append)
+                        .value_name("REPORT")
+                        .help("JSON reports, e.g., written with --sink json=FILE."),
+                )
+                .arg(
+                    Arg::new("format")
+                        .long("format")
+                        .required(false)
+                        .action(clap::ArgAction::Set)
+                        .value_parser(SinkFormat::from_str)
+                        .default_value("json")
+                        .value_name("FORMAT")
+                        .help("Format to write, see --sink."),
+                )
+                .arg(
+                    Arg::new("output")
+                        .short('o')
+                        .long("output")
+                        .required(false)
+                        .action(clap::ArgAction::Set)
+                        .default_value("stdout")
+                        .value_name("TARGET")
+                        .help("Target to write to, see --sink."),
+                )
+                .arg(
+                    Arg::new("float-decimals")
+                        .long("float-decimals")
+                        .required(false)
+                        .action(clap::ArgAction::Set)
+                        .value_parser(clap::value_parser!(u32).range(0..=17))
+                        .value_name("DIGITS")
+                        .help("Round floats to DIGITS decimals, see --float-decimals of the analysis."),
+                ),
+        )
+        .subcommand(
+            clap::Command::new("trend")
+                .about("Track the composition of a product across its versions.")
+                .arg(
+                    arg!(--db <FILE> "Results database, see --results-db.")
+                        .required(true)
+                        .value_parser(clap::value_parser!(PathBuf)),
+                )
+                .arg(arg!(--product <TAG> "Product to track.").required(true))
+                .arg(
+                    Arg::new("min-change")
+                        .long("min-change")
+                        .required(false)
+                        .action(clap::ArgAction::Set)
+                        .value_parser(clap::value_parser!(f64))
+                        .default_value("20")
+                        .value_name("PERCENT")
+                        .help("Report arches whose size changed by more than this."),
+                ),
+        )
+        .subcommand(
+            clap::Command::new("common")
+                .about("Find regions with near-identical bigram distributions in two inputs.")
+                .arg(
+                    Arg::new("a")
+                        .required(true)
+                        .value_name("A")
+                        .help("Input whose windows are searched for in B."),
+                )
+                .arg(Arg::new("b").required(true).value_name("B"))
+                .arg(
+                    Arg::new("window-size")
+                        .short('w')
+                        .long("window-size")
+                        .required(false)
+                        .action(clap::ArgAction::Set)
+                        .value_parser(parse_window_size)
+                        .default_value("1000")
+                        .help("Size of the compared windows."),
+                )
+                .arg(
+                    Arg::new("stride")
+                        .long("stride")
+                        .required(false)
+                        .action(clap::ArgAction::Set)
+                        .value_parser(parse_nonzero_hex)
+                        .default_value("100")
+                        .help("Distance between the windows of B, those of A do not overlap."),
+                )
+                .arg(
+                    Arg::new("max-div")
+                        .long("max-div")
+                        .required(false)
+                        .action(clap::ArgAction::Set)
+                        .value_parser(clap::value_parser!(f64))
+                        .default_value("0.05")
+                        .value_name("DIV")
+                        .help("Largest Jensen-Shannon divergence of the bigrams of matching windows."),
+                )
+                .arg(arg!(--identical "Only match windows with identical bytes.")),
+        )
+        .subcommand(
+            clap::Command::new("segment")
+                .about("Split a file into homogeneous segments by change-points of its bigram statistics, without a corpus.")
+                .arg(Arg::new("file").required(true).value_name("FILE"))
+                .arg(
+                    Arg::new("window-size")
+                        .short('w')
+                        .long("window-size")
+                        .required(false)
+                        .action(clap::ArgAction::Set)
+                        .value_parser(parse_window_size)
+                        .default_value("400")
+                        .help("Size of the windows, the resolution of the change-points."),
+                )
+                .arg(
+                    Arg::new("context")
+                        .long("context")
+                        .required(false)
+                        .action(clap::ArgAction::Set)
+                        .value_parser(clap::value_parser!(u64).range(1..))
+                        .default_value("4")
+                        .value_name("WINDOWS")
+                        .help("Compare this many windows on each side of a boundary, segments are at least this many windows."),
+                )
+                .arg(
+                    Arg::new("min-score")
+                        .long("min-score")
+                        .required(false)
+                        .action(clap::ArgAction::Set)
+                        .value_parser(clap::value_parser!(f64))
+                        .default_value("8")
+                        .value_name("SIGMAS")
+                        .help("Report boundaries whose divergence exceeds the median by this many robust standard deviations."),
+                ),
+        )
+        .subcommand(
+            clap::Command::new("tune")
+                .about("Score a file once, then change thresholds and consolidation with commands from stdin and see the regions right away.")
+                .arg(Arg::new("file").required(true).value_name("FILE"))
+                .arg(
+                    Arg::new("corpus-dir")
+                        .long("corpus-dir")
+                        .env("CODEREC_CORPUS_DIR")
+                        .required(false)
+                        .action(clap::ArgAction::Append)
+                        .value_parser(clap::value_parser!(PathBuf))
+                        .value_name("DIR")
+                        .help("Also load the corpus entries in DIR, see --corpus-dir of the analysis."),
+                )
+                .arg(
+                    Arg::new("config")
+                        .short('c')
+                        .long("config")
+                        .env("CODEREC_CONFIG")
+                        .required(false)
+                        .action(clap::ArgAction::Set)
+                        .value_parser(clap::value_parser!(PathBuf))
+                        .value_name("FILE")
+                        .help("TOML file with the settings at the start."),
+                )
+                .arg(
+                    Arg::new("profile")
+                        .long("profile")
+                        .env("CODEREC_PROFILE")
+                        .required(false)
+                        .action(clap::ArgAction::Set)
+                        .value_parser(Profile::from_str)
+                        .default_value("default")
+                        .value_name("PROFILE")
+                        .help("Built-in thresholds at the start, overridden by --config."),
+                )
+                .arg(
+                    Arg::new("window-size")
+                        .short('w')
+                        .long("window-size")
+                        .required(false)
+                        .action(clap::ArgAction::Set)
+                        .value_parser(parse_window_size)
+                        .help("Size of the scored windows, see --window-size of the analysis."),
+                )
+                .arg(arg!(--"no-plots" "Do not plot the regions after each change."))
+                .arg(arg!(--plots "Plot the regions after each change even if plot.enabled is false.")),
+        )
+        .subcommand(
+            clap::Command::new("diff")
+                .about("Report the regions that appeared, disappeared, changed arch, or moved between two files or two saved JSON reports.")
+                .arg(Arg::new("a").required(true).value_name("A"))
+                .arg(Arg::new("b").required(true).value_name("B"))
+                .arg(
+                    Arg::new("corpus-dir")
+                        .long("corpus-dir")
+                        .env("CODEREC_CORPUS_DIR")
+                        .required(false)
+                        .action(clap::ArgAction::Append)
+                        .value_parser(clap::value_parser!(PathBuf))
+                        .value_name("DIR")
+                        .help("Also load the corpus entries in DIR, see --corpus-dir of the analysis."),
+                )
+                .arg(
+                    Arg::new("config")
+                        .short('c')
+                        .long("config")
+                        .env("CODEREC_CONFIG")
+                        .required(false)
+                        .action(clap::ArgAction::Set)
+                        .value_parser(clap::value_parser!(PathBuf))
+                        .value_name("FILE")
+                        .help("TOML file with the settings of the analysis of files."),
+                )
+                .arg(
+                    Arg::new("profile")
+                        .long("profile")
+                        .env("CODEREC_PROFILE")
+                        .required(false)
+                        .action(clap::ArgAction::Set)
+                        .value_parser(Profile::from_str)
+                        .default_value("default")
+                        .value_name("PROFILE")
+                        .help("Built-in thresholds of the analysis of files, overridden by --config."),
+                )
+                .arg(
+                    Arg::new("window-size")
+                        .short('w')
+                        .long("window-size")
+                        .required(false)
+                        .action(clap::ArgAction::Set)
+                        .value_parser(parse_window_size)
+                        .help("Size of the scored windows, see --window-size of the analysis."),
+                )
+                .arg(
+                    Arg::new("tolerance")
+                        .long("tolerance")
+                        .required(false)
+                        .action(clap::ArgAction::Set)
+                        .value_parser(parse_nonzero_hex)
+                        .value_name("BYTES")
+                        .help("Largest difference of the boundaries of the same region, defaults to the larger window size."),
+                ),
+        );
+
+    let args = app.get_matches();
+
+    let level = if args.get_flag("debug") {
+        log::Level::Debug
+    } else if args.get_flag("verbose") || args.contains_id("trace") {
+        log::Level::Info
+    } else if args.get_flag("quiet") {
+        log::Level::Error
+    } else {
+        log::Level::Warn
+    };
+    simple_logger::init_with_level(level)?;
+
+    if args.get_flag("nice") {
+        lower_priority();
+    }
+    if let Some(threads) = args.get_one::<usize>("threads") {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(*threads)
+            .build_global()
+            .context("Could not create the thread pool")?;
+    }
+
+    if let Some(("corpus", corpus_args)) = args.subcommand() {
+        return run_corpus(corpus_args).map(|_| ExitCode::SUCCESS);
+    }
+    if let Some(("convert", convert_args)) = args.subcommand() {
+        return run_convert(convert_args).map(|_| ExitCode::SUCCESS);
+    }
+    if let Some(("trend", trend_args)) = args.subcommand() {
+        return run_trend(trend_args).map(|_| ExitCode::SUCCESS);
+    }
+    if let Some(("common", common_args)) = args.subcommand() {
+        return run_common(common_args).map(|_| ExitCode::SUCCESS);
+    }
+    if let Some(("segment", segment_args)) = args.subcommand() {
+        return run_segment(segment_args).map(|_| ExitCode::SUCCESS);
+    }
+    if let Some(("tune", tune_args)) = args.subcommand() {
+        return run_tune(tune_args).map(|_| ExitCode::SUCCESS);
+    }
+    if let Some(("diff", diff_args)) = args.subcommand() {
+        return run_diff(diff_args).map(|_| ExitCode::SUCCESS);
+    }
+
+    let config = Config::load(
+        *args.get_one("profile").unwrap(),
+        args.get_one::<PathBuf>("config").map(PathBuf::as_path),
+    )
+    .map_err(|e| UsageError(format!("{:#}", e)))?;
+
+    let redact = args.get_flag("redact");
+    // Plots of small files show the value of each byte.
+    let big_file = resolve_flag(&args, "big-file", "no-big-file", config.plot.big_file)? || redact;
+    let plots = resolve_flag(&args, "plots", "no-plots", config.plot.enabled)?;
+    if let Some(format) = args
+        .get_many::<ScriptFormat>("emit")
+        .into_iter()
+        .flatten()
+        .find(|format| redact && format.embeds_bytes())
+    {
+        bail!(UsageError(format!(
+            "--emit {} writes raw bytes, which --redact forbids",
+            format
+        )));
+    }
+    // Options of plots require them, whether the command line or the config
+    // disables them.
+    if !plots {
+        for id in [
+            "plot-divs",
+            "plot-heatmap",
+            "plot-hilbert",
+            "plot-format",
+            "plot-size",
+            "plot-dpi",
+            "upload-plots",
+        ] {
+            if matches!(
+                args.value_source(id),
+                Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+            ) {
+                bail!(UsageError(format!(
+                    "--{} requires plots, which --no-plots or plot.enabled disable",
+                    id
+                )));
+            }
+        }
+    }
+    let plot_divs = resolve_flag(&args, "plot-divs", "no-plot-divs", config.plot.divs)?;
+    let plot_heatmap = resolve_flag(
+        &args,
+        "plot-heatmap",
+        "no-plot-heatmap",
+        config.plot.heatmap,
+    )?;
+    let plot_hilbert = resolve_flag(
+        &args,
+        "plot-hilbert",
+        "no-plot-hilbert",
+        config.plot.hilbert,
+    )?;
+    let plot_options = PlotOptions {
+        format: args
+            .get_one::<PlotFormat>("plot-format")
+            .copied()
+            .or(config.plot.format),
+        size: args
+            .get_one::<(u32, u32)>("plot-size")
+            .copied()
+            .or(config.plot.size),
+        dpi: args.get_one::<u32>("plot-dpi").copied().or(config.plot.dpi),
+    };
+    if args.contains_id("min-region-length")
+        && *args.get_one::<Consolidation>("consolidation").unwrap() != Consolidation::MinLength
+    {
+        bail!(UsageError(
+            "--min-region-length requires --consolidation min-length".to_owned()
+        ));
+    }
+    let deny_arches: Vec<String> = match args.get_many::<String>("deny-arch") {
+        Some(arches) => arches.cloned().collect(),
+        None => config.policy.deny_arches,
+    };
+
+    let base_address: &u64 = args.get_one("base").unwrap();
+
+    let mut options = DetectionOptions {
+        trace: args.get_one::<Range<usize>>("trace").cloned(),
+        window_size: args
+            .get_one::<usize>("window-size")
+            .copied()
+            .or(config.window.size),
+        min_windows: args
+            .get_one::<usize>("min-windows")
+            .copied()
+            .or(config.window.min_windows),
+        stride: args
+            .get_one::<usize>("stride")
+            .copied()
+            .or(config.window.stride),
+        metric: *args.get_one("metric").unwrap(),
+        thresholds: config.thresholds,
+        filters: FilterConfig {
+            min_region_windows: args
+                .get_one::<usize>("min-region-windows")
+                .copied()
+                .or(config.filters.min_region_windows),
+            max_gap: args
+                .get_one::<usize>("max-gap")
+                .copied()
+                .or(config.filters.max_gap),
+        },
+        segmenter: *args.get_one("segmenter").unwrap(),
+        consolidation: *args.get_one("consolidation").unwrap(),
+        min_region_length: args.get_one::<usize>("min-region-length").copied(),
+        align: args.get_one::<usize>("align").copied(),
+        min_padding: args
+            .get_flag("detect-padding")
+            .then(|| *args.get_one::<usize>("min-padding").unwrap()),
+        detect_dtb: args.get_flag("detect-dtb"),
+        numa: None,
+        scorer: None,
+    };
+
+    if args.get_flag("print-effective-config") {
+        let effective = Config {
+            thresholds: options.thresholds.clone(),
+            window: WindowConfig {
+                size: options.window_size,
+                min_windows: options.min_windows,
+                stride: options.stride,
+            },
+            plot: PlotConfig {
+                enabled: plots,
+                divs: plot_divs,
+                heatmap: plot_heatmap,
+                hilbert: plot_hilbert,
+                big_file,
+                format: plot_options.format,
+                size: plot_options.size,
+                dpi: plot_options.dpi,
+            },
+            filters: options.filters.clone(),
+            families: config.families,
+            policy: PolicyConfig { deny_arches },
+        };
+        print!("{}", toml::to_string(&effective)?);
+
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    let mut corpus_stats = load_corpus_with_dirs(&args, args.get_flag("strict-corpus"))?;
+    if let Some(path) = args.get_one::<PathBuf>("bootstrap") {
+        let arch: &String = args.get_one("bootstrap-arch").unwrap();
+        if arch.is_empty() || arch.contains(['/', '\\']) {
+            bail!(UsageError(format!("Invalid arch name: {:?}", arch)));
+        }
+        if corpus_stats.iter().any(|stats| stats.arch == *arch) {
+            bail!(UsageError(format!("{} is already in the corpus", arch)));
+        }
+        let data =
+            std::fs::read(path).with_context(|| format!("Could not read {}", path.display()))?;
+        let name = path.display().to_string();
+        let Some(sample) = pick_sample(
+            &name,
+            &data,
+            &corpus_stats,
+            options.metric,
+            &options.thresholds,
+        ) else {
+            bail!(UsageError(format!(
+                "{}: no segment of at least {:#x} bytes looks like code of an unknown arch",
+                name, MIN_SAMPLE_SIZE
+            )));
+        };
+        info!("{}: bootstrapped {} from {:#x?}", name, arch, sample);
+        if let Some(dir) = args.get_one::<PathBuf>("bootstrap-out") {
+            let out = dir.join(format!("{}.corpus", arch));
+            std::fs::write(&out, &data[sample.clone()])
+                .with_context(|| format!("Could not write {}", out.display()))?;
+        }
+        corpus_stats.push(bootstrap_entry(arch, &data[sample]));
+    }
+    corpus_stats.sort_unstable_by(|a, b| a.arch.cmp(&b.arch));
+
+    if args.get_flag("plot-corpus") {
+        let plot_metadata = PlotMetadata::new(&corpus_stats, plot_options);
         for arch in corpus_stats.iter() {
-            arch.plot_tg();
-            arch.plot_cond_prob();
+            arch.plot_tg(&plot_metadata);
+            arch.plot_cond_prob(&plot_metadata);
         }
 
-        return Ok(());
+        return Ok(ExitCode::SUCCESS);
+    }
+    if args.get_flag("plot-corpus-matrix") {
+        let matrix = CorpusMatrix::new(&corpus_stats, options.metric);
+        let csv = "corpus_matrix.csv";
+        std::fs::File::create(csv)
+            .and_then(|file| matrix.write_csv(&mut std::io::BufWriter::new(file)))
+            .with_context(|| format!("Could not write {}", csv))?;
+        info!("Wrote {}", csv);
+        let plot_metadata = PlotMetadata::new(&corpus_stats, plot_options);
+        info!(
+            "Wrote {}",
+            crate::plotting::plot_corpus_matrix(&matrix, &plot_metadata)
+        );
+
+        return Ok(ExitCode::SUCCESS);
     }
 
     info!("Corpus size: {}", corpus_stats.len());
+    let plot_metadata = plots.then(|| PlotMetadata::new(&corpus_stats, plot_options));
+
+    if args.get_flag("f32") {
+        #[cfg(feature = "f32")]
+        {
+            options.scorer = Some(Arc::new(DenseCorpus::new(&corpus_stats, options.metric)));
+        }
+        #[cfg(not(feature = "f32"))]
+        bail!(UsageError(
+            "Built without f32 support, cannot use dense tables".to_owned()
+        ));
+    }
+    if args.get_flag("numa") {
+        match NumaPools::new(&corpus_stats)? {
+            Some(pools) => {
+                info!("NUMA nodes: {:?}", pools);
+                options.numa = Some(Arc::new(pools));
+            }
+            None => warn!("Less than two NUMA nodes, using the default thread pool"),
+        }
+    }
+
+    // Arches that each file must contain, none for data-only files.
+    let mut expect_arch: Option<Vec<&String>> = args
+        .get_many::<String>("expect-arch")
+        .map(Iterator::collect);
+    if let Some(expected) = expect_arch.as_mut() {
+        if expected.len() > 1 && expected.iter().any(|arch| *arch == "none") {
+            bail!(UsageError(
+                "--expect-arch: none excludes other arches".to_owned()
+            ));
+        }
+        expected.retain(|arch| *arch != "none");
+        if let Some(arch) = expected
+            .iter()
+            .find(|arch| !corpus_stats.iter().any(|stats| stats.arch == ***arch))
+        {
+            bail!(UsageError(format!(
+                "--expect-arch: {} is not in the corpus",
+                arch
+            )));
+        }
+    }
+    let corpus_arches: HashSet<Arch> = corpus_stats
+        .iter()
+        .map(|stats| stats.arch.clone())
+        .collect();
+
+    let mut pipeline = Pipeline::with_corpus(
+        corpus_stats,
+        options,
+        ProcessingOptions {
+            split_min_size: args
+                .get_flag("split-mixed")
+                .then(|| *args.get_one::<usize>("min-split-size").unwrap()),
+            refine_granularity: args
+                .get_flag("refine")
+                .then(|| *args.get_one::<usize>("refine-granularity").unwrap()),
+            verify_min_ratio: args
+                .get_flag("verify")
+                .then(|| *args.get_one::<f64>("verify-min-ratio").unwrap()),
+            detect_text: args.get_flag("detect-text"),
+            classify_unknown: args.get_flag("classify-unknown"),
+        },
+    );
+    // Shared by the closures that add plots and reports.
+    let bundle = match args.get_one::<String>("bundle") {
+        Some(target) => {
+            let diagnostics = DiagnosticLog::default();
+            pipeline.add_hooks(Box::new(diagnostics.clone()));
+            Some(RefCell::new(Bundle::create(
+                target,
+                std::env::args().collect(),
+                diagnostics,
+            )?))
+        }
+        None => None,
+    };
+
+    let sink_options = SinkOptions {
+        http: HttpOptions {
+            headers: args
+                .get_many::<(String, String)>("post-header")
+                .into_iter()
+                .flatten()
+                .cloned()
+                .collect(),
+            retries: *args.get_one("post-retries").unwrap(),
+        },
+        mq_region_events: args.get_flag("mq-region-events"),
+        ndjson_region_events: args.get_flag("ndjson-region-events"),
+        float_decimals: args.get_one::<u32>("float-decimals").copied(),
+    };
+    // Only --format and --output on the command line conflict with --sink,
+    // those from the environment are ignored.
+    if let Some(id) = ["format", "output"].into_iter().find(|id| {
+        args.contains_id("sink") && args.value_source(id) == Some(ValueSource::CommandLine)
+    }) {
+        bail!(UsageError(format!("--{} cannot be used with --sink", id)));
+    }
+    let mut sink_specs: Vec<SinkSpec> = match args.get_many::<SinkSpec>("sink") {
+        Some(specs) => specs.cloned().collect(),
+        None => vec![SinkSpec {
+            format: *args.get_one("format").unwrap(),
+            target: args.get_one::<String>("output").unwrap().clone(),
+        }],
+    };
+    sink_specs.extend(
+        args.get_many::<String>("post-results")
+            .into_iter()
+            .flatten()
+            .map(|url| SinkSpec {
+                format: SinkFormat::Json,
+                target: url.clone(),
+            }),
+    );
+    let mut sinks: Vec<Box<dyn ReportSink>> = sink_specs
+        .iter()
+        .map(|spec| spec.open(&sink_options))
+        .collect::<Result<_>>()?;
+
+    let upload_plots = args.get_one::<String>("upload-plots");
+    if let Some(prefix) = upload_plots {
+        if !is_remote(prefix) {
+            bail!(UsageError(format!(
+                "Plots can only be uploaded to s3:// URLs, got {}",
+                prefix
+            )));
+        }
+        #[cfg(not(feature = "s3"))]
+        bail!(UsageError(format!(
+            "Built without S3 support, cannot upload plots to {}",
+            prefix
+        )));
+    }
+
+    #[cfg(not(feature = "verify"))]
+    if args.get_flag("verify") {
+        bail!(UsageError(
+            "Built without capstone support, cannot verify regions".to_owned()
+        ));
+    }
+
+    let families = Families::new(&config.families);
+    let deny_list = (!deny_arches.is_empty())
+        .then(|| DenyList::new(&deny_arches, families.clone(), &corpus_arches))
+        .transpose()
+        .map_err(|e| UsageError(format!("--deny-arch: {}", e)))?;
+    let group_by_family = args.get_one::<String>("group-by").unwrap() == "family";
+    let rules = args
+        .get_one::<PathBuf>("rules")
+        .map(|path| RuleSet::load(path, families.clone()))
+        .transpose()
+        .map_err(|e| UsageError(format!("{:#}", e)))?;
+    let mut exit_code = 0;
+
+    let input_options = InputOptions {
+        io_throttle: args
+            .get_one::<u64>("io-throttle")
+            .map(|mbps| mbps * 1_000_000),
+    };
+
+    let mut cas_store = args
+        .get_one::<PathBuf>("extract-cas")
+        .map(|dir| CasStore::open(dir))
+        .transpose()?;
+    let known_hashes = args
+        .get_many::<PathBuf>("hash-set")
+        .map(|paths| -> Result<KnownHashes> {
+            let mut known_hashes = KnownHashes::default();
+            for path in paths {
+                known_hashes.load(path)?;
+            }
+            Ok(known_hashes)
+        })
+        .transpose()
+        .map_err(|e| UsageError(format!("--hash-set: {:#}", e)))?;
+    let mut region_dir = args
+        .get_one::<PathBuf>("extract")
+        .map(|dir| RegionDir::open(dir))
+        .transpose()?;
+
+    // Log the seed such that runs without an explicit one can be reproduced.
+    let seed = args
+        .get_one::<u64>("seed")
+        .copied()
+        .unwrap_or_else(rand::random);
+    info!("Seed: {}", seed);
+
+    let mut dataset = args
+        .get_one::<PathBuf>("export-dataset")
+        .map(|dir| {
+            DatasetWriter::open(
+                dir,
+                args.get_one::<usize>("dataset-per-label").copied(),
+                seed,
+            )
+        })
+        .transpose()?;
+
+    let meta: Option<BTreeMap<String, String>> = args
+        .get_many::<(String, String)>("meta")
+        .map(|meta| meta.cloned().collect());
+
+    // The parts are read up front and analyzed as one image.
+    let concat = args
+        .get_flag("concat")
+        .then(|| {
+            let files: Vec<&String> = args.get_many::<String>("files").unwrap().collect();
+            read_concat(&files, &input_options)
+        })
+        .transpose()?;
+    let offset = *args.get_one::<u64>("offset").unwrap_or(&0);
+
+    let mut results_db = args
+        .get_one::<PathBuf>("results-db")
+        .map(|path| ResultsDb::open(path))
+        .transpose()?;
+
+    let mut analyze = |file: &String,
+                       name: &str,
+                       data: &[u8],
+                       unreadable: &[Range<usize>],
+                       base_address: u64,
+                       member: Option<Member>|
+     -> Result<CliJsonOutput> {
+        let start = Instant::now();
+        let analysis = pipeline.analyze(name, data, unreadable);
+        let elapsed = start.elapsed();
+        let processes_res = &analysis.res;
+
+        if let Some(plot_metadata) = &plot_metadata {
+            let mut plot_files = Vec::new();
+            if plot_divs {
+                plot_files.extend(crate::plotting::plot_divs(
+                    name,
+                    data.len(),
+                    processes_res,
+                    plot_metadata,
+                ));
+            }
+            if plot_heatmap {
+                plot_files.push(crate::plotting::plot_heatmap(
+                    name,
+                    data.len(),
+                    processes_res,
+                    base_address,
+                    plot_metadata,
+                ));
+            }
+            if plot_hilbert {
+                plot_files.push(crate::plotting::plot_hilbert(
+                    name,
+                    data,
+                    processes_res,
+                    plot_metadata,
+                ));
+            }
+
+            plot_files.push(crate::plotting::plot_regions(
+                name,
+                data.len(),
+                data,
+                processes_res,
+                big_file,
+                base_address,
+                plot_metadata,
+            ));
+
+            if let Some(bundle) = bundle.as_ref() {
+                for plot in plot_files.iter() {
+                    bundle.borrow_mut().add_plot(file, plot)?;
+                }
+            }
+            #[cfg(feature = "s3")]
+            if let Some(prefix) = upload_plots {
+                for plot in plot_files.iter() {
+                    crate::remote::upload_file(prefix, plot)?;
+                }
+            }
+        }
+
+        let mut report = pipeline.report(name, &analysis);
+        report.member = member;
+        report.meta = meta.clone();
+        if args.get_flag("detect-swap") {
+            report.swap = detect_swap(
+                pipeline.corpus(),
+                data,
+                name,
+                processes_res,
+                pipeline.detection_options(),
+            );
+        }
+        if group_by_family {
+            report.group_by_family(&families);
+        }
+        if args.get_flag("include-unknown") {
+            report.add_gaps();
+        }
+        if let Some(load_address) = report
+            .member
+            .as_ref()
+            .and_then(|member| member.load_address)
+        {
+            report.add_addresses(load_address);
+        }
+        if let (Some((_, parts)), None) = (concat.as_ref(), report.member.as_ref()) {
+            report.add_parts(parts, offset as usize);
+        }
+        if let Some(known_hashes) = known_hashes.as_ref() {
+            known_hashes.tag(&mut report, data);
+        }
+        if args.get_flag("summary") {
+            report.add_summary(elapsed);
+        }
+        if let Some(k) = args.get_one::<usize>("top-k") {
+            report.add_candidates(processes_res, *k);
+        }
+        if args.get_flag("decision-dump") {
+            report.add_decisions(processes_res);
+        }
+        if let Some(rules) = rules.as_ref() {
+            let alerts = rules.evaluate(&report);
+            for alert in alerts.iter() {
+                warn!("{}: alert {}", report.file, alert.rule);
+                exit_code = max(exit_code, alert.exit_code);
+            }
+            report.alerts = Some(alerts);
+        }
+        if let Some(results_db) = results_db.as_mut() {
+            let version = args.get_one::<String>("product-version").unwrap_or(file);
+            results_db.append(&ResultRecord::new(
+                args.get_one::<String>("product").unwrap(),
+                version,
+                &report,
+            ))?;
+        }
+        if let Some(dataset) = dataset.as_mut() {
+            dataset.add_samples(name, processes_res, data)?;
+        }
+        if let Some(cas_store) = cas_store.as_mut() {
+            cas_store.store_regions(&report, data)?;
+        }
+        if let Some(region_dir) = region_dir.as_mut() {
+            region_dir.write_regions(&report, data)?;
+        }
+
+        Ok(report)
+    };
 
-    for file in args.get_many::<String>("files").unwrap() {
-        let file_data = std::fs::read(file).with_context(|| format!("Could not open {}", file))?;
+    let nest_options = NestOptions {
+        streams: args.get_flag("decompress"),
+        encoded: args.get_flag("decode-text"),
+        archives: args.get_flag("recurse"),
+        max_size: *args.get_one::<usize>("max-decompressed-size").unwrap(),
+        redact,
+    };
+
+    let mut detected = false;
+    let mut unexpected = false;
+    let mut denied = false;
+    let mut process = |file: &String,
+                       name: &str,
+                       data: &[u8],
+                       unreadable: &[Range<usize>],
+                       base_address: u64,
+                       member: Option<Member>|
+     -> Result<()> {
+        // Scripts read the regions from the file, so the data has to be
+        // stored in it as is.
+        let offset = match &member {
+            Some(member) => member
+                .offset
+                .map(|member_offset| offset + member_offset as u64),
+            None => Some(offset),
+        };
+        let mut report = analyze(file, name, data, unreadable, base_address, member)?;
+        analyze_nested(
+            &mut report,
+            data,
+            &nest_options,
+            *args.get_one::<usize>("max-depth").unwrap(),
+            &mut |name, data, member| analyze(file, name, data, &[], 0, Some(member)),
+        )?;
+        detected |= report.has_regions();
+        if let Some(expected) = expect_arch.as_ref() {
+            let arches = report.arches();
+            if expected.is_empty() {
+                let code: Vec<&str> = arches
+                    .iter()
+                    .copied()
+                    .filter(|arch| corpus_arches.contains(*arch))
+                    .collect();
+                if !code.is_empty() {
+                    warn!(
+                        "{}: expected no code, detected {}",
+                        report.file,
+                        code.join(", ")
+                    );
+                    unexpected = true;
+                }
+            }
+            for arch in expected.iter() {
+                if !arches.contains(arch.as_str()) {
+                    warn!("{}: expected {}, not detected", report.file, arch);
+                    unexpected = true;
+                }
+            }
+        }
+        if let Some(deny_list) = deny_list.as_ref() {
+            denied |= deny_list.apply(&mut report) > 0;
+        }
+        for sink in sinks.iter_mut() {
+            sink.write_report(&report)?;
+        }
+        if let Some(bundle) = bundle.as_ref() {
+            bundle.borrow_mut().add_report(file, &report)?;
+        }
+        for format in args.get_many::<ScriptFormat>("emit").into_iter().flatten() {
+            let Some(offset) = offset else {
+                warn!(
+                    "{}: not stored in {} as is, cannot emit scripts",
+                    name, file
+                );
+                break;
+            };
+            let input = ScriptInput {
+                path: file,
+                offset,
+                base_address,
+                data,
+                redact,
+            };
+            let script = emit_script(*format, &input, &report)?;
+            info!("Wrote {}", script);
+        }
+
+        Ok(())
+    };
+
+    let container_options = ContainerOptions {
+        uefi: args.get_flag("uefi"),
+        max_size: *args.get_one::<usize>("max-decompressed-size").unwrap(),
+    };
 
-        let (data, name, base_address) = if let Some(offset) = args.get_one::<u64>("offset") {
+    let mut analyze_file = |file: &String| -> Result<()> {
+        // Only the analyzed part is read, which avoids fetching whole objects
+        // from remote stores.
+        // Read errors of local files only cost the ranges that could not be
+        // read.
+        let read = |range: Option<Range<u64>>| match concat.as_ref() {
+            Some((image, _)) => match range {
+                Some(range) => image
+                    .get(range.start as usize..range.end as usize)
+                    .map(<[u8]>::to_vec)
+                    .with_context(|| {
+                        format!(
+                            "range {:#x}..{:#x} is out of bounds of the image",
+                            range.start, range.end
+                        )
+                    })
+                    .map(|data| Input {
+                        data,
+                        unreadable: Vec::new(),
+                    }),
+                None => Ok(Input {
+                    data: image.clone(),
+                    unreadable: Vec::new(),
+                }),
+            },
+            None => read_input_lossy(file, range, &input_options),
+        };
+        let (input, name, base_address) = if let Some(offset) = args.get_one::<u64>("offset") {
             let length: &u64 = args.get_one("length").unwrap();
             let name = format!("{}_o{:x}_l{:x}", file, offset, length);
 
             (
-                &file_data[*offset as usize..(offset + length) as usize],
+                read(Some(*offset..offset + length))?,
                 name,
                 *base_address + *offset,
             )
         } else {
-            (file_data.as_slice(), file.clone(), *base_address)
+            (read(None)?, file.clone(), *base_address)
+        };
+        let layout = match args.get_one::<usize>("page-size") {
+            Some(page_size) => Some(NandLayout {
+                page_size: *page_size,
+                oob_size: *args.get_one::<usize>("oob-size").unwrap(),
+            }),
+            None if args.get_flag("detect-oob") => detect_layout(&name, &input.data),
+            None => None,
+        };
+        let input = match layout {
+            Some(layout) => {
+                info!(
+                    "{}: stripping spare areas of {:#x} bytes after each page of {:#x} bytes",
+                    name, layout.oob_size, layout.page_size
+                );
+                Input {
+                    data: layout.strip(&input.data),
+                    unreadable: input
+                        .unreadable
+                        .iter()
+                        .map(|range| layout.strip_range(range))
+                        .filter(|range| !range.is_empty())
+                        .collect(),
+                }
+            }
+            None => input,
         };
+        let data = input.data.as_slice();
+
+        if let Some((format, blocks)) = decode_records(&name, data)
+            .with_context(|| format!("{}: cannot decode records", name))?
+        {
+            info!(
+                "{}: decoded {} blocks of {} records",
+                name,
+                blocks.len(),
+                format.name()
+            );
+            for block in blocks {
+                let range = block.range();
+                let mut member = Member {
+                    format: format.name().to_owned(),
+                    id: format!("{:x}", range.start),
+                    path: format!("{:#x}..{:#x}", range.start, range.end),
+                    offset: None,
+                    load_address: Some(range.start),
+                    partition: None,
+                };
+                if redact {
+                    member.redact();
+                }
+                process(
+                    file,
+                    &member.report_name(&name),
+                    &block.data,
+                    &[],
+                    range.start,
+                    Some(member),
+                )?;
+            }
+            return Ok(());
+        }
+        let members = unpack(&name, data, &container_options);
+        if !members.is_empty() {
+            for (mut member, content) in members {
+                if redact {
+                    member.redact();
+                }
+                let base_address = member
+                    .load_address
+                    .or(member.offset.map(|offset| base_address + offset as u64))
+                    .unwrap_or(0);
+                process(
+                    file,
+                    &member.report_name(&name),
+                    &content,
+                    &[],
+                    base_address,
+                    Some(member),
+                )?;
+            }
+            return Ok(());
+        }
+        if let Some(hint) = mode_hint(data, nest_options.streams, nest_options.archives) {
+            warn!("{}: {}", name, hint);
+        }
+        process(file, &name, data, &input.unreadable, base_address, None)
+    };
+
+    // A file that cannot be analyzed does not keep the others from being
+    // analyzed.
+    let mut failed = 0;
+    let files = args.get_many::<String>("files").unwrap();
+    // Parts of an image are analyzed once, under the name of the first one.
+    let files = if concat.is_some() {
+        files.take(1).collect::<Vec<_>>()
+    } else {
+        files.collect()
+    };
+    for file in files {
+        if let Err(e) = analyze_file(file) {
+            error!("{}: {:#}", file, e);
+            failed += 1;
+        }
+    }
+
+    for sink in sinks.iter_mut() {
+        sink.finish()?;
+    }
+    if let Some(bundle) = bundle {
+        bundle.into_inner().finish()?;
+    }
+
+    if failed > 0 {
+        warn!("{} files could not be analyzed", failed);
+        exit_code = EXIT_PARTIAL;
+    } else if denied {
+        exit_code = EXIT_DENIED;
+    } else if unexpected {
+        exit_code = EXIT_UNEXPECTED;
+    } else if exit_code == 0 && !detected && args.get_flag("fail-on-empty") {
+        exit_code = EXIT_NO_DETECTIONS;
+    }
+
+    Ok(ExitCode::from(exit_code))
+}
+
+/// Data within the analyzed data that is analyzed on its own.
+struct NestOptions {
+    /// Compressed streams, see `--decompress`.
+    streams: bool,
+    /// Encoded runs in text regions, see `--decode-text`.
+    encoded: bool,
+    /// Members of archives, see `--recurse`.
+    archives: bool,
+    /// Streams and members that decompress to more bytes are skipped.
+    max_size: usize,
+    /// Redact the names of streams and members, see `Member::redact`.
+    redact: bool,
+}
 
-        let raw_res = detect_code(&corpus_stats, data, &name);
-        let processes_res: ProcessedDetectionResult = raw_res.into();
+/// Analyzes the members of the archive or the compressed streams and encoded
+/// runs in `data`, which `report` is about, with `analyze` and nests their
+/// reports in it, up to `depth` levels deep.
+fn analyze_nested<F>(
+    report: &mut CliJsonOutput,
+    data: &[u8],
+    options: &NestOptions,
+    depth: usize,
+    analyze: &mut F,
+) -> Result<()>
+where
+    F: FnMut(&str, &[u8], Member) -> Result<CliJsonOutput>,
+{
+    if depth == 0 {
+        return Ok(());
+    }
 
-        if !args.get_flag("no-plots") {
-            if args.get_flag("plot-divs") {
-                crate::plotting::plot_divs(&name, data.len(), &processes_res);
+    // Streams within an archive are parts of its members.
+    if let Some(members) = options
+        .archives
+        .then(|| find_members(&report.file, data, options.max_size))
+        .flatten()
+    {
+        info!("{}: {} archive members", report.file, members.len());
+        let mut nested = BTreeMap::new();
+        for (mut member, content) in members {
+            if options.redact {
+                member.redact();
             }
+            let path = member.path.clone();
+            let mut report = analyze(&member.report_name(&report.file), &content, member)?;
+            analyze_nested(&mut report, &content, options, depth - 1, analyze)?;
+            nested.insert(path, report);
+        }
+        report.members = Some(nested);
 
-            crate::plotting::plot_regions(
-                &name,
-                data.len(),
-                data,
-                &processes_res,
-                big_file,
-                base_address,
+        return Ok(());
+    }
+
+    let mut streams = Vec::new();
+    if options.streams {
+        streams = find_streams(&report.file, data, options.max_size);
+        if !streams.is_empty() {
+            info!("{}: decompressed {} streams", report.file, streams.len());
+        }
+    }
+    if options.encoded {
+        let blobs = find_encoded(report, data, options.max_size);
+        if !blobs.is_empty() {
+            info!("{}: decoded {} encoded runs", report.file, blobs.len());
+        }
+        streams.extend(blobs);
+    }
+    if streams.is_empty() {
+        return Ok(());
+    }
+
+    let mut nested = Vec::new();
+    for (mut member, content) in streams {
+        if options.redact {
+            member.redact();
+        }
+        let mut stream = analyze(&member.report_name(&report.file), &content, member)?;
+        analyze_nested(&mut stream, &content, options, depth - 1, analyze)?;
+        nested.push(stream);
+    }
+    report.streams = Some(nested);
+
+    Ok(())
+}
+
+/// Lowers the scheduling priority of the process. Must be called before any
+/// threads are spawned, e.g., by rayon, as they inherit the priority.
+fn lower_priority() {
+    #[cfg(unix)]
+    {
+        // SAFETY: `setpriority` has no memory safety preconditions.
+        if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, 19) } != 0 {
+            warn!(
+                "Could not lower priority: {}",
+                std::io::Error::last_os_error()
             );
         }
+    }
+    #[cfg(not(unix))]
+    warn!("Lowering the priority is not supported on this platform");
+}
 
-        if !args.get_flag("no-out") {
-            serde_json::to_writer(
-                io::stdout().lock(),
-                &CliJsonOutput::from((name.as_str(), &processes_res)),
-            )
-            .unwrap()
+fn run_convert(args: &ArgMatches) -> Result<()> {
+    let spec = SinkSpec {
+        format: *args.get_one("format").unwrap(),
+        target: args.get_one::<String>("output").unwrap().clone(),
+    };
+    let mut sink = spec.open(&SinkOptions {
+        float_decimals: args.get_one::<u32>("float-decimals").copied(),
+        ..Default::default()
+    })?;
+
+    for name in args.get_many::<String>("reports").unwrap() {
+        let reports = read_reports(name)?;
+        info!("{}: {} reports", name, reports.len());
+        for report in reports.iter() {
+            sink.write_report(report)?;
+        }
+    }
+
+    sink.finish()
+}
+
+fn run_trend(args: &ArgMatches) -> Result<()> {
+    let product: &String = args.get_one("product").unwrap();
+    let records = ResultsDb::read(args.get_one::<PathBuf>("db").unwrap(), product)?;
+    if records.is_empty() {
+        bail!("No results for product {}", product);
+    }
+
+    let trend = Trend::new(product, &records, *args.get_one("min-change").unwrap());
+    for change in trend.changes.iter() {
+        info!(
+            "{}: {} {:?} from {} to {} bytes since {}",
+            change.version, change.arch, change.kind, change.before, change.after, change.previous
+        );
+    }
+    println!("{}", serde_json::to_string(&trend)?);
+
+    Ok(())
+}
+
+fn run_common(args: &ArgMatches) -> Result<()> {
+    let options = CommonOptions {
+        window_size: *args.get_one("window-size").unwrap(),
+        stride: *args.get_one("stride").unwrap(),
+        max_div: *args.get_one("max-div").unwrap(),
+        identical: args.get_flag("identical"),
+    };
+    let read =
+        |name: &String| std::fs::read(name).with_context(|| format!("Could not read {}", name));
+    let (a, b): (&String, &String) = (args.get_one("a").unwrap(), args.get_one("b").unwrap());
+
+    let regions = find_common(&read(a)?, &read(b)?, &options);
+    for region in regions.iter() {
+        info!(
+            "{:#x?} of {} matches {:#x?} of {}, divergence {:.3}",
+            region.a, a, region.b, b, region.divergence
+        );
+    }
+    let report = CommonReport {
+        a: a.clone(),
+        b: b.clone(),
+        window_size: options.window_size,
+        regions,
+    };
+    println!("{}", serde_json::to_string(&report)?);
+
+    Ok(())
+}
+
+fn run_segment(args: &ArgMatches) -> Result<()> {
+    let options = SegmentOptions {
+        window_size: *args.get_one("window-size").unwrap(),
+        context: *args.get_one::<u64>("context").unwrap() as usize,
+        min_score: *args.get_one("min-score").unwrap(),
+    };
+    let file: &String = args.get_one("file").unwrap();
+    let data = std::fs::read(file).with_context(|| format!("Could not read {}", file))?;
+
+    let report = segment(file, &data, &options);
+    for segment in report.segments.iter() {
+        info!(
+            "{:#x?} of {}, entropy {:.2}",
+            segment.range, file, segment.entropy
+        );
+    }
+    println!("{}", serde_json::to_string(&report)?);
+
+    Ok(())
+}
+
+fn run_tune(args: &ArgMatches) -> Result<()> {
+    let config = Config::load(
+        *args.get_one("profile").unwrap(),
+        args.get_one::<PathBuf>("config").map(PathBuf::as_path),
+    )
+    .map_err(|e| UsageError(format!("{:#}", e)))?;
+    let mut corpus_stats = load_corpus_with_dirs(args, false)?;
+    corpus_stats.sort_unstable_by(|a, b| a.arch.cmp(&b.arch));
+    let file: &String = args.get_one("file").unwrap();
+    let data = std::fs::read(file).with_context(|| format!("Could not read {}", file))?;
+
+    let options = TuneOptions {
+        plots: resolve_flag(args, "plots", "no-plots", config.plot.enabled)?.then_some(
+            PlotOptions {
+                format: config.plot.format,
+                size: config.plot.size,
+                dpi: config.plot.dpi,
+            },
+        ),
+        detection: subcommand_detection_options(args, config),
+    };
+    let mut sink = SinkSpec {
+        format: SinkFormat::Table,
+        target: "stdout".to_owned(),
+    }
+    .open(&SinkOptions::default())?;
+    tune(
+        file,
+        &data,
+        &corpus_stats,
+        &options,
+        std::io::stdin().lock(),
+        sink.as_mut(),
+    )?;
+
+    sink.finish()
+}
+
+fn run_diff(args: &ArgMatches) -> Result<()> {
+    let config = Config::load(
+        *args.get_one("profile").unwrap(),
+        args.get_one::<PathBuf>("config").map(PathBuf::as_path),
+    )
+    .map_err(|e| UsageError(format!("{:#}", e)))?;
+    let options = subcommand_detection_options(args, config);
+    let mut corpus_stats = None;
+
+    // Saved reports are used as they are, all other inputs are analyzed.
+    let mut report = |name: &String| -> Result<CliJsonOutput> {
+        let data = read_input(name, None, &InputOptions::default())?;
+        if let Ok(mut reports) = parse_reports(&data) {
+            if reports.len() > 1 {
+                return Err(UsageError(format!(
+                    "{} contains {} reports, compare one file at a time",
+                    name,
+                    reports.len()
+                ))
+                .into());
+            }
+            if let Some(report) = reports.pop() {
+                info!("{}: saved report of {}", name, report.file);
+                return Ok(report);
+            }
+        }
+        if corpus_stats.is_none() {
+            let mut stats = load_corpus_with_dirs(args, false)?;
+            stats.sort_unstable_by(|a, b| a.arch.cmp(&b.arch));
+            corpus_stats = Some(stats);
         }
+        let res = detect_code(
+            corpus_stats.as_ref().unwrap(),
+            &data,
+            &[],
+            name,
+            &options,
+            &[],
+        )
+        .into();
+
+        Ok(CliJsonOutput::from((name.as_str(), &res)))
+    };
+    let (a, b) = (
+        report(args.get_one("a").unwrap())?,
+        report(args.get_one("b").unwrap())?,
+    );
+
+    let tolerance = args
+        .get_one::<usize>("tolerance")
+        .copied()
+        .unwrap_or(max(a.window_size, b.window_size));
+    let diff = diff_reports(&a, &b, tolerance);
+    for change in diff.changes.iter() {
+        let describe = |region: &Option<DiffRegion>| match region {
+            Some(region) => format!("{:#x?} {}", region.range, region.arch),
+            None => "-".to_owned(),
+        };
+        info!(
+            "{:?}: {} -> {}",
+            change.kind,
+            describe(&change.a),
+            describe(&change.b)
+        );
     }
+    println!("{}", serde_json::to_string(&diff)?);
 
     Ok(())
 }
 
+/// Detection options of the subcommands that analyze a file, from `--config`,
+/// `--profile`, and `--window-size`.
+fn subcommand_detection_options(args: &ArgMatches, config: Config) -> DetectionOptions {
+    DetectionOptions {
+        window_size: args
+            .get_one::<usize>("window-size")
+            .copied()
+            .or(config.window.size),
+        min_windows: config.window.min_windows,
+        stride: config.window.stride,
+        thresholds: config.thresholds,
+        filters: config.filters,
+        ..Default::default()
+    }
+}
+
+/// Loads the embedded corpus and the entries in the directories of
+/// `--corpus-dir`, which replace embedded entries of the same arch.
+fn load_corpus_with_dirs(args: &ArgMatches, strict: bool) -> Result<Vec<CorpusStats>> {
+    let mut corpus_stats = load_corpus();
+    for dir in args.get_many::<PathBuf>("corpus-dir").into_iter().flatten() {
+        for entry in load_corpus_dir(dir, strict)? {
+            corpus_stats.retain(|stats| stats.arch != entry.arch);
+            corpus_stats.push(entry);
+        }
+    }
+
+    Ok(corpus_stats)
+}
+
+fn run_corpus(args: &ArgMatches) -> Result<()> {
+    match args.subcommand() {
+        Some(("train", train_args)) => {
+            let arch: &String = train_args.get_one("arch").unwrap();
+            let output = match train_args.get_one::<PathBuf>("output") {
+                Some(output) => output.clone(),
+                None => PathBuf::from(format!("{}.corpus", arch)),
+            };
+            let samples: Vec<_> = train_args
+                .get_many::<PathBuf>("samples")
+                .unwrap()
+                .map(PathBuf::as_path)
+                .collect();
+
+            train_corpus_entry(
+                arch,
+                &samples,
+                &output,
+                train_args.get_one::<PathBuf>("stats").map(PathBuf::as_path),
+            )
+        }
+        Some(("merge", merge_args)) => merge_corpus_entry(
+            merge_args.get_one::<PathBuf>("manifest").unwrap(),
+            merge_args.get_one::<PathBuf>("output").unwrap(),
+        ),
+        Some(("impact", impact_args)) => {
+            let mut entries = Vec::new();
+            for path in impact_args.get_many::<PathBuf>("entries").unwrap() {
+                let Some(loaded) = load_corpus_entry(path) else {
+                    bail!("{}: unknown corpus entry type", path.display());
+                };
+                entries
+                    .extend(loaded.with_context(|| format!("Could not load {}", path.display()))?);
+            }
+
+            let impact = corpus_impact(
+                load_corpus(),
+                entries,
+                impact_args.get_one::<PathBuf>("benchmark").unwrap(),
+            )?;
+            for confusion in impact.confusions.iter() {
+                info!(
+                    "{}: {} windows now labeled {} instead of {}",
+                    confusion.label, confusion.windows, confusion.after, confusion.before
+                );
+            }
+            for (arch, windows) in impact.stolen.iter() {
+                info!("{}: {} windows taken by the new entries", arch, windows);
+            }
+            if impact.correct_after < impact.correct_before {
+                warn!(
+                    "New entries reduce correctly labeled windows from {} to {} of {}",
+                    impact.correct_before, impact.correct_after, impact.windows
+                );
+            }
+            println!("{}", serde_json::to_string(&impact)?);
+
+            Ok(())
+        }
+        Some(("validate-f32", validate_args)) => {
+            #[cfg(feature = "f32")]
+            {
+                let validation = crate::dense::validate_f32(
+                    &load_corpus(),
+                    validate_args.get_one::<PathBuf>("benchmark").unwrap(),
+                )?;
+                if validation.changed > 0 {
+                    warn!(
+                        "{} of {} windows are labeled differently with f32, {} instead of {} correctly",
+                        validation.changed,
+                        validation.windows,
+                        validation.correct_f32,
+                        validation.correct_f64
+                    );
+                }
+                println!("{}", serde_json::to_string(&validation)?);
+
+                Ok(())
+            }
+            #[cfg(not(feature = "f32"))]
+            {
+                let _ = validate_args;
+                bail!("Built without f32 support, cannot validate dense tables");
+            }
+        }
+        _ => unreachable!(),
+    }
+}
+
 pub fn detect_file(path: &str) -> Result<String> {
-    let corpus_stats = load_corpus();
+    let pipeline = Pipeline::new(DetectionOptions::default(), ProcessingOptions::default());
     let data = std::fs::read(path).with_context(|| format!("Could not open {}", path))?;
-    let raw_res = detect_code(&corpus_stats, &data, path);
-    let res: ProcessedDetectionResult = raw_res.into();
-    Ok(serde_json::to_string(&CliJsonOutput::from((path, &res)))?)
+    // Floats are written as by the command line without --float-decimals.
+    let report = float::to_value(&pipeline.process(path, &data), None)?;
+    Ok(serde_json::to_string(&report)?)
 }
 
 #[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
 pub extern "C" fn coderec_detect_file(path: *const c_char) -> *mut c_char {
     if path.is_null() {
         return std::ptr::null_mut();
@@ -567,11 +3614,12 @@ pub extern "C" fn coderec_detect_file(path: *const c_char) -> *mut c_char {
 }
 
 #[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
 pub extern "C" fn coderec_free_string(s: *mut c_char) {
     if s.is_null() {
         return;
     }
-    unsafe { CString::from_raw(s) };
+    drop(unsafe { CString::from_raw(s) });
 }
 
 #[no_mangle]
@@ -580,13 +3628,145 @@ pub extern "system" fn Java_com_goerdes_correlf_components_CoderecJni_detectFile
     _class: JClass,
     jpath: JString,
 ) -> jstring {
-    let path: String = env
-        .get_string(&jpath)
-        .expect("Invalid jstring")
-        .into();
+    let path: String = env.get_string(&jpath).expect("Invalid jstring").into();
     let result = detect_file(&path).unwrap_or_default();
-    let output = env
-        .new_string(result)
-        .expect("Can't create jstring");
+    let output = env.new_string(result).expect("Can't create jstring");
     output.into_raw()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns a tar archive of regular files.
+    fn tar(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut tar = Vec::new();
+        for (name, content) in files {
+            let mut header = [0u8; 512];
+            header[..name.len()].copy_from_slice(name.as_bytes());
+            header[100..108].copy_from_slice(b"0000644\0");
+            header[124..136].copy_from_slice(format!("{:011o}\0", content.len()).as_bytes());
+            header[156] = b'0';
+            header[257..265].copy_from_slice(b"ustar\x0000");
+            header[148..156].copy_from_slice(b"        ");
+            let sum: u32 = header.iter().map(|byte| *byte as u32).sum();
+            header[148..156].copy_from_slice(format!("{:06o}\0 ", sum).as_bytes());
+
+            tar.extend_from_slice(&header);
+            tar.extend_from_slice(content);
+            tar.resize(tar.len().next_multiple_of(512), 0);
+        }
+        tar.resize(tar.len() + 1024, 0);
+
+        tar
+    }
+
+    /// File sizes around the boundaries of the tiers and the largest size.
+    const HALF_WINDOW_LENS: [usize; 13] = [
+        0,
+        1,
+        0x1000,
+        0x1001,
+        0x8000,
+        0x8001,
+        0x20000,
+        0x20001,
+        0x100000,
+        0x100001,
+        0x1000000,
+        0x1000001,
+        usize::MAX,
+    ];
+
+    #[test]
+    fn half_window_is_monotonic() {
+        let mut lens: Vec<usize> = HALF_WINDOW_LENS.to_vec();
+        lens.extend((0..usize::BITS).flat_map(|bit| {
+            let len = 1usize << bit;
+            [len - 1, len, len + 1, len | len >> 1]
+        }));
+        lens.sort_unstable();
+
+        for pair in lens.windows(2) {
+            assert!(
+                default_half_window(pair[0]) <= default_half_window(pair[1]),
+                "{:#x}: {:#x}, {:#x}: {:#x}",
+                pair[0],
+                default_half_window(pair[0]),
+                pair[1],
+                default_half_window(pair[1])
+            );
+        }
+    }
+
+    #[test]
+    fn half_window_is_bounded() {
+        for len in HALF_WINDOW_LENS
+            .into_iter()
+            .chain((0..usize::BITS).map(|bit| 1 << bit))
+        {
+            let half_window = default_half_window(len);
+            assert!(half_window >= 0x100, "{:#x}", len);
+            if len > 0x1000000 {
+                assert!(half_window * 2 <= len / MIN_WINDOWS, "{:#x}", len);
+            }
+        }
+    }
+
+    #[test]
+    fn windows_and_strides_are_not_empty() {
+        let corpus = load_corpus();
+        let data: Vec<u8> = (0..0x400u32).map(|idx| (idx * 7 % 251) as u8).collect();
+        let sizes = [
+            None,
+            Some(0),
+            Some(1),
+            Some(3),
+            Some(0x40),
+            Some(usize::MAX),
+        ];
+
+        for window_size in sizes {
+            for min_windows in [None, Some(0), Some(1), Some(usize::MAX)] {
+                // Small strides are valid but slow.
+                for stride in [None, Some(0), Some(0x40), Some(usize::MAX)] {
+                    for align in sizes {
+                        let options = DetectionOptions {
+                            window_size,
+                            min_windows,
+                            stride,
+                            align,
+                            ..Default::default()
+                        };
+                        let res = detect_code(&corpus, &data, &[], "test", &options, &[]);
+                        assert!(res.win_sz > 0 && res.stride > 0, "{:?}", options);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn empty_archive_member() {
+        let corpus = load_corpus();
+        let code: Vec<u8> = (0..0x4000u32).map(|idx| (idx * 7 % 251) as u8).collect();
+        let data = tar(&[("code.bin", &code), ("empty", &[])]);
+
+        let members = find_members("test.tar", &data, usize::MAX).unwrap();
+        assert_eq!(members.len(), 2);
+        for (member, content) in members {
+            let res: ProcessedDetectionResult = detect_code(
+                &corpus,
+                &content,
+                &[],
+                &member.path,
+                &DetectionOptions::default(),
+                &[],
+            )
+            .into();
+            if content.is_empty() {
+                assert!(res.segments.is_empty());
+            }
+        }
+    }
+}
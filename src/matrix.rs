@@ -0,0 +1,125 @@
+/*
+    Copyright 2024 - Valentin Obst <coderec@vpao.io>
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+*/
+//! Divergences between all pairs of corpus entries, see
+//! `--plot-corpus-matrix`.
+//!
+//! An entry is scored against every other entry as if it was the data of a
+//! window, so a low divergence means that its code is easily mistaken for the
+//! other arch. The arches are ordered by average-linkage clustering on the
+//! divergences in both directions, which places confusable arches next to each
+//! other.
+
+use crate::metric::MetricKind;
+use crate::output::csv_field;
+use crate::{Arch, CorpusStats};
+
+use std::io::{self, Write};
+
+use rayon::prelude::*;
+
+pub struct CorpusMatrix {
+    pub metric: MetricKind,
+    /// In the order of the clustering.
+    pub arches: Vec<Arch>,
+    /// Divergence of the entry of the row from the entry of the column.
+    pub bigrams: Vec<Vec<f64>>,
+    pub trigrams: Vec<Vec<f64>>,
+}
+
+impl CorpusMatrix {
+    pub fn new(corpus: &[CorpusStats], metric: MetricKind) -> Self {
+        let (bigrams, trigrams): (Vec<Vec<f64>>, Vec<Vec<f64>>) = corpus
+            .par_iter()
+            .map(|p| {
+                corpus
+                    .iter()
+                    .map(|q| {
+                        let div = p.compute_divergence(q, metric.metric());
+                        (div.bigrams, div.trigrams)
+                    })
+                    .unzip()
+            })
+            .unzip();
+
+        let order = cluster_order(&bigrams, &trigrams);
+        let reorder = |matrix: &[Vec<f64>]| -> Vec<Vec<f64>> {
+            order
+                .iter()
+                .map(|&row| order.iter().map(|&col| matrix[row][col]).collect())
+                .collect()
+        };
+
+        Self {
+            metric,
+            arches: order.iter().map(|&idx| corpus[idx].arch.clone()).collect(),
+            bigrams: reorder(&bigrams),
+            trigrams: reorder(&trigrams),
+        }
+    }
+
+    /// Writes a row per arch and n-gram size, with a column per arch.
+    pub fn write_csv(&self, out: &mut dyn Write) -> io::Result<()> {
+        write!(out, "ngrams,arch")?;
+        for arch in self.arches.iter() {
+            write!(out, ",{}", csv_field(arch))?;
+        }
+        writeln!(out)?;
+
+        for (ngrams, matrix) in [("bigrams", &self.bigrams), ("trigrams", &self.trigrams)] {
+            for (arch, row) in self.arches.iter().zip(matrix) {
+                write!(out, "{},{}", ngrams, csv_field(arch))?;
+                for div in row {
+                    write!(out, ",{}", div)?;
+                }
+                writeln!(out)?;
+            }
+        }
+
+        out.flush()
+    }
+}
+
+/// Returns the order of the leaves of the average-linkage clustering of the
+/// entries by their mean divergence in both directions.
+fn cluster_order(bigrams: &[Vec<f64>], trigrams: &[Vec<f64>]) -> Vec<usize> {
+    let n = bigrams.len();
+    let dist = |i: usize, j: usize| {
+        (bigrams[i][j] + bigrams[j][i] + trigrams[i][j] + trigrams[j][i]) / 4.0
+    };
+
+    let mut clusters: Vec<Vec<usize>> = (0..n).map(|idx| vec![idx]).collect();
+    while clusters.len() > 1 {
+        let mut closest = (0, 1, f64::INFINITY);
+        for a in 0..clusters.len() {
+            for b in a + 1..clusters.len() {
+                let sum: f64 = clusters[a]
+                    .iter()
+                    .flat_map(|&i| clusters[b].iter().map(move |&j| (i, j)))
+                    .map(|(i, j)| dist(i, j))
+                    .sum();
+                let avg = sum / (clusters[a].len() * clusters[b].len()) as f64;
+                if avg < closest.2 {
+                    closest = (a, b, avg);
+                }
+            }
+        }
+
+        let merged = clusters.remove(closest.1);
+        clusters[closest.0].extend(merged);
+    }
+
+    clusters.pop().unwrap_or_default()
+}
@@ -0,0 +1,127 @@
+/*
+    Copyright 2024 - Valentin Obst <coderec@vpao.io>
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+*/
+//! Measures for the distance between the n-gram distributions of a window and
+//! a corpus entry.
+//!
+//! The corpus distributions `q` assign a non-zero frequency to every n-gram,
+//! while the distribution `p` of a window is sparse. All metrics are thus
+//! written as a sum over the n-grams of the window plus a correction that
+//! accounts for the remaining n-grams.
+
+use std::str::FromStr;
+
+pub trait Metric: Sync {
+    /// Contribution of an n-gram with frequency `p` in the window and `q` in
+    /// the corpus entry.
+    fn term(&self, p: f64, q: f64) -> f64;
+
+    /// Turns the sum of all terms into the final value. `q_covered` is the
+    /// total frequency in the corpus entry of the n-grams in the window.
+    fn finish(&self, sum: f64, _q_covered: f64) -> f64 {
+        sum
+    }
+
+    /// Whether the metric is the cross entropy of the window with the corpus
+    /// entry minus the entropy of the window. It is then computed from the
+    /// logarithms of the frequencies, without calling `term`.
+    fn is_relative_entropy(&self) -> bool {
+        false
+    }
+}
+
+/// Kullback–Leibler divergence of the window from the corpus entry.
+pub struct KullbackLeibler;
+
+impl Metric for KullbackLeibler {
+    fn term(&self, p: f64, q: f64) -> f64 {
+        p * (p / q).ln()
+    }
+
+    fn is_relative_entropy(&self) -> bool {
+        true
+    }
+}
+
+/// Jensen–Shannon divergence, symmetric and bounded by `ln 2`.
+pub struct JensenShannon;
+
+impl Metric for JensenShannon {
+    fn term(&self, p: f64, q: f64) -> f64 {
+        let m = (p + q) / 2.0;
+
+        0.5 * (p * (p / m).ln() + q * (q / m).ln())
+    }
+
+    fn finish(&self, sum: f64, q_covered: f64) -> f64 {
+        // Each n-gram that is not in the window contributes `0.5 * q * ln 2`.
+        sum + 0.5 * std::f64::consts::LN_2 * (1.0 - q_covered).max(0.0)
+    }
+}
+
+/// Pearson's chi-squared divergence of the window from the corpus entry.
+pub struct ChiSquared;
+
+impl Metric for ChiSquared {
+    fn term(&self, p: f64, q: f64) -> f64 {
+        p * p / q
+    }
+
+    fn finish(&self, sum: f64, _q_covered: f64) -> f64 {
+        // Sum over (p - q)^2 / q, where p and q both sum to one.
+        sum - 1.0
+    }
+}
+
+/// Metrics that can be selected on the command line.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MetricKind {
+    #[default]
+    Kl,
+    Js,
+    Chi2,
+}
+
+impl MetricKind {
+    pub fn metric(self) -> &'static dyn Metric {
+        match self {
+            Self::Kl => &KullbackLeibler,
+            Self::Js => &JensenShannon,
+            Self::Chi2 => &ChiSquared,
+        }
+    }
+
+    /// Name on the command line.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Kl => "kl",
+            Self::Js => "js",
+            Self::Chi2 => "chi2",
+        }
+    }
+}
+
+impl FromStr for MetricKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "kl" => Ok(Self::Kl),
+            "js" => Ok(Self::Js),
+            "chi2" => Ok(Self::Chi2),
+            _ => Err(format!("unknown metric {:?}, expected kl, js, or chi2", s)),
+        }
+    }
+}
@@ -0,0 +1,86 @@
+/*
+    Copyright 2024 - Valentin Obst <coderec@vpao.io>
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+*/
+//! Publishing reports to message queues.
+
+use crate::float;
+use crate::output::{CliJsonOutput, ReportSink};
+
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use kafka::producer::{Producer, Record, RequiredAcks};
+
+const ACK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Publishes the JSON report of each file to a Kafka topic, keyed by the
+/// file name.
+pub struct KafkaSink {
+    producer: Producer,
+    topic: String,
+    region_events: bool,
+    float_decimals: Option<u32>,
+}
+
+impl KafkaSink {
+    /// `target` is of the form `HOST:PORT[,HOST:PORT...]/TOPIC`.
+    pub fn new(target: &str, region_events: bool, float_decimals: Option<u32>) -> Result<Self> {
+        let Some((hosts, topic)) = target.rsplit_once('/') else {
+            bail!("Expected HOSTS/TOPIC, got {:?}", target);
+        };
+        if hosts.is_empty() || topic.is_empty() {
+            bail!("Expected HOSTS/TOPIC, got {:?}", target);
+        }
+
+        let producer = Producer::from_hosts(hosts.split(',').map(str::to_owned).collect())
+            .with_ack_timeout(ACK_TIMEOUT)
+            .with_required_acks(RequiredAcks::One)
+            .create()
+            .with_context(|| format!("Could not connect to Kafka at {}", hosts))?;
+
+        Ok(Self {
+            producer,
+            topic: topic.to_owned(),
+            region_events,
+            float_decimals,
+        })
+    }
+
+    fn publish(&mut self, key: &str, value: String) -> Result<()> {
+        self.producer
+            .send(&Record::from_key_value(&self.topic, key, value))
+            .with_context(|| format!("Could not publish to topic {}", self.topic))
+    }
+}
+
+impl ReportSink for KafkaSink {
+    fn write_report(&mut self, report: &CliJsonOutput) -> Result<()> {
+        self.publish(
+            &report.file,
+            serde_json::to_string(&float::to_value(report, self.float_decimals)?)?,
+        )?;
+
+        if self.region_events {
+            for event in report.region_events() {
+                self.publish(
+                    &report.file,
+                    serde_json::to_string(&float::to_value(&event, self.float_decimals)?)?,
+                )?;
+            }
+        }
+
+        Ok(())
+    }
+}
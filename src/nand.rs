@@ -0,0 +1,121 @@
+/*
+    Copyright 2024 - Valentin Obst <coderec@vpao.io>
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+*/
+//! Spare areas of raw NAND dumps, see `--page-size` and `--detect-oob`.
+//!
+//! Raw dumps follow each page with its spare (OOB) area, which holds the bad
+//! block marker and ECC bytes. These are stripped before the analysis, such
+//! that offsets in the report are offsets in the data area of the flash.
+//!
+//! Common layouts are detected by their bad block marker: in all of them, the
+//! spare area of pages of good blocks starts with two `0xff` bytes, which is
+//! unlikely to hold at the same place in other layouts. Erased pages match
+//! every layout and are ignored.
+
+use std::ops::Range;
+
+use log::debug;
+
+/// Page and spare area sizes of common NAND chips.
+const COMMON_LAYOUTS: [(usize, usize); 8] = [
+    (512, 16),
+    (2048, 64),
+    (2048, 128),
+    (4096, 128),
+    (4096, 224),
+    (4096, 256),
+    (8192, 448),
+    (8192, 640),
+];
+
+/// Minimum number of pages with data that a detected layout must have.
+const MIN_PAGES: usize = 8;
+/// Minimum share of pages with data whose spare area starts with the bad
+/// block marker.
+const MIN_MARKED: f64 = 0.9;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NandLayout {
+    pub page_size: usize,
+    pub oob_size: usize,
+}
+
+impl NandLayout {
+    /// Returns the pages of `data` without their spare areas. A trailing
+    /// partial page is kept as far as it has data.
+    pub fn strip(&self, data: &[u8]) -> Vec<u8> {
+        data.chunks(self.page_size + self.oob_size)
+            .flat_map(|chunk| &chunk[..chunk.len().min(self.page_size)])
+            .copied()
+            .collect()
+    }
+
+    /// Returns the range of the stripped data that `range` of the raw data
+    /// corresponds to, which is empty if it lies within a spare area.
+    pub fn strip_range(&self, range: &Range<usize>) -> Range<usize> {
+        let chunk_size = self.page_size + self.oob_size;
+        let strip = |offset: usize| {
+            offset / chunk_size * self.page_size + (offset % chunk_size).min(self.page_size)
+        };
+
+        strip(range.start)..strip(range.end)
+    }
+
+    /// Share of the pages with data in `data` whose spare area starts with the
+    /// bad block marker, if there are enough of them.
+    fn marked(&self, data: &[u8]) -> Option<f64> {
+        let chunk_size = self.page_size + self.oob_size;
+        if !data.len().is_multiple_of(chunk_size) {
+            return None;
+        }
+
+        let (mut pages, mut marked) = (0, 0);
+        for chunk in data.chunks_exact(chunk_size) {
+            if chunk.iter().all(|byte| *byte == 0xff) {
+                continue;
+            }
+            pages += 1;
+            if chunk[self.page_size..].starts_with(&[0xff, 0xff]) {
+                marked += 1;
+            }
+        }
+        if pages < MIN_PAGES {
+            return None;
+        }
+
+        Some(marked as f64 / pages as f64)
+    }
+}
+
+/// Detects which of the common layouts `data`, which is the content of
+/// `file`, is a raw dump of, if any.
+pub fn detect_layout(file: &str, data: &[u8]) -> Option<NandLayout> {
+    let (layout, marked) = COMMON_LAYOUTS
+        .iter()
+        .map(|(page_size, oob_size)| NandLayout {
+            page_size: *page_size,
+            oob_size: *oob_size,
+        })
+        .filter_map(|layout| Some((layout, layout.marked(data)?)))
+        .inspect(|(layout, marked)| {
+            debug!(
+                "{}: {:#x}+{:#x}: {:.2} of pages marked",
+                file, layout.page_size, layout.oob_size, marked
+            )
+        })
+        .max_by(|(_, a), (_, b)| a.total_cmp(b))?;
+
+    (marked >= MIN_MARKED).then_some(layout)
+}
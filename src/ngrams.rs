@@ -0,0 +1,208 @@
+/*
+    Copyright 2024 - Valentin Obst <coderec@vpao.io>
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+*/
+//! Raw n-gram counts and their serialized form.
+//!
+//! This module is also compiled into the build script, which bakes the
+//! counts of the embedded corpus into the binary. Thus, it must only depend
+//! on `std`.
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::io::{self, Read, Write};
+
+/// Magic at the start of serialized counts.
+pub const MAGIC: &[u8; 4] = b"CRNG";
+/// Version of the serialized format.
+pub const VERSION: u8 = 1;
+/// Size of the chunks in which `NgramCounts::from_reader` reads.
+const CHUNK_SIZE: usize = 0x100000;
+
+/// Absolute number of occurrences of each uni-, bi-, and trigram.
+#[derive(Debug, Default, Clone)]
+pub struct NgramCounts {
+    pub ungrams: HashMap<u8, f64>,
+    pub bigrams: HashMap<(u8, u8), f64>,
+    pub trigrams: HashMap<(u8, u8, u8), f64>,
+}
+
+impl NgramCounts {
+    pub fn from_bytes(data: &[u8]) -> Self {
+        let mut counts = Self::default();
+        counts.add(data);
+
+        counts
+    }
+
+    /// Like `from_bytes`, but reads the data in chunks such that it never has
+    /// to be in memory as a whole.
+    pub fn from_reader<R: Read>(r: &mut R) -> io::Result<Self> {
+        let mut counts = Self::default();
+        let mut buf = vec![0u8; CHUNK_SIZE + 2];
+        // The last two bytes of each chunk are kept such that the n-grams
+        // that cross the chunk boundary are counted.
+        let mut carry = 0;
+        loop {
+            let n = match r.read(&mut buf[carry..]) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            let len = carry + n;
+            counts.add(&buf[..len]);
+
+            carry = len.min(2);
+            buf.copy_within(len - carry..len, 0);
+        }
+
+        Ok(counts)
+    }
+
+    /// Merges the counts of several samples. Each sample is scaled such that
+    /// its share of the total number of trigrams is proportional to its
+    /// weight, regardless of its size. Samples without trigrams are ignored.
+    pub fn weighted_sum(samples: &[(Self, f64)]) -> Self {
+        let size = |counts: &Self| counts.trigrams.values().sum::<f64>();
+        let samples: Vec<_> = samples
+            .iter()
+            .filter(|(counts, _)| size(counts) > 0.0)
+            .collect();
+        let total: f64 = samples.iter().map(|(counts, _)| size(counts)).sum();
+        let total_weight: f64 = samples.iter().map(|(_, weight)| weight).sum();
+
+        let mut merged = Self::default();
+        for (counts, weight) in samples {
+            let scale = weight / total_weight * total / size(counts);
+            add_scaled(&mut merged.ungrams, &counts.ungrams, scale);
+            add_scaled(&mut merged.bigrams, &counts.bigrams, scale);
+            add_scaled(&mut merged.trigrams, &counts.trigrams, scale);
+        }
+
+        merged
+    }
+
+    fn add(&mut self, data: &[u8]) {
+        for w in data.windows(3) {
+            *self.ungrams.entry(w[0]).or_insert(0.0) += 1.0;
+            *self.bigrams.entry((w[0], w[1])).or_insert(0.0) += 1.0;
+            *self.trigrams.entry((w[0], w[1], w[2])).or_insert(0.0) += 1.0;
+        }
+    }
+
+    /// Writes the counts in a compact binary format. Counts are rounded to
+    /// integers.
+    pub fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        write_table(w, &self.ungrams, |ug| vec![*ug])?;
+        write_table(w, &self.bigrams, |bg| vec![bg.0, bg.1])?;
+        write_table(w, &self.trigrams, |tg| vec![tg.0, tg.1, tg.2])
+    }
+
+    /// Reads counts that were written by `write_to`.
+    pub fn read_from<R: Read>(r: &mut R) -> io::Result<Self> {
+        Ok(Self {
+            ungrams: read_table(r, |k: [u8; 1]| k[0])?,
+            bigrams: read_table(r, |k: [u8; 2]| (k[0], k[1]))?,
+            trigrams: read_table(r, |k: [u8; 3]| (k[0], k[1], k[2]))?,
+        })
+    }
+}
+
+fn add_scaled<K: Eq + Hash + Copy>(to: &mut HashMap<K, f64>, from: &HashMap<K, f64>, scale: f64) {
+    for (k, count) in from {
+        *to.entry(*k).or_insert(0.0) += count * scale;
+    }
+}
+
+fn write_table<W: Write, K: Ord + Copy>(
+    w: &mut W,
+    table: &HashMap<K, f64>,
+    key_bytes: impl Fn(&K) -> Vec<u8>,
+) -> io::Result<()> {
+    // Sort to get reproducible output.
+    let mut entries: Vec<_> = table.iter().collect();
+    entries.sort_unstable_by_key(|(k, _)| **k);
+
+    write_varint(w, entries.len() as u64)?;
+    for (k, count) in entries {
+        w.write_all(&key_bytes(k))?;
+        write_varint(w, count.round() as u64)?;
+    }
+
+    Ok(())
+}
+
+fn read_table<R: Read, K: Eq + Hash, const N: usize>(
+    r: &mut R,
+    key: impl Fn([u8; N]) -> K,
+) -> io::Result<HashMap<K, f64>> {
+    let len = read_varint(r)? as usize;
+    // Do not trust the length for the allocation, a corrupt file could make us
+    // reserve huge amounts of memory.
+    let mut table = HashMap::with_capacity(len.min(1 << 16));
+
+    for _ in 0..len {
+        let mut k = [0u8; N];
+        r.read_exact(&mut k)?;
+        table.insert(key(k), read_varint(r)? as f64);
+    }
+
+    Ok(table)
+}
+
+pub fn write_str<W: Write>(w: &mut W, s: &str) -> io::Result<()> {
+    write_varint(w, s.len() as u64)?;
+    w.write_all(s.as_bytes())
+}
+
+pub fn read_str<R: Read>(r: &mut R) -> io::Result<String> {
+    let len = read_varint(r)? as usize;
+    let mut buf = Vec::new();
+    r.take(len as u64).read_to_end(&mut buf)?;
+    if buf.len() != len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+
+    String::from_utf8(buf).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// LEB128 encoding of unsigned integers.
+pub fn write_varint<W: Write>(w: &mut W, mut v: u64) -> io::Result<()> {
+    loop {
+        let byte = (v & 0x7f) as u8;
+        v >>= 7;
+        if v == 0 {
+            return w.write_all(&[byte]);
+        }
+        w.write_all(&[byte | 0x80])?;
+    }
+}
+
+pub fn read_varint<R: Read>(r: &mut R) -> io::Result<u64> {
+    let mut v = 0u64;
+    for shift in (0..64).step_by(7) {
+        let mut byte = [0u8];
+        r.read_exact(&mut byte)?;
+        v |= ((byte[0] & 0x7f) as u64) << shift;
+        if byte[0] & 0x80 == 0 {
+            return Ok(v);
+        }
+    }
+
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "varint too long",
+    ))
+}
@@ -0,0 +1,201 @@
+/*
+    Copyright 2024 - Valentin Obst <coderec@vpao.io>
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+*/
+//! Topology-aware scheduling of the window analysis.
+//!
+//! With the default thread pool, all threads look up n-grams in the corpus
+//! tables in the memory of the node that loaded the corpus. Instead, each
+//! NUMA node gets a thread pool that is pinned to its CPUs and a copy of the
+//! corpus, which is made by a thread of the pool and thus allocated in the
+//! memory of the node. The windows of a file are split into contiguous chunks,
+//! one per node, in proportion to the number of CPUs of the node.
+
+use crate::corpus::CorpusStats;
+
+use std::fmt;
+use std::fs;
+use std::panic;
+use std::thread;
+
+use anyhow::Result;
+use log::{debug, warn};
+use rayon::prelude::*;
+use rayon::{ThreadPool, ThreadPoolBuilder};
+
+const NODE_DIR: &str = "/sys/devices/system/node";
+
+/// Parses a list of CPUs like `0-3,8-11`.
+fn parse_cpulist(list: &str) -> Option<Vec<usize>> {
+    let mut cpus = Vec::new();
+    for range in list.trim().split(',').filter(|range| !range.is_empty()) {
+        match range.split_once('-') {
+            Some((first, last)) => cpus.extend(first.parse::<usize>().ok()?..=last.parse().ok()?),
+            None => cpus.push(range.parse().ok()?),
+        }
+    }
+
+    Some(cpus)
+}
+
+/// Returns the CPUs of each NUMA node with CPUs, sorted by node.
+fn topology() -> Vec<(usize, Vec<usize>)> {
+    let Ok(entries) = fs::read_dir(NODE_DIR) else {
+        return Vec::new();
+    };
+
+    let mut nodes: Vec<(usize, Vec<usize>)> = entries
+        .flatten()
+        .filter_map(|entry| {
+            let node = entry
+                .file_name()
+                .to_str()?
+                .strip_prefix("node")?
+                .parse()
+                .ok()?;
+            let cpus = parse_cpulist(&fs::read_to_string(entry.path().join("cpulist")).ok()?)?;
+            Some((node, cpus))
+        })
+        .filter(|(_, cpus)| !cpus.is_empty())
+        .collect();
+    nodes.sort();
+
+    nodes
+}
+
+/// Restricts the calling thread to `cpus`.
+fn pin(cpus: &[usize]) {
+    #[cfg(target_os = "linux")]
+    {
+        // SAFETY: The set is initialized before it is passed by reference
+        // with its size, `CPU_SET` ignores CPUs that do not fit the set.
+        let res = unsafe {
+            let mut set: libc::cpu_set_t = std::mem::zeroed();
+            for cpu in cpus {
+                libc::CPU_SET(*cpu, &mut set);
+            }
+            libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set)
+        };
+        if res != 0 {
+            warn!(
+                "Could not pin thread to CPUs {:?}: {}",
+                cpus,
+                std::io::Error::last_os_error()
+            );
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
+    warn!(
+        "Pinning threads to CPUs {:?} is not supported on this platform",
+        cpus
+    );
+}
+
+struct NodePool {
+    node: usize,
+    cpus: Vec<usize>,
+    pool: ThreadPool,
+    corpus: Vec<CorpusStats>,
+}
+
+/// Thread pools and corpus copies for each NUMA node.
+pub struct NumaPools {
+    nodes: Vec<NodePool>,
+}
+
+impl fmt::Debug for NumaPools {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map()
+            .entries(self.nodes.iter().map(|node| (node.node, &node.cpus)))
+            .finish()
+    }
+}
+
+impl NumaPools {
+    /// Creates the pools for the nodes of this machine with copies of
+    /// `corpus`, which must be the corpus that windows are analyzed with.
+    /// Returns `None` if the machine does not have several nodes.
+    pub fn new(corpus: &[CorpusStats]) -> Result<Option<Self>> {
+        let topology = topology();
+        if topology.len() < 2 {
+            return Ok(None);
+        }
+
+        let mut nodes = Vec::new();
+        for (node, cpus) in topology {
+            let pinned = cpus.clone();
+            let pool = ThreadPoolBuilder::new()
+                .num_threads(cpus.len())
+                .thread_name(move |idx| format!("node{}-{}", node, idx))
+                .start_handler(move |_| pin(&pinned))
+                .build()?;
+            let corpus = pool.install(|| corpus.to_vec());
+            debug!("NUMA node {}: {} CPUs", node, cpus.len());
+
+            nodes.push(NodePool {
+                node,
+                cpus,
+                pool,
+                corpus,
+            });
+        }
+
+        Ok(Some(Self { nodes }))
+    }
+
+    /// Maps `f` over the windows at `starts` and returns the results in the
+    /// same order. `f` is called with the corpus copy of the node that runs
+    /// it.
+    pub(crate) fn map<T, F>(&self, starts: Vec<usize>, f: F) -> Vec<T>
+    where
+        T: Send,
+        F: Fn(&[CorpusStats], usize) -> T + Sync,
+    {
+        let total_cpus: usize = self.nodes.iter().map(|node| node.cpus.len()).sum();
+        let mut chunks = Vec::new();
+        let mut rest = starts.as_slice();
+        for (idx, node) in self.nodes.iter().enumerate() {
+            let len = if idx + 1 == self.nodes.len() {
+                rest.len()
+            } else {
+                (starts.len() * node.cpus.len() / total_cpus).min(rest.len())
+            };
+            let (chunk, tail) = rest.split_at(len);
+            chunks.push((node, chunk));
+            rest = tail;
+        }
+
+        let f = &f;
+        thread::scope(|scope| {
+            let handles: Vec<_> = chunks
+                .into_iter()
+                .map(|(node, chunk)| {
+                    scope.spawn(move || {
+                        node.pool.install(|| {
+                            chunk
+                                .par_iter()
+                                .map(|start| f(&node.corpus, *start))
+                                .collect::<Vec<T>>()
+                        })
+                    })
+                })
+                .collect();
+
+            handles
+                .into_iter()
+                .flat_map(|handle| handle.join().unwrap_or_else(|e| panic::resume_unwind(e)))
+                .collect()
+        })
+    }
+}
@@ -13,54 +13,1024 @@
     See the License for the specific language governing permissions and
     limitations under the License.
 */
-//! Command line JSON output.
+//! Command line output.
+//!
+//! The reports for the analyzed files are passed to one or more sinks, each of
+//! which writes them in some format to some target.
 
-use crate::{Arch, ProcessedDetectionResult};
+use crate::binwalk::BinwalkSink;
+use crate::concat::{part_ranges, Part, PartRange};
+use crate::config::{AppliedThresholds, Thresholds};
+use crate::consolidate::consolidate;
+use crate::container::Member;
+use crate::endian::{self, EndianInfo};
+use crate::family::Families;
+use crate::float;
+use crate::hashset::Known;
+use crate::http::{HttpOptions, HttpSink};
+use crate::input::is_remote;
+#[cfg(feature = "kafka")]
+use crate::mq::KafkaSink;
+use crate::policy::Denied;
+#[cfg(feature = "s3")]
+use crate::remote::ObjectWriter;
+use crate::rules::Alert;
+use crate::sarif::SarifSink;
+#[cfg(feature = "sqlite")]
+use crate::sqlite::SqliteSink;
+use crate::swap::SwapInfo;
+#[cfg(feature = "verify")]
+use crate::verify::Verification;
+use crate::{
+    final_range_decision, is_traced, Arch, DecisionBranch, ProcessedDetectionResult, RangeResult,
+    TRACE_TARGET,
+};
 
+use std::collections::{BTreeMap, BTreeSet};
 use std::convert::From;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
 use std::ops::Range;
+use std::str::FromStr;
+use std::time::Duration;
 
+use anyhow::{bail, Context, Result};
 use itertools::Itertools;
-use serde::Serialize;
+use log::info;
+use serde::{Deserialize, Serialize};
 
-/// Information that is printed to stdout for each analyzed file.
-#[derive(Serialize)]
+/// Report for each analyzed file.
+///
+/// This is the canonical model of the results, every output format is
+/// derived from it and `read_reports` reads it back from JSON reports.
+#[derive(Serialize, Deserialize)]
 pub struct CliJsonOutput {
+    /// How the report was produced, missing in reports of older versions.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub header: Option<ReportHeader>,
     /// Name of the analyzed file.
-    file: String,
-    /// Consolidated detection results.
-    range_results: Vec<(Range<usize>, usize, Arch)>,
+    pub file: String,
+    /// Number of analyzed bytes.
+    pub size: usize,
+    /// Origin of the analyzed bytes if they are part of a container.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub member: Option<Member>,
+    /// Metadata of the input that is carried verbatim, see `--meta`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub meta: Option<BTreeMap<String, String>>,
+    /// Size of the analyzed windows.
+    pub window_size: usize,
+    /// Distance between consecutive windows.
+    pub stride: usize,
+    /// Consolidated detection results: range, size, and arch.
+    pub range_results: Vec<(Range<usize>, usize, Arch)>,
+    /// Confidence of each region, see `regions`. Reports of earlier versions
+    /// have none.
+    #[serde(default)]
+    pub confidences: Vec<f64>,
+    /// Load addresses of the regions, see `add_addresses`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub addresses: Option<Vec<Range<u64>>>,
+    /// Files that the analyzed image is concatenated from, see `--concat`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parts: Option<Vec<Part>>,
+    /// Bytes of the parts that each region covers, see `add_parts`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub part_ranges: Option<Vec<Vec<PartRange>>>,
+    /// Parts of the file between the regions, see `add_gaps`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub gaps: Option<Vec<Gap>>,
+    /// Percentage of bytes that are part of a region.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub coverage: Option<f64>,
+    /// Endianness of each region whose arch has a byte-swapped sibling.
+    pub endianness: Vec<Option<EndianInfo>>,
+    /// Closest arches for each region, see `add_candidates`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub candidates: Option<Vec<Vec<Candidate>>>,
+    /// Share of the file that each arch makes up.
+    pub composition: Composition,
+    /// Arch that makes up the largest part of the file, if any.
+    pub dominant_arch: Option<Arch>,
+    /// Overview of the file for triage, see `add_summary`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub summary: Option<Summary>,
+    /// Inputs of the main heuristic for the windows of each region, see
+    /// `add_decisions`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub decisions: Option<Vec<Vec<WindowDecision>>>,
+    /// Alerting rules that fired.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub alerts: Option<Vec<Alert>>,
+    /// The file or regions whose hash is in a hash set, see `--hash-set`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub known: Option<Vec<Known>>,
+    /// Regions of arches of the deny-list, see `--deny-arch`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub denied: Option<Vec<Denied>>,
+    /// Byte-swapped view of the file that is closer to the corpus.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub swap: Option<SwapInfo>,
+    /// Reports of the members of the archive, by path, see `--recurse`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub members: Option<BTreeMap<String, CliJsonOutput>>,
+    /// Reports of the compressed streams in the file, see `--decompress`, and
+    /// of the encoded runs in its text regions, see `--decode-text`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub streams: Option<Vec<CliJsonOutput>>,
+    /// Disassembly of the regions, see `--verify`.
+    #[cfg(feature = "verify")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub verification: Option<Vec<Verification>>,
+}
+
+/// Version of the layout of the reports, incremented on incompatible changes.
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// Tool, corpus, and parameters that produced a report, such that stored
+/// reports remain interpretable and comparable.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ReportHeader {
+    pub schema_version: u32,
+    pub version: String,
+    /// Number of corpus entries and their hash, see `corpus_hash`.
+    pub corpus_entries: usize,
+    pub corpus_sha256: String,
+    pub window_size: usize,
+    pub stride: usize,
+    pub metric: String,
+    pub thresholds: Thresholds,
+}
+
+impl ReportHeader {
+    pub(crate) fn new(
+        corpus_entries: usize,
+        corpus_sha256: &str,
+        res: &ProcessedDetectionResult,
+    ) -> Self {
+        Self {
+            schema_version: SCHEMA_VERSION,
+            version: env!("CARGO_PKG_VERSION").to_owned(),
+            corpus_entries,
+            corpus_sha256: corpus_sha256.to_owned(),
+            window_size: res.win_sz,
+            stride: res.stride,
+            metric: res.metric.name().to_owned(),
+            thresholds: res.thresholds.clone(),
+        }
+    }
+}
+
+/// Percentage of bytes per arch.
+#[derive(Serialize, Deserialize, Default)]
+pub struct Composition {
+    pub arches: BTreeMap<Arch, f64>,
+    /// Bytes that are not part of any region.
+    pub unknown: f64,
+}
+
+/// Overview of a file, see `--summary`.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Summary {
+    pub dominant_arch: Option<Arch>,
+    /// Percentage of bytes per arch.
+    pub arches: BTreeMap<Arch, f64>,
+    /// Percentage of bytes that are not part of any region.
+    pub unknown: f64,
+    /// Number of regions.
+    pub regions: usize,
+    /// Time that the analysis of the file took, without nested reports.
+    pub analysis_ms: u64,
+}
+
+/// Part of the file that is not covered by any region.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Gap {
+    pub range: Range<usize>,
+    pub size: usize,
+    pub kind: String,
+}
+
+/// Divergences of an arch, averaged over the windows of a region.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Candidate {
+    pub arch: Arch,
+    pub bigrams: f64,
+    pub trigrams: f64,
+}
+
+/// Closest arch of a window and the statistics of the divergences of all
+/// arches, in bi- or trigrams.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct DecisionInput {
+    pub arch: Arch,
+    pub div: f64,
+    pub mean: f64,
+    pub variance: f64,
+}
+
+impl From<&RangeResult> for DecisionInput {
+    fn from(res: &RangeResult) -> Self {
+        Self {
+            arch: res.arch.clone(),
+            div: res.div,
+            mean: res.range_mean,
+            variance: res.range_var,
+        }
+    }
+}
+
+/// Inputs and outcome of `final_range_decision` for a window.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct WindowDecision {
+    pub window: Range<usize>,
+    pub bigrams: DecisionInput,
+    pub trigrams: DecisionInput,
+    pub thresholds: AppliedThresholds,
+    pub branch: DecisionBranch,
+    pub arch: Option<Arch>,
+    pub confidence: f64,
 }
 
 impl From<(&str, &ProcessedDetectionResult)> for CliJsonOutput {
     fn from((file, res): (&str, &ProcessedDetectionResult)) -> Self {
-        let mut range_to_final_result: Vec<_> = res.range_to_final_result.iter().collect();
-        range_to_final_result
-            .sort_unstable_by(|(range_a, _), (range_b, _)| range_a.start.cmp(&range_b.start));
-        let runs = range_to_final_result
-            .iter()
-            .chunk_by(|(_, arch_op)| (*arch_op).clone());
+        let regions = consolidate(
+            &res.segments,
+            res.consolidation,
+            res.min_region_length.unwrap_or(res.win_sz),
+            res.align,
+        );
+
+        let (range_results, confidences) = regions
+            .into_iter()
+            .filter_map(|region| {
+                if res
+                    .segments_in(&region.range)
+                    .iter()
+                    .any(|segment| is_traced(&res.trace, &segment.window))
+                {
+                    info!(
+                        target: TRACE_TARGET,
+                        "{:x?}: merged {} windows into {:?}{}",
+                        region.range,
+                        region.segments,
+                        region.arch,
+                        if region.arch.is_none() { ", dropped" } else { "" }
+                    );
+                }
 
-        CliJsonOutput {
+                region.arch.map(|arch| {
+                    (
+                        (region.range.clone(), region.range.len(), arch),
+                        region.confidence,
+                    )
+                })
+            })
+            .unzip();
+        let mut output = CliJsonOutput {
+            header: None,
             file: file.to_owned(),
-            range_results: runs
-                .into_iter()
-                .filter_map(|(arch_op, mut ranges)| {
-                    let first_range = ranges.next().unwrap().0.clone();
-                    let last_range = match ranges.last() {
-                        Some((range, _)) => (*range).clone(),
-                        None => first_range.clone(),
+            size: res.segments.last().map_or(0, |segment| segment.range.end),
+            member: None,
+            meta: None,
+            window_size: res.win_sz,
+            stride: res.stride,
+            range_results,
+            confidences,
+            addresses: None,
+            parts: None,
+            part_ranges: None,
+            gaps: None,
+            coverage: None,
+            endianness: Vec::new(),
+            candidates: None,
+            composition: Composition::default(),
+            dominant_arch: None,
+            decisions: None,
+            summary: None,
+            alerts: None,
+            known: None,
+            denied: None,
+            swap: None,
+            members: None,
+            streams: None,
+            #[cfg(feature = "verify")]
+            verification: None,
+        };
+        output.endianness = output
+            .range_results
+            .iter()
+            .map(|(range, _, arch)| endian::detect(arch, &res.segment_divs(res.segments_in(range))))
+            .collect();
+        output.summarize();
+
+        output
+    }
+}
+
+impl CliJsonOutput {
+    /// Regions with their range, size, arch, and confidence, which is NaN
+    /// for reports of versions that did not have any.
+    pub fn regions(&self) -> impl Iterator<Item = (&Range<usize>, usize, &Arch, f64)> {
+        self.range_results
+            .iter()
+            .enumerate()
+            .map(|(idx, (range, size, arch))| {
+                let confidence = self.confidences.get(idx).copied().unwrap_or(f64::NAN);
+                (range, *size, arch, confidence)
+            })
+    }
+
+    /// Replaces the arch of each region with its family and merges adjacent
+    /// regions of the same family.
+    pub fn group_by_family(&mut self, families: &Families) {
+        let regions: Vec<_> = self
+            .regions()
+            .map(|(range, size, arch, confidence)| (range.clone(), size, arch.clone(), confidence))
+            .collect();
+        let mut grouped: Vec<(Range<usize>, usize, Arch, f64)> = Vec::new();
+        let mut endianness: Vec<Option<EndianInfo>> = Vec::new();
+        for ((range, size, arch, confidence), endian) in
+            regions.into_iter().zip(self.endianness.drain(..))
+        {
+            let family = families.family(&arch).to_owned();
+            match grouped.last_mut() {
+                Some(last) if last.0.end == range.start && last.2 == family => {
+                    // Only keep the endianness if both regions agree on it.
+                    let last_endian = endianness.last_mut().unwrap();
+                    *last_endian = match (last_endian.take(), endian) {
+                        (Some(a), Some(b)) if a.endianness == b.endianness => Some(EndianInfo {
+                            endianness: a.endianness,
+                            swap_confidence: (a.swap_confidence * last.1 as f64
+                                + b.swap_confidence * size as f64)
+                                / (last.1 + size) as f64,
+                        }),
+                        _ => None,
                     };
+                    last.3 = (last.3 * last.1 as f64 + confidence * size as f64)
+                        / (last.1 + size) as f64;
+                    last.0.end = range.end;
+                    last.1 += size;
+                }
+                _ => {
+                    grouped.push((range, size, family, confidence));
+                    endianness.push(endian);
+                }
+            }
+        }
+        (self.range_results, self.confidences) = grouped
+            .into_iter()
+            .map(|(range, size, arch, confidence)| ((range, size, arch), confidence))
+            .unzip();
+        self.endianness = endianness;
+        self.summarize();
+    }
+
+    /// Computes the composition of the file from the regions.
+    fn summarize(&mut self) {
+        let mut arches: BTreeMap<Arch, usize> = BTreeMap::new();
+        for (_, size, arch) in self.range_results.iter() {
+            *arches.entry(arch.clone()).or_default() += size;
+        }
+        let known: usize = arches.values().sum();
+        let percent = |n: usize| {
+            if self.size == 0 {
+                0.0
+            } else {
+                100.0 * n as f64 / self.size as f64
+            }
+        };
+
+        self.dominant_arch = arches
+            .iter()
+            .max_by_key(|(_, n)| **n)
+            .map(|(arch, _)| arch.clone());
+        self.composition = Composition {
+            unknown: percent(self.size - known),
+            arches: arches
+                .into_iter()
+                .map(|(arch, n)| (arch, percent(n)))
+                .collect(),
+        };
+    }
 
-                    arch_op.map(|arch| {
-                        (
-                            first_range.start..last_range.end,
-                            last_range.end - first_range.start,
+    /// Whether a region was detected in the file or its members and streams.
+    pub fn has_regions(&self) -> bool {
+        !self.range_results.is_empty()
+            || self
+                .members
+                .iter()
+                .flat_map(|members| members.values())
+                .chain(self.streams.iter().flatten())
+                .any(|nested| nested.has_regions())
+    }
+
+    /// Arches of the regions of the report and its nested reports.
+    pub fn arches(&self) -> BTreeSet<&str> {
+        self.range_results
+            .iter()
+            .map(|(_, _, arch)| arch.as_str())
+            .chain(
+                self.members
+                    .iter()
+                    .flat_map(|members| members.values())
+                    .chain(self.streams.iter().flatten())
+                    .flat_map(|nested| nested.arches()),
+            )
+            .collect()
+    }
+
+    /// Adds the summary of the regions, for an analysis that took `elapsed`.
+    pub fn add_summary(&mut self, elapsed: Duration) {
+        self.summary = Some(Summary {
+            dominant_arch: self.dominant_arch.clone(),
+            arches: self.composition.arches.clone(),
+            unknown: self.composition.unknown,
+            regions: self.range_results.len(),
+            analysis_ms: elapsed.as_millis() as u64,
+        });
+    }
+
+    /// Adds the parts of the file that are not covered by any region and the
+    /// coverage.
+    pub fn add_gaps(&mut self) {
+        let mut gaps = Vec::new();
+        let mut start = 0;
+        for (range, ..) in self.range_results.iter() {
+            if range.start > start {
+                gaps.push(start..range.start);
+            }
+            start = range.end;
+        }
+        if self.size > start {
+            gaps.push(start..self.size);
+        }
+
+        self.coverage = Some(100.0 - self.composition.unknown);
+        self.gaps = Some(
+            gaps.into_iter()
+                .map(|range| Gap {
+                    size: range.len(),
+                    range,
+                    kind: "unknown".to_owned(),
+                })
+                .collect(),
+        );
+    }
+
+    /// Adds the load address of each region for data that is loaded at
+    /// `load_address`.
+    pub fn add_addresses(&mut self, load_address: u64) {
+        self.addresses = Some(
+            self.range_results
+                .iter()
+                .map(|(range, ..)| {
+                    load_address + range.start as u64..load_address + range.end as u64
+                })
+                .collect(),
+        );
+    }
+
+    /// Adds the parts of the image and the bytes of the parts that each
+    /// region covers, for data at `offset` in the image.
+    pub fn add_parts(&mut self, parts: &[Part], offset: usize) {
+        self.part_ranges = Some(
+            self.range_results
+                .iter()
+                .map(|(range, ..)| part_ranges(parts, &(offset + range.start..offset + range.end)))
+                .collect(),
+        );
+        self.parts = Some(parts.to_vec());
+    }
+
+    /// Adds the `k` closest arches of each region, sorted by their trigram
+    /// divergence.
+    pub(crate) fn add_candidates(&mut self, res: &ProcessedDetectionResult, k: usize) {
+        self.candidates = Some(
+            self.range_results
+                .iter()
+                .map(|(range, ..)| {
+                    let mut candidates: Vec<_> = res
+                        .segment_divs(res.segments_in(range))
+                        .into_iter()
+                        .map(|(arch, (bigrams, trigrams))| Candidate {
                             arch,
-                        )
-                    })
+                            bigrams,
+                            trigrams,
+                        })
+                        .collect();
+                    candidates.sort_unstable_by(|a, b| a.trigrams.total_cmp(&b.trigrams));
+                    candidates.truncate(k);
+
+                    candidates
                 })
                 .collect(),
+        );
+    }
+
+    /// Adds the inputs and outcome of the main heuristic for each analyzed
+    /// window of each region. The verdict of a window can differ from that
+    /// of its region, e.g., if it was bridged or dropped.
+    pub(crate) fn add_decisions(&mut self, res: &ProcessedDetectionResult) {
+        self.decisions = Some(
+            self.range_results
+                .iter()
+                .map(|(range, ..)| {
+                    res.segments_in(range)
+                        .iter()
+                        .filter_map(|segment| {
+                            // Windows of padding were not analyzed.
+                            let res_bg = res.range_to_result_bg.get(&segment.window)?;
+                            let res_tg = res.range_to_result_tg.get(&segment.window)?;
+                            let decision = final_range_decision(res_bg, res_tg, &res.thresholds);
+
+                            Some(WindowDecision {
+                                window: segment.window.clone(),
+                                bigrams: res_bg.into(),
+                                trigrams: res_tg.into(),
+                                thresholds: res.thresholds.applied(&res_bg.arch, &res_tg.arch),
+                                branch: decision.branch,
+                                arch: decision.arch,
+                                confidence: decision.confidence,
+                            })
+                        })
+                        .collect()
+                })
+                .collect(),
+        );
+    }
+}
+
+/// Record of a single region, for sinks that also write one per region.
+#[derive(Serialize)]
+pub(crate) struct RegionEvent<'a> {
+    file: &'a str,
+    range: &'a Range<usize>,
+    size: usize,
+    arch: &'a str,
+    confidence: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    meta: Option<&'a BTreeMap<String, String>>,
+}
+
+impl CliJsonOutput {
+    pub(crate) fn region_events(&self) -> impl Iterator<Item = RegionEvent<'_>> {
+        self.regions()
+            .map(|(range, size, arch, confidence)| RegionEvent {
+                file: &self.file,
+                range,
+                size,
+                arch,
+                confidence,
+                meta: self.meta.as_ref(),
+            })
+    }
+}
+
+/// Destination for the reports of the analyzed files.
+pub trait ReportSink {
+    /// Called once for every analyzed file.
+    fn write_report(&mut self, report: &CliJsonOutput) -> Result<()>;
+
+    /// Called after all files have been analyzed.
+    fn finish(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Formats that reports can be written in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SinkFormat {
+    Json,
+    /// One JSON object per line.
+    Ndjson,
+    /// One row per region.
+    Csv,
+    /// One YAML document per file.
+    Yaml,
+    /// One SARIF log for all files.
+    Sarif,
+    /// Aligned columns for each file, for humans.
+    Table,
+    /// Text layout of binwalk's signature scan.
+    Binwalk,
+    /// JSON log layout of binwalk 3.
+    BinwalkJson,
+    /// Rows of a SQLite database, see `sqlite`.
+    Sqlite,
+}
+
+impl FromStr for SinkFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(Self::Json),
+            "ndjson" => Ok(Self::Ndjson),
+            "csv" => Ok(Self::Csv),
+            "yaml" => Ok(Self::Yaml),
+            "sarif" => Ok(Self::Sarif),
+            "table" => Ok(Self::Table),
+            "binwalk" => Ok(Self::Binwalk),
+            "binwalk-json" => Ok(Self::BinwalkJson),
+            "sqlite" => Ok(Self::Sqlite),
+            _ => Err(format!("unknown format {:?}", s)),
         }
     }
 }
+
+/// Settings that apply to all sinks of some kind.
+#[derive(Debug, Clone, Default)]
+pub struct SinkOptions {
+    pub http: HttpOptions,
+    /// Message queue sinks also publish one message per region.
+    #[cfg_attr(not(feature = "kafka"), allow(dead_code))]
+    pub mq_region_events: bool,
+    /// NDJSON sinks also write one line per region.
+    pub ndjson_region_events: bool,
+    /// Round floats to this many decimals, see `float`.
+    pub float_decimals: Option<u32>,
+}
+
+/// Command line description of a sink, `FORMAT[=TARGET]`. The target defaults
+/// to stdout, HTTP(S) URLs are POSTed to, `kafka://HOSTS/TOPIC` publishes
+/// to a Kafka topic, and `s3://BUCKET/KEY` uploads to an object store. SQLite
+/// databases need a local path.
+#[derive(Debug, Clone)]
+pub struct SinkSpec {
+    pub format: SinkFormat,
+    pub target: String,
+}
+
+impl FromStr for SinkSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (format, target) = s.split_once('=').unwrap_or((s, "stdout"));
+        if target.is_empty() {
+            return Err(format!("empty target in {:?}", s));
+        }
+
+        Ok(Self {
+            format: format.parse()?,
+            target: target.to_owned(),
+        })
+    }
+}
+
+impl SinkSpec {
+    pub fn open(&self, options: &SinkOptions) -> Result<Box<dyn ReportSink>> {
+        if self.target.starts_with("http://") || self.target.starts_with("https://") {
+            if self.format != SinkFormat::Json {
+                bail!("Only JSON reports can be POSTed to {}", self.target);
+            }
+            return Ok(Box::new(HttpSink::new(
+                &self.target,
+                &options.http,
+                options.float_decimals,
+            )));
+        }
+        if let Some(kafka) = self.target.strip_prefix("kafka://") {
+            if self.format != SinkFormat::Json {
+                bail!("Only JSON reports can be published to {}", self.target);
+            }
+            #[cfg(feature = "kafka")]
+            return Ok(Box::new(KafkaSink::new(
+                kafka,
+                options.mq_region_events,
+                options.float_decimals,
+            )?));
+            #[cfg(not(feature = "kafka"))]
+            bail!("Built without Kafka support, cannot publish to {}", kafka);
+        }
+        if self.format == SinkFormat::Sqlite {
+            if matches!(self.target.as_str(), "stdout" | "-") || is_remote(&self.target) {
+                bail!("SQLite databases need a local path, got {}", self.target);
+            }
+            #[cfg(feature = "sqlite")]
+            return Ok(Box::new(SqliteSink::open(
+                &self.target,
+                options.float_decimals,
+            )?));
+            #[cfg(not(feature = "sqlite"))]
+            bail!(
+                "Built without SQLite support, cannot write to {}",
+                self.target
+            );
+        }
+
+        let out = open_target(&self.target)?;
+
+        Ok(match self.format {
+            SinkFormat::Json => Box::new(JsonSink {
+                out,
+                float_decimals: options.float_decimals,
+            }),
+            SinkFormat::Ndjson => Box::new(NdjsonSink {
+                out,
+                region_events: options.ndjson_region_events,
+                float_decimals: options.float_decimals,
+            }),
+            SinkFormat::Csv => Box::new(CsvSink {
+                out,
+                meta_keys: None,
+                float_decimals: options.float_decimals,
+            }),
+            SinkFormat::Yaml => Box::new(YamlSink {
+                out,
+                float_decimals: options.float_decimals,
+            }),
+            SinkFormat::Sarif => Box::new(SarifSink::new(out, options.float_decimals)),
+            SinkFormat::Table => Box::new(TableSink {
+                out,
+                float_decimals: options.float_decimals,
+            }),
+            SinkFormat::Binwalk => Box::new(BinwalkSink::new(out, false)),
+            SinkFormat::BinwalkJson => Box::new(BinwalkSink::new(out, true)),
+            SinkFormat::Sqlite => unreachable!(),
+        })
+    }
+}
+
+/// Output that sinks write to.
+pub trait TargetWriter: Write {
+    /// Called once after everything has been written.
+    fn close(&mut self) -> Result<()> {
+        Ok(self.flush()?)
+    }
+}
+
+impl TargetWriter for io::Stdout {}
+
+impl TargetWriter for BufWriter<File> {}
+
+/// Opens the target of a sink for writing, `stdout` and `-` refer to the
+/// standard output.
+pub fn open_target(target: &str) -> Result<Box<dyn TargetWriter>> {
+    if is_remote(target) {
+        #[cfg(feature = "s3")]
+        return Ok(Box::new(ObjectWriter::new(target)?));
+        #[cfg(not(feature = "s3"))]
+        bail!("Built without S3 support, cannot write to {}", target);
+    }
+
+    Ok(match target {
+        "stdout" | "-" => Box::new(io::stdout()),
+        path => Box::new(BufWriter::new(
+            File::create(path).with_context(|| format!("Could not create {}", path))?,
+        )),
+    })
+}
+
+/// Writes one JSON object per file.
+struct JsonSink {
+    out: Box<dyn TargetWriter>,
+    float_decimals: Option<u32>,
+}
+
+impl ReportSink for JsonSink {
+    fn write_report(&mut self, report: &CliJsonOutput) -> Result<()> {
+        serde_json::to_writer(
+            &mut self.out,
+            &float::to_value(report, self.float_decimals)?,
+        )?;
+        self.out.flush()?;
+
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.out.close()
+    }
+}
+
+/// Writes one JSON object per line for each file and, if enabled, for each of
+/// its regions.
+struct NdjsonSink {
+    out: Box<dyn TargetWriter>,
+    region_events: bool,
+    float_decimals: Option<u32>,
+}
+
+impl ReportSink for NdjsonSink {
+    fn write_report(&mut self, report: &CliJsonOutput) -> Result<()> {
+        serde_json::to_writer(
+            &mut self.out,
+            &float::to_value(report, self.float_decimals)?,
+        )?;
+        writeln!(self.out)?;
+        if self.region_events {
+            for event in report.region_events() {
+                serde_json::to_writer(
+                    &mut self.out,
+                    &float::to_value(&event, self.float_decimals)?,
+                )?;
+                writeln!(self.out)?;
+            }
+        }
+        self.out.flush()?;
+
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.out.close()
+    }
+}
+
+/// Quotes `field` if it contains a separator, quote, or line break.
+pub(crate) fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
+
+/// Writes one row per region, including the regions of the reports of
+/// archive members and compressed streams. The metadata of the first report
+/// adds a column per key.
+struct CsvSink {
+    out: Box<dyn TargetWriter>,
+    /// Keys of the metadata columns, once the header is written.
+    meta_keys: Option<Vec<String>>,
+    float_decimals: Option<u32>,
+}
+
+impl CsvSink {
+    fn write_rows(&mut self, report: &CliJsonOutput, meta_keys: &[String]) -> Result<()> {
+        let file = csv_field(&report.file);
+        let meta: String = meta_keys
+            .iter()
+            .map(|key| {
+                let value = report.meta.as_ref().and_then(|meta| meta.get(key));
+                format!(",{}", csv_field(value.map_or("", String::as_str)))
+            })
+            .collect();
+        for (range, size, arch, confidence) in report.regions() {
+            writeln!(
+                self.out,
+                "{},{},{},{},{},{}{}",
+                file,
+                range.start,
+                range.end,
+                size,
+                csv_field(arch),
+                float::format(confidence, self.float_decimals),
+                meta
+            )?;
+        }
+        for nested in report
+            .members
+            .iter()
+            .flat_map(|members| members.values())
+            .chain(report.streams.iter().flatten())
+        {
+            self.write_rows(nested, meta_keys)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl ReportSink for CsvSink {
+    fn write_report(&mut self, report: &CliJsonOutput) -> Result<()> {
+        let meta_keys = match self.meta_keys.take() {
+            Some(meta_keys) => meta_keys,
+            None => {
+                let meta_keys: Vec<String> = report
+                    .meta
+                    .iter()
+                    .flatten()
+                    .map(|(key, _)| key.clone())
+                    .collect();
+                writeln!(
+                    self.out,
+                    "file,start,end,size,arch,confidence{}",
+                    meta_keys
+                        .iter()
+                        .map(|key| format!(",{}", csv_field(key)))
+                        .join("")
+                )?;
+                meta_keys
+            }
+        };
+        self.write_rows(report, &meta_keys)?;
+        self.meta_keys = Some(meta_keys);
+        self.out.flush()?;
+
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.out.close()
+    }
+}
+
+/// Writes one YAML document for each file.
+struct YamlSink {
+    out: Box<dyn TargetWriter>,
+    float_decimals: Option<u32>,
+}
+
+impl ReportSink for YamlSink {
+    fn write_report(&mut self, report: &CliJsonOutput) -> Result<()> {
+        writeln!(self.out, "---")?;
+        serde_norway::to_writer(
+            &mut self.out,
+            &float::to_value(report, self.float_decimals)?,
+        )?;
+        self.out.flush()?;
+
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.out.close()
+    }
+}
+
+/// Writes the regions of each file as a table with aligned columns, followed
+/// by the tables of its archive members and compressed streams.
+struct TableSink {
+    out: Box<dyn TargetWriter>,
+    float_decimals: Option<u32>,
+}
+
+impl TableSink {
+    fn write_table(&mut self, report: &CliJsonOutput) -> Result<()> {
+        writeln!(
+            self.out,
+            "{}: {} bytes, window {:#x}, dominant arch {}",
+            report.file,
+            report.size,
+            report.window_size,
+            report.dominant_arch.as_deref().unwrap_or("none")
+        )?;
+        for (key, value) in report.meta.iter().flatten() {
+            writeln!(self.out, "  {}: {}", key, value)?;
+        }
+        if let Some(summary) = report.summary.as_ref() {
+            writeln!(
+                self.out,
+                "  {} regions, {}% unknown, analyzed in {}ms",
+                summary.regions,
+                float::format(summary.unknown, self.float_decimals.or(Some(2))),
+                summary.analysis_ms
+            )?;
+        }
+
+        let rows: Vec<[String; 5]> = report
+            .regions()
+            .map(|(range, size, arch, confidence)| {
+                [
+                    format!("{:#x}", range.start),
+                    format!("{:#x}", range.end),
+                    size.to_string(),
+                    arch.clone(),
+                    float::format(confidence, self.float_decimals.or(Some(2))),
+                ]
+            })
+            .collect();
+        let header = ["START", "END", "SIZE", "ARCH", "CONFIDENCE"].map(str::to_owned);
+        let mut widths = [0; 5];
+        for row in rows.iter().chain([&header]) {
+            for (width, field) in widths.iter_mut().zip(row.iter()) {
+                *width = (*width).max(field.chars().count());
+            }
+        }
+        for row in [&header].into_iter().chain(rows.iter()) {
+            let line = row
+                .iter()
+                .zip(widths.iter())
+                .map(|(field, width)| format!("{:width$}", field, width = width))
+                .join("  ");
+            writeln!(self.out, "  {}", line.trim_end())?;
+        }
+        writeln!(self.out)?;
+
+        for nested in report
+            .members
+            .iter()
+            .flat_map(|members| members.values())
+            .chain(report.streams.iter().flatten())
+        {
+            self.write_table(nested)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl ReportSink for TableSink {
+    fn write_report(&mut self, report: &CliJsonOutput) -> Result<()> {
+        self.write_table(report)?;
+        self.out.flush()?;
+
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.out.close()
+    }
+}
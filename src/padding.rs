@@ -0,0 +1,122 @@
+/*
+    Copyright 2024 - Valentin Obst <coderec@vpao.io>
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+*/
+//! Detection of padding and erased flash.
+//!
+//! Long runs of a single byte, e.g., `0x00` or `0xff`, or of a short
+//! repeating fill pattern are found before the actual analysis. Windows that
+//! lie entirely within such a run are not analyzed, and the runs are reported
+//! as regions of the pseudo-arch `padding`.
+
+use crate::dtb::DTB;
+use crate::input::UNREADABLE;
+use crate::Segment;
+
+use std::cmp::max;
+use std::ops::Range;
+
+/// Pseudo-arch of padding regions.
+pub const PADDING: &str = "padding";
+
+/// Lengths of the fill patterns that are recognized.
+const PERIODS: &[usize] = &[1, 2, 4];
+
+/// Returns the sorted, disjoint runs of at least `min_size` bytes that repeat
+/// a fill pattern.
+pub fn find_padding(data: &[u8], min_size: usize) -> Vec<Range<usize>> {
+    let mut runs = Vec::new();
+    for &period in PERIODS {
+        let mut start = 0;
+        for idx in period..=data.len() {
+            if idx < data.len() && data[idx] == data[idx - period] {
+                continue;
+            }
+            if idx - start >= max(min_size, 2 * period) {
+                runs.push(start..idx);
+            }
+            // The pattern of the next run may start at the mismatch.
+            start = idx + 1 - period;
+        }
+    }
+    runs.sort_unstable_by_key(|run| run.start);
+
+    // Runs of different periods may overlap, e.g., a run of zeros also
+    // repeats every two bytes.
+    let mut merged: Vec<Range<usize>> = Vec::new();
+    for run in runs {
+        match merged.last_mut() {
+            Some(last) if run.start <= last.end => last.end = max(last.end, run.end),
+            _ => merged.push(run),
+        }
+    }
+
+    merged
+}
+
+/// Returns true if `arch` is the pseudo-arch of runs that are found before
+/// the analysis and thus exact, i.e., of padding, device tree blobs, or
+/// unreadable ranges.
+pub fn is_exact(arch: Option<&str>) -> bool {
+    matches!(arch, Some(PADDING | DTB | UNREADABLE))
+}
+
+/// Returns true if `window` lies entirely within one of the sorted, disjoint
+/// `runs`.
+pub fn is_within(runs: &[Range<usize>], window: &Range<usize>) -> bool {
+    let idx = runs.partition_point(|run| run.end < window.end);
+
+    runs.get(idx)
+        .is_some_and(|run| run.start <= window.start && window.end <= run.end)
+}
+
+/// Cuts the sorted, disjoint `runs` out of `segments` and inserts them as
+/// segments of `arch`. The window of such a segment is the run itself.
+pub fn apply_runs(segments: Vec<Segment>, runs: &[Range<usize>], arch: &str) -> Vec<Segment> {
+    let mut result: Vec<Segment> = runs
+        .iter()
+        .map(|run| Segment {
+            range: run.clone(),
+            window: run.clone(),
+            arch: Some(arch.to_owned()),
+            confidence: 1.0,
+        })
+        .collect();
+
+    for segment in segments {
+        let first = runs.partition_point(|run| run.end <= segment.range.start);
+        let mut start = segment.range.start;
+        for run in runs[first..]
+            .iter()
+            .take_while(|run| run.start < segment.range.end)
+        {
+            if run.start > start {
+                result.push(Segment {
+                    range: start..run.start,
+                    ..segment.clone()
+                });
+            }
+            start = max(start, run.end);
+        }
+        if start < segment.range.end {
+            result.push(Segment {
+                range: start..segment.range.end,
+                ..segment
+            });
+        }
+    }
+    result.sort_unstable_by_key(|segment| segment.range.start);
+
+    result
+}
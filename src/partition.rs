@@ -0,0 +1,319 @@
+/*
+    Copyright 2024 - Valentin Obst <coderec@vpao.io>
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+*/
+//! Partitions of disk images with an MBR or GPT partition table.
+//!
+//! Disk images are analyzed partition by partition instead of in one pass
+//! over the whole image, and each partition is reported with its entry in the
+//! partition table. GPT disks are recognized by the header in their second
+//! sector, which has a checksum. MBR disks are recognized by the boot
+//! signature and a table of consistent entries, as boot sectors of file
+//! systems have the same signature. Logical partitions are found by following
+//! the chain of extended boot records.
+
+use crate::container::{read_u16, read_u32, read_u64, Member};
+use crate::uefi::format_guid;
+
+use std::ops::Range;
+
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+
+const MBR_SECTOR_SIZE: usize = 512;
+const MBR_SIGNATURE: u16 = 0xaa55;
+const MBR_TABLE_OFFSET: usize = 446;
+const MBR_ENTRY_SIZE: usize = 16;
+const MBR_TYPE_PROTECTIVE: u8 = 0xee;
+const MBR_TYPES_EXTENDED: [u8; 3] = [0x05, 0x0f, 0x85];
+/// Maximum number of logical partitions, which ends loops in corrupt chains.
+const MAX_LOGICAL_PARTITIONS: usize = 128;
+
+const GPT_MAGIC: &[u8] = b"EFI PART";
+const GPT_SECTOR_SIZES: [usize; 2] = [512, 4096];
+const GPT_MIN_HEADER_SIZE: usize = 92;
+const GPT_MIN_ENTRY_SIZE: usize = 128;
+const GPT_ATTRIBUTE_LEGACY_BOOTABLE: u64 = 1 << 2;
+
+/// Entry of a partition table.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Partition {
+    /// MBR partition type as a hex byte, or the GPT partition type GUID.
+    #[serde(rename = "type")]
+    pub partition_type: String,
+    /// Name of a GPT partition.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    pub bootable: bool,
+    pub first_sector: u64,
+    pub sectors: u64,
+    pub sector_size: usize,
+}
+
+impl Partition {
+    fn range(&self) -> Range<u64> {
+        let start = self.first_sector * self.sector_size as u64;
+
+        start..start + self.sectors * self.sector_size as u64
+    }
+}
+
+struct MbrEntry {
+    bootable: bool,
+    partition_type: u8,
+    first_sector: u64,
+    sectors: u64,
+}
+
+/// Parses the partition table of the MBR or EBR in the first sector of
+/// `data`. Returns `None` if there is none or an entry is invalid.
+fn parse_mbr(data: &[u8]) -> Option<Vec<MbrEntry>> {
+    if read_u16(data, 510)? != MBR_SIGNATURE {
+        return None;
+    }
+
+    let mut entries = Vec::new();
+    for idx in 0..4 {
+        let entry = &data[MBR_TABLE_OFFSET + idx * MBR_ENTRY_SIZE..][..MBR_ENTRY_SIZE];
+        let first_sector = read_u32(entry, 8).unwrap() as u64;
+        let sectors = read_u32(entry, 12).unwrap() as u64;
+        if entry[4] == 0 {
+            continue;
+        }
+        if !matches!(entry[0], 0x00 | 0x80) || first_sector == 0 || sectors == 0 {
+            return None;
+        }
+        entries.push(MbrEntry {
+            bootable: entry[0] == 0x80,
+            partition_type: entry[4],
+            first_sector,
+            sectors,
+        });
+    }
+
+    Some(entries)
+}
+
+/// Returns the entries of the MBR of `data` if they are a plausible
+/// partition table of the disk image in `data`.
+fn mbr_entries(data: &[u8]) -> Option<Vec<MbrEntry>> {
+    let mut entries = parse_mbr(data)?;
+    if entries.is_empty() {
+        return None;
+    }
+
+    // Partitions start in the image and do not overlap.
+    let sectors = (data.len() / MBR_SECTOR_SIZE) as u64;
+    if entries.iter().any(|entry| entry.first_sector >= sectors) {
+        return None;
+    }
+    entries.sort_by_key(|entry| entry.first_sector);
+    if entries
+        .windows(2)
+        .any(|pair| pair[0].first_sector + pair[0].sectors > pair[1].first_sector)
+    {
+        return None;
+    }
+
+    Some(entries)
+}
+
+/// Returns the sector size of the disk image in `data` if it has a GPT
+/// header, which is in the second sector.
+fn gpt_sector_size(data: &[u8]) -> Option<usize> {
+    GPT_SECTOR_SIZES.into_iter().find_map(|sector_size| {
+        let header = data.get(sector_size..)?;
+        if !header.starts_with(GPT_MAGIC) {
+            return None;
+        }
+        let header_size = read_u32(header, 12)? as usize;
+        if !(GPT_MIN_HEADER_SIZE..=sector_size).contains(&header_size) {
+            return None;
+        }
+
+        // The checksum field counts as zero.
+        let mut zeroed = header.get(..header_size)?.to_vec();
+        zeroed[16..20].fill(0);
+        (crc32fast::hash(&zeroed) == read_u32(header, 16)?).then_some(sector_size)
+    })
+}
+
+pub fn is_disk_image(data: &[u8]) -> bool {
+    gpt_sector_size(data).is_some() || mbr_entries(data).is_some()
+}
+
+fn gpt_partitions(file: &str, data: &[u8], sector_size: usize) -> Vec<Partition> {
+    let header = &data[sector_size..];
+    let entries_sector = read_u64(header, 72).unwrap();
+    let count = read_u32(header, 80).unwrap() as usize;
+    let entry_size = read_u32(header, 84).unwrap() as usize;
+    if entry_size < GPT_MIN_ENTRY_SIZE {
+        warn!("{}: invalid GPT entry size {}", file, entry_size);
+        return Vec::new();
+    }
+
+    let Some(table) = (entries_sector as usize)
+        .checked_mul(sector_size)
+        .and_then(|start| data.get(start..start.checked_add(count.checked_mul(entry_size)?)?))
+    else {
+        warn!("{}: truncated GPT partition entries", file);
+        return Vec::new();
+    };
+    if crc32fast::hash(table) != read_u32(header, 88).unwrap() {
+        warn!("{}: GPT partition entries have an invalid checksum", file);
+        return Vec::new();
+    }
+
+    table
+        .chunks_exact(entry_size)
+        .filter(|entry| entry[..16].iter().any(|byte| *byte != 0))
+        .map(|entry| {
+            let first_sector = read_u64(entry, 32).unwrap();
+            let last_sector = read_u64(entry, 40).unwrap();
+            let units: Vec<u16> = entry[56..128]
+                .chunks_exact(2)
+                .map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+                .take_while(|unit| *unit != 0)
+                .collect();
+            let name = String::from_utf16_lossy(&units);
+
+            Partition {
+                partition_type: format_guid(&entry[..16]),
+                name: (!name.is_empty()).then_some(name),
+                bootable: read_u64(entry, 48).unwrap() & GPT_ATTRIBUTE_LEGACY_BOOTABLE != 0,
+                first_sector,
+                sectors: (last_sector + 1).saturating_sub(first_sector),
+                sector_size,
+            }
+        })
+        .collect()
+}
+
+fn mbr_partition(entry: &MbrEntry, base: u64) -> Partition {
+    Partition {
+        partition_type: format!("{:#04x}", entry.partition_type),
+        name: None,
+        bootable: entry.bootable,
+        first_sector: base + entry.first_sector,
+        sectors: entry.sectors,
+        sector_size: MBR_SECTOR_SIZE,
+    }
+}
+
+/// Returns the logical partitions in the extended partition at
+/// `extended_start`.
+fn logical_partitions(file: &str, data: &[u8], extended_start: u64) -> Vec<Partition> {
+    let mut partitions = Vec::new();
+    // EBRs link to the next one relative to the extended partition, and
+    // describe their partition relative to themselves.
+    let mut ebr = extended_start;
+    while partitions.len() < MAX_LOGICAL_PARTITIONS {
+        let Some(entries) = data
+            .get(ebr as usize * MBR_SECTOR_SIZE..)
+            .and_then(parse_mbr)
+        else {
+            warn!(
+                "{}: invalid extended boot record at sector {:#x}",
+                file, ebr
+            );
+            break;
+        };
+
+        let mut next = None;
+        for entry in entries.iter() {
+            if MBR_TYPES_EXTENDED.contains(&entry.partition_type) {
+                next = Some(extended_start + entry.first_sector);
+            } else {
+                partitions.push(mbr_partition(entry, ebr));
+            }
+        }
+        match next {
+            Some(sector) if sector > ebr => ebr = sector,
+            _ => break,
+        }
+    }
+
+    partitions
+}
+
+/// Returns the partitions of the disk image in `data`, which is the content
+/// of `file`.
+pub fn find_partitions(file: &str, data: &[u8]) -> Vec<(Member, Vec<u8>)> {
+    let mbr = mbr_entries(data).unwrap_or_default();
+    let (format, partitions) = match gpt_sector_size(data) {
+        Some(sector_size) => ("gpt", gpt_partitions(file, data, sector_size)),
+        None if mbr
+            .iter()
+            .any(|entry| entry.partition_type == MBR_TYPE_PROTECTIVE) =>
+        {
+            warn!("{}: protective MBR without a valid GPT header", file);
+            return Vec::new();
+        }
+        None => {
+            let mut partitions = Vec::new();
+            for entry in mbr.iter() {
+                if MBR_TYPES_EXTENDED.contains(&entry.partition_type) {
+                    partitions.extend(logical_partitions(file, data, entry.first_sector));
+                } else {
+                    partitions.push(mbr_partition(entry, 0));
+                }
+            }
+            ("mbr", partitions)
+        }
+    };
+    debug!(
+        "{}: {} disk image with {} partitions",
+        file,
+        format,
+        partitions.len()
+    );
+
+    partitions
+        .into_iter()
+        .enumerate()
+        .filter_map(|(idx, partition)| {
+            let range = partition.range();
+            let id = format!("p{}", idx + 1);
+            let start = range.start as usize;
+            let Some(content) = data.get(start..) else {
+                warn!(
+                    "{}: partition {} starts past the end of the image",
+                    file, id
+                );
+                return None;
+            };
+            let content = if range.end as usize > data.len() {
+                warn!("{}: partition {} is truncated", file, id);
+                content
+            } else {
+                &content[..(range.end - range.start) as usize]
+            };
+            if content.is_empty() {
+                return None;
+            }
+
+            Some((
+                Member {
+                    format: format.to_owned(),
+                    path: partition.name.clone().unwrap_or_else(|| id.clone()),
+                    id,
+                    offset: Some(start),
+                    load_address: None,
+                    partition: Some(partition),
+                },
+                content.to_vec(),
+            ))
+        })
+        .collect()
+}
@@ -0,0 +1,218 @@
+/*
+    Copyright 2024 - Valentin Obst <coderec@vpao.io>
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+*/
+//! Analysis of a single file, from the detection to the report.
+//!
+//! Embedding applications run a `Pipeline` on their data and can observe it
+//! through `Hooks` instead of parsing the serialized reports. The stages are:
+//!
+//! 1. detection of the arch of each window, see `--segmenter`,
+//! 2. splitting of unknown windows with mixed evidence, see `--split-mixed`,
+//! 3. refinement of region boundaries, see `--refine`,
+//! 4. verification by disassembly, see `--verify`,
+//! 5. labeling of text and unknown regions, see `--detect-text` and
+//!    `--classify-unknown`,
+//! 6. consolidation of the segments into the regions of the report.
+
+use crate::corpus::{corpus_hash, load_corpus, CorpusStats};
+use crate::entropy::label_unknown;
+use crate::output::{CliJsonOutput, ReportHeader};
+use crate::refine::refine_boundaries;
+use crate::split::split_mixed;
+use crate::text::label_text;
+#[cfg(feature = "verify")]
+use crate::verify::{verify_regions, Verification};
+use crate::{detect_code, DetectionOptions, ProcessedDetectionResult, Segment};
+
+use std::ops::Range;
+use std::sync::OnceLock;
+
+use log::{log, Level};
+
+/// Callbacks that observe a pipeline. All methods do nothing by default.
+pub trait Hooks: Sync {
+    /// Called whenever a window was analyzed, with the number of analyzed
+    /// windows and of all windows of the file. Windows are analyzed in
+    /// parallel, i.e., calls can come from any thread.
+    fn on_progress(&self, _file: &str, _done: usize, _total: usize) {}
+
+    /// Called for the verdict of each window, in file order, once the
+    /// detection is done.
+    fn on_window(&self, _file: &str, _segment: &Segment) {}
+
+    /// Called for each region of the report.
+    fn on_region(&self, _file: &str, _range: &Range<usize>, _arch: &str, _confidence: f64) {}
+
+    /// Called for warnings and other notable events, which are also logged.
+    fn on_diagnostic(&self, _file: &str, _level: Level, _message: &str) {}
+}
+
+/// Logs `message` and passes it to all `hooks`.
+pub(crate) fn diagnostic(hooks: &[Box<dyn Hooks>], file: &str, level: Level, message: &str) {
+    log!(level, "{}: {}", file, message);
+    for hooks in hooks {
+        hooks.on_diagnostic(file, level, message);
+    }
+}
+
+/// Optional stages that run after the detection.
+#[derive(Debug, Clone, Default)]
+pub struct ProcessingOptions {
+    /// Split unknown windows with mixed evidence down to this size.
+    pub split_min_size: Option<usize>,
+    /// Refine region boundaries with this granularity.
+    pub refine_granularity: Option<usize>,
+    /// Disassemble the regions and demote those with a lower share of valid
+    /// instructions. Requires the `verify` feature.
+    pub verify_min_ratio: Option<f64>,
+    /// Label regions of encoded text.
+    pub detect_text: bool,
+    /// Label unknown regions by their byte statistics.
+    pub classify_unknown: bool,
+}
+
+/// Results of all stages for a file.
+pub(crate) struct Analysis {
+    pub res: ProcessedDetectionResult,
+    #[cfg(feature = "verify")]
+    pub verification: Option<Vec<Verification>>,
+}
+
+pub struct Pipeline {
+    corpus: Vec<CorpusStats>,
+    /// Hash of `corpus` for the report headers, computed on first use.
+    corpus_hash: OnceLock<String>,
+    detection: DetectionOptions,
+    processing: ProcessingOptions,
+    hooks: Vec<Box<dyn Hooks>>,
+}
+
+impl Pipeline {
+    /// Pipeline that uses the embedded corpus.
+    pub fn new(detection: DetectionOptions, processing: ProcessingOptions) -> Self {
+        Self::with_corpus(load_corpus(), detection, processing)
+    }
+
+    /// Pipeline that uses the entries in `corpus`, e.g., the embedded corpus
+    /// extended by `load_corpus_dir`.
+    pub fn with_corpus(
+        corpus: Vec<CorpusStats>,
+        detection: DetectionOptions,
+        processing: ProcessingOptions,
+    ) -> Self {
+        Self {
+            corpus,
+            corpus_hash: OnceLock::new(),
+            detection,
+            processing,
+            hooks: Vec::new(),
+        }
+    }
+
+    pub fn add_hooks(&mut self, hooks: Box<dyn Hooks>) {
+        self.hooks.push(hooks);
+    }
+
+    pub(crate) fn corpus(&self) -> &[CorpusStats] {
+        &self.corpus
+    }
+
+    pub(crate) fn detection_options(&self) -> &DetectionOptions {
+        &self.detection
+    }
+
+    /// Runs all stages on `data` and returns the report.
+    pub fn process(&self, name: &str, data: &[u8]) -> CliJsonOutput {
+        let analysis = self.analyze(name, data, &[]);
+
+        self.report(name, &analysis)
+    }
+
+    /// Runs all stages up to the consolidation on `data`, in which the
+    /// `unreadable` ranges are zero-filled.
+    pub(crate) fn analyze(&self, name: &str, data: &[u8], unreadable: &[Range<usize>]) -> Analysis {
+        let mut res: ProcessedDetectionResult = detect_code(
+            &self.corpus,
+            data,
+            unreadable,
+            name,
+            &self.detection,
+            &self.hooks,
+        )
+        .into();
+        for hooks in self.hooks.iter() {
+            for segment in res.segments.iter() {
+                hooks.on_window(name, segment);
+            }
+        }
+
+        if let Some(min_size) = self.processing.split_min_size {
+            split_mixed(&self.corpus, data, &mut res, min_size);
+        }
+        if let Some(granularity) = self.processing.refine_granularity {
+            refine_boundaries(&self.corpus, data, &mut res, granularity);
+        }
+
+        #[cfg(feature = "verify")]
+        let verification = self
+            .processing
+            .verify_min_ratio
+            .map(|min_ratio| verify_regions(&mut res, data, min_ratio));
+        #[cfg(not(feature = "verify"))]
+        if self.processing.verify_min_ratio.is_some() {
+            diagnostic(
+                &self.hooks,
+                name,
+                Level::Warn,
+                "built without capstone support, skipping verification",
+            );
+        }
+
+        if self.processing.detect_text {
+            label_text(&mut res, data);
+        }
+        if self.processing.classify_unknown {
+            label_unknown(&mut res, data);
+        }
+
+        Analysis {
+            res,
+            #[cfg(feature = "verify")]
+            verification,
+        }
+    }
+
+    /// Consolidates the segments of `analysis` into the report.
+    pub(crate) fn report(&self, name: &str, analysis: &Analysis) -> CliJsonOutput {
+        let mut report = CliJsonOutput::from((name, &analysis.res));
+        report.header = Some(ReportHeader::new(
+            self.corpus.len(),
+            self.corpus_hash.get_or_init(|| corpus_hash(&self.corpus)),
+            &analysis.res,
+        ));
+        #[cfg(feature = "verify")]
+        {
+            report.verification = analysis.verification.clone();
+        }
+
+        for hooks in self.hooks.iter() {
+            for (range, _, arch, confidence) in report.regions() {
+                hooks.on_region(name, range, arch, confidence);
+            }
+        }
+
+        report
+    }
+}
@@ -14,13 +14,30 @@
     limitations under the License.
 */
 
-use crate::{CorpusStats, ProcessedDetectionResult, RangeResult};
+use crate::corpus::corpus_hash;
+use crate::dtb::DTB;
+use crate::endian;
+use crate::entropy::{entropy_curve, ByteStats, EntropyClass};
+use crate::input::UNREADABLE;
+use crate::interactive::{write_plot, Chart, Ngrams};
+use crate::matrix::CorpusMatrix;
+use crate::padding::PADDING;
+use crate::text::TextClass;
+use crate::{Arch, CorpusStats, ProcessedDetectionResult, RangeResult};
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::ops::Range;
+use std::str::FromStr;
 
 use itertools::Itertools;
-use log::info;
+use log::{info, warn};
 use plotters::coord::combinators::IntoLogRange;
+use plotters::coord::Shift;
 use plotters::prelude::full_palette::{GREY, ORANGE};
 use plotters::prelude::*;
+use serde::{Deserialize, Serialize};
 
 const RESOLUTION_3D: (u32, u32) = (3000, 3000);
 const MARGIN_3D: u32 = 100;
@@ -34,11 +51,325 @@ const CAPTION_STYLE_2D: (&str, u32, FontStyle, &RGBColor) =
 const LABEL_STYLE_2D: (&str, u32, FontStyle, &RGBColor) =
     ("Calibri", 12, FontStyle::Normal, &BLACK);
 
+const RESOLUTION_REGIONS: (u32, u32) = (5000, 500);
+const RESOLUTION_HTML: (u32, u32) = (1200, 600);
+/// Largest number of points of the entropy curve of the regions plot.
+const MAX_ENTROPY_POINTS: usize = 10_000;
+const HEATMAP_WIDTH: u32 = 3000;
+/// Height of the row of an arch in the heatmap.
+const HEATMAP_ROW: u32 = 12;
+const RESOLUTION_HILBERT: (u32, u32) = (1300, 1024);
+/// Largest side of the Hilbert curve in cells, a power of two.
+const MAX_HILBERT_SIDE: usize = 256;
+/// Width of the legend of the Hilbert curve plot.
+const HILBERT_LEGEND: u32 = 276;
+/// Side of the cell of a pair of arches in the corpus matrix.
+const MATRIX_CELL: u32 = 16;
+/// Room for the arch labels of the corpus matrix.
+const MATRIX_LABELS: u32 = 150;
+const MATRIX_MIN_SIDE: u32 = 600;
+
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+/// Offset of the horizontal and vertical resolution in the headers of a BMP
+/// file.
+const BMP_RESOLUTION: usize = 38;
+const INCH_PER_METER: f64 = 39.3701;
+
+/// File formats of the plots.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PlotFormat {
+    Png,
+    Svg,
+    Bmp,
+    /// Interactive plots, see `interactive`. Corpus plots are SVG files.
+    Html,
+}
+
+impl PlotFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            Self::Png => "png",
+            Self::Svg => "svg",
+            Self::Bmp => "bmp",
+            Self::Html => "html",
+        }
+    }
+}
+
+impl FromStr for PlotFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "png" => Ok(Self::Png),
+            "svg" => Ok(Self::Svg),
+            "bmp" => Ok(Self::Bmp),
+            "html" => Ok(Self::Html),
+            _ => Err(format!(
+                "unknown plot format {:?}, expected png, svg, bmp, or html",
+                s
+            )),
+        }
+    }
+}
+
+/// Output settings of all plots, each unset one keeps the default of the
+/// plot: SVG files of 3000x3000 pixels for divergences and the corpus, and
+/// PNG files of 5000x500 pixels for regions. HTML plots are 1200x600 pixels.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PlotOptions {
+    pub format: Option<PlotFormat>,
+    /// Width and height in pixels.
+    pub size: Option<(u32, u32)>,
+    /// Resolution for printing, embedded into the plots. SVG plots get a
+    /// size in inches instead of pixels.
+    pub dpi: Option<u32>,
+}
+
+/// Provenance of the plots, which is embedded into each plot file such that
+/// it can be traced back to how it was produced: tEXt chunks of PNG files and
+/// a comment at the start of SVG files.
+#[derive(Debug, Clone)]
+pub struct PlotMetadata {
+    entries: Vec<(String, String)>,
+    options: PlotOptions,
+}
+
+impl PlotMetadata {
+    pub fn new(corpus: &[CorpusStats], options: PlotOptions) -> Self {
+        Self {
+            options,
+            entries: vec![
+                (
+                    "Software".to_owned(),
+                    format!("coderec {}", env!("CARGO_PKG_VERSION")),
+                ),
+                ("coderec:corpus-sha256".to_owned(), corpus_hash(corpus)),
+            ],
+        }
+    }
+
+    /// Adds the parameters of the analysis of `file_name` to the metadata.
+    fn with_analysis(&self, file_name: &str, det_res: &ProcessedDetectionResult) -> Self {
+        let mut metadata = self.clone();
+        metadata.entries.extend(
+            [
+                ("file", file_name.to_owned()),
+                ("window-size", det_res.win_sz.to_string()),
+                ("stride", det_res.stride.to_string()),
+                ("metric", format!("{:?}", det_res.metric)),
+                ("thresholds", format!("{:?}", det_res.thresholds)),
+                ("filters", format!("{:?}", det_res.filters)),
+                ("consolidation", format!("{:?}", det_res.consolidation)),
+                (
+                    "min-region-length",
+                    format!("{:?}", det_res.min_region_length),
+                ),
+            ]
+            .into_iter()
+            .map(|(key, value)| (format!("coderec:{}", key), value)),
+        );
+
+        metadata
+    }
+
+    fn format(&self, default: PlotFormat) -> PlotFormat {
+        self.options.format.unwrap_or(default)
+    }
+
+    fn size(&self, default: (u32, u32)) -> (u32, u32) {
+        self.options.size.unwrap_or(default)
+    }
+
+    /// Format of the plots without an interactive version, i.e., the corpus
+    /// plots and the heatmap.
+    fn static_format(&self, default: PlotFormat) -> PlotFormat {
+        match self.format(default) {
+            PlotFormat::Html => default,
+            format => format,
+        }
+    }
+
+    fn plot_name(&self, stem: &str, format: PlotFormat) -> String {
+        format!("{}.{}", stem, format.extension())
+    }
+
+    /// Embeds the metadata and the resolution into the plot at `path`,
+    /// failures are only logged as the plot itself is fine. BMP files have no
+    /// place for the metadata.
+    fn embed(&self, path: &str) {
+        let res = fs::read(path).and_then(|mut data| {
+            let data = if path.ends_with(".png") {
+                self.embed_png(&data)?
+            } else if path.ends_with(".bmp") {
+                if let Some(dpi) = self.options.dpi {
+                    let ppm = (dpi as f64 * INCH_PER_METER).round() as u32;
+                    for offset in [BMP_RESOLUTION, BMP_RESOLUTION + 4] {
+                        data.get_mut(offset..offset + 4)
+                            .ok_or_else(|| {
+                                io::Error::new(io::ErrorKind::InvalidData, "not a BMP file")
+                            })?
+                            .copy_from_slice(&ppm.to_le_bytes());
+                    }
+                }
+                data
+            } else if path.ends_with(".html") {
+                self.embed_html(&data)
+            } else {
+                self.embed_svg(&data)
+            };
+            fs::write(path, data)
+        });
+        if let Err(e) = res {
+            warn!("{}: could not embed metadata: {}", path, e);
+        }
+    }
+
+    /// Inserts a tEXt chunk per entry after the IHDR chunk, which must be
+    /// the first one. Characters outside of Latin-1 are replaced.
+    fn embed_png(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        let ihdr_end = data
+            .get(PNG_SIGNATURE.len()..PNG_SIGNATURE.len() + 4)
+            .filter(|_| data.starts_with(PNG_SIGNATURE))
+            .map(|len| {
+                PNG_SIGNATURE.len() + 12 + u32::from_be_bytes(len.try_into().unwrap()) as usize
+            })
+            .filter(|end| *end <= data.len())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "not a PNG file"))?;
+
+        let mut embedded = data[..ihdr_end].to_vec();
+        for (key, value) in self.entries.iter() {
+            let mut chunk = b"tEXt".to_vec();
+            chunk.extend(key.bytes());
+            chunk.push(0);
+            chunk.extend(value.chars().map(|c| u8::try_from(c).unwrap_or(b'?')));
+
+            push_png_chunk(&mut embedded, chunk);
+        }
+        if let Some(dpi) = self.options.dpi {
+            // Pixels per meter on both axes.
+            let ppm = (dpi as f64 * INCH_PER_METER).round() as u32;
+            let mut chunk = b"pHYs".to_vec();
+            chunk.extend(ppm.to_be_bytes());
+            chunk.extend(ppm.to_be_bytes());
+            chunk.push(1);
+            push_png_chunk(&mut embedded, chunk);
+        }
+        embedded.extend(&data[ihdr_end..]);
+
+        Ok(embedded)
+    }
+
+    /// Inserts a comment with one line per entry after the XML declaration,
+    /// if any. With a resolution, the size of
+    /// the root element is given in inches, its view box keeps the pixels.
+    fn embed_svg(&self, data: &[u8]) -> Vec<u8> {
+        let start = if data.starts_with(b"<?xml") {
+            data.windows(2)
+                .position(|window| window == b"?>")
+                .map_or(0, |idx| idx + 2)
+        } else {
+            0
+        };
+
+        let mut embedded = data[..start].to_vec();
+        embedded.extend(self.comment().as_bytes());
+        match self.options.dpi {
+            Some(dpi) => embedded.extend(svg_in_inches(&data[start..], dpi)),
+            None => embedded.extend(&data[start..]),
+        }
+
+        embedded
+    }
+
+    /// Inserts a comment like that of SVG files after the first line, the
+    /// document type declaration, which must come first.
+    fn embed_html(&self, data: &[u8]) -> Vec<u8> {
+        let start = data
+            .iter()
+            .position(|b| *b == b'\n')
+            .map_or(data.len(), |idx| idx + 1);
+
+        let mut embedded = data[..start].to_vec();
+        embedded.extend(self.comment().as_bytes());
+        embedded.extend(&data[start..]);
+
+        embedded
+    }
+
+    /// Returns an XML comment with one line per entry. Comments must not
+    /// contain `--`.
+    fn comment(&self) -> String {
+        let mut comment = String::from("<!--\n");
+        for (key, value) in self.entries.iter() {
+            comment.push_str(&format!("{}: {}\n", key, value).replace("--", "- -"));
+        }
+        comment.push_str("-->\n");
+
+        comment
+    }
+}
+
+/// Appends `chunk`, its type followed by its data, to the PNG file `data`.
+fn push_png_chunk(data: &mut Vec<u8>, chunk: Vec<u8>) {
+    data.extend((chunk.len() as u32 - 4).to_be_bytes());
+    data.extend(&chunk);
+    data.extend(crc32fast::hash(&chunk).to_be_bytes());
+}
+
+/// Replaces the width and height in pixels of the first `svg` element of
+/// `data` by those in inches at `dpi`.
+fn svg_in_inches(data: &[u8], dpi: u32) -> Vec<u8> {
+    let Some(tag) = data
+        .windows(4)
+        .position(|window| window == b"<svg")
+        .and_then(|start| {
+            let end = start + data[start..].iter().position(|b| *b == b'>')?;
+            Some(start..end)
+        })
+    else {
+        return data.to_vec();
+    };
+
+    let mut element = String::from_utf8_lossy(&data[tag.clone()]).into_owned();
+    for attribute in ["width", "height"] {
+        let prefix = format!(" {}=\"", attribute);
+        let Some(value_start) = element.find(&prefix).map(|idx| idx + prefix.len()) else {
+            continue;
+        };
+        let Some(value_end) = element[value_start..]
+            .find('"')
+            .map(|idx| idx + value_start)
+        else {
+            continue;
+        };
+        if let Ok(pixels) = element[value_start..value_end].parse::<f64>() {
+            let inches = format!("{:.3}in", pixels / dpi as f64);
+            element.replace_range(value_start..value_end, &inches);
+        }
+    }
+
+    let mut converted = data[..tag.start].to_vec();
+    converted.extend(element.as_bytes());
+    converted.extend(&data[tag.end..]);
+
+    converted
+}
+
 impl CorpusStats {
-    pub fn plot_tg(&self) {
-        let plot_name = format!("{}_tg.svg", self.arch);
+    pub fn plot_tg(&self, metadata: &PlotMetadata) {
+        let format = metadata.static_format(PlotFormat::Svg);
+        let plot_name = metadata.plot_name(&format!("{}_tg", self.arch), format);
+        let size = metadata.size(RESOLUTION_3D);
+        match format {
+            PlotFormat::Svg => self.draw_tg(SVGBackend::new(&plot_name, size).into_drawing_area()),
+            _ => self.draw_tg(BitMapBackend::new(&plot_name, size).into_drawing_area()),
+        }
+        metadata.embed(&plot_name);
+    }
 
-        let drawing_area = SVGBackend::new(&plot_name, RESOLUTION_3D).into_drawing_area();
+    fn draw_tg<DB: DrawingBackend>(&self, drawing_area: DrawingArea<DB, Shift>) {
         drawing_area.fill(&WHITE).unwrap();
 
         let mut chart_builder = ChartBuilder::on(&drawing_area);
@@ -92,11 +423,24 @@ impl CorpusStats {
             .z_labels(20)
             .draw()
             .unwrap();
+
+        drawing_area.present().unwrap();
     }
 
-    pub fn plot_cond_prob(&self) {
-        let plot_name = format!("{}_cond_prob.svg", self.arch);
-        let drawing_area = SVGBackend::new(&plot_name, RESOLUTION_3D).into_drawing_area();
+    pub fn plot_cond_prob(&self, metadata: &PlotMetadata) {
+        let format = metadata.static_format(PlotFormat::Svg);
+        let plot_name = metadata.plot_name(&format!("{}_cond_prob", self.arch), format);
+        let size = metadata.size(RESOLUTION_3D);
+        match format {
+            PlotFormat::Svg => {
+                self.draw_cond_prob(SVGBackend::new(&plot_name, size).into_drawing_area())
+            }
+            _ => self.draw_cond_prob(BitMapBackend::new(&plot_name, size).into_drawing_area()),
+        }
+        metadata.embed(&plot_name);
+    }
+
+    fn draw_cond_prob<DB: DrawingBackend>(&self, drawing_area: DrawingArea<DB, Shift>) {
         drawing_area.fill(&WHITE).unwrap();
 
         let mut chart_builder = ChartBuilder::on(&drawing_area);
@@ -141,6 +485,8 @@ impl CorpusStats {
             .z_labels(20)
             .draw()
             .unwrap();
+
+        drawing_area.present().unwrap();
     }
 }
 
@@ -152,6 +498,29 @@ fn arch_idx_to_color(arch_idx: usize) -> RGBAColor {
     ))
 }
 
+/// Returns the color of `arch` in the plots of `det_res`. Pseudo-arches of
+/// entropy and text classes, padding, device tree blobs, and unreadable
+/// ranges get the colors after those of the corpus entries.
+pub fn arch_color(det_res: &ProcessedDetectionResult, arch: &str) -> RGBAColor {
+    let arch_to_idx = &det_res.arch_to_idx;
+    let arch_idx = match arch_to_idx.get(arch) {
+        Some(arch_idx) => *arch_idx,
+        None => {
+            arch_to_idx.len()
+                + EntropyClass::ALL
+                    .iter()
+                    .map(|class| class.label())
+                    .chain(TextClass::ALL.iter().map(|class| class.label()))
+                    .chain([PADDING, DTB, UNREADABLE])
+                    .position(|label| label == arch)
+                    .unwrap()
+        }
+    };
+
+    arch_idx_to_color(arch_idx)
+}
+
+/// Returns the name of the generated plot.
 pub fn plot_regions(
     file_name: &str,
     file_len: usize,
@@ -159,184 +528,344 @@ pub fn plot_regions(
     det_res: &ProcessedDetectionResult,
     big_file: bool,
     base_address: u64,
-) {
-    let win_sz = det_res.win_sz;
-    let arch_to_idx = &det_res.arch_to_idx;
-    let arch_to_best_map = &det_res.arch_to_final_ranges;
-
+    metadata: &PlotMetadata,
+) -> String {
     let file_name = file_name.split("/").last().unwrap();
-    let plot_name = format!("{}_w{}_regions.png", file_name, win_sz);
+    let format = metadata.format(PlotFormat::Png);
+    let plot_name = metadata.plot_name(
+        &format!("{}_w{}_regions", file_name, det_res.win_sz),
+        format,
+    );
+    // Entropy of the analyzed windows, or of coarser ones for large files.
+    let stride = det_res.stride.max(file_len.div_ceil(MAX_ENTROPY_POINTS));
+    let entropy = entropy_curve(file_bytes, det_res.win_sz.max(stride), stride);
+    let regions = RegionsPlot {
+        file_name,
+        file_len,
+        file_bytes,
+        det_res,
+        big_file,
+        base_address,
+        entropy,
+    };
+    let size = metadata.size(RESOLUTION_REGIONS);
+    match format {
+        PlotFormat::Svg => regions.draw(SVGBackend::new(&plot_name, size).into_drawing_area()),
+        PlotFormat::Png | PlotFormat::Bmp => {
+            regions.draw(BitMapBackend::new(&plot_name, size).into_drawing_area())
+        }
+        PlotFormat::Html => write_plot(
+            &plot_name,
+            &format!("{}, regions", file_name),
+            det_res,
+            file_len,
+            base_address,
+            metadata.size(RESOLUTION_HTML),
+            Chart::Regions {
+                entropy: &regions.entropy,
+            },
+        )
+        .unwrap(),
+    }
+    metadata.with_analysis(file_name, det_res).embed(&plot_name);
 
-    let root = BitMapBackend::new(&plot_name, (5000, 500)).into_drawing_area();
-    root.fill(&WHITE).unwrap();
+    plot_name
+}
 
-    let mut chart = ChartBuilder::on(&root)
-        .caption(format!("{}, regions", file_name), CAPTION_STYLE_2D)
-        .margin(5)
-        .top_x_label_area_size(40)
-        .x_label_area_size(40)
-        .y_label_area_size(40)
-        .right_y_label_area_size(40)
-        .build_cartesian_2d(0..file_len, 0..256)
-        .unwrap();
+/// Arguments of `plot_regions`.
+struct RegionsPlot<'a> {
+    file_name: &'a str,
+    file_len: usize,
+    file_bytes: &'a [u8],
+    det_res: &'a ProcessedDetectionResult,
+    big_file: bool,
+    base_address: u64,
+    /// Entropy in bits per byte by offset, on the secondary axis.
+    entropy: Vec<(usize, f64)>,
+}
 
-    let binding = |coord: (usize, i32), size, style| {
-        EmptyElement::at(coord) + Circle::new((0, 0), size, style)
-    };
-    for (arch, ranges) in arch_to_best_map.iter() {
-        let arch_idx = *arch_to_idx.get(arch).unwrap();
-        let style = arch_idx_to_color(arch_idx);
+impl RegionsPlot<'_> {
+    fn draw<DB: DrawingBackend>(&self, root: DrawingArea<DB, Shift>) {
+        let RegionsPlot {
+            file_name,
+            file_len,
+            file_bytes,
+            det_res,
+            big_file,
+            base_address,
+            ..
+        } = *self;
+        let arch_to_best_map = &det_res.arch_to_final_segments;
+
+        root.fill(&WHITE).unwrap();
 
+        let mut chart = ChartBuilder::on(&root)
+            .caption(format!("{}, regions", file_name), CAPTION_STYLE_2D)
+            .margin(5)
+            .top_x_label_area_size(40)
+            .x_label_area_size(40)
+            .y_label_area_size(40)
+            .right_y_label_area_size(40)
+            .build_cartesian_2d(0..file_len, 0..256)
+            .unwrap()
+            .set_secondary_coord(0..file_len, 0.0..8.0);
+
+        let binding = |coord: (usize, i32), size, style| {
+            EmptyElement::at(coord) + Circle::new((0, 0), size, style)
+        };
+        for (arch, segments) in arch_to_best_map.iter() {
+            let style = arch_color(det_res, arch);
+            let label = match endian::detect(arch, &det_res.segment_divs(segments.iter())) {
+                Some(info) => format!(
+                    "{} ({}, swap {:.2})",
+                    arch, info.endianness, info.swap_confidence
+                ),
+                None => arch.clone(),
+            };
+
+            if !big_file {
+                let arch_ranges_bytes_ser = PointSeries::of_element(
+                    segments
+                        .iter()
+                        .flat_map(|segment| segment.range.clone())
+                        .map(|offset| (offset, file_bytes[offset] as i32)),
+                    2,
+                    style,
+                    &binding,
+                );
+                chart
+                    .draw_series(arch_ranges_bytes_ser)
+                    .unwrap()
+                    .label(&label)
+                    .legend(move |(x, y)| {
+                        Rectangle::new([(x - 10, y + 10), (x, y)], style.filled())
+                    });
+            } else {
+                chart
+                    .draw_series(segments.iter().flat_map(|segment| {
+                        let (range, window) = (&segment.range, &segment.window);
+                        // Encode information about the absolute divergence of the
+                        // closest arch in bi- and trigrams. Also highlight cases
+                        // where bi- and trigrams disagreed.
+                        const MAX_DIV_BEST_BG: f64 = 10.0;
+                        const MAX_DIV_BEST_TG: f64 = 10.0;
+
+                        let (Some(res_bg), Some(res_tg)) = (
+                            det_res.range_to_result_bg.get(window),
+                            det_res.range_to_result_tg.get(window),
+                        ) else {
+                            // Windows of padding were not analyzed, fill the
+                            // whole height.
+                            return [
+                                Rectangle::new(
+                                    [(range.start, 0), (range.end, 128)],
+                                    style.filled(),
+                                ),
+                                Rectangle::new(
+                                    [(range.start, 128), (range.end, 255)],
+                                    style.filled(),
+                                ),
+                            ]
+                            .into_iter();
+                        };
+
+                        let style_bg = if arch == &res_bg.arch {
+                            style
+                        } else {
+                            RGBAColor::from(GREY)
+                        };
+                        let style_tg = if arch == &res_tg.arch {
+                            style
+                        } else {
+                            RGBAColor::from(GREY)
+                        };
+
+                        let mut range_res_bg =
+                            (12.8 * (MAX_DIV_BEST_BG - res_bg.div.floor())) as i32;
+                        let mut range_res_tg =
+                            256 - (12.8 * (MAX_DIV_BEST_TG - res_tg.div.floor())) as i32;
+
+                        if range_res_bg < 0 {
+                            range_res_bg = 1;
+                        }
+                        if range_res_tg < 0 {
+                            range_res_tg = 254;
+                        }
+
+                        [
+                            Rectangle::new(
+                                [(range.start, 0), (range.end, range_res_bg)],
+                                style_bg.filled(),
+                            ),
+                            Rectangle::new(
+                                [(range.start, range_res_tg), (range.end, 255)],
+                                style_tg.filled(),
+                            ),
+                        ]
+                        .into_iter()
+                    }))
+                    .unwrap()
+                    .label(&label)
+                    .legend(move |(x, y)| {
+                        Rectangle::new([(x - 10, y + 10), (x, y)], style.filled())
+                    });
+            }
+        }
         if !big_file {
             let arch_ranges_bytes_ser = PointSeries::of_element(
-                ranges
+                det_res
+                    .segments
                     .iter()
-                    .flat_map(|range| range.clone())
+                    .filter(|segment| segment.arch.is_none())
+                    .flat_map(|segment| segment.range.clone())
                     .map(|offset| (offset, file_bytes[offset] as i32)),
                 2,
-                style,
+                GREY,
                 &binding,
             );
             chart
                 .draw_series(arch_ranges_bytes_ser)
                 .unwrap()
-                .label(arch)
-                .legend(move |(x, y)| Rectangle::new([(x - 10, y + 10), (x, y)], style.filled()));
+                .label("unknown")
+                .legend(move |(x, y)| Rectangle::new([(x - 10, y + 10), (x, y)], GREY.filled()));
         } else {
             chart
-                .draw_series(ranges.iter().flat_map(|range| {
-                    // Encode information about the absolute divergence of the
-                    // closest arch in bi- and trigrams. Also highlight cases
-                    // where bi- and trigrams disagreed.
-                    const MAX_DIV_BEST_BG: f64 = 10.0;
-                    const MAX_DIV_BEST_TG: f64 = 10.0;
-
-                    let style_bg = if arch == &det_res.range_to_result_bg.get(range).unwrap().arch {
-                        style
-                    } else {
-                        RGBAColor::from(GREY)
-                    };
-                    let style_tg = if arch == &det_res.range_to_result_tg.get(range).unwrap().arch {
-                        style
-                    } else {
-                        RGBAColor::from(GREY)
-                    };
-
-                    let mut range_res_bg = (12.8
-                        * (MAX_DIV_BEST_BG
-                            - det_res.range_to_result_bg.get(range).unwrap().div.floor()))
-                        as i32;
-                    let mut range_res_tg = 256 - (12.8
-                        * (MAX_DIV_BEST_TG
-                            - det_res.range_to_result_tg.get(range).unwrap().div.floor()))
-                        as i32;
-
-                    if range_res_bg < 0 {
-                        range_res_bg = 1;
-                    }
-                    if range_res_tg < 0 {
-                        range_res_tg = 254;
-                    }
-
-                    [
-                        Rectangle::new(
-                            [(range.start, 0), (range.end, range_res_bg)],
-                            style_bg.filled(),
-                        ),
-                        Rectangle::new(
-                            [(range.start, range_res_tg), (range.end, 255)],
-                            style_tg.filled(),
-                        ),
-                    ].into_iter()
-                }))
+                .draw_series(
+                    det_res
+                        .segments
+                        .iter()
+                        .filter(|segment| segment.arch.is_none())
+                        .map(|segment| &segment.range)
+                        .map(|range| {
+                            Rectangle::new([(range.start, 0), (range.end, 255)], GREY.filled())
+                        }),
+                )
                 .unwrap()
-                .label(arch)
-                .legend(move |(x, y)| Rectangle::new([(x - 10, y + 10), (x, y)], style.filled()));
+                .label("unknown")
+                .legend(move |(x, y)| Rectangle::new([(x - 10, y + 10), (x, y)], GREY.filled()));
         }
-    }
-    if !big_file {
-        let arch_ranges_bytes_ser = PointSeries::of_element(
-            det_res
-                .range_to_final_result
-                .iter()
-                .filter_map(|(range, arch_op)| match arch_op {
-                    None => Some(range),
-                    _ => None,
-                })
-                .flat_map(|range| range.clone())
-                .map(|offset| (offset, file_bytes[offset] as i32)),
-            2,
-            GREY,
-            &binding,
-        );
+        // Not black, which is the color of the first arch.
         chart
-            .draw_series(arch_ranges_bytes_ser)
+            .draw_secondary_series(LineSeries::new(
+                self.entropy.iter().copied(),
+                RED.stroke_width(2),
+            ))
             .unwrap()
-            .label("unknown")
-            .legend(move |(x, y)| Rectangle::new([(x - 10, y + 10), (x, y)], GREY.filled()));
-    } else {
+            .label("entropy")
+            .legend(|(x, y)| PathElement::new([(x - 10, y + 5), (x, y + 5)], RED));
+
         chart
-            .draw_series(
-                det_res
-                    .range_to_final_result
-                    .iter()
-                    .filter_map(|(range, arch_op)| match arch_op {
-                        None => Some(range),
-                        _ => None,
-                    })
-                    .map(|range| {
-                        Rectangle::new([(range.start, 0), (range.end, 255)], GREY.filled())
-                    }),
-            )
-            .unwrap()
-            .label("unknown")
-            .legend(move |(x, y)| Rectangle::new([(x - 10, y + 10), (x, y)], GREY.filled()));
-    }
-
-    chart
-        .configure_series_labels()
-        .position(SeriesLabelPosition::UpperRight)
-        .margin(20)
-        .legend_area_size(5)
-        .border_style(BLUE)
-        .background_style(BLUE.mix(0.1))
-        .label_font(LABEL_STYLE_2D)
-        .draw()
-        .unwrap();
-    chart
-        .configure_mesh()
-        .x_labels(100)
-        .y_labels(24)
-        .max_light_lines(4)
-        .x_label_formatter(&|offset| format!("{:x}", { *offset + base_address as usize }))
-        .y_label_formatter(&|offset| format!("{:x}", *offset as usize))
-        .label_style(LABEL_STYLE_2D)
-        .draw()
-        .unwrap();
+            .configure_series_labels()
+            .position(SeriesLabelPosition::UpperRight)
+            .margin(20)
+            .legend_area_size(5)
+            .border_style(BLUE)
+            .background_style(BLUE.mix(0.1))
+            .label_font(LABEL_STYLE_2D)
+            .draw()
+            .unwrap();
+        chart
+            .configure_mesh()
+            .x_labels(100)
+            .y_labels(24)
+            .max_light_lines(4)
+            .x_label_formatter(&|offset| format!("{:x}", { *offset + base_address as usize }))
+            .y_label_formatter(&|offset| format!("{:x}", *offset as usize))
+            .label_style(LABEL_STYLE_2D)
+            .draw()
+            .unwrap();
+        chart
+            .configure_secondary_axes()
+            .x_labels(100)
+            .x_label_formatter(&|offset| format!("{:x}", { *offset + base_address as usize }))
+            .y_labels(9)
+            .y_label_formatter(&|bits| format!("{:.0}", bits))
+            .label_style(LABEL_STYLE_2D)
+            .draw()
+            .unwrap();
 
-    root.present().unwrap();
+        root.present().unwrap();
+    }
+}
+
+/// Axis of divergences on a log scale, which must neither start at zero nor
+/// be empty, as is the case for files without windows.
+fn log_range(min: f64, max: f64) -> Range<f64> {
+    let min = min.max(0.1);
+    min..max.max(min * 10.0)
 }
 
-pub fn plot_divs(file_name: &str, file_len: usize, det_res: &ProcessedDetectionResult) {
+/// Returns the names of the generated plots.
+pub fn plot_divs(
+    file_name: &str,
+    file_len: usize,
+    det_res: &ProcessedDetectionResult,
+    metadata: &PlotMetadata,
+) -> [String; 2] {
+    let file_name = file_name.split("/").last().unwrap();
+    let format = metadata.format(PlotFormat::Svg);
+    let plot_name_bg = metadata.plot_name(&format!("{}_w{}_bg", file_name, det_res.win_sz), format);
+    let plot_name_tg = metadata.plot_name(&format!("{}_w{}_tg", file_name, det_res.win_sz), format);
+
+    info!("Generating: {}, {}", plot_name_bg, plot_name_tg);
+
+    let size = metadata.size(RESOLUTION_3D);
+    match format {
+        PlotFormat::Svg => draw_divs(
+            file_name,
+            file_len,
+            det_res,
+            SVGBackend::new(&plot_name_bg, size).into_drawing_area(),
+            SVGBackend::new(&plot_name_tg, size).into_drawing_area(),
+        ),
+        PlotFormat::Png | PlotFormat::Bmp => draw_divs(
+            file_name,
+            file_len,
+            det_res,
+            BitMapBackend::new(&plot_name_bg, size).into_drawing_area(),
+            BitMapBackend::new(&plot_name_tg, size).into_drawing_area(),
+        ),
+        PlotFormat::Html => {
+            for (plot_name, ngrams, label) in [
+                (&plot_name_bg, Ngrams::Bigrams, "bigrams"),
+                (&plot_name_tg, Ngrams::Trigrams, "trigrams"),
+            ] {
+                write_plot(
+                    plot_name,
+                    &format!("{}, w{}, {}", file_name, det_res.win_sz, label),
+                    det_res,
+                    file_len,
+                    0,
+                    metadata.size(RESOLUTION_HTML),
+                    Chart::Divs(ngrams),
+                )
+                .unwrap();
+            }
+        }
+    }
+    let metadata = metadata.with_analysis(file_name, det_res);
+    metadata.embed(&plot_name_bg);
+    metadata.embed(&plot_name_tg);
+
+    [plot_name_bg, plot_name_tg]
+}
+
+fn draw_divs<DB: DrawingBackend>(
+    file_name: &str,
+    file_len: usize,
+    det_res: &ProcessedDetectionResult,
+    drawing_area_bg: DrawingArea<DB, Shift>,
+    drawing_area_tg: DrawingArea<DB, Shift>,
+) {
     let win_sz = det_res.win_sz;
-    let max_kl_bg = det_res.max_kl_bg;
-    let min_kl_bg = det_res.min_kl_bg;
-    let max_kl_tg = det_res.max_kl_tg;
-    let min_kl_tg = det_res.min_kl_tg;
+    let kl_range_bg = log_range(det_res.min_kl_bg, det_res.max_kl_bg);
+    let kl_range_tg = log_range(det_res.min_kl_tg, det_res.max_kl_tg);
     let range_to_result_bg = &det_res.range_to_result_bg;
     let range_to_result_tg = &det_res.range_to_result_tg;
     let arch_to_idx = &det_res.arch_to_idx;
     let idx_to_arch = &det_res.idx_to_arch;
 
-    let file_name = file_name.split("/").last().unwrap();
-    let plot_name_bg = format!("{}_w{}_bg.svg", file_name, win_sz);
-    let plot_name_tg = format!("{}_w{}_tg.svg", file_name, win_sz);
-
-    info!("Generating: {}, {}", plot_name_bg, plot_name_tg);
-
-    let drawing_area_bg = SVGBackend::new(&plot_name_bg, RESOLUTION_3D).into_drawing_area();
     drawing_area_bg.fill(&WHITE).unwrap();
-    let drawing_area_tg = SVGBackend::new(&plot_name_tg, RESOLUTION_3D).into_drawing_area();
     drawing_area_tg.fill(&WHITE).unwrap();
 
     let mut chart_builder_bg = ChartBuilder::on(&drawing_area_bg);
@@ -359,14 +888,14 @@ pub fn plot_divs(file_name: &str, file_len: usize, det_res: &ProcessedDetectionR
     let mut chart_context_bg = chart_builder_bg
         .build_cartesian_3d(
             0..det_res.kl_arch_to_range_bg.len(),
-            (min_kl_bg..max_kl_bg).log_scale(),
+            kl_range_bg.log_scale(),
             0.0..(file_len as f64),
         )
         .unwrap();
     let mut chart_context_tg = chart_builder_tg
         .build_cartesian_3d(
             0..det_res.kl_arch_to_range_tg.len(),
-            (min_kl_tg..max_kl_tg).log_scale(),
+            kl_range_tg.log_scale(),
             0.0..(file_len as f64),
         )
         .unwrap();
@@ -427,7 +956,7 @@ pub fn plot_divs(file_name: &str, file_len: usize, det_res: &ProcessedDetectionR
         EmptyElement::at(coord)
             + Circle::new((0, 0), size, style)
             + Text::new(
-                if ((coord.2 as usize).next_multiple_of(win_sz) / win_sz) % 0x4 == 0 {
+                if (coord.2 as usize).div_ceil(win_sz).is_multiple_of(0x4) {
                     idx_to_arch.get(&coord.0).unwrap().to_string()
                 } else {
                     String::from("")
@@ -470,7 +999,7 @@ pub fn plot_divs(file_name: &str, file_len: usize, det_res: &ProcessedDetectionR
     chart_context_bg
         .configure_axes()
         .z_formatter(&|offset| format!("{:x}", *offset as usize))
-        .x_formatter(&|arch_idx| idx_to_arch.get(arch_idx).unwrap().to_owned())
+        .x_formatter(&|arch_idx| idx_to_arch.get(arch_idx).cloned().unwrap_or_default())
         .tick_size(15)
         .x_max_light_lines(10)
         .y_max_light_lines(20)
@@ -485,7 +1014,7 @@ pub fn plot_divs(file_name: &str, file_len: usize, det_res: &ProcessedDetectionR
     chart_context_tg
         .configure_axes()
         .z_formatter(&|offset| format!("{:x}", *offset as usize))
-        .x_formatter(&|arch_idx| idx_to_arch.get(arch_idx).unwrap().to_owned())
+        .x_formatter(&|arch_idx| idx_to_arch.get(arch_idx).cloned().unwrap_or_default())
         .tick_size(15)
         .x_max_light_lines(10)
         .y_max_light_lines(20)
@@ -496,4 +1025,424 @@ pub fn plot_divs(file_name: &str, file_len: usize, det_res: &ProcessedDetectionR
         .z_labels(20)
         .draw()
         .unwrap();
+
+    drawing_area_bg.present().unwrap();
+    drawing_area_tg.present().unwrap();
+}
+
+/// Returns the name of the generated heatmap of the divergence of each arch
+/// in each window, by bigrams and trigrams. Adjacent windows are merged into
+/// one column per pixel by the mean of the logarithm of their divergences.
+pub fn plot_heatmap(
+    file_name: &str,
+    file_len: usize,
+    det_res: &ProcessedDetectionResult,
+    base_address: u64,
+    metadata: &PlotMetadata,
+) -> String {
+    let file_name = file_name.split("/").last().unwrap();
+    let format = metadata.static_format(PlotFormat::Png);
+    let plot_name = metadata.plot_name(
+        &format!("{}_w{}_heatmap", file_name, det_res.win_sz),
+        format,
+    );
+    let arches = det_res.arch_to_idx.len() as u32;
+    let size = metadata.size((HEATMAP_WIDTH, 2 * (arches * HEATMAP_ROW + 100)));
+    match format {
+        PlotFormat::Svg => draw_heatmap(
+            file_name,
+            file_len,
+            det_res,
+            base_address,
+            SVGBackend::new(&plot_name, size).into_drawing_area(),
+        ),
+        _ => draw_heatmap(
+            file_name,
+            file_len,
+            det_res,
+            base_address,
+            BitMapBackend::new(&plot_name, size).into_drawing_area(),
+        ),
+    }
+    metadata.with_analysis(file_name, det_res).embed(&plot_name);
+
+    plot_name
+}
+
+/// Cell of the heatmap: the range of the column, the arch index, and the
+/// mean logarithm of the divergences.
+type HeatmapCell = (Range<usize>, usize, f64);
+
+fn heatmap_cells(
+    file_len: usize,
+    det_res: &ProcessedDetectionResult,
+    kl_range_to_arch: &HashMap<Range<usize>, Vec<(Arch, f64)>>,
+    columns: usize,
+) -> Vec<HeatmapCell> {
+    let mut windows: Vec<&Range<usize>> = kl_range_to_arch.keys().collect();
+    windows.sort_unstable_by_key(|window| window.start);
+    let columns = columns.clamp(1, windows.len().max(1));
+
+    let mut cells = Vec::new();
+    for column in 0..columns {
+        let first = column * windows.len() / columns;
+        let next = (column + 1) * windows.len() / columns;
+        if first == next {
+            continue;
+        }
+        let range = windows[first].start..windows.get(next).map_or(file_len, |w| w.start);
+
+        let mut sums = vec![(0.0, 0usize); det_res.arch_to_idx.len()];
+        for window in windows[first..next].iter() {
+            for (arch, div) in kl_range_to_arch[*window].iter() {
+                if div.is_finite() && *div > 0.0 {
+                    let sum = &mut sums[det_res.arch_to_idx[arch]];
+                    sum.0 += div.ln();
+                    sum.1 += 1;
+                }
+            }
+        }
+        cells.extend(
+            sums.into_iter()
+                .enumerate()
+                .filter(|(_, (_, n))| *n > 0)
+                .map(|(arch_idx, (sum, n))| (range.clone(), arch_idx, sum / n as f64)),
+        );
+    }
+
+    cells
+}
+
+fn draw_heatmap<DB: DrawingBackend>(
+    file_name: &str,
+    file_len: usize,
+    det_res: &ProcessedDetectionResult,
+    base_address: u64,
+    root: DrawingArea<DB, Shift>,
+) {
+    let arches = det_res.arch_to_idx.len();
+    root.fill(&WHITE).unwrap();
+
+    let panels = root.split_evenly((2, 1));
+    for (area, (ngrams, kl_range_to_arch)) in panels.iter().zip([
+        ("bigrams", &det_res.kl_range_to_arch_bg),
+        ("trigrams", &det_res.kl_range_to_arch_tg),
+    ]) {
+        let mut chart = ChartBuilder::on(area)
+            .caption(
+                format!(
+                    "{}, w{}, {}, brighter is closer",
+                    file_name, det_res.win_sz, ngrams
+                ),
+                ("sans-serif", 30, FontStyle::Normal, &BLACK),
+            )
+            .margin(5)
+            .x_label_area_size(30)
+            .y_label_area_size(150)
+            .build_cartesian_2d(0..file_len, (0..arches).into_segmented())
+            .unwrap();
+
+        let columns = chart.plotting_area().dim_in_pixel().0 as usize;
+        let cells = heatmap_cells(file_len, det_res, kl_range_to_arch, columns);
+        let (min, max) = cells
+            .iter()
+            .fold((f64::INFINITY, f64::NEG_INFINITY), |(min, max), cell| {
+                (min.min(cell.2), max.max(cell.2))
+            });
+        // The lowest divergence is the brightest.
+        let span = (max - min).max(f64::EPSILON);
+        chart
+            .draw_series(cells.into_iter().map(|(range, arch_idx, value)| {
+                Rectangle::new(
+                    [
+                        (range.start, SegmentValue::Exact(arch_idx)),
+                        (range.end, SegmentValue::Exact(arch_idx + 1)),
+                    ],
+                    ViridisRGB::get_color_normalized(max - value, 0.0, span).filled(),
+                )
+            }))
+            .unwrap();
+
+        chart
+            .configure_mesh()
+            .disable_mesh()
+            .x_labels(50)
+            .y_labels(arches)
+            .x_label_formatter(&|offset| format!("{:x}", { *offset + base_address as usize }))
+            .y_label_formatter(&|value| match value {
+                SegmentValue::CenterOf(idx) => {
+                    det_res.idx_to_arch.get(idx).cloned().unwrap_or_default()
+                }
+                _ => String::new(),
+            })
+            .label_style(LABEL_STYLE_2D)
+            .draw()
+            .unwrap();
+    }
+
+    root.present().unwrap();
+}
+
+/// Returns the coordinates of the `d`th cell of a Hilbert curve that fills a
+/// square of `side` cells, a power of two.
+fn hilbert_cell(side: usize, mut d: usize) -> (usize, usize) {
+    let (mut x, mut y) = (0, 0);
+    let mut s = 1;
+    while s < side {
+        let rx = 1 & (d / 2);
+        let ry = 1 & (d ^ rx);
+        if ry == 0 {
+            if rx == 1 {
+                x = s - 1 - x;
+                y = s - 1 - y;
+            }
+            std::mem::swap(&mut x, &mut y);
+        }
+        x += s * rx;
+        y += s * ry;
+        d /= 4;
+        s *= 2;
+    }
+
+    (x, y)
+}
+
+/// Gray of unknown data, the brighter the higher its entropy.
+fn entropy_gray(entropy: f64) -> RGBColor {
+    let level = (32.0 + entropy / 8.0 * 192.0) as u8;
+    RGBColor(level, level, level)
+}
+
+/// Returns the name of the generated plot of the file along a Hilbert curve,
+/// which keeps close offsets close to each other. Each cell is colored by the
+/// arch of its first byte, unknown data in gray by the entropy of its window.
+pub fn plot_hilbert(
+    file_name: &str,
+    file_bytes: &[u8],
+    det_res: &ProcessedDetectionResult,
+    metadata: &PlotMetadata,
+) -> String {
+    let file_name = file_name.split("/").last().unwrap();
+    let format = metadata.static_format(PlotFormat::Png);
+    let plot_name = metadata.plot_name(
+        &format!("{}_w{}_hilbert", file_name, det_res.win_sz),
+        format,
+    );
+    let size = metadata.size(RESOLUTION_HILBERT);
+    match format {
+        PlotFormat::Svg => draw_hilbert(
+            file_name,
+            file_bytes,
+            det_res,
+            SVGBackend::new(&plot_name, size).into_drawing_area(),
+        ),
+        _ => draw_hilbert(
+            file_name,
+            file_bytes,
+            det_res,
+            BitMapBackend::new(&plot_name, size).into_drawing_area(),
+        ),
+    }
+    metadata.with_analysis(file_name, det_res).embed(&plot_name);
+
+    plot_name
+}
+
+fn draw_hilbert<DB: DrawingBackend>(
+    file_name: &str,
+    file_bytes: &[u8],
+    det_res: &ProcessedDetectionResult,
+    root: DrawingArea<DB, Shift>,
+) {
+    root.fill(&WHITE).unwrap();
+    let (width, height) = root.dim_in_pixel();
+    let (curve, legend) =
+        root.split_horizontally(width.saturating_sub(HILBERT_LEGEND).clamp(1, height));
+
+    let len = file_bytes.len();
+    let side = ((len as f64).sqrt().ceil() as usize)
+        .next_power_of_two()
+        .clamp(1, MAX_HILBERT_SIDE);
+    let bytes_per_cell = len.div_ceil(side * side).max(1);
+    let cell = curve.dim_in_pixel().0.min(height) as f64 / side as f64;
+
+    // Arches in the order of their first cell.
+    let mut arches: Vec<&Arch> = Vec::new();
+    let mut entropy: HashMap<usize, f64> = HashMap::new();
+    let mut segment_idx = 0;
+    for d in 0..len.div_ceil(bytes_per_cell) {
+        let offset = d * bytes_per_cell;
+        while det_res
+            .segments
+            .get(segment_idx)
+            .is_some_and(|segment| segment.range.end <= offset)
+        {
+            segment_idx += 1;
+        }
+        let color = match det_res.segments.get(segment_idx) {
+            Some(segment) if segment.range.contains(&offset) => match &segment.arch {
+                Some(arch) => {
+                    if !arches.contains(&arch) {
+                        arches.push(arch);
+                    }
+                    arch_color(det_res, arch)
+                }
+                None => RGBAColor::from(entropy_gray(*entropy.entry(segment_idx).or_insert_with(
+                    || ByteStats::new(&file_bytes[segment.window.clone()]).entropy,
+                ))),
+            },
+            // Bytes that were not analyzed, e.g., the end of the file.
+            _ => RGBAColor::from(entropy_gray(
+                ByteStats::new(&file_bytes[offset..(offset + bytes_per_cell).min(len)]).entropy,
+            )),
+        };
+
+        let (x, y) = hilbert_cell(side, d);
+        let corner = |x: usize, y: usize| ((x as f64 * cell) as i32, (y as f64 * cell) as i32);
+        curve
+            .draw(&Rectangle::new(
+                [corner(x, y), corner(x + 1, y + 1)],
+                color.filled(),
+            ))
+            .unwrap();
+    }
+
+    let style = LABEL_STYLE_2D.into_text_style(&legend);
+    let text = |s: &str, x: i32, y: i32| {
+        legend
+            .draw(&Text::new(s.to_owned(), (x, y), style.clone()))
+            .unwrap();
+    };
+    text(file_name, 10, 10);
+    text(
+        &format!("w{}, {} bytes per cell", det_res.win_sz, bytes_per_cell),
+        10,
+        30,
+    );
+    let mut y = 60;
+    for arch in arches {
+        legend
+            .draw(&Rectangle::new(
+                [(10, y), (22, y + 12)],
+                arch_color(det_res, arch).filled(),
+            ))
+            .unwrap();
+        text(arch, 30, y);
+        y += 20;
+    }
+    for bits in 0..=8 {
+        legend
+            .draw(&Rectangle::new(
+                [(10 + bits * 12, y), (22 + bits * 12, y + 12)],
+                entropy_gray(bits as f64).filled(),
+            ))
+            .unwrap();
+    }
+    text("unknown, by entropy 0 to 8 bits/byte", 10, y + 16);
+
+    root.present().unwrap();
+}
+
+/// Returns the name of the generated plot of the divergence between each pair
+/// of corpus entries, for bigrams and trigrams side by side.
+pub fn plot_corpus_matrix(matrix: &CorpusMatrix, metadata: &PlotMetadata) -> String {
+    let format = metadata.static_format(PlotFormat::Png);
+    let plot_name = metadata.plot_name("corpus_matrix", format);
+    let side = (matrix.arches.len() as u32 * MATRIX_CELL + MATRIX_LABELS + 60).max(MATRIX_MIN_SIDE);
+    let size = metadata.size((2 * side, side));
+    match format {
+        PlotFormat::Svg => draw_corpus_matrix(
+            matrix,
+            SVGBackend::new(&plot_name, size).into_drawing_area(),
+        ),
+        _ => draw_corpus_matrix(
+            matrix,
+            BitMapBackend::new(&plot_name, size).into_drawing_area(),
+        ),
+    }
+    metadata.embed(&plot_name);
+
+    plot_name
+}
+
+fn draw_corpus_matrix<DB: DrawingBackend>(matrix: &CorpusMatrix, root: DrawingArea<DB, Shift>) {
+    let n = matrix.arches.len();
+    root.fill(&WHITE).unwrap();
+
+    let panels = root.split_evenly((1, 2));
+    for (area, (ngrams, values)) in panels
+        .iter()
+        .zip([("bigrams", &matrix.bigrams), ("trigrams", &matrix.trigrams)])
+    {
+        let mut chart = ChartBuilder::on(area)
+            .caption(
+                format!("{}, {}, row against column", ngrams, matrix.metric.name()),
+                ("sans-serif", 20, FontStyle::Normal, &BLACK),
+            )
+            .margin(5)
+            .x_label_area_size(MATRIX_LABELS)
+            .y_label_area_size(MATRIX_LABELS)
+            // Segmented ranges include their end.
+            .build_cartesian_2d(
+                (0..n.saturating_sub(1)).into_segmented(),
+                (0..n.saturating_sub(1)).into_segmented(),
+            )
+            .unwrap();
+
+        // The diagonal is left out of the scale, as an arch is always closest
+        // to itself.
+        let (min, max) = values
+            .iter()
+            .enumerate()
+            .flat_map(|(row, divs)| {
+                divs.iter()
+                    .enumerate()
+                    .filter(move |(col, div)| *col != row && div.is_finite())
+            })
+            .fold(
+                (f64::INFINITY, f64::NEG_INFINITY),
+                |(min, max), (_, div)| (min.min(*div), max.max(*div)),
+            );
+        let span = (max - min).max(f64::EPSILON);
+        chart
+            .draw_series(values.iter().enumerate().flat_map(|(row, divs)| {
+                // The first row is at the top.
+                let y = n - 1 - row;
+                divs.iter().enumerate().map(move |(col, div)| {
+                    Rectangle::new(
+                        [
+                            (SegmentValue::Exact(col), SegmentValue::Exact(y)),
+                            (SegmentValue::Exact(col + 1), SegmentValue::Exact(y + 1)),
+                        ],
+                        ViridisRGB::get_color_normalized((max - div).clamp(0.0, span), 0.0, span)
+                            .filled(),
+                    )
+                })
+            }))
+            .unwrap();
+
+        let arch = |value: &SegmentValue<usize>, flip: bool| match value {
+            SegmentValue::CenterOf(idx) if *idx < n => {
+                matrix.arches[if flip { n - 1 - idx } else { *idx }].clone()
+            }
+            _ => String::new(),
+        };
+        chart
+            .configure_mesh()
+            .disable_mesh()
+            .x_labels(n)
+            .y_labels(n)
+            .x_label_formatter(&|value| arch(value, false))
+            .y_label_formatter(&|value| arch(value, true))
+            .label_style(LABEL_STYLE_2D)
+            .x_label_style(
+                LABEL_STYLE_2D
+                    .into_text_style(area)
+                    .transform(FontTransform::Rotate90),
+            )
+            .draw()
+            .unwrap();
+    }
+
+    root.present().unwrap();
 }
@@ -0,0 +1,113 @@
+/*
+    Copyright 2024 - Valentin Obst <coderec@vpao.io>
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+*/
+//! Deny-lists of arches, see `--deny-arch`.
+//!
+//! Supply-chain scans of firmware images fail if code of an arch that the
+//! device does not run is found, e.g., x86 code in an ARM-only image. Entries
+//! of the list are corpus arches or family names, which deny all arches of the
+//! family.
+
+use crate::family::Families;
+use crate::output::CliJsonOutput;
+
+use std::collections::HashSet;
+use std::ops::Range;
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+/// Region of a denied arch.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Denied {
+    pub range: Range<usize>,
+    pub arch: String,
+    /// Entry of the deny-list that the arch matches, the arch or its family.
+    pub entry: String,
+}
+
+pub struct DenyList {
+    entries: HashSet<String>,
+    families: Families,
+}
+
+impl DenyList {
+    /// Deny-list of `entries`, each of which must be one of `arches` or the
+    /// name of a family.
+    pub fn new(
+        entries: &[String],
+        families: Families,
+        arches: &HashSet<String>,
+    ) -> Result<Self, String> {
+        if let Some(entry) = entries
+            .iter()
+            .find(|entry| !arches.contains(*entry) && !families.is_family(entry))
+        {
+            return Err(format!(
+                "{} is neither an arch of the corpus nor a family",
+                entry
+            ));
+        }
+
+        Ok(Self {
+            entries: entries.iter().cloned().collect(),
+            families,
+        })
+    }
+
+    /// Entry of the deny-list that denies `arch`, if any.
+    fn entry<'a>(&'a self, arch: &'a str) -> Option<&'a str> {
+        [arch, self.families.family(arch)]
+            .into_iter()
+            .find(|name| self.entries.contains(*name))
+    }
+
+    /// Adds the regions of denied arches to `report` and its nested reports.
+    /// Returns the number of such regions.
+    pub fn apply(&self, report: &mut CliJsonOutput) -> usize {
+        let denied: Vec<Denied> = report
+            .range_results
+            .iter()
+            .filter_map(|(range, _, arch)| {
+                self.entry(arch).map(|entry| Denied {
+                    range: range.clone(),
+                    arch: arch.clone(),
+                    entry: entry.to_owned(),
+                })
+            })
+            .collect();
+        for denied in denied.iter() {
+            warn!(
+                "{}: {:#x}..{:#x} is {}, denied by {}",
+                report.file, denied.range.start, denied.range.end, denied.arch, denied.entry
+            );
+        }
+        let mut count = denied.len();
+        if !denied.is_empty() {
+            report.denied = Some(denied);
+        }
+
+        for nested in report
+            .members
+            .iter_mut()
+            .flat_map(|members| members.values_mut())
+            .chain(report.streams.iter_mut().flatten())
+        {
+            count += self.apply(nested);
+        }
+
+        count
+    }
+}
@@ -0,0 +1,137 @@
+/*
+    Copyright 2024 - Valentin Obst <coderec@vpao.io>
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+*/
+//! radare2 scripts, see `--emit r2`.
+//!
+//! `r2 -i SCRIPT FILE` opens the analyzed file with a section and a flag per
+//! detected region, mapped at its load address, arch and bitness hints at the
+//! start of each region, and a comment with its arch and confidence. The
+//! session defaults to the arch that the largest part of the regions has.
+
+use crate::emit::{comment, PlacedRegion};
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+/// Returns the r2 arch plugin, the bitness, and whether `arch` is big endian,
+/// or `None` if r2 does not ship a plugin for it.
+fn asm_arch(arch: &str) -> Option<(&'static str, u32, bool)> {
+    let asm = match arch {
+        "X86" => ("x86", 32, false),
+        "X86-64" => ("x86", 64, false),
+        "8086" => ("x86", 16, false),
+        "ARMel" | "ARMhf" => ("arm", 32, false),
+        "ARMeb" => ("arm", 32, true),
+        "Thumb" => ("arm", 16, false),
+        "ARM64" => ("arm", 64, false),
+        "MIPSel" => ("mips", 32, false),
+        "MIPSeb" => ("mips", 32, true),
+        "MIPS16" => ("mips", 16, true),
+        "PPCel" => ("ppc", 32, false),
+        "PPCeb" => ("ppc", 32, true),
+        "RISC-V" => ("riscv", 64, false),
+        "SPARC" => ("sparc", 32, true),
+        "M68k" => ("m68k", 32, true),
+        "AVR" => ("avr", 16, false),
+        "MSP430" => ("msp430", 16, false),
+        "SH4" => ("sh", 32, false),
+        "6502" => ("6502", 16, false),
+        "Z80" => ("z80", 8, false),
+        "8051" => ("8051", 8, true),
+        "HP-PA" => ("hppa", 32, true),
+        "V850" => ("v850", 32, false),
+        "TriCore" => ("tricore", 32, false),
+        "Xtensa" => ("xtensa", 32, false),
+        "CR16" => ("cr16", 16, false),
+        "H8-300" => ("h8300", 16, true),
+        "S-390" => ("s390", 64, true),
+        _ => return None,
+    };
+
+    Some(asm)
+}
+
+/// Returns the script that replays `regions` of the file at `path`, which
+/// `file` was analyzed from.
+pub fn script(file: &str, path: &str, regions: &[PlacedRegion]) -> String {
+    let file_name = comment(file.rsplit('/').next().unwrap());
+    let mut script = String::new();
+    writeln!(
+        script,
+        "# Regions that coderec {} detected in {}.",
+        env!("CARGO_PKG_VERSION"),
+        file_name
+    )
+    .unwrap();
+    writeln!(script, "#").unwrap();
+    writeln!(script, "# r2 -i {}.r2 {}", file_name, comment(path)).unwrap();
+
+    let mut sizes: BTreeMap<&str, usize> = BTreeMap::new();
+    for region in regions {
+        if asm_arch(region.arch).is_some() {
+            *sizes.entry(region.arch).or_default() += region.size;
+        } else {
+            writeln!(
+                script,
+                "# Skipped {:#x}..{:#x}, r2 has no arch plugin for {}.",
+                region.offset,
+                region.offset + region.size as u64,
+                region.arch
+            )
+            .unwrap();
+        }
+    }
+    writeln!(script).unwrap();
+
+    if let Some((arch, _)) = sizes.iter().max_by_key(|(_, size)| **size) {
+        let (asm, bits, big_endian) = asm_arch(arch).unwrap();
+        writeln!(script, "e asm.arch={}", asm).unwrap();
+        writeln!(script, "e asm.bits={}", bits).unwrap();
+        writeln!(script, "e cfg.bigendian={}", big_endian).unwrap();
+    }
+    for region in regions {
+        let Some((asm, bits, _)) = asm_arch(region.arch) else {
+            continue;
+        };
+        let name = format!(
+            "coderec.{}_{:x}",
+            region.arch.replace('-', "_"),
+            region.offset
+        );
+        writeln!(script).unwrap();
+        writeln!(
+            script,
+            "S {:#x} {:#x} {:#x} {:#x} {} r-x",
+            region.offset, region.address, region.size, region.size, name
+        )
+        .unwrap();
+        writeln!(
+            script,
+            "f {} {:#x} @ {:#x}",
+            name, region.size, region.address
+        )
+        .unwrap();
+        writeln!(script, "aha {} @ {:#x}", asm, region.address).unwrap();
+        writeln!(script, "ahb {} @ {:#x}", bits, region.address).unwrap();
+        writeln!(
+            script,
+            "CC coderec: {}, confidence {:.2} @ {:#x}",
+            region.arch, region.confidence, region.address
+        )
+        .unwrap();
+    }
+
+    script
+}
@@ -0,0 +1,396 @@
+/*
+    Copyright 2024 - Valentin Obst <coderec@vpao.io>
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+*/
+//! Decoding of text record formats, i.e., Intel HEX, Motorola S-records, and
+//! TI-TXT.
+//!
+//! Inputs that consist of such records are decoded into a memory image. Holes
+//! between the records are filled with erased flash bytes unless they are so
+//! large that the image is split into blocks, which are analyzed separately
+//! and report their regions by load address.
+
+use std::ops::Range;
+
+use anyhow::{bail, Context, Result};
+use log::warn;
+
+/// Holes between records up to this size are filled, larger ones start a new
+/// block.
+const MAX_HOLE: u64 = 0x10000;
+/// Value of erased flash bytes.
+const ERASED: u8 = 0xff;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordFormat {
+    IntelHex,
+    SRecord,
+    TiTxt,
+}
+
+impl RecordFormat {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::IntelHex => "ihex",
+            Self::SRecord => "srec",
+            Self::TiTxt => "ti-txt",
+        }
+    }
+
+    /// Returns the format of `data`, if it looks like a record file.
+    fn detect(data: &[u8]) -> Option<Self> {
+        if !data
+            .iter()
+            .all(|byte| (0x20..0x7f).contains(byte) || matches!(byte, b'\t' | b'\r' | b'\n'))
+        {
+            return None;
+        }
+        let first = data
+            .split(|byte| *byte == b'\n')
+            .map(|line| line.trim_ascii())
+            .find(|line| !line.is_empty())?;
+
+        match first {
+            [b':', ..] => Some(Self::IntelHex),
+            [b'S', b'0'..=b'9', ..] => Some(Self::SRecord),
+            [b'@', ..] => Some(Self::TiTxt),
+            _ => None,
+        }
+    }
+
+    /// Checks that `line` is a valid record of this format.
+    fn check_record(self, line: &str) -> Result<()> {
+        match self {
+            Self::IntelHex => ihex_record(line).map(|_| ()),
+            Self::SRecord => srec_record(line).map(|_| ()),
+            Self::TiTxt => {
+                let Some(address) = line.strip_prefix('@') else {
+                    bail!("record does not start with '@'");
+                };
+                u64::from_str_radix(address, 16)
+                    .map(|_| ())
+                    .with_context(|| format!("invalid address {:?}", address))
+            }
+        }
+    }
+}
+
+/// Contiguous bytes of a memory image.
+#[derive(Debug, Clone)]
+pub struct Block {
+    pub address: u64,
+    pub data: Vec<u8>,
+}
+
+impl Block {
+    pub fn range(&self) -> Range<u64> {
+        self.address..self.address + self.data.len() as u64
+    }
+}
+
+fn parse_hex(digits: &str) -> Result<Vec<u8>> {
+    if !digits.len().is_multiple_of(2) {
+        bail!("odd number of hex digits");
+    }
+    (0..digits.len())
+        .step_by(2)
+        .map(|idx| {
+            u8::from_str_radix(&digits[idx..idx + 2], 16)
+                .with_context(|| format!("invalid hex digits {:?}", &digits[idx..idx + 2]))
+        })
+        .collect()
+}
+
+fn be_address(bytes: &[u8]) -> u64 {
+    bytes
+        .iter()
+        .fold(0, |address, byte| address << 8 | *byte as u64)
+}
+
+/// Non-empty lines of `text` and their line numbers.
+fn lines(text: &str) -> impl Iterator<Item = (usize, &str)> {
+    text.lines()
+        .map(str::trim)
+        .enumerate()
+        .filter(|(_, line)| !line.is_empty())
+        .map(|(idx, line)| (idx + 1, line))
+}
+
+/// Returns the bytes of an Intel HEX record after checking its length and
+/// checksum.
+fn ihex_record(line: &str) -> Result<Vec<u8>> {
+    let Some(digits) = line.strip_prefix(':') else {
+        bail!("record does not start with ':'");
+    };
+    let bytes = parse_hex(digits)?;
+    if bytes.len() < 5 || bytes.len() != bytes[0] as usize + 5 {
+        bail!("invalid record length");
+    }
+    if bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) != 0 {
+        bail!("invalid checksum");
+    }
+
+    Ok(bytes)
+}
+
+/// Returns the data records of an Intel HEX file.
+fn decode_ihex(text: &str) -> Result<Vec<Block>> {
+    let mut records = Vec::new();
+    let mut base = 0;
+    for (number, line) in lines(text) {
+        let bytes = ihex_record(line).with_context(|| format!("line {}", number))?;
+        let payload = &bytes[4..bytes.len() - 1];
+        match bytes[3] {
+            0x00 => records.push(Block {
+                address: base + be_address(&bytes[1..3]),
+                data: payload.to_vec(),
+            }),
+            0x01 => break,
+            0x02 => base = be_address(payload) << 4,
+            0x04 => base = be_address(payload) << 16,
+            // Start addresses.
+            0x03 | 0x05 => {}
+            record_type => bail!("line {}: unknown record type {:02x}", number, record_type),
+        }
+    }
+
+    Ok(records)
+}
+
+/// Returns the type and bytes of an S-record after checking its length and
+/// checksum.
+fn srec_record(line: &str) -> Result<(u8, Vec<u8>)> {
+    let Some(digits) = line.strip_prefix('S').filter(|digits| !digits.is_empty()) else {
+        bail!("record does not start with 'S'");
+    };
+    let bytes = parse_hex(&digits[1..])?;
+    if bytes.is_empty() || bytes.len() != bytes[0] as usize + 1 {
+        bail!("invalid record length");
+    }
+    if bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) != 0xff {
+        bail!("invalid checksum");
+    }
+
+    Ok((digits.as_bytes()[0], bytes))
+}
+
+/// Returns the data records of a Motorola S-record file.
+fn decode_srec(text: &str) -> Result<Vec<Block>> {
+    let mut records = Vec::new();
+    for (number, line) in lines(text) {
+        let (record_type, bytes) = srec_record(line).with_context(|| format!("line {}", number))?;
+        let address_len = match record_type {
+            b'1' => 2,
+            b'2' => 3,
+            b'3' => 4,
+            b'7' | b'8' | b'9' => break,
+            // Header and record counts.
+            b'0' | b'5' | b'6' => continue,
+            _ => bail!(
+                "line {}: unknown record type S{}",
+                number,
+                record_type as char
+            ),
+        };
+        if bytes.len() < address_len + 2 {
+            bail!("line {}: invalid record length", number);
+        }
+        records.push(Block {
+            address: be_address(&bytes[1..1 + address_len]),
+            data: bytes[1 + address_len..bytes.len() - 1].to_vec(),
+        });
+    }
+
+    Ok(records)
+}
+
+/// Returns the sections of a TI-TXT file.
+fn decode_ti_txt(text: &str) -> Result<Vec<Block>> {
+    let mut records: Vec<Block> = Vec::new();
+    for (number, line) in lines(text) {
+        if let Some(address) = line.strip_prefix('@') {
+            records.push(Block {
+                address: u64::from_str_radix(address, 16)
+                    .with_context(|| format!("line {}: invalid address {:?}", number, address))?,
+                data: Vec::new(),
+            });
+        } else if line.eq_ignore_ascii_case("q") {
+            break;
+        } else {
+            let Some(record) = records.last_mut() else {
+                bail!("line {}: data before the first address", number);
+            };
+            for digits in line.split_ascii_whitespace() {
+                record
+                    .data
+                    .extend(parse_hex(digits).with_context(|| format!("line {}", number))?);
+            }
+        }
+    }
+
+    Ok(records)
+}
+
+/// Merges the records, sorted by their address, into blocks.
+fn build_image(mut records: Vec<Block>) -> Vec<Block> {
+    records.sort_by_key(|record| record.address);
+
+    let mut blocks: Vec<Block> = Vec::new();
+    for record in records.into_iter().filter(|record| !record.data.is_empty()) {
+        match blocks.last_mut() {
+            Some(block) if record.address <= block.range().end + MAX_HOLE => {
+                let start = (record.address - block.address) as usize;
+                let end = start + record.data.len();
+                if end > block.data.len() {
+                    block.data.resize(end, ERASED);
+                }
+                block.data[start..end].copy_from_slice(&record.data);
+            }
+            _ => blocks.push(record),
+        }
+    }
+
+    blocks
+}
+
+/// Decodes `data`, which is the content of `file`, into the blocks of a
+/// memory image if it is a record file, returns `None` if it is not. Inputs
+/// whose first record is invalid are no record files, e.g., batch files that
+/// start with `@echo off`, errors in later records are errors.
+pub fn decode_records(file: &str, data: &[u8]) -> Result<Option<(RecordFormat, Vec<Block>)>> {
+    let Some(format) = RecordFormat::detect(data) else {
+        return Ok(None);
+    };
+    // Only ASCII passes the detection.
+    let text = std::str::from_utf8(data).unwrap();
+    if let Some((number, line)) = lines(text).next() {
+        if let Err(e) = format.check_record(line) {
+            warn!(
+                "{}: line {} looks like a {} record but is invalid, analyzing the raw bytes: {:#}",
+                file,
+                number,
+                format.name(),
+                e
+            );
+            return Ok(None);
+        }
+    }
+
+    let records = match format {
+        RecordFormat::IntelHex => decode_ihex(text),
+        RecordFormat::SRecord => decode_srec(text),
+        RecordFormat::TiTxt => decode_ti_txt(text),
+    }
+    .with_context(|| format!("invalid {} input", format.name()))?;
+
+    Ok(Some((format, build_image(records))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const IHEX_DATA: [u8; 16] = [
+        0x21, 0x46, 0x01, 0x36, 0x01, 0x21, 0x47, 0x01, 0x36, 0x00, 0x7e, 0xfe, 0x09, 0xd2, 0x19,
+        0x01,
+    ];
+
+    #[test]
+    fn ihex() {
+        let text = ":020000040800F2\n:10010000214601360121470136007EFE09D2190140\n:00000001FF\n";
+        let (format, blocks) = decode_records("test.hex", text.as_bytes())
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(format, RecordFormat::IntelHex);
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].address, 0x0800_0100);
+        assert_eq!(blocks[0].data, IHEX_DATA);
+    }
+
+    #[test]
+    fn srec() {
+        let text = "S00600004844521B\nS1130000285F245F2212226A000424290008237C2A\nS9030000FC\n";
+        let (format, blocks) = decode_records("test.srec", text.as_bytes())
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(format, RecordFormat::SRecord);
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].address, 0);
+        assert_eq!(
+            blocks[0].data,
+            [
+                0x28, 0x5f, 0x24, 0x5f, 0x22, 0x12, 0x22, 0x6a, 0x00, 0x04, 0x24, 0x29, 0x00, 0x08,
+                0x23, 0x7c
+            ]
+        );
+    }
+
+    #[test]
+    fn ti_txt() {
+        let text = "@f000\n31 40 00 03\nb2 40\n@f002\n80 5a\n@20000\n01 02\nq\n";
+        let (format, blocks) = decode_records("test.txt", text.as_bytes())
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(format, RecordFormat::TiTxt);
+        // The later section overrides the earlier one, the hole to the last
+        // one is too large to be filled.
+        assert_eq!(blocks.len(), 2);
+        assert_eq!(blocks[0].address, 0xf000);
+        assert_eq!(blocks[0].data, [0x31, 0x40, 0x80, 0x5a, 0xb2, 0x40]);
+        assert_eq!(blocks[1].address, 0x20000);
+        assert_eq!(blocks[1].data, [0x01, 0x02]);
+    }
+
+    #[test]
+    fn holes_are_erased() {
+        let text = "@0\n01\n@4\n02\n";
+        let (_, blocks) = decode_records("test.txt", text.as_bytes())
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].data, [0x01, ERASED, ERASED, ERASED, 0x02]);
+    }
+
+    #[test]
+    fn not_records() {
+        for text in [
+            "@echo off\r\necho hello\r\n",
+            ":: comment of a batch file\n",
+            "Some text that starts like an S-record\n",
+            "S1 is not hex\n",
+            "plain text\n",
+        ] {
+            assert!(
+                decode_records("test", text.as_bytes()).unwrap().is_none(),
+                "{:?}",
+                text
+            );
+        }
+        assert!(decode_records("test", &[b':', 0x00, 0xff])
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn invalid_later_record() {
+        let text = ":10010000214601360121470136007EFE09D2190140\n:10011000214601360121470136007EFE09D2190140\n";
+        let err = decode_records("test.hex", text.as_bytes()).unwrap_err();
+
+        assert!(format!("{:#}", err).contains("line 2"), "{:#}", err);
+    }
+}
@@ -0,0 +1,123 @@
+/*
+    Copyright 2024 - Valentin Obst <coderec@vpao.io>
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+*/
+//! Refinement of the boundaries between regions.
+//!
+//! Without refinement, region boundaries are determined by the window layout.
+//! This pass searches for a better boundary around each transition by
+//! classifying small probe windows.
+
+use crate::padding::is_exact;
+use crate::{
+    classify_window, is_traced, CorpusStats, ProcessedDetectionResult, Segment, TRACE_TARGET,
+};
+
+use std::cmp::min;
+use std::ops::Range;
+
+use log::info;
+use rayon::prelude::*;
+
+/// Moves the boundary between each pair of neighboring segments with
+/// different verdicts such that it is accurate up to `granularity` bytes.
+pub fn refine_boundaries(
+    corpus_stats: &[CorpusStats],
+    data: &[u8],
+    res: &mut ProcessedDetectionResult,
+    granularity: usize,
+) {
+    // Probes must be large enough to get meaningful statistics.
+    let probe_sz = min(granularity * 4, res.win_sz);
+
+    let boundaries: Vec<Option<usize>> = res
+        .segments
+        .par_windows(2)
+        .map(|pair| {
+            let (left, right) = (&pair[0], &pair[1]);
+            // Padding runs and device tree blobs are exact already.
+            if left.arch == right.arch
+                || [left, right]
+                    .iter()
+                    .any(|segment| is_exact(segment.arch.as_deref()))
+            {
+                return None;
+            }
+
+            let boundary =
+                find_boundary(corpus_stats, data, res, left, right, granularity, probe_sz);
+            if is_traced(&res.trace, &left.window) || is_traced(&res.trace, &right.window) {
+                info!(
+                    target: TRACE_TARGET,
+                    "{:x?}: moved boundary between {:?} and {:?} from {:x} to {:x}",
+                    left.window.start..right.window.end,
+                    left.arch,
+                    right.arch,
+                    left.range.end,
+                    boundary
+                );
+            }
+
+            Some(boundary)
+        })
+        .collect();
+
+    let mut segments = res.segments.clone();
+    for (idx, boundary) in boundaries.into_iter().enumerate() {
+        if let Some(boundary) = boundary {
+            // Never let a boundary move past the previous one.
+            let boundary = boundary.clamp(segments[idx].range.start, segments[idx + 1].range.end);
+            segments[idx].range.end = boundary;
+            segments[idx + 1].range.start = boundary;
+        }
+    }
+    res.set_segments(segments);
+}
+
+/// Binary search for the boundary between the centers of the two windows.
+fn find_boundary(
+    corpus_stats: &[CorpusStats],
+    data: &[u8],
+    res: &ProcessedDetectionResult,
+    left: &Segment,
+    right: &Segment,
+    granularity: usize,
+    probe_sz: usize,
+) -> usize {
+    let center = |range: &Range<usize>| (range.start + range.end) / 2;
+    let (mut lo, mut hi) = (center(&left.window), center(&right.window));
+
+    while hi > lo + granularity {
+        let mid = (lo + hi) / 2;
+        // The verdict for a probe that contains the boundary is dominated by
+        // the side that makes up the larger part of it.
+        let probe = mid.saturating_sub(probe_sz / 2)..min(mid + probe_sz / 2, data.len());
+        let arch_op = classify_window(corpus_stats, &data[probe], res.metric, &res.thresholds).arch;
+
+        if arch_op == left.arch {
+            lo = mid;
+        } else if arch_op == right.arch {
+            hi = mid;
+        // Anything else is closer to unknown.
+        } else if left.arch.is_none() {
+            lo = mid;
+        } else if right.arch.is_none() {
+            hi = mid;
+        } else {
+            break;
+        }
+    }
+
+    ((lo + hi) / 2 + granularity / 2) / granularity * granularity
+}
@@ -0,0 +1,149 @@
+/*
+    Copyright 2024 - Valentin Obst <coderec@vpao.io>
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+*/
+//! Reading inputs from and writing outputs to S3.
+//!
+//! Credentials, region, and endpoint are taken from the usual `AWS_*`
+//! environment variables.
+
+use crate::output::TargetWriter;
+
+use std::io::{self, Write};
+use std::ops::Range;
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use log::{debug, info};
+use object_store::aws::AmazonS3Builder;
+use object_store::path::Path as ObjectPath;
+use object_store::{ObjectStore, ObjectStoreExt, PutPayload};
+use tokio::runtime::{Builder, Runtime};
+
+/// Splits `s3://BUCKET/KEY` into a store for the bucket and the key.
+fn open_object(url: &str) -> Result<(Box<dyn ObjectStore>, ObjectPath)> {
+    let Some((bucket, key)) = url
+        .strip_prefix("s3://")
+        .and_then(|rest| rest.split_once('/'))
+    else {
+        bail!("Expected s3://BUCKET/KEY, got {:?}", url);
+    };
+    if bucket.is_empty() || key.is_empty() {
+        bail!("Expected s3://BUCKET/KEY, got {:?}", url);
+    }
+
+    let store = AmazonS3Builder::from_env()
+        .with_bucket_name(bucket)
+        .build()
+        .with_context(|| format!("Could not open bucket {}", bucket))?;
+    let key = ObjectPath::parse(key).with_context(|| format!("Invalid key in {}", url))?;
+
+    Ok((Box::new(store), key))
+}
+
+fn runtime() -> Result<Runtime> {
+    Ok(Builder::new_current_thread().enable_all().build()?)
+}
+
+/// Reads the object at `url`. If `range` is given, only those bytes are
+/// requested from the store.
+pub fn read_object(url: &str, range: Option<Range<u64>>) -> Result<Vec<u8>> {
+    let (store, key) = open_object(url)?;
+
+    runtime()?
+        .block_on(async {
+            let data = match range {
+                Some(range) => store.get_range(&key, range).await?.to_vec(),
+                None => store.get(&key).await?.bytes().await?.to_vec(),
+            };
+            debug!("Read {} bytes from {}", data.len(), url);
+
+            Ok::<_, object_store::Error>(data)
+        })
+        .with_context(|| format!("Could not read {}", url))
+}
+
+/// Returns the size of the object at `url`.
+pub fn object_size(url: &str) -> Result<u64> {
+    let (store, key) = open_object(url)?;
+
+    Ok(runtime()?
+        .block_on(store.head(&key))
+        .with_context(|| format!("Could not read {}", url))?
+        .size)
+}
+
+/// Replaces the object at `url` with `data`.
+pub fn write_object(url: &str, data: Vec<u8>) -> Result<()> {
+    let (store, key) = open_object(url)?;
+    let len = data.len();
+
+    runtime()?
+        .block_on(store.put(&key, PutPayload::from(data)))
+        .with_context(|| format!("Could not write {}", url))?;
+    debug!("Wrote {} bytes to {}", len, url);
+
+    Ok(())
+}
+
+/// Uploads the local file `path` to `PREFIX/FILE_NAME` and removes it.
+pub fn upload_file(prefix: &str, path: &str) -> Result<()> {
+    let file_name = Path::new(path)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .with_context(|| format!("Invalid file name {}", path))?;
+    let url = format!("{}/{}", prefix.trim_end_matches('/'), file_name);
+
+    let data = std::fs::read(path).with_context(|| format!("Could not read {}", path))?;
+    write_object(&url, data)?;
+    std::fs::remove_file(path).with_context(|| format!("Could not remove {}", path))?;
+    info!("Uploaded {} to {}", path, url);
+
+    Ok(())
+}
+
+/// Collects everything that is written and uploads it as a single object
+/// once the sink is done.
+pub struct ObjectWriter {
+    url: String,
+    buf: Vec<u8>,
+}
+
+impl ObjectWriter {
+    pub fn new(url: &str) -> Result<Self> {
+        // Fail early on malformed URLs rather than after the analysis.
+        open_object(url)?;
+
+        Ok(Self {
+            url: url.to_owned(),
+            buf: Vec::new(),
+        })
+    }
+}
+
+impl Write for ObjectWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buf.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl TargetWriter for ObjectWriter {
+    fn close(&mut self) -> Result<()> {
+        write_object(&self.url, std::mem::take(&mut self.buf))
+    }
+}
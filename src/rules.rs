@@ -0,0 +1,174 @@
+/*
+    Copyright 2024 - Valentin Obst <coderec@vpao.io>
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+*/
+//! Alerting rules that are evaluated on the results of each file.
+//!
+//! A rule selects the regions of some arches, or families thereof, that
+//! satisfy optional size and confidence limits. It fires if the selected
+//! regions make up a share of the file within the given bounds, or, for
+//! `absent` rules, if no region was selected. Example:
+//!
+//! ```yaml
+//! rules:
+//!   - name: foreign-code
+//!     arches: [x86]
+//!     min_size: 0x1000
+//!     min_confidence: 0.6
+//!     exit_code: 20
+//!   - name: no-mips
+//!     arches: [MIPS]
+//!     absent: true
+//! ```
+//!
+//! Rules that fire exit with their exit code, `EXIT_RULE` unless set. Codes up
+//! to `EXIT_MAX_RESERVED` are reserved for the other outcomes of a run.
+
+use crate::family::Families;
+use crate::output::CliJsonOutput;
+use crate::{EXIT_MAX_RESERVED, EXIT_RULE};
+
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use log::info;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RulesFile {
+    rules: Vec<Rule>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Rule {
+    name: String,
+    /// Arches or families whose regions are selected, all if empty.
+    #[serde(default)]
+    arches: Vec<String>,
+    #[serde(default)]
+    min_size: usize,
+    #[serde(default)]
+    min_confidence: f64,
+    /// Bounds on the share of the file, in percent.
+    #[serde(default)]
+    min_percent: f64,
+    #[serde(default = "default_max_percent")]
+    max_percent: f64,
+    /// Fire if no region was selected.
+    #[serde(default)]
+    absent: bool,
+    #[serde(default = "default_exit_code")]
+    exit_code: u8,
+}
+
+fn default_max_percent() -> f64 {
+    100.0
+}
+
+fn default_exit_code() -> u8 {
+    EXIT_RULE
+}
+
+/// Rule that fired for a file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Alert {
+    pub rule: String,
+    /// Number of selected regions.
+    pub regions: usize,
+    /// Share of the file that the selected regions make up, in percent.
+    pub percent: f64,
+    #[serde(skip)]
+    pub exit_code: u8,
+}
+
+pub struct RuleSet {
+    rules: Vec<Rule>,
+    families: Families,
+}
+
+impl RuleSet {
+    /// Loads the rules in `path`, arches are matched by name or by their
+    /// family in `families`.
+    pub fn load(path: &Path, families: Families) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Could not read {}", path.display()))?;
+        let file: RulesFile = serde_norway::from_str(&text)
+            .with_context(|| format!("Invalid rules {}", path.display()))?;
+
+        for rule in file.rules.iter() {
+            if rule.exit_code <= EXIT_MAX_RESERVED {
+                bail!(
+                    "{}: exit code {} of rule {} is reserved, use one above {}",
+                    path.display(),
+                    rule.exit_code,
+                    rule.name,
+                    EXIT_MAX_RESERVED
+                );
+            }
+        }
+
+        Ok(Self {
+            rules: file.rules,
+            families,
+        })
+    }
+
+    /// Returns the alerts of all rules that fire for `report`.
+    pub fn evaluate(&self, report: &CliJsonOutput) -> Vec<Alert> {
+        self.rules
+            .iter()
+            .filter_map(|rule| {
+                let selected: Vec<_> = report
+                    .regions()
+                    .filter(|(_, size, arch, confidence)| {
+                        (rule.arches.is_empty()
+                            || rule
+                                .arches
+                                .iter()
+                                .any(|a| a == *arch || a == self.families.family(arch)))
+                            && *size >= rule.min_size
+                            && *confidence >= rule.min_confidence
+                    })
+                    .collect();
+                let bytes: usize = selected.iter().map(|(_, size, ..)| size).sum();
+                let percent = if report.size == 0 {
+                    0.0
+                } else {
+                    100.0 * bytes as f64 / report.size as f64
+                };
+
+                let fires = if rule.absent {
+                    selected.is_empty()
+                } else {
+                    !selected.is_empty()
+                        && rule.min_percent <= percent
+                        && percent <= rule.max_percent
+                };
+                if !fires {
+                    return None;
+                }
+                info!("{}: rule {} fired", report.file, rule.name);
+
+                Some(Alert {
+                    rule: rule.name.clone(),
+                    regions: selected.len(),
+                    percent,
+                    exit_code: rule.exit_code,
+                })
+            })
+            .collect()
+    }
+}
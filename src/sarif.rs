@@ -0,0 +1,156 @@
+/*
+    Copyright 2024 - Valentin Obst <coderec@vpao.io>
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+*/
+//! SARIF logs for code scanning dashboards.
+//!
+//! All files go into a single run, which is written once the analysis is
+//! done. Each region is a result of the `region` rule whose location is its
+//! byte range in the file, each alert one of the `alert` rule, and each region
+//! of a denied arch one of the `denied` rule, as an error. Regions of
+//! archive members and compressed streams are located in their report's
+//! file.
+
+use crate::float;
+use crate::output::{CliJsonOutput, ReportSink, TargetWriter};
+
+use anyhow::Result;
+use serde_json::{json, Value};
+
+const SCHEMA: &str = "https://json.schemastore.org/sarif-2.1.0.json";
+const REGION_RULE: &str = "region";
+const ALERT_RULE: &str = "alert";
+const DENIED_RULE: &str = "denied";
+
+pub struct SarifSink {
+    out: Box<dyn TargetWriter>,
+    float_decimals: Option<u32>,
+    results: Vec<Value>,
+}
+
+impl SarifSink {
+    pub fn new(out: Box<dyn TargetWriter>, float_decimals: Option<u32>) -> Self {
+        Self {
+            out,
+            float_decimals,
+            results: Vec::new(),
+        }
+    }
+
+    fn add_results(&mut self, report: &CliJsonOutput) {
+        let location = |byte_offset: usize, byte_length: usize| {
+            json!({
+                "physicalLocation": {
+                    "artifactLocation": { "uri": report.file },
+                    "region": { "byteOffset": byte_offset, "byteLength": byte_length },
+                },
+            })
+        };
+
+        for (range, size, arch, confidence) in report.regions() {
+            let mut properties = json!({ "arch": arch, "confidence": confidence });
+            if let Some(meta) = report.meta.as_ref() {
+                properties["meta"] = json!(meta);
+            }
+            self.results.push(json!({
+                "ruleId": REGION_RULE,
+                "level": "note",
+                "message": {
+                    "text": format!("{:#x}..{:#x}: {}", range.start, range.end, arch),
+                },
+                "locations": [location(range.start, size)],
+                "properties": properties,
+            }));
+        }
+        for alert in report.alerts.iter().flatten() {
+            self.results.push(json!({
+                "ruleId": ALERT_RULE,
+                "level": "warning",
+                "message": {
+                    "text": format!(
+                        "{}: {} regions, {:.2}% of the file",
+                        alert.rule, alert.regions, alert.percent
+                    ),
+                },
+                "locations": [location(0, report.size)],
+                "properties": { "rule": alert.rule, "regions": alert.regions, "percent": alert.percent },
+            }));
+        }
+        for denied in report.denied.iter().flatten() {
+            self.results.push(json!({
+                "ruleId": DENIED_RULE,
+                "level": "error",
+                "message": {
+                    "text": format!(
+                        "{:#x}..{:#x}: {} is denied by {}",
+                        denied.range.start, denied.range.end, denied.arch, denied.entry
+                    ),
+                },
+                "locations": [location(denied.range.start, denied.range.len())],
+                "properties": { "arch": denied.arch, "entry": denied.entry },
+            }));
+        }
+
+        for nested in report
+            .members
+            .iter()
+            .flat_map(|members| members.values())
+            .chain(report.streams.iter().flatten())
+        {
+            self.add_results(nested);
+        }
+    }
+}
+
+impl ReportSink for SarifSink {
+    fn write_report(&mut self, report: &CliJsonOutput) -> Result<()> {
+        self.add_results(report);
+
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        let log = json!({
+            "$schema": SCHEMA,
+            "version": "2.1.0",
+            "runs": [{
+                "tool": {
+                    "driver": {
+                        "name": "coderec",
+                        "version": env!("CARGO_PKG_VERSION"),
+                        "rules": [
+                            {
+                                "id": REGION_RULE,
+                                "shortDescription": { "text": "Region of code of a single arch." },
+                            },
+                            {
+                                "id": ALERT_RULE,
+                                "shortDescription": { "text": "Alerting rule that fired, see --rules." },
+                            },
+                            {
+                                "id": DENIED_RULE,
+                                "shortDescription": { "text": "Region of an arch of the deny-list, see --deny-arch." },
+                            },
+                        ],
+                    },
+                },
+                "results": std::mem::take(&mut self.results),
+            }],
+        });
+        serde_json::to_writer(&mut self.out, &float::to_value(&log, self.float_decimals)?)?;
+        writeln!(self.out)?;
+
+        self.out.close()
+    }
+}
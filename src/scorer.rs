@@ -0,0 +1,54 @@
+/*
+    Copyright 2024 - Valentin Obst <coderec@vpao.io>
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+*/
+//! Scoring of the windows.
+//!
+//! A `WindowScorer` turns the n-gram statistics of a window into the evidence
+//! that the decision layer decides on the arch of the window from. The
+//! divergences from the corpus entries are the default evidence, further
+//! scorers can be selected per run through `DetectionOptions::scorer`.
+
+use crate::corpus::CorpusStats;
+use crate::metric::MetricKind;
+use crate::{calculate_kl, RangeFullKlRes};
+
+use std::fmt;
+
+/// Scores single windows for the detection.
+pub(crate) trait WindowScorer: fmt::Debug + Send + Sync {
+    /// Name of the scorer in logs.
+    fn name(&self) -> &'static str;
+
+    /// Evidence for the window with the statistics `window`. The corpus is
+    /// passed in since it is copied to each NUMA node.
+    fn score(&self, corpus_stats: &[CorpusStats], window: &CorpusStats) -> RangeFullKlRes;
+}
+
+/// Divergences of the window from each corpus entry under a metric, the
+/// default scorer.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct DivergenceScorer {
+    pub metric: MetricKind,
+}
+
+impl WindowScorer for DivergenceScorer {
+    fn name(&self) -> &'static str {
+        "divergence"
+    }
+
+    fn score(&self, corpus_stats: &[CorpusStats], window: &CorpusStats) -> RangeFullKlRes {
+        calculate_kl(corpus_stats, window, self.metric)
+    }
+}
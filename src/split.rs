@@ -0,0 +1,153 @@
+/*
+    Copyright 2024 - Valentin Obst <coderec@vpao.io>
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+*/
+//! Sub-window analysis of windows with mixed evidence, see `--split-mixed`.
+//!
+//! A window that is half string table and half code is close to neither, and
+//! its segment ends up unknown even if the code would be detected on its own.
+//! This pass splits such windows in halves and classifies them separately,
+//! halves that are still unknown are split again down to a minimum size.
+//! Windows that are too far from any arch are left alone, their halves are
+//! most likely too far as well.
+
+use crate::{
+    classify_window, final_range_decision, is_traced, CorpusStats, Decision, DecisionBranch,
+    ProcessedDetectionResult, Segment, TRACE_TARGET,
+};
+
+use std::ops::Range;
+
+use log::info;
+use rayon::prelude::*;
+
+/// Replaces each unknown segment whose window has mixed evidence by the
+/// verdicts for the parts of its window, which are at least `min_size`
+/// bytes.
+pub fn split_mixed(
+    corpus_stats: &[CorpusStats],
+    data: &[u8],
+    res: &mut ProcessedDetectionResult,
+    min_size: usize,
+) {
+    let segments: Vec<Vec<Segment>> = res
+        .segments
+        .par_iter()
+        .map(|segment| {
+            if segment.arch.is_some() || !is_mixed(res, &segment.window) {
+                return vec![segment.clone()];
+            }
+
+            let mut parts = Vec::new();
+            split_window(
+                corpus_stats,
+                data,
+                res,
+                segment.window.clone(),
+                min_size,
+                &mut parts,
+            );
+            let segments = split_segment(segment, parts);
+            if is_traced(&res.trace, &segment.window) {
+                for part in segments.iter() {
+                    info!(
+                        target: TRACE_TARGET,
+                        "{:x?}: split window, {:x?} is {:?} with confidence {:.2}",
+                        segment.window,
+                        part.window,
+                        part.arch,
+                        part.confidence
+                    );
+                }
+            }
+
+            segments
+        })
+        .collect();
+
+    res.set_segments(segments.into_iter().flatten().collect());
+}
+
+/// Returns true if the verdict for `window` was not that it is too far from
+/// all archs.
+fn is_mixed(res: &ProcessedDetectionResult, window: &Range<usize>) -> bool {
+    let (Some(res_bg), Some(res_tg)) = (
+        res.range_to_result_bg.get(window),
+        res.range_to_result_tg.get(window),
+    ) else {
+        return false;
+    };
+
+    final_range_decision(res_bg, res_tg, &res.thresholds).branch != DecisionBranch::TooFar
+}
+
+/// Classifies both halves of `window` and recurses into unknown halves that
+/// can be split again. Appends the verdicts to `parts` in file order.
+fn split_window(
+    corpus_stats: &[CorpusStats],
+    data: &[u8],
+    res: &ProcessedDetectionResult,
+    window: Range<usize>,
+    min_size: usize,
+    parts: &mut Vec<(Range<usize>, Decision)>,
+) {
+    let mid = (window.start + window.end) / 2;
+    for half in [window.start..mid, mid..window.end] {
+        let decision = classify_window(
+            corpus_stats,
+            &data[half.clone()],
+            res.metric,
+            &res.thresholds,
+        );
+        if decision.arch.is_none()
+            && decision.branch != DecisionBranch::TooFar
+            && half.len() / 2 >= min_size
+        {
+            split_window(corpus_stats, data, res, half, min_size, parts);
+        } else {
+            parts.push((half, decision));
+        }
+    }
+}
+
+/// Turns the verdicts for the parts of the window of `segment` into segments
+/// that cover the range of `segment`.
+fn split_segment(segment: &Segment, parts: Vec<(Range<usize>, Decision)>) -> Vec<Segment> {
+    let last = parts.len().saturating_sub(1);
+
+    parts
+        .into_iter()
+        .enumerate()
+        .map(|(idx, (window, decision))| {
+            let clamp = |offset: usize| offset.clamp(segment.range.start, segment.range.end);
+            let start = if idx == 0 {
+                segment.range.start
+            } else {
+                clamp(window.start)
+            };
+            let end = if idx == last {
+                segment.range.end
+            } else {
+                clamp(window.end)
+            };
+
+            Segment {
+                range: start..end,
+                window,
+                arch: decision.arch,
+                confidence: decision.confidence,
+            }
+        })
+        .collect()
+}
@@ -0,0 +1,218 @@
+/*
+    Copyright 2024 - Valentin Obst <coderec@vpao.io>
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+*/
+//! Writing reports to SQLite databases, see `--sink sqlite=PATH`.
+//!
+//! Each report is a row of `reports` with its JSON, and each of its regions
+//! and those of its archive members and compressed streams is a row of
+//! `regions`, such that results can be queried with SQL. Reports are added
+//! to existing databases.
+
+use crate::float;
+use crate::output::{CliJsonOutput, ReportSink};
+
+use anyhow::{Context, Result};
+use rusqlite::{params, Connection, Transaction};
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS reports (
+    id INTEGER PRIMARY KEY,
+    file TEXT NOT NULL,
+    size INTEGER NOT NULL,
+    dominant_arch TEXT,
+    unknown REAL NOT NULL,
+    report TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS regions (
+    report INTEGER NOT NULL REFERENCES reports (id),
+    file TEXT NOT NULL,
+    range_start INTEGER NOT NULL,
+    range_end INTEGER NOT NULL,
+    size INTEGER NOT NULL,
+    arch TEXT NOT NULL,
+    confidence REAL
+);
+CREATE INDEX IF NOT EXISTS regions_arch ON regions (arch);
+";
+
+/// Writes each report and its regions to the database at a path.
+pub struct SqliteSink {
+    connection: Connection,
+    path: String,
+    float_decimals: Option<u32>,
+}
+
+impl SqliteSink {
+    pub fn open(path: &str, float_decimals: Option<u32>) -> Result<Self> {
+        let connection =
+            Connection::open(path).with_context(|| format!("Could not open {}", path))?;
+        connection
+            .execute_batch(SCHEMA)
+            .with_context(|| format!("Could not create the tables of {}", path))?;
+
+        Ok(Self {
+            connection,
+            path: path.to_owned(),
+            float_decimals,
+        })
+    }
+}
+
+/// Rounds `x` like the other sinks, non-finite values are written as null.
+fn real(x: f64, decimals: Option<u32>) -> Option<f64> {
+    x.is_finite()
+        .then(|| decimals.map_or(x + 0.0, |decimals| float::round(x, decimals)))
+}
+
+fn insert_regions(
+    tx: &Transaction,
+    id: i64,
+    report: &CliJsonOutput,
+    decimals: Option<u32>,
+) -> Result<()> {
+    let mut insert = tx.prepare_cached(
+        "INSERT INTO regions (report, file, range_start, range_end, size, arch, confidence)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+    )?;
+    for (range, size, arch, confidence) in report.regions() {
+        insert.execute(params![
+            id,
+            report.file,
+            range.start,
+            range.end,
+            size,
+            arch,
+            real(confidence, decimals)
+        ])?;
+    }
+    for nested in report
+        .members
+        .iter()
+        .flat_map(|members| members.values())
+        .chain(report.streams.iter().flatten())
+    {
+        insert_regions(tx, id, nested, decimals)?;
+    }
+
+    Ok(())
+}
+
+impl ReportSink for SqliteSink {
+    fn write_report(&mut self, report: &CliJsonOutput) -> Result<()> {
+        let json = serde_json::to_string(&float::to_value(report, self.float_decimals)?)?;
+        let tx = self.connection.transaction()?;
+        tx.execute(
+            "INSERT INTO reports (file, size, dominant_arch, unknown, report)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                report.file,
+                report.size,
+                report.dominant_arch,
+                real(report.composition.unknown, self.float_decimals).unwrap_or_default(),
+                json
+            ],
+        )?;
+        let id = tx.last_insert_rowid();
+        insert_regions(&tx, id, report, self.float_decimals)?;
+        tx.commit()
+            .with_context(|| format!("Could not write {} to {}", report.file, self.path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{detect_code, load_corpus, DetectionOptions, ProcessedDetectionResult};
+
+    #[test]
+    fn reports_and_regions() {
+        let corpus = load_corpus();
+        let data = [
+            b"The quick brown fox jumps over the lazy dog. ".repeat(400),
+            vec![0; 0x4000],
+        ]
+        .concat();
+        let res: ProcessedDetectionResult = detect_code(
+            &corpus,
+            &data,
+            &[],
+            "text",
+            &DetectionOptions::default(),
+            &[],
+        )
+        .into();
+        let report = CliJsonOutput::from(("text.bin", &res));
+        assert!(!report.range_results.is_empty());
+
+        let path = std::env::temp_dir()
+            .join(format!("coderec-sqlite-{}.db", std::process::id()))
+            .display()
+            .to_string();
+        // Reports are added to the database.
+        for _ in 0..2 {
+            let mut sink = SqliteSink::open(&path, Some(2)).unwrap();
+            sink.write_report(&report).unwrap();
+            sink.finish().unwrap();
+        }
+
+        let connection = Connection::open(&path).unwrap();
+        let reports: i64 = connection
+            .query_row("SELECT count(*) FROM reports", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(reports, 2);
+        let mut query = connection
+            .prepare("SELECT range_start, range_end, size, arch, confidence FROM regions WHERE report = 1")
+            .unwrap();
+        let regions: Vec<(usize, usize, usize, String, f64)> = query
+            .query_map([], |row| {
+                Ok((
+                    row.get(0)?,
+                    row.get(1)?,
+                    row.get(2)?,
+                    row.get(3)?,
+                    row.get(4)?,
+                ))
+            })
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap();
+        let expected: Vec<_> = report
+            .regions()
+            .map(|(range, size, arch, confidence)| {
+                (
+                    range.start,
+                    range.end,
+                    size,
+                    arch.clone(),
+                    float::round(confidence, 2),
+                )
+            })
+            .collect();
+        assert_eq!(regions, expected);
+        let json: String = connection
+            .query_row("SELECT report FROM reports WHERE id = 2", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&json).unwrap(),
+            float::to_value(&report, Some(2)).unwrap()
+        );
+
+        drop(query);
+        drop(connection);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,109 @@
+/*
+    Copyright 2024 - Valentin Obst <coderec@vpao.io>
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+*/
+//! Compressed streams embedded in the input, see `--decompress`.
+//!
+//! The input is searched for the headers of gzip, xz, LZMA, zstd, and LZ4
+//! streams, e.g., the payload of a self-decompressing kernel. Each stream
+//! that decompresses is analyzed on its own, and searched for streams in
+//! turn. Its report is nested in the report of the data that contains it.
+
+use crate::container::{decompress_stream, read_u32, read_u64, Member};
+
+use log::{debug, warn};
+
+/// Headers of the streams and their compression method, named as in U-Boot.
+const SIGNATURES: [(&[u8], &str); 6] = [
+    (&[0x1f, 0x8b, 0x08], "gzip"),
+    (&[0xfd, b'7', b'z', b'X', b'Z', 0x00], "xz"),
+    (&[0x28, 0xb5, 0x2f, 0xfd], "zstd"),
+    (&[0x04, 0x22, 0x4d, 0x18], "lz4"),
+    // Legacy frames, as used by Linux.
+    (&[0x02, 0x21, 0x4c, 0x18], "lz4"),
+    // Default properties and a dictionary size that is a multiple of 64KiB.
+    (&[0x5d, 0x00, 0x00], "lzma"),
+];
+
+/// LZMA headers have no magic, so also require a sensible dictionary size and
+/// an unknown or plausible decompressed size.
+fn is_lzma_header(data: &[u8], max_size: usize) -> bool {
+    let (Some(dict_size), Some(size)) = (read_u32(data, 1), read_u64(data, 5)) else {
+        return false;
+    };
+
+    dict_size != 0
+        && dict_size <= 1 << 30
+        && (size == u64::MAX || (size != 0 && size <= max_size as u64))
+}
+
+/// Returns the streams in `data`, which is the content of `file`, that
+/// decompress to at most `max_size` bytes, together with their decompressed
+/// content.
+pub fn find_streams(file: &str, data: &[u8], max_size: usize) -> Vec<(Member, Vec<u8>)> {
+    let mut streams = Vec::new();
+    let mut offset = 0;
+    while offset < data.len() {
+        let rest = &data[offset..];
+        let Some(&(_, method)) = SIGNATURES
+            .iter()
+            .find(|(magic, _)| rest.starts_with(magic))
+            .filter(|(_, method)| *method != "lzma" || is_lzma_header(rest, max_size))
+        else {
+            offset += 1;
+            continue;
+        };
+
+        match decompress_stream(method, rest, max_size) {
+            Ok((content, size)) if !content.is_empty() => {
+                debug!(
+                    "{}: {} stream of {} bytes at {:#x} decompresses to {} bytes",
+                    file,
+                    method,
+                    size,
+                    offset,
+                    content.len()
+                );
+                streams.push((
+                    Member {
+                        format: method.to_owned(),
+                        id: format!("{:x}", offset),
+                        path: format!("{:#x}", offset),
+                        offset: None,
+                        load_address: None,
+                        partition: None,
+                    },
+                    content,
+                ));
+                // Signatures within the stream are only coincidences.
+                offset += size.max(1);
+            }
+            Ok(_) => offset += 1,
+            // Without a magic, most LZMA headers are coincidences.
+            Err(e) if method == "lzma" => {
+                debug!("{}: no {} stream at {:#x}: {:#}", file, method, offset, e);
+                offset += 1;
+            }
+            Err(e) => {
+                warn!(
+                    "{}: skipping {} stream at {:#x}: {:#}",
+                    file, method, offset, e
+                );
+                offset += 1;
+            }
+        }
+    }
+
+    streams
+}
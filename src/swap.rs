@@ -0,0 +1,145 @@
+/*
+    Copyright 2024 - Valentin Obst <coderec@vpao.io>
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+*/
+//! Detection of byte-swapped inputs.
+//!
+//! Flash dumps are often read out with the wrong byte order in each 16- or
+//! 32-bit word. The file is analyzed again with the bytes of each word
+//! reversed, using the same windows, and the swapped view is reported if it
+//! is much closer to the corpus.
+
+use crate::corpus::CorpusStats;
+use crate::output::CliJsonOutput;
+use crate::{detect_code, Arch, DetectionOptions, ProcessedDetectionResult};
+
+use std::collections::HashSet;
+use std::ops::Range;
+
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+
+/// Word sizes that are tried.
+const GRANULARITIES: &[usize] = &[2, 4];
+
+/// Minimum relative decrease of the mean trigram divergence for a swapped
+/// view to be reported.
+const MIN_IMPROVEMENT: f64 = 0.25;
+
+/// Swapped view of a file that is closer to the corpus than the file itself.
+#[derive(Serialize, Deserialize)]
+pub struct SwapInfo {
+    /// Size of the words whose bytes are reversed.
+    pub granularity: usize,
+    /// Mean trigram divergence of the closest arch over the windows that were
+    /// attributed to some arch in either view.
+    pub divergence: f64,
+    /// The same for the unswapped file.
+    pub unswapped_divergence: f64,
+    /// Regions detected in the swapped view, as in the report.
+    pub range_results: Vec<(Range<usize>, usize, Arch)>,
+    /// Confidence of each region.
+    pub confidences: Vec<f64>,
+}
+
+/// Reverses the bytes of each `granularity` sized word. Trailing bytes that
+/// do not fill a word are kept as is.
+pub fn swap_bytes(data: &[u8], granularity: usize) -> Vec<u8> {
+    let mut swapped = data.to_vec();
+    for word in swapped.chunks_exact_mut(granularity) {
+        word.reverse();
+    }
+
+    swapped
+}
+
+/// Analyzed windows of `res` that were attributed to some arch.
+fn code_windows(res: &ProcessedDetectionResult) -> HashSet<Range<usize>> {
+    res.segments
+        .iter()
+        .filter(|segment| {
+            segment.arch.is_some() && res.range_to_result_tg.contains_key(&segment.window)
+        })
+        .map(|segment| segment.window.clone())
+        .collect()
+}
+
+fn mean_divergence(res: &ProcessedDetectionResult, windows: &HashSet<Range<usize>>) -> f64 {
+    windows
+        .iter()
+        .map(|window| res.range_to_result_tg[window].div)
+        .sum::<f64>()
+        / windows.len() as f64
+}
+
+/// Analyzes the swapped views of `data` with the windows of `res` and
+/// returns the best one if it is much closer to the corpus than `res`.
+pub fn detect_swap(
+    corpus_stats: &[CorpusStats],
+    data: &[u8],
+    name: &str,
+    res: &ProcessedDetectionResult,
+    options: &DetectionOptions,
+) -> Option<SwapInfo> {
+    let options = DetectionOptions {
+        trace: None,
+        window_size: Some(res.win_sz),
+        min_windows: None,
+        stride: Some(res.stride),
+        ..options.clone()
+    };
+
+    let mut best: Option<SwapInfo> = None;
+    for &granularity in GRANULARITIES {
+        let swapped = swap_bytes(data, granularity);
+        let swapped_res: ProcessedDetectionResult =
+            detect_code(corpus_stats, &swapped, &[], name, &options, &[]).into();
+
+        // Windows that are noise in both views would only dilute the means.
+        let mut windows = code_windows(res);
+        windows.extend(code_windows(&swapped_res));
+        if windows.is_empty() {
+            continue;
+        }
+        let divergence = mean_divergence(&swapped_res, &windows);
+        let unswapped_divergence = mean_divergence(res, &windows);
+        debug!(
+            "{}: mean trigram divergence {} unswapped, {} swapped in {}-byte words",
+            name, unswapped_divergence, divergence, granularity
+        );
+
+        if divergence <= unswapped_divergence * (1.0 - MIN_IMPROVEMENT)
+            && best
+                .as_ref()
+                .is_none_or(|best| divergence < best.divergence)
+        {
+            let report = CliJsonOutput::from((name, &swapped_res));
+            best = Some(SwapInfo {
+                granularity,
+                divergence,
+                unswapped_divergence,
+                range_results: report.range_results,
+                confidences: report.confidences,
+            });
+        }
+    }
+
+    let best = best?;
+    warn!(
+        "{}: looks byte-swapped in {}-byte words, mean trigram divergence {:.2} instead of {:.2}",
+        name, best.granularity, best.divergence, best.unswapped_divergence
+    );
+
+    Some(best)
+}
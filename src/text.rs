@@ -0,0 +1,192 @@
+/*
+    Copyright 2024 - Valentin Obst <coderec@vpao.io>
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+*/
+//! Detection of text and text encodings.
+//!
+//! String tables and encoded blobs are often closer to some exotic arch than
+//! to the `_words` corpus entries. The window of each segment is thus checked
+//! for the characters that make up ASCII text, UTF-16 strings, Base64, or hex
+//! encoded data, and labeled with a pseudo-arch if it matches, regardless of
+//! the arch it was attributed to.
+
+use crate::padding::is_exact;
+use crate::{is_traced, ProcessedDetectionResult, TRACE_TARGET};
+
+use log::info;
+
+/// Minimum share of the bytes, or code units for UTF-16, that must belong to
+/// an encoding.
+const MIN_SHARE: f64 = 0.95;
+/// Minimum share of the bytes that must be printable for ASCII text, the rest
+/// may be whitespace and string terminators.
+const MIN_PRINTABLE_SHARE: f64 = 0.6;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TextClass {
+    Ascii,
+    Utf16Le,
+    Utf16Be,
+    Base64,
+    Hex,
+}
+
+impl TextClass {
+    pub const ALL: [Self; 5] = [
+        Self::Ascii,
+        Self::Utf16Le,
+        Self::Utf16Be,
+        Self::Base64,
+        Self::Hex,
+    ];
+
+    /// Pseudo-arch that is reported for regions of this class.
+    pub fn label(self) -> &'static str {
+        match self {
+            Self::Ascii => "text/ascii",
+            Self::Utf16Le => "text/utf-16le",
+            Self::Utf16Be => "text/utf-16be",
+            Self::Base64 => "text/base64",
+            Self::Hex => "text/hex",
+        }
+    }
+}
+
+fn share(count: usize, total: usize) -> f64 {
+    count as f64 / total as f64
+}
+
+fn is_printable(byte: u8) -> bool {
+    (0x20..0x7f).contains(&byte)
+}
+
+fn is_space(byte: u8) -> bool {
+    matches!(byte, b' ' | b'\t' | b'\r' | b'\n')
+}
+
+fn is_text(byte: u8) -> bool {
+    is_printable(byte) || is_space(byte) || byte == 0
+}
+
+/// Share of the code units that are ASCII characters in the given byte order,
+/// with `data` starting at an even offset. Units that are both zero are not
+/// counted as characters but do not count against the encoding either.
+fn utf16_share(data: &[u8], little_endian: bool) -> f64 {
+    let units = data.len() / 2;
+    let (mut chars, mut nuls) = (0, 0);
+    for unit in data.chunks_exact(2) {
+        let (low, high) = if little_endian {
+            (unit[0], unit[1])
+        } else {
+            (unit[1], unit[0])
+        };
+        if high != 0 {
+            continue;
+        }
+        if low == 0 {
+            nuls += 1;
+        } else if is_text(low) {
+            chars += 1;
+        }
+    }
+
+    // Mostly zeros is not text.
+    if share(chars, units) < MIN_PRINTABLE_SHARE {
+        return 0.0;
+    }
+
+    share(chars + nuls, units)
+}
+
+/// Returns the encoding of `data`, which starts at an even offset, and the
+/// share of the data that matches it.
+pub fn classify(data: &[u8]) -> Option<(TextClass, f64)> {
+    if data.len() < 2 {
+        return None;
+    }
+    let count = |pred: fn(u8) -> bool| data.iter().filter(|byte| pred(**byte)).count();
+
+    let hex = share(
+        count(|byte| byte.is_ascii_hexdigit() || is_space(byte)),
+        data.len(),
+    );
+    let base64 = share(
+        count(|byte| {
+            byte.is_ascii_alphanumeric() || matches!(byte, b'+' | b'/' | b'=' | b'\r' | b'\n')
+        }),
+        data.len(),
+    );
+    let text = share(count(is_text), data.len());
+    let printable = share(count(is_printable), data.len());
+    let spaces = share(count(is_space), data.len());
+
+    // Hex digits are also Base64 characters, and both are also ASCII text. Try
+    // the most specific encoding first.
+    if hex >= MIN_SHARE && spaces < 1.0 - MIN_SHARE {
+        return Some((TextClass::Hex, hex));
+    }
+    // Base64 uses all character classes but no spaces.
+    if base64 >= MIN_SHARE
+        && count(|byte| byte.is_ascii_uppercase()) > 0
+        && count(|byte| byte.is_ascii_lowercase()) > 0
+        && count(|byte| byte.is_ascii_digit()) > 0
+        && count(|byte| byte == b' ') == 0
+    {
+        return Some((TextClass::Base64, base64));
+    }
+    if text >= MIN_SHARE && printable >= MIN_PRINTABLE_SHARE {
+        return Some((TextClass::Ascii, text));
+    }
+
+    let le = utf16_share(data, true);
+    let be = utf16_share(data, false);
+    if le >= MIN_SHARE && le >= be {
+        return Some((TextClass::Utf16Le, le));
+    }
+    if be >= MIN_SHARE {
+        return Some((TextClass::Utf16Be, be));
+    }
+
+    None
+}
+
+/// Labels the segments of `res` whose window is text with the pseudo-arch of
+/// its encoding, except for padding and device tree blobs. `data` are the analyzed bytes.
+pub fn label_text(res: &mut ProcessedDetectionResult, data: &[u8]) {
+    let mut segments = res.segments.clone();
+    for segment in segments
+        .iter_mut()
+        .filter(|segment| !is_exact(segment.arch.as_deref()))
+    {
+        // Keep UTF-16 code units aligned.
+        let start = segment.window.start & !1;
+        let class = classify(&data[start..segment.window.end]);
+
+        if is_traced(&res.trace, &segment.window) {
+            info!(
+                target: TRACE_TARGET,
+                "{:x?}: text {:?}",
+                segment.window,
+                class
+            );
+        }
+
+        if let Some((class, share)) = class {
+            segment.arch = Some(class.label().to_owned());
+            segment.confidence = share;
+        }
+    }
+
+    res.set_segments(segments);
+}
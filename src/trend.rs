@@ -0,0 +1,133 @@
+/*
+    Copyright 2024 - Valentin Obst <coderec@vpao.io>
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+*/
+//! Composition of a product across its versions.
+//!
+//! Versions are ordered by the time at which they were first added to the
+//! results database. Files of the same version are added up.
+
+use crate::db::ResultRecord;
+use crate::Arch;
+
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+
+#[derive(Debug, Serialize)]
+pub struct VersionSummary {
+    pub version: String,
+    pub files: usize,
+    pub size: usize,
+    /// Number of bytes per arch.
+    pub arches: BTreeMap<Arch, usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangeKind {
+    Appeared,
+    Disappeared,
+    Grew,
+    Shrank,
+}
+
+/// Significant change of an arch between two successive versions.
+#[derive(Debug, Serialize)]
+pub struct Change {
+    pub version: String,
+    pub previous: String,
+    pub arch: Arch,
+    pub kind: ChangeKind,
+    /// Bytes in the previous and in this version.
+    pub before: usize,
+    pub after: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Trend {
+    pub product: String,
+    pub versions: Vec<VersionSummary>,
+    pub changes: Vec<Change>,
+}
+
+impl Trend {
+    /// Summarizes the `records` of `product` and reports arches whose size
+    /// changed by more than `min_change` percent.
+    pub fn new(product: &str, records: &[ResultRecord], min_change: f64) -> Self {
+        let mut versions: Vec<VersionSummary> = Vec::new();
+        for record in records {
+            let idx = match versions
+                .iter()
+                .position(|summary| summary.version == record.version)
+            {
+                Some(idx) => idx,
+                None => {
+                    versions.push(VersionSummary {
+                        version: record.version.clone(),
+                        files: 0,
+                        size: 0,
+                        arches: BTreeMap::new(),
+                    });
+                    versions.len() - 1
+                }
+            };
+            let summary = &mut versions[idx];
+            summary.files += 1;
+            summary.size += record.size;
+            for (arch, size) in record.arches.iter() {
+                *summary.arches.entry(arch.clone()).or_default() += size;
+            }
+        }
+
+        let mut changes = Vec::new();
+        for pair in versions.windows(2) {
+            let (prev, cur) = (&pair[0], &pair[1]);
+            let mut arches: Vec<_> = prev.arches.keys().chain(cur.arches.keys()).collect();
+            arches.sort_unstable();
+            arches.dedup();
+
+            for arch in arches {
+                let before = prev.arches.get(arch).copied().unwrap_or(0);
+                let after = cur.arches.get(arch).copied().unwrap_or(0);
+                let kind = if before == 0 {
+                    ChangeKind::Appeared
+                } else if after == 0 {
+                    ChangeKind::Disappeared
+                } else if after as f64 > before as f64 * (1.0 + min_change / 100.0) {
+                    ChangeKind::Grew
+                } else if (after as f64) < before as f64 * (1.0 - min_change / 100.0) {
+                    ChangeKind::Shrank
+                } else {
+                    continue;
+                };
+
+                changes.push(Change {
+                    version: cur.version.clone(),
+                    previous: prev.version.clone(),
+                    arch: arch.clone(),
+                    kind,
+                    before,
+                    after,
+                });
+            }
+        }
+
+        Self {
+            product: product.to_owned(),
+            versions,
+            changes,
+        }
+    }
+}
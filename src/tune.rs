@@ -0,0 +1,229 @@
+/*
+    Copyright 2024 - Valentin Obst <coderec@vpao.io>
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+*/
+//! Interactive tuning of the thresholds, see `coderec tune`.
+//!
+//! The divergences of all windows are computed once. Each command changes the
+//! settings that only apply to the divergences, i.e., the thresholds, the
+//! filters, the segmenter, and the consolidation, and prints the resulting
+//! regions right away, which takes a fraction of a full run. Thresholds and
+//! filters are set by their key in the configuration file, e.g.,
+//! `set thresholds.max_abs_div_tg 5.5`, and `config` prints them in its
+//! format once they fit.
+
+use crate::config::{merge_tables, Config};
+use crate::consolidate::Consolidation;
+use crate::corpus::CorpusStats;
+use crate::hmm::Segmenter;
+use crate::output::{CliJsonOutput, ReportSink};
+use crate::plotting::{plot_regions, PlotMetadata, PlotOptions};
+use crate::{detect_code, parse_nonzero_hex, DetectionOptions, DetectionResult};
+
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::str::FromStr;
+use std::time::Instant;
+
+use anyhow::{bail, Result};
+use log::{info, warn};
+
+const HELP: &str = "\
+Commands:
+  set KEY VALUE  Set a threshold or filter by its key in the config file, e.g.,
+                 thresholds.max_abs_div_bg 4.5 or filters.max_gap 0x400, or
+                 consolidation, min-region-length, or segmenter
+  show           Print the regions again
+  config         Print the settings, as config file and command line options
+  reset          Restore the settings at the start
+  help           Print this help
+  quit           Leave, as does the end of the input";
+
+/// Settings that are applied to the divergences.
+#[derive(Debug, Clone)]
+struct Settings {
+    /// Thresholds and filters, in the format of the configuration file.
+    config: toml::Table,
+    /// Values of the command line options, by option name.
+    flags: BTreeMap<&'static str, String>,
+}
+
+impl Settings {
+    fn new(options: &DetectionOptions) -> Self {
+        let config = Config {
+            thresholds: options.thresholds.clone(),
+            filters: options.filters.clone(),
+            ..Default::default()
+        };
+        let config = toml::Table::try_from(config).unwrap();
+        let mut flags = BTreeMap::new();
+        flags.insert(
+            "segmenter",
+            match options.segmenter {
+                Segmenter::Window => "window",
+                Segmenter::Hmm => "hmm",
+            }
+            .to_owned(),
+        );
+        flags.insert(
+            "consolidation",
+            match options.consolidation {
+                Consolidation::Strict => "strict",
+                Consolidation::MinLength => "min-length",
+                Consolidation::ScoreWeighted => "score-weighted",
+            }
+            .to_owned(),
+        );
+        if let Some(min_region_length) = options.min_region_length {
+            flags.insert("min-region-length", format!("{:#x}", min_region_length));
+        }
+
+        Self {
+            config: ["thresholds", "filters"]
+                .into_iter()
+                .filter_map(|key| Some((key.to_owned(), config.get(key)?.clone())))
+                .collect(),
+            flags,
+        }
+    }
+
+    /// Sets `key` to `value`, see `HELP`.
+    fn set(&mut self, key: &str, value: &str) -> Result<()> {
+        let flag = match key {
+            "segmenter" => Segmenter::from_str(value).map(|_| "segmenter"),
+            "consolidation" => Consolidation::from_str(value).map(|_| "consolidation"),
+            "min-region-length" => parse_nonzero_hex(value).map(|_| "min-region-length"),
+            _ => {
+                if !key.starts_with("thresholds.") && !key.starts_with("filters.") {
+                    bail!("{} is neither a threshold, a filter, nor an option", key);
+                }
+                let over: toml::Table = format!("{} = {}", key, value).parse()?;
+                let mut config = self.config.clone();
+                merge_tables(&mut config, over);
+                Config::from_table(config.clone())?;
+                self.config = config;
+
+                return Ok(());
+            }
+        };
+        self.flags
+            .insert(flag.map_err(anyhow::Error::msg)?, value.to_owned());
+
+        Ok(())
+    }
+
+    /// Applies the settings to the scored windows of `scored`.
+    fn apply(&self, scored: &mut DetectionResult) {
+        let config = Config::from_table(self.config.clone()).unwrap();
+        let flag = |name| self.flags.get(name).map(String::as_str);
+        scored.thresholds = config.thresholds;
+        scored.filters = config.filters;
+        scored.segmenter = Segmenter::from_str(flag("segmenter").unwrap()).unwrap();
+        scored.consolidation = Consolidation::from_str(flag("consolidation").unwrap()).unwrap();
+        scored.min_region_length = flag("min-region-length").map(|v| parse_nonzero_hex(v).unwrap());
+    }
+}
+
+/// Settings of a tuning session.
+pub struct TuneOptions {
+    /// Options of the scoring and the settings at the start.
+    pub detection: DetectionOptions,
+    /// Plot the regions after each change with these options.
+    pub plots: Option<PlotOptions>,
+}
+
+/// Scores the windows of `data`, the content of `file`, and reads commands
+/// from `input` until it ends, printing the regions to `sink`.
+pub fn tune(
+    file: &str,
+    data: &[u8],
+    corpus: &[CorpusStats],
+    options: &TuneOptions,
+    mut input: impl BufRead,
+    sink: &mut dyn ReportSink,
+) -> Result<()> {
+    let now = Instant::now();
+    let scored = detect_code(corpus, data, &[], file, &options.detection, &[]);
+    info!(
+        "{}: scored in {}ms, see help for the commands",
+        file,
+        now.elapsed().as_millis()
+    );
+    let plot_metadata = options
+        .plots
+        .map(|plot_options| PlotMetadata::new(corpus, plot_options));
+
+    let initial = Settings::new(&options.detection);
+    let mut settings = initial.clone();
+    let mut show = true;
+    let interactive = io::stdin().is_terminal();
+    let mut line = String::new();
+    loop {
+        if show {
+            let now = Instant::now();
+            let mut applied = scored.clone();
+            settings.apply(&mut applied);
+            let res = applied.into();
+            let mut report = CliJsonOutput::from((file, &res));
+            report.add_summary(now.elapsed());
+            sink.write_report(&report)?;
+            if let Some(plot_metadata) = &plot_metadata {
+                let plot = plot_regions(file, data.len(), data, &res, false, 0, plot_metadata);
+                info!("Wrote {}", plot);
+            }
+        }
+
+        if interactive {
+            eprint!("> ");
+            io::stderr().flush()?;
+        }
+        line.clear();
+        if input.read_line(&mut line)? == 0 {
+            break;
+        }
+        let words: Vec<&str> = line.split_whitespace().collect();
+        show = false;
+        match words.as_slice() {
+            [] => (),
+            ["set", key, value @ ..] if !value.is_empty() => {
+                match settings.set(key, &value.join(" ")) {
+                    Ok(()) => show = true,
+                    Err(e) => warn!("{}: {:#}", key, e),
+                }
+            }
+            ["show"] => show = true,
+            ["config"] => {
+                print!("{}", toml::to_string(&settings.config)?);
+                println!(
+                    "# {}",
+                    settings
+                        .flags
+                        .iter()
+                        .map(|(name, value)| format!("--{} {}", name, value))
+                        .collect::<Vec<_>>()
+                        .join(" ")
+                );
+            }
+            ["reset"] => {
+                settings = initial.clone();
+                show = true;
+            }
+            ["help"] => println!("{}", HELP),
+            ["quit" | "exit"] => break,
+            _ => warn!("Unknown command {:?}, see help", line.trim()),
+        }
+    }
+
+    Ok(())
+}
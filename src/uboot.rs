@@ -0,0 +1,269 @@
+/*
+    Copyright 2024 - Valentin Obst <coderec@vpao.io>
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+*/
+//! Payloads of U-Boot images.
+//!
+//! Legacy uImages have a header with the type, compression, and load address
+//! of the payload, multi-file images have several payloads. FIT images are
+//! device trees whose `/images` node has one child per component, e.g., a
+//! kernel, ramdisk, or device tree, whose data is embedded in the tree or
+//! follows it. Payloads are decompressed if they are gzip or LZMA compressed.
+
+use crate::container::{decompress_stream, Member};
+use crate::fdt::{self, fdt_size, read_be_u32};
+
+use log::{debug, warn};
+
+const UIMAGE_MAGIC: u32 = 0x27051956;
+const UIMAGE_HEADER_SIZE: usize = 64;
+const UIMAGE_TYPE_MULTI: u8 = 4;
+
+/// Names of the uImage compression methods, by their number.
+const UIMAGE_COMPRESSION: [&str; 7] = ["none", "gzip", "bzip2", "lzma", "lzo", "lz4", "zstd"];
+
+fn uimage_type(image_type: u8) -> &'static str {
+    match image_type {
+        1 => "standalone",
+        2 => "kernel",
+        3 => "ramdisk",
+        4 => "multi",
+        5 => "firmware",
+        6 => "script",
+        7 => "filesystem",
+        8 => "flat_dt",
+        _ => "payload",
+    }
+}
+
+/// Returns true if `data` starts with a uImage header with a valid checksum.
+pub fn is_uimage(data: &[u8]) -> bool {
+    let Some(header) = data.get(..UIMAGE_HEADER_SIZE) else {
+        return false;
+    };
+    if read_be_u32(header, 0) != Some(UIMAGE_MAGIC) {
+        return false;
+    }
+
+    let mut zeroed = header.to_vec();
+    zeroed[4..8].fill(0);
+    crc32fast::hash(&zeroed) == read_be_u32(header, 4).unwrap()
+}
+
+/// Decompresses a payload to at most `max_size` bytes and returns the member
+/// for it, or logs why it is skipped.
+fn unpack_payload(
+    file: &str,
+    member: Member,
+    compression: &str,
+    data: &[u8],
+    max_size: usize,
+) -> Option<(Member, Vec<u8>)> {
+    // Uncompressed payloads are already in memory.
+    let limit = if compression == "none" {
+        usize::MAX
+    } else {
+        max_size
+    };
+    match decompress_stream(compression, data, limit) {
+        Ok((decompressed, _)) => {
+            debug!(
+                "{}: {} has {} bytes, {} compressed",
+                file,
+                member.path,
+                decompressed.len(),
+                compression
+            );
+            let member = Member {
+                offset: member.offset.filter(|_| compression == "none"),
+                ..member
+            };
+            Some((member, decompressed))
+        }
+        Err(e) => {
+            warn!("{}: skipping {}: {:#}", file, member.path, e);
+            None
+        }
+    }
+}
+
+/// Returns the payloads of the uImage in `data`, which is the content of
+/// `file`. Payloads are decompressed to at most `max_size` bytes.
+pub fn find_uimage_payloads(file: &str, data: &[u8], max_size: usize) -> Vec<(Member, Vec<u8>)> {
+    let header = &data[..UIMAGE_HEADER_SIZE];
+    let size = read_be_u32(header, 12).unwrap() as usize;
+    let load_address = read_be_u32(header, 16).unwrap() as u64;
+    let image_type = header[30];
+    let compression = UIMAGE_COMPRESSION
+        .get(header[31] as usize)
+        .copied()
+        .unwrap_or("unknown");
+    let name = String::from_utf8_lossy(&header[32..])
+        .trim_end_matches('\0')
+        .to_owned();
+
+    let Some(body) = data.get(UIMAGE_HEADER_SIZE..UIMAGE_HEADER_SIZE + size) else {
+        warn!("{}: truncated uImage", file);
+        return Vec::new();
+    };
+    if crc32fast::hash(body) != read_be_u32(header, 24).unwrap() {
+        warn!("{}: invalid uImage data checksum", file);
+    }
+
+    // The payloads of multi-file images follow a zero terminated list of
+    // their sizes, each is padded to four bytes.
+    let mut payloads = Vec::new();
+    if image_type == UIMAGE_TYPE_MULTI {
+        let sizes: Vec<usize> = (0..)
+            .map_while(|idx| read_be_u32(body, 4 * idx))
+            .take_while(|size| *size != 0)
+            .map(|size| size as usize)
+            .collect();
+        let mut offset = 4 * (sizes.len() + 1);
+        for (idx, size) in sizes.into_iter().enumerate() {
+            let Some(payload) = body.get(offset..offset + size) else {
+                warn!("{}: truncated uImage part {}", file, idx);
+                break;
+            };
+            payloads.push((format!("part{}", idx), offset, payload, None));
+            offset = (offset + size).next_multiple_of(4);
+        }
+    } else {
+        payloads.push((
+            uimage_type(image_type).to_owned(),
+            0,
+            body,
+            Some(load_address),
+        ));
+    }
+
+    payloads
+        .into_iter()
+        .filter_map(|(id, offset, payload, load_address)| {
+            let member = Member {
+                format: "uimage".to_owned(),
+                path: if name.is_empty() {
+                    id.clone()
+                } else {
+                    format!("{}/{}", name, id)
+                },
+                id,
+                offset: Some(UIMAGE_HEADER_SIZE + offset),
+                load_address,
+                partition: None,
+            };
+            unpack_payload(file, member, compression, payload, max_size)
+        })
+        .collect()
+}
+
+/// Returns the components of the FIT image in `data`, which is the content
+/// of `file`. Returns nothing if `data` is some other device tree. Components
+/// are decompressed to at most `max_size` bytes.
+pub fn find_fit_images(file: &str, data: &[u8], max_size: usize) -> Vec<(Member, Vec<u8>)> {
+    let root = match fdt::parse(data) {
+        Ok(root) => root,
+        Err(e) => {
+            warn!("{}: invalid device tree: {:#}", file, e);
+            return Vec::new();
+        }
+    };
+    let Some(images) = root.child("images") else {
+        return Vec::new();
+    };
+    // External data follows the tree, aligned to four bytes.
+    let external = fdt_size(data).unwrap().next_multiple_of(4);
+
+    images
+        .children
+        .iter()
+        .filter_map(|image| {
+            let (offset, payload) = if let Some(payload) = image.property("data") {
+                (None, payload)
+            } else {
+                let size = image.number("data-size")? as usize;
+                let offset = match image.number("data-position") {
+                    Some(position) => position as usize,
+                    None => external + image.number("data-offset")? as usize,
+                };
+                match data.get(offset..offset + size) {
+                    Some(payload) => (Some(offset), payload),
+                    None => {
+                        warn!("{}: truncated FIT image {}", file, image.name);
+                        return None;
+                    }
+                }
+            };
+
+            let member = Member {
+                format: "fit".to_owned(),
+                id: image.name.replace('/', "_"),
+                path: format!(
+                    "images/{}/{}",
+                    image.name,
+                    image.string("type").unwrap_or("payload")
+                ),
+                offset,
+                load_address: image.number("load"),
+                partition: None,
+            };
+            unpack_payload(
+                file,
+                member,
+                image.string("compression").unwrap_or("none"),
+                payload,
+                max_size,
+            )
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Write;
+
+    use flate2::{write::GzEncoder, Compression};
+
+    /// uImage of a kernel with `payload` compressed with gzip.
+    fn uimage(payload: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(payload).unwrap();
+        let body = encoder.finish().unwrap();
+
+        let mut image = vec![0; UIMAGE_HEADER_SIZE];
+        image[0..4].copy_from_slice(&UIMAGE_MAGIC.to_be_bytes());
+        image[12..16].copy_from_slice(&(body.len() as u32).to_be_bytes());
+        image[24..28].copy_from_slice(&crc32fast::hash(&body).to_be_bytes());
+        image[30] = 2;
+        image[31] = 1;
+        let header_crc = crc32fast::hash(&image);
+        image[4..8].copy_from_slice(&header_crc.to_be_bytes());
+        image.extend_from_slice(&body);
+
+        image
+    }
+
+    #[test]
+    fn payloads_are_bounded() {
+        let image = uimage(&[0; 0x10000]);
+        assert!(is_uimage(&image));
+
+        let payloads = find_uimage_payloads("uImage", &image, 0x10000);
+        assert_eq!(payloads.len(), 1);
+        assert_eq!(payloads[0].1.len(), 0x10000);
+        assert!(find_uimage_payloads("uImage", &image, 0xffff).is_empty());
+    }
+}
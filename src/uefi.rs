@@ -0,0 +1,339 @@
+/*
+    Copyright 2024 - Valentin Obst <coderec@vpao.io>
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+*/
+//! Traversal of UEFI firmware volumes.
+//!
+//! Volumes are found anywhere in the input by their signature. Each FFS file
+//! with an executable section, i.e., a PE32, TE, PIC, or 16-bit compatibility
+//! image, is a module, as is each raw file. Modules are identified by their
+//! GUID, prefixed by their UI name if they have one. Nested volumes are
+//! traversed, and sections without compression, LZMA compressed sections,
+//! and GUID-defined sections that need no processing are unpacked. Other encapsulations, e.g.,
+//! the EFI standard compression, are skipped with a warning.
+
+use crate::container::{decompress_stream, read_u16, read_u24, read_u32, read_u64, Member};
+
+use log::{debug, warn};
+
+const FVH_SIGNATURE: &[u8] = b"_FVH";
+/// Offset of the signature in the volume header.
+const FVH_SIGNATURE_OFFSET: usize = 40;
+const FVH_MIN_SIZE: usize = 56;
+/// Volumes with this attribute have erased bytes of `0xff`.
+const FVB2_ERASE_POLARITY: u32 = 0x800;
+const FFS_ATTRIB_LARGE_FILE: u8 = 0x01;
+const GUIDED_SECTION_PROCESSING_REQUIRED: u16 = 0x01;
+const LZMA_GUID: &str = "EE4E5898-3914-4259-9D6E-DC7BD79403CF";
+/// Maximum nesting of volumes and encapsulation sections.
+const MAX_DEPTH: usize = 16;
+
+const FILE_TYPE_RAW: u8 = 0x01;
+const FILE_TYPE_PAD: u8 = 0xf0;
+
+const SECTION_COMPRESSION: u8 = 0x01;
+const SECTION_GUID_DEFINED: u8 = 0x02;
+const SECTION_PE32: u8 = 0x10;
+const SECTION_PIC: u8 = 0x11;
+const SECTION_TE: u8 = 0x12;
+const SECTION_USER_INTERFACE: u8 = 0x15;
+const SECTION_COMPATIBILITY16: u8 = 0x16;
+const SECTION_FIRMWARE_VOLUME_IMAGE: u8 = 0x17;
+
+/// Formats the GUID in the first 16 bytes of `data`.
+pub(crate) fn format_guid(data: &[u8]) -> String {
+    format!(
+        "{:08X}-{:04X}-{:04X}-{}-{}",
+        read_u32(data, 0).unwrap(),
+        read_u16(data, 4).unwrap(),
+        read_u16(data, 6).unwrap(),
+        data[8..10]
+            .iter()
+            .map(|b| format!("{:02X}", b))
+            .collect::<String>(),
+        data[10..16]
+            .iter()
+            .map(|b| format!("{:02X}", b))
+            .collect::<String>(),
+    )
+}
+
+fn align(offset: usize, alignment: usize) -> usize {
+    offset.next_multiple_of(alignment)
+}
+
+/// Returns the length of the volume at `offset` in `data`, if there is one.
+fn volume_len(data: &[u8], offset: usize) -> Option<usize> {
+    let header = data.get(offset..offset + FVH_MIN_SIZE)?;
+    if &header[FVH_SIGNATURE_OFFSET..FVH_SIGNATURE_OFFSET + 4] != FVH_SIGNATURE {
+        return None;
+    }
+    let len = usize::try_from(read_u64(header, 32)?).ok()?;
+    let header_len = read_u16(header, 48)? as usize;
+    if header_len < FVH_MIN_SIZE || len < header_len || len > data.len() - offset {
+        return None;
+    }
+
+    Some(len)
+}
+
+/// Executable sections of an FFS file.
+#[derive(Default)]
+struct FileSections {
+    ui_name: Option<String>,
+    images: Vec<(&'static str, Vec<u8>, Option<usize>)>,
+}
+
+struct Walker<'a> {
+    file: &'a str,
+    /// Compressed sections that decompress to more bytes are skipped.
+    max_size: usize,
+    modules: Vec<(Member, Vec<u8>)>,
+}
+
+impl Walker<'_> {
+    fn walk_volume(&mut self, fv: &[u8], path: &str, offset: Option<usize>, depth: usize) {
+        if depth > MAX_DEPTH {
+            warn!("{}: {} is nested too deeply", self.file, path);
+            return;
+        }
+        let header_len = read_u16(fv, 48).unwrap() as usize;
+        let erased = if read_u32(fv, 44).unwrap() & FVB2_ERASE_POLARITY != 0 {
+            0xff
+        } else {
+            0x00
+        };
+        let mut pos = match read_u16(fv, 52).unwrap() as usize {
+            0 => header_len,
+            ext_offset => match read_u32(fv, ext_offset + 16) {
+                Some(ext_size) => ext_offset + ext_size as usize,
+                None => header_len,
+            },
+        };
+        pos = align(pos, 8);
+        debug!(
+            "{}: {} at {:x?} with {} bytes",
+            self.file,
+            path,
+            offset,
+            fv.len()
+        );
+
+        while pos + 24 <= fv.len() {
+            let header = &fv[pos..pos + 24];
+            // Free space.
+            if header.iter().all(|b| *b == erased) {
+                break;
+            }
+            let attributes = header[19];
+            let (size, header_size) = if attributes & FFS_ATTRIB_LARGE_FILE != 0 {
+                (read_u64(fv, pos + 24).map_or(0, |size| size as usize), 32)
+            } else {
+                (read_u24(header, 20).unwrap() as usize, 24)
+            };
+            if size < header_size || size > fv.len() - pos {
+                warn!("{}: {}: invalid FFS file at 0x{:x}", self.file, path, pos);
+                break;
+            }
+
+            let guid = format_guid(header);
+            let file_type = header[18];
+            let body = &fv[pos + header_size..pos + size];
+            let body_offset = offset.map(|offset| offset + pos + header_size);
+            let file_path = format!("{}/{}", path, guid);
+            match file_type {
+                FILE_TYPE_PAD => {}
+                FILE_TYPE_RAW => self.add_modules(
+                    &guid,
+                    &file_path,
+                    FileSections {
+                        ui_name: None,
+                        images: vec![("RAW", body.to_vec(), body_offset)],
+                    },
+                ),
+                _ => {
+                    let mut sections = FileSections::default();
+                    self.walk_sections(body, &file_path, body_offset, depth, &mut sections);
+                    self.add_modules(&guid, &file_path, sections);
+                }
+            }
+
+            pos = align(pos + size, 8);
+        }
+    }
+
+    fn walk_sections(
+        &mut self,
+        data: &[u8],
+        path: &str,
+        offset: Option<usize>,
+        depth: usize,
+        sections: &mut FileSections,
+    ) {
+        if depth > MAX_DEPTH {
+            warn!("{}: {} is nested too deeply", self.file, path);
+            return;
+        }
+
+        let mut pos = 0;
+        while pos + 4 <= data.len() {
+            let section_type = data[pos + 3];
+            let (size, header_size) = match read_u24(data, pos).unwrap() {
+                0xffffff => (read_u32(data, pos + 4).map_or(0, |size| size as usize), 8),
+                size => (size as usize, 4),
+            };
+            if size < header_size || size > data.len() - pos {
+                warn!("{}: {}: invalid section at 0x{:x}", self.file, path, pos);
+                break;
+            }
+            let body = &data[pos + header_size..pos + size];
+            let body_offset = offset.map(|offset| offset + pos + header_size);
+
+            match section_type {
+                SECTION_COMPRESSION if body.len() >= 5 => match body[4] {
+                    0 => self.walk_sections(
+                        &body[5..],
+                        path,
+                        body_offset.map(|offset| offset + 5),
+                        depth + 1,
+                        sections,
+                    ),
+                    compression => warn!(
+                        "{}: {}: skipping section with compression type {}",
+                        self.file, path, compression
+                    ),
+                },
+                SECTION_GUID_DEFINED if body.len() >= 20 => {
+                    let guid = format_guid(body);
+                    let data_offset = read_u16(body, 16).unwrap() as usize;
+                    let attributes = read_u16(body, 18).unwrap();
+                    let Some(inner) = data.get(pos + data_offset..pos + size) else {
+                        warn!("{}: {}: invalid data offset of {}", self.file, path, guid);
+                        pos = align(pos + size, 4);
+                        continue;
+                    };
+
+                    if guid == LZMA_GUID {
+                        match decompress_stream("lzma", inner, self.max_size) {
+                            Ok((decompressed, _)) => {
+                                self.walk_sections(&decompressed, path, None, depth + 1, sections)
+                            }
+                            Err(e) => warn!("{}: {}: {:#}", self.file, path, e),
+                        }
+                    } else if attributes & GUIDED_SECTION_PROCESSING_REQUIRED == 0 {
+                        self.walk_sections(
+                            inner,
+                            path,
+                            offset.map(|offset| offset + pos + data_offset),
+                            depth + 1,
+                            sections,
+                        );
+                    } else {
+                        warn!(
+                            "{}: {}: skipping section encapsulated by {}",
+                            self.file, path, guid
+                        );
+                    }
+                }
+                SECTION_PE32 => sections.images.push(("PE32", body.to_vec(), body_offset)),
+                SECTION_PIC => sections.images.push(("PIC", body.to_vec(), body_offset)),
+                SECTION_TE => sections.images.push(("TE", body.to_vec(), body_offset)),
+                SECTION_COMPATIBILITY16 => {
+                    sections
+                        .images
+                        .push(("COMPAT16", body.to_vec(), body_offset))
+                }
+                SECTION_USER_INTERFACE => {
+                    let units: Vec<u16> = body
+                        .chunks_exact(2)
+                        .map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+                        .take_while(|unit| *unit != 0)
+                        .collect();
+                    sections.ui_name = Some(String::from_utf16_lossy(&units));
+                }
+                SECTION_FIRMWARE_VOLUME_IMAGE => match volume_len(body, 0) {
+                    Some(len) => self.walk_volume(&body[..len], path, body_offset, depth + 1),
+                    None => warn!("{}: {}: invalid nested volume", self.file, path),
+                },
+                _ => {}
+            }
+
+            pos = align(pos + size, 4);
+        }
+    }
+
+    fn add_modules(&mut self, guid: &str, path: &str, sections: FileSections) {
+        // UI names need not be unique, but GUIDs are.
+        let name = match sections.ui_name {
+            Some(ui_name) => format!("{}_{}", ui_name, guid),
+            None => guid.to_owned(),
+        };
+        let count = sections.images.len();
+        for (idx, (kind, data, offset)) in sections.images.into_iter().enumerate() {
+            let id = if count > 1 {
+                format!("{}_{}", name, idx)
+            } else {
+                name.clone()
+            };
+            self.modules.push((
+                Member {
+                    format: "uefi".to_owned(),
+                    id: id.replace('/', "_"),
+                    path: format!("{}/{}", path, kind),
+                    offset,
+                    load_address: None,
+                    partition: None,
+                },
+                data,
+            ));
+        }
+    }
+}
+
+/// Returns the modules of all firmware volumes in `data`, which is the
+/// content of `file`, together with their content. Compressed sections are
+/// decompressed to at most `max_size` bytes.
+pub fn find_modules(file: &str, data: &[u8], max_size: usize) -> Vec<(Member, Vec<u8>)> {
+    let mut walker = Walker {
+        file,
+        max_size,
+        modules: Vec::new(),
+    };
+
+    let mut volumes = 0;
+    let mut offset = 0;
+    while offset + FVH_MIN_SIZE <= data.len() {
+        match volume_len(data, offset) {
+            Some(len) => {
+                walker.walk_volume(
+                    &data[offset..offset + len],
+                    &format!("FV{}", volumes),
+                    Some(offset),
+                    0,
+                );
+                volumes += 1;
+                offset = align(offset + len, 8);
+            }
+            None => offset += 8,
+        }
+    }
+    debug!(
+        "{}: {} modules in {} volumes",
+        file,
+        walker.modules.len(),
+        volumes
+    );
+
+    walker.modules
+}
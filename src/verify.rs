@@ -0,0 +1,261 @@
+/*
+    Copyright 2024 - Valentin Obst <coderec@vpao.io>
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+*/
+//! Verification of detected regions by disassembling them with capstone.
+//!
+//! Each region whose arch is supported by capstone is disassembled with a
+//! linear sweep. Whenever an invalid instruction is hit, the sweep skips the
+//! smallest instruction size of the arch and continues. Regions where the
+//! share of valid instructions is too low are reported as unknown.
+
+use crate::{is_traced, Arch, ProcessedDetectionResult, TRACE_TARGET};
+
+use std::ops::Range;
+
+use capstone::prelude::*;
+use capstone::Endian;
+use itertools::Itertools;
+use log::{debug, info};
+use serde::{Deserialize, Serialize};
+
+/// Result of disassembling a region.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Verification {
+    pub range: Range<usize>,
+    pub arch: Arch,
+    /// Number of valid instructions.
+    pub instructions: usize,
+    /// Share of valid instructions among all instructions and skipped units.
+    pub valid_ratio: f64,
+    /// Whether the region failed the verification and is now unknown.
+    pub demoted: bool,
+}
+
+/// Returns a disassembler for `arch` and the smallest instruction size of
+/// the arch, or `None` if capstone does not support it.
+fn disassembler(arch: &str) -> Option<(Capstone, usize)> {
+    let (cs, unit) = match arch {
+        "X86" => (
+            Capstone::new()
+                .x86()
+                .mode(arch::x86::ArchMode::Mode32)
+                .build(),
+            1,
+        ),
+        "X86-64" => (
+            Capstone::new()
+                .x86()
+                .mode(arch::x86::ArchMode::Mode64)
+                .build(),
+            1,
+        ),
+        "8086" => (
+            Capstone::new()
+                .x86()
+                .mode(arch::x86::ArchMode::Mode16)
+                .build(),
+            1,
+        ),
+        "ARMel" | "ARMhf" | "ARMeb" => (
+            Capstone::new()
+                .arm()
+                .mode(arch::arm::ArchMode::Arm)
+                .endian(endian(arch))
+                .build(),
+            4,
+        ),
+        "Thumb" => (
+            Capstone::new()
+                .arm()
+                .mode(arch::arm::ArchMode::Thumb)
+                .build(),
+            2,
+        ),
+        "ARM64" => (
+            Capstone::new()
+                .arm64()
+                .mode(arch::arm64::ArchMode::Arm)
+                .build(),
+            4,
+        ),
+        "MIPSel" | "MIPSeb" => (
+            Capstone::new()
+                .mips()
+                .mode(arch::mips::ArchMode::Mips32)
+                .endian(endian(arch))
+                .build(),
+            4,
+        ),
+        "PPCel" | "PPCeb" => (
+            Capstone::new()
+                .ppc()
+                .mode(arch::ppc::ArchMode::Mode32)
+                .endian(endian(arch))
+                .build(),
+            4,
+        ),
+        "RISC-V" => (
+            Capstone::new()
+                .riscv()
+                .mode(arch::riscv::ArchMode::RiscV64)
+                .extra_mode([arch::riscv::ArchExtraMode::RiscVC].into_iter())
+                .build(),
+            2,
+        ),
+        "SPARC" => (
+            Capstone::new()
+                .sparc()
+                .mode(arch::sparc::ArchMode::Default)
+                .build(),
+            4,
+        ),
+        "M68k" => (
+            Capstone::new()
+                .m68k()
+                .mode(arch::m68k::ArchMode::M68k040)
+                .build(),
+            2,
+        ),
+        "S-390" => (
+            Capstone::new()
+                .sysz()
+                .mode(arch::sysz::ArchMode::Default)
+                .build(),
+            2,
+        ),
+        "68HC08" => (
+            Capstone::new()
+                .m680x()
+                .mode(arch::m680x::ArchMode::M680x6808)
+                .build(),
+            1,
+        ),
+        "68HC11" => (
+            Capstone::new()
+                .m680x()
+                .mode(arch::m680x::ArchMode::M680x6811)
+                .build(),
+            1,
+        ),
+        "TMS320C6x" => (
+            Capstone::new()
+                .tms320c64x()
+                .mode(arch::tms320c64x::ArchMode::Default)
+                .build(),
+            4,
+        ),
+        _ => return None,
+    };
+
+    Some((cs.ok()?, unit))
+}
+
+fn endian(arch: &str) -> Endian {
+    if arch.ends_with("eb") {
+        Endian::Big
+    } else {
+        Endian::Little
+    }
+}
+
+/// Disassembles `code` with a linear sweep and returns the number of valid
+/// instructions and the number of skipped units.
+fn linear_sweep(cs: &Capstone, unit: usize, code: &[u8]) -> (usize, usize) {
+    let mut valid = 0;
+    let mut invalid = 0;
+    let mut offset = 0;
+    while offset + unit <= code.len() {
+        // Stops at the first invalid instruction.
+        let decoded: usize = match cs.disasm_all(&code[offset..], offset as u64) {
+            Ok(insns) => {
+                valid += insns.len();
+                insns.iter().map(|insn| insn.len()).sum()
+            }
+            Err(_) => 0,
+        };
+        offset += decoded;
+        if offset + unit <= code.len() {
+            invalid += 1;
+            offset += unit;
+        }
+    }
+
+    (valid, invalid)
+}
+
+/// Disassembles the regions of `res` whose arch is supported and demotes
+/// those with a share of valid instructions below `min_ratio` to unknown.
+/// `data` are the analyzed bytes.
+pub fn verify_regions(
+    res: &mut ProcessedDetectionResult,
+    data: &[u8],
+    min_ratio: f64,
+) -> Vec<Verification> {
+    let mut segments = res.segments.clone();
+    let mut verifications = Vec::new();
+
+    let runs = segments.iter_mut().chunk_by(|segment| segment.arch.clone());
+    for (arch_op, run) in runs.into_iter() {
+        let Some(arch) = arch_op else {
+            continue;
+        };
+        let Some((cs, unit)) = disassembler(&arch) else {
+            continue;
+        };
+        let mut run: Vec<_> = run.collect();
+        let range = run.first().unwrap().range.start..run.last().unwrap().range.end;
+
+        let (valid, invalid) = linear_sweep(&cs, unit, &data[range.clone()]);
+        let valid_ratio = if valid + invalid == 0 {
+            0.0
+        } else {
+            valid as f64 / (valid + invalid) as f64
+        };
+        let demoted = valid_ratio < min_ratio;
+
+        if is_traced(&res.trace, &range) {
+            info!(
+                target: TRACE_TARGET,
+                "{:x?}: {} valid and {} invalid {} instructions",
+                range,
+                valid,
+                invalid,
+                arch
+            );
+        }
+        if demoted {
+            debug!(
+                "{:x?}: demoted {} region, {:.2} valid instructions",
+                range, arch, valid_ratio
+            );
+            for segment in run.iter_mut() {
+                segment.arch = None;
+                segment.confidence = 0.0;
+            }
+        }
+
+        verifications.push(Verification {
+            range,
+            arch,
+            instructions: valid,
+            valid_ratio,
+            demoted,
+        });
+    }
+
+    res.set_segments(segments);
+
+    verifications
+}
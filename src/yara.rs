@@ -0,0 +1,160 @@
+/*
+    Copyright 2024 - Valentin Obst <coderec@vpao.io>
+
+    Licensed under the Apache License, Version 2.0 (the "License");
+    you may not use this file except in compliance with the License.
+    You may obtain a copy of the License at
+
+        http://www.apache.org/licenses/LICENSE-2.0
+
+    Unless required by applicable law or agreed to in writing, software
+    distributed under the License is distributed on an "AS IS" BASIS,
+    WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+    See the License for the specific language governing permissions and
+    limitations under the License.
+*/
+//! YARA rules, see `--emit yara`.
+//!
+//! Each region gets a rule with literal snippets of its bytes, such that the
+//! same embedded code can be hunted for in other samples. Generic n-grams of
+//! the arch would match all code of the arch, so the snippets are taken
+//! evenly spread over the region instead, each the one of its part with the
+//! most distinct bytes, which skips padding and tables of small values. Half
+//! of the snippets must match, which tolerates patched or relocated code.
+
+use crate::emit::{comment, PlacedRegion};
+
+use std::cmp::Reverse;
+use std::fmt::Write;
+
+/// Length of the snippets in bytes.
+const SNIPPET_LEN: usize = 16;
+/// Largest number of snippets per rule.
+const MAX_SNIPPETS: usize = 8;
+/// Snippets with fewer distinct bytes are not characteristic.
+const MIN_DISTINCT_BYTES: usize = 8;
+/// Distance between the candidate snippets.
+const SNIPPET_STEP: usize = 4;
+
+fn distinct_bytes(snippet: &[u8]) -> usize {
+    let mut seen = [false; 256];
+    for byte in snippet {
+        seen[*byte as usize] = true;
+    }
+
+    seen.iter().filter(|seen| **seen).count()
+}
+
+/// Returns the characteristic snippets of `code`.
+fn snippets(code: &[u8]) -> Vec<&[u8]> {
+    let part_len = code.len() / MAX_SNIPPETS;
+    if part_len < SNIPPET_LEN {
+        return code
+            .chunks_exact(SNIPPET_LEN)
+            .filter(|snippet| distinct_bytes(snippet) >= MIN_DISTINCT_BYTES)
+            .take(MAX_SNIPPETS)
+            .collect();
+    }
+
+    code.chunks_exact(part_len)
+        .filter_map(|part| {
+            part.windows(SNIPPET_LEN)
+                .step_by(SNIPPET_STEP)
+                .map(|snippet| (distinct_bytes(snippet), snippet))
+                .filter(|(distinct, _)| *distinct >= MIN_DISTINCT_BYTES)
+                .min_by_key(|(distinct, _)| Reverse(*distinct))
+                .map(|(_, snippet)| snippet)
+        })
+        .collect()
+}
+
+/// Quotes `s` as a YARA string, which only knows `\"`, `\\`, and `\xHH`
+/// escapes for other bytes.
+fn string(s: &str) -> String {
+    let mut string = String::from("\"");
+    for byte in s.bytes() {
+        match byte {
+            b'"' => string.push_str("\\\""),
+            b'\\' => string.push_str("\\\\"),
+            b' '..=b'~' => string.push(byte as char),
+            _ => write!(string, "\\x{:02x}", byte).unwrap(),
+        }
+    }
+    string.push('"');
+
+    string
+}
+
+/// Valid YARA identifier for `name`.
+fn identifier(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect()
+}
+
+/// Returns the rules for `regions` of `file`, whose bytes are in `data`
+/// starting at offset `offset` in the file.
+pub fn rules(file: &str, data: &[u8], offset: u64, regions: &[PlacedRegion]) -> String {
+    let file_name = file.rsplit('/').next().unwrap();
+
+    let mut rules = String::new();
+    writeln!(
+        rules,
+        "// Regions of {} detected by coderec {}.",
+        comment(file_name),
+        env!("CARGO_PKG_VERSION")
+    )
+    .unwrap();
+    for region in regions {
+        let start = (region.offset - offset) as usize;
+        let snippets = snippets(&data[start..start + region.size]);
+        if snippets.is_empty() {
+            writeln!(
+                rules,
+                "\n// {:#x}: {} has no characteristic snippets",
+                region.offset, region.arch
+            )
+            .unwrap();
+            continue;
+        }
+
+        writeln!(rules).unwrap();
+        writeln!(
+            rules,
+            "rule coderec_{}_{:x}_{}",
+            identifier(file_name),
+            region.offset,
+            identifier(region.arch)
+        )
+        .unwrap();
+        writeln!(rules, "{{").unwrap();
+        writeln!(rules, "    meta:").unwrap();
+        writeln!(rules, "        file = {}", string(file_name)).unwrap();
+        writeln!(rules, "        offset = \"{:#x}\"", region.offset).unwrap();
+        writeln!(rules, "        size = {}", region.size).unwrap();
+        writeln!(rules, "        arch = {}", string(region.arch)).unwrap();
+        writeln!(rules, "        confidence = \"{:.2}\"", region.confidence).unwrap();
+        writeln!(rules, "    strings:").unwrap();
+        for (idx, snippet) in snippets.iter().enumerate() {
+            let hex: Vec<String> = snippet.iter().map(|byte| format!("{:02X}", byte)).collect();
+            writeln!(rules, "        $s{} = {{ {} }}", idx, hex.join(" ")).unwrap();
+        }
+        writeln!(rules, "    condition:").unwrap();
+        writeln!(rules, "        {} of them", snippets.len().div_ceil(2)).unwrap();
+        writeln!(rules, "}}").unwrap();
+    }
+
+    rules
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn string_escapes() {
+        assert_eq!(string("fw.bin"), r#""fw.bin""#);
+        assert_eq!(string(r#"a"b\c"#), r#""a\"b\\c""#);
+        assert_eq!(string("a\nb\u{1}\u{e9}"), r#""a\x0ab\x01\xc3\xa9""#);
+    }
+}